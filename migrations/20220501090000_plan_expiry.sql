@@ -0,0 +1,6 @@
+-- Adds a scheduled downgrade date for account plans.
+
+alter table accounts add column if not exists plan_expires_at timestamp with time zone;
+
+create index accounts_plan_expires_at_idx on accounts (plan_expires_at)
+where plan_expires_at is not null;
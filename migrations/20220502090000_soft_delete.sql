@@ -0,0 +1,3 @@
+-- Marks soft-deleted accounts.
+
+alter table accounts add column if not exists deleted_at timestamp with time zone;
@@ -0,0 +1,9 @@
+-- Stores a canonical form of each account's email, so that one mailbox
+-- can't register several accounts through aliases such as user+tag@.
+-- How addresses are canonicalized is set by `accounts.email_aliases`.
+
+alter table accounts add column if not exists canonical_email text;
+
+update accounts set canonical_email = lower(email) where canonical_email is null;
+
+create unique index if not exists accounts_unique_canonical_email_idx on accounts (canonical_email);
@@ -0,0 +1,4 @@
+-- Adds a per-account session generation. Bumping it invalidates every
+-- session issued before, e.g. to log out everywhere.
+
+alter table accounts add column if not exists session_version integer not null default 0;
@@ -0,0 +1,11 @@
+-- Records recent sends of each kind of email to each address, so that
+-- jobs can skip sending the same email again within a cooldown window.
+
+create table if not exists email_sends (
+    id serial primary key,
+    template text not null,
+    recipient text not null,
+    sent_at timestamp with time zone not null default now()
+);
+
+create index if not exists email_sends_template_recipient_idx on email_sends (template, recipient, sent_at);
@@ -0,0 +1,12 @@
+-- Records which template variant each account was assigned for an email
+-- type, for A/B tests, so that follow-ups use the same variant.
+
+create table if not exists email_variants (
+    account_id integer not null references accounts (id) on delete cascade,
+    email_type text not null,
+    variant text not null,
+    send_count integer not null default 0,
+    assigned_at timestamp with time zone not null default now(),
+    last_sent_at timestamp with time zone,
+    primary key (account_id, email_type)
+);
@@ -0,0 +1,6 @@
+-- Tracks whether the verification email reached each account's address:
+-- 0 queued, 1 sent, 2 bounced. Existing accounts are assumed delivered.
+
+alter table accounts add column if not exists email_delivery_status integer not null default 1;
+
+alter table accounts alter column email_delivery_status set default 0;
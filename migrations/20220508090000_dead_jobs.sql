@@ -0,0 +1,13 @@
+-- Jobs that failed in a way retrying can't fix, e.g. an email to an
+-- address that hard bounced, kept for inspection instead of retried.
+
+create table if not exists dead_jobs (
+  id uuid primary key,
+  created_at timestamp with time zone not null,
+  died_at timestamp with time zone not null default now(),
+  failed_attempts int not null,
+  message jsonb not null,
+  error text not null
+);
+
+create index index_dead_jobs_on_died_at on dead_jobs (died_at);
@@ -0,0 +1,5 @@
+-- Lets jobs be split into named queues, each served by its own worker.
+
+alter table queue add column if not exists queue_name text not null default 'default';
+
+create index index_queue_on_queue_name_status on queue (queue_name, status);
@@ -0,0 +1,6 @@
+-- Jobs with a higher priority are pulled first; 0 keeps the old
+-- first-scheduled, first-run order.
+
+alter table queue add column if not exists priority int not null default 0;
+
+create index index_queue_on_priority_scheduled_for on queue (queue_name, status, priority desc, scheduled_for);
@@ -0,0 +1,9 @@
+-- For password rotation policies: when each password was set, when its
+-- owner was last reminded to change it, and whether they must change it
+-- before logging in again. Existing passwords count from now.
+
+alter table accounts add column if not exists password_changed_at timestamp with time zone not null default now();
+
+alter table accounts add column if not exists password_expiry_reminded_at timestamp with time zone;
+
+alter table accounts add column if not exists must_change_password boolean not null default false;
@@ -0,0 +1,6 @@
+-- Keeps each identity's provider access token and when it expires, for
+-- calling the provider's API on the user's behalf later.
+
+alter table identities add column if not exists access_token text;
+
+alter table identities add column if not exists access_token_expires_at timestamp with time zone;
@@ -0,0 +1,11 @@
+-- OAuth authorizations in progress, keyed by the state nonce sent to the
+-- provider, for when `oauth.flow_storage` is "database". Rows are deleted
+-- when the callback uses them, and expire after a few minutes.
+
+create table if not exists oauth_flows (
+  state text primary key,
+  flow jsonb not null,
+  created_at timestamp with time zone not null default now()
+);
+
+create index index_oauth_flows_on_created_at on oauth_flows (created_at);
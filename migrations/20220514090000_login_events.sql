@@ -0,0 +1,12 @@
+-- Successful logins, for showing users their recent sign-ins. Only
+-- recorded when `accounts.record_logins` is on.
+
+create table if not exists login_events (
+    id serial primary key,
+    account_id int not null references accounts (id) on delete cascade,
+    ip text,
+    user_agent text,
+    created timestamp with time zone not null default now()
+);
+
+create index index_login_events_on_account_id_created on login_events (account_id, created desc);
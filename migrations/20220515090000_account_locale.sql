@@ -0,0 +1,4 @@
+-- The language to send an account's emails in, e.g. "fr" or "pt-BR".
+-- Null means the default, `EMAIL_DEFAULT_LOCALE`.
+
+alter table accounts add column if not exists locale text;
@@ -0,0 +1,11 @@
+-- Idempotency keys for queued jobs: while a key is unexpired, pushing
+-- another job under it returns the first job's id instead. Kept apart
+-- from `queue` so keys outlive the jobs, which are deleted when done.
+
+create table if not exists job_keys (
+    key text primary key,
+    job_id uuid not null,
+    expires_at timestamp with time zone not null
+);
+
+create index if not exists job_keys_expires_at_idx on job_keys (expires_at);
@@ -0,0 +1,17 @@
+-- Passkeys (WebAuthn credentials) registered to accounts. `passkey` is the
+-- credential as the webauthn library stores it, including its public key
+-- and signature counter. Every credential of an account shares the same
+-- `user_handle`, the random id authenticators know the account by.
+
+create table if not exists webauthn_credentials (
+    id serial primary key,
+    account_id int not null references accounts (id) on delete cascade,
+    user_handle uuid not null,
+    credential_id text not null unique,
+    name text not null,
+    passkey jsonb not null,
+    created timestamp with time zone not null default now(),
+    last_used_at timestamp with time zone
+);
+
+create index index_webauthn_credentials_on_account_id on webauthn_credentials (account_id);
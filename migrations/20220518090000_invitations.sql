@@ -0,0 +1,17 @@
+-- Invitations to register, for `accounts.registration = "invite_only"`.
+-- `token_hash` is the SHA-256 of the token sent in the invitation email,
+-- so the tokens themselves aren't kept. An invitation is bound to its
+-- `email`, stored as `accounts.email` is, and is used up once an account
+-- registers with it.
+
+create table if not exists invitations (
+    id serial primary key,
+    email text not null,
+    token_hash text not null unique,
+    inviter_id int references accounts (id) on delete set null,
+    created timestamp with time zone not null default now(),
+    expires_at timestamp with time zone not null,
+    consumed_at timestamp with time zone
+);
+
+create index index_invitations_on_email on invitations (email);
@@ -0,0 +1,13 @@
+-- Stores emails normalized, with their domain lowercased, as registration
+-- now does, and gives accounts registered through OAuth, which had none,
+-- a canonical email, unless another account has it already. Encrypted
+-- values (see `pii`) can't be changed here; those accounts are still found
+-- by their address as registered.
+
+update accounts
+set email = substring(email from '^(.*)@') || lower(substring(email from '@[^@]*$'))
+where email not like 'enc:%' and email ~ '@[^@]*[A-Z][^@]*$';
+
+update accounts set canonical_email = lower(email)
+where canonical_email is null and email not like 'enc:%'
+  and not exists (select 1 from accounts other where other.canonical_email = lower(accounts.email));
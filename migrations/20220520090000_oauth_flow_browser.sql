@@ -0,0 +1,7 @@
+-- Ties each stored OAuth flow to the browser that started it, by a hash of
+-- a nonce kept in that browser's private cookie. Flows started before this
+-- can't be checked, so they are dropped; they expire within minutes anyway.
+
+delete from oauth_flows;
+
+alter table oauth_flows add column browser_hash text not null;
@@ -0,0 +1,7 @@
+-- Soft deleted accounts kept their email, so the address couldn't register
+-- again. They now get a tombstone address at a reserved domain instead, as
+-- `Account::delete` does from now on.
+
+update accounts
+set email = 'deleted-' || id || '@deleted.invalid', canonical_email = null
+where deleted_at is not null;
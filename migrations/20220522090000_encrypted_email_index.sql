@@ -0,0 +1,11 @@
+-- The unique index on lower(email) can't hold emails encrypted by the
+-- pii-encryption feature: their base64url ciphertext is case sensitive,
+-- so lowering it can make different addresses collide. Only plaintext
+-- emails are compared ignoring case now. Encrypted ones are normalized
+-- before they are encrypted, and the unique canonical_email covers
+-- addresses differing only in case.
+
+drop index if exists accounts_unique_lower_email_idx;
+
+create unique index if not exists accounts_unique_email_idx on accounts
+    ((case when email like 'enc:%' then email else lower(email) end));
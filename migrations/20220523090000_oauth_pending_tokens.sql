@@ -0,0 +1,12 @@
+-- Provider tokens from an OAuth callback, kept until the user confirms
+-- their details and the tokens are saved on the identity. The browser only
+-- holds the key, in a private cookie; rows are stored under a hash of it,
+-- and expire after a few minutes.
+
+create table if not exists oauth_pending_tokens (
+  key_hash text primary key,
+  tokens text not null,
+  created_at timestamp with time zone not null default now()
+);
+
+create index index_oauth_pending_tokens_on_created_at on oauth_pending_tokens (created_at);
@@ -0,0 +1,13 @@
+-- Recurring jobs are the unit variants of `Message`, stored as a bare JSON
+-- string. Only one of each may be queued at a time, so that servers
+-- starting together can't both seed one, and each run only reschedules
+-- the next if no other is waiting.
+
+delete from queue as a using queue as b
+  where a.status = 0 and b.status = 0
+    and jsonb_typeof(a.message) = 'string'
+    and a.message = b.message
+    and (a.failed_attempts, a.id) > (b.failed_attempts, b.id);
+
+create unique index index_queue_on_recurring_message on queue (message)
+  where status = 0 and jsonb_typeof(message) = 'string';
@@ -0,0 +1,9 @@
+-- One send per kind of email and address, so that two jobs claiming the
+-- same send at once can't both get it.
+
+delete from email_sends as a using email_sends as b
+  where a.template = b.template and a.recipient = b.recipient and a.id < b.id;
+
+create unique index if not exists email_sends_unique_template_recipient_idx on email_sends (template, recipient);
+
+drop index if exists email_sends_template_recipient_idx;
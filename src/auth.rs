@@ -1,8 +1,22 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
 use anyhow::anyhow;
-use rocket::http::{Cookie, CookieJar};
+use chrono::Utc;
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::time::Duration;
+use serde::{Deserialize, Serialize};
 use serde_json;
 
-use crate::models::User;
+use rocket_db_pools::Connection;
+
+use crate::config::AppConfig;
+use crate::database::AppDb;
+use crate::jobs::{Message, PostgresQueue};
+use crate::models::{Account, SessionState, User};
 use crate::error;
 
 /// `Authentication` is kind of a request guard - it returns a Future which will resolve
@@ -10,24 +24,317 @@ use crate::error;
 /// that'd tie them to a user profile, or if the session cache can't be read, or if the database
 /// has issues, or... pick your poison I guess.
 
+/// What's stored in the private session cookie. The cookie's `max_age`
+/// is only a hint to the browser, so the expiry is checked against the
+/// payload as well.
+#[derive(Debug, Serialize, Deserialize)]
+struct Session {
+    user: User,
+    issued_at: i64,
+    ttl: i64,
+}
+
+impl Session {
+    fn is_expired(&self) -> bool {
+        Utc::now().timestamp() >= self.issued_at + self.ttl
+    }
+}
+
 #[inline(always)]
 pub fn is_authenticated(cookies: &CookieJar) -> bool {
-    cookies.get_private("sku").is_some()
+    matches!(user(cookies), Ok(user) if !user.is_anonymous)
+}
+
+/// Logs in `user`. Sessions expire after `accounts.session_ttl` seconds,
+/// in a cookie the browser drops when it closes. With `remember` set, they
+/// last `accounts.session_remember_ttl` seconds instead, in a cookie that
+/// persists that long. Either way the session still ends early when the password
+/// changes or the user logs out everywhere; see the `User` guard. The
+/// cookie gets the domain and path from `cookies` in `config`.
+pub fn set_user(cookies: &CookieJar, config: &AppConfig, user: User, remember: bool) {
+    let ttl = config.accounts.session_lifetime(remember);
+    let session = Session {
+        user,
+        issued_at: Utc::now().timestamp(),
+        ttl,
+    };
+
+    let mut cookie = Cookie::new("sku", serde_json::json!(session).to_string());
+    if remember {
+        cookie.set_max_age(Duration::seconds(ttl));
+    }
+    cookies.add_private(config.cookies.scoped(cookie));
 }
 
-pub fn set_user(cookies: &CookieJar, user: User) {
-    cookies.add_private(
-        Cookie::new("sku", serde_json::json!(user).to_string()));
+/// Shown when a login is refused by `password_expired`.
+pub const PASSWORD_EXPIRED_MESSAGE: &str = "your password has expired; we've emailed you a link to choose a new one";
+
+/// Seconds within which another login past the password rotation
+/// deadline doesn't queue another reset email.
+const PASSWORD_EXPIRED_EMAIL_WINDOW: i64 = 600;
+
+/// Whether the account is past the password rotation deadline, and so
+/// can't log in, however it authenticates, until it sets a new password.
+/// If so, a link to set one is emailed, at most once per
+/// `PASSWORD_EXPIRED_EMAIL_WINDOW`. Check it before `set_user` for every
+/// new login; the `User` guard ends existing sessions.
+pub async fn password_expired(account_id: i32, queue: &PostgresQueue, conn: &mut sqlx::PgConnection) -> error::Result<bool> {
+    if !Account::must_change_password(account_id, conn).await? {
+        return Ok(false);
+    }
+
+    let email = Account::get(account_id, conn).await?.email;
+    let _ignore = queue.push_idempotent(
+        &format!("password-expired:{}", email.to_lowercase()),
+        PASSWORD_EXPIRED_EMAIL_WINDOW,
+        Message::SendResetPasswordEmail(email),
+    ).await;
+    Ok(true)
 }
 
-pub fn clear_user(cookies: &CookieJar) {
-    cookies.remove_private(Cookie::named("sku"));
+pub fn clear_user(cookies: &CookieJar, config: &AppConfig) {
+    cookies.remove_private(config.cookies.scoped(Cookie::named("sku")));
 }
 
+/// The logged in user, or an anonymous one if there is no session or
+/// it has expired.
 pub fn user(cookies: &CookieJar) -> error::Result<User> {
     match cookies.get_private("sku") {
-        Some(cookie) => serde_json::from_str::<User>(cookie.value())
+        Some(cookie) => serde_json::from_str::<Session>(cookie.value())
+            .map(|session| if session.is_expired() { User::default() } else { session.user })
             .map_err(|_| error::Error::from(anyhow!("corrupt session cookie"))),
         None => Ok(User::default()),
     }
 }
+
+/// Request guard for routes that require a logged in user. Anonymous
+/// requests forward, so that a lower ranked route on the same path can
+/// serve guests; see `routes::guests` for the ones that send them to the
+/// login page.
+///
+/// Sessions whose fingerprint or version no longer match the account
+/// are cleared and forward the same way, so a password change or logging
+/// out everywhere ends them, within `accounts.session_cache_ttl`.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = error::Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match user(req.cookies()) {
+            Ok(user) if !user.is_anonymous => {
+                let default_config = AppConfig::default();
+                let config = req.rocket().state::<AppConfig>().unwrap_or(&default_config);
+
+                match current_session(req, user.id, config.accounts.session_cache_ttl).await {
+                    Ok(Some(current)) if current.matches(&user) => Outcome::Success(user),
+                    Ok(_) => {
+                        clear_user(req.cookies(), config);
+                        Outcome::Forward(())
+                    },
+                    Err(e) => Outcome::Failure((Status::InternalServerError, e)),
+                }
+            },
+            Ok(_) => Outcome::Forward(()),
+            Err(e) => Outcome::Failure((Status::Unauthorized, e)),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Recently checked session states, by account id, for
+    /// `accounts.session_cache_ttl`.
+    static ref SESSION_CACHE: Mutex<HashMap<i32, (Instant, Option<SessionState>)>> = Mutex::new(HashMap::new());
+}
+
+/// Drops the cached session state for an account, after changing it.
+/// The cache is per process, so other app servers keep theirs until it
+/// is `accounts.session_cache_ttl` old.
+pub fn forget_session(id: i32) {
+    SESSION_CACHE.lock().unwrap().remove(&id);
+}
+
+/// Looks up the account's current session state, at most once per
+/// request, and from the cache if it is fresh enough.
+async fn current_session(req: &Request<'_>, id: i32, cache_ttl: u64) -> error::Result<Option<SessionState>> {
+    if cache_ttl > 0 {
+        if let Some((checked, state)) = SESSION_CACHE.lock().unwrap().get(&id) {
+            if checked.elapsed().as_secs() < cache_ttl {
+                return Ok(state.clone());
+            }
+        }
+    }
+
+    struct Cached(Option<SessionState>);
+
+    let cached = req.local_cache_async(async {
+        let mut db = match req.guard::<Connection<AppDb>>().await {
+            Outcome::Success(db) => db,
+            _ => return None,
+        };
+        Account::current_session(id, db.as_mut()).await
+            .ok()
+            .map(Cached)
+    }).await;
+
+    let state = cached.as_ref()
+        .map(|cached| cached.0.clone())
+        .ok_or_else(|| error::Error::from(anyhow!("could not check session")))?;
+
+    if cache_ttl > 0 {
+        SESSION_CACHE.lock().unwrap().insert(id, (Instant::now(), state.clone()));
+    }
+
+    Ok(state)
+}
+
+/// Request guard for admin-only routes. Anonymous requests forward, as
+/// for `User`; logged in users who aren't admins get `Forbidden`.
+#[derive(Debug)]
+pub struct AdminUser(pub User);
+
+impl AdminUser {
+    pub fn user(&self) -> &User {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = error::Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = try_outcome!(req.guard::<User>().await);
+        if user.is_admin {
+            Outcome::Success(AdminUser(user))
+        } else {
+            Outcome::Failure((Status::Forbidden,
+                error::Error::with_status(anyhow!("admin only"), Status::Forbidden)))
+        }
+    }
+}
+
+/// Request guard that only succeeds for anonymous requests, for the guest
+/// side of a path that also has a `User` route. Logged in users forward.
+#[derive(Debug)]
+pub struct Guest;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Guest {
+    type Error = error::Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if is_authenticated(req.cookies()) {
+            Outcome::Forward(())
+        } else {
+            Outcome::Success(Guest)
+        }
+    }
+}
+
+/// The longest `User-Agent` kept in the login log.
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
+/// Where a request came from, for the login log: the client's IP address
+/// and `User-Agent`, when known.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    /// Adds a login to the account's log, if `accounts.record_logins` is
+    /// on. Failing to is only logged, as the login itself succeeded.
+    pub async fn record_login(&self, config: &AppConfig, account_id: i32, conn: &mut sqlx::PgConnection) {
+        if !config.accounts.record_logins {
+            return;
+        }
+
+        let recorded = Account::record_login(account_id, self.ip.as_deref(), self.user_agent.as_deref(), conn).await;
+        if let Err(e) = recorded {
+            rocket::error!("could not record login for account {}: {}", account_id, e);
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientInfo {
+            ip: req.client_ip().map(|ip| ip.to_string()),
+            user_agent: req.headers().get_one("User-Agent")
+                .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::asynchronous::Client;
+    use rocket::{get, routes, State};
+
+    use super::*;
+
+    #[get("/login?<remember>")]
+    fn login(cookies: &CookieJar<'_>, config: &State<AppConfig>, remember: bool) {
+        set_user(cookies, config, User { id: 1, is_anonymous: false, ..User::default() }, remember);
+    }
+
+    async fn client(config: AppConfig) -> Client {
+        Client::tracked(rocket::build().manage(config).mount("/", routes![login])).await.unwrap()
+    }
+
+    fn config() -> AppConfig {
+        let mut config = AppConfig::default();
+        config.accounts.session_ttl = 60;
+        config.accounts.session_remember_ttl = 3600;
+        config
+    }
+
+    #[test]
+    fn session_lifetimes_default_to_a_day_or_a_month() {
+        let accounts = AppConfig::default().accounts;
+        assert_eq!(accounts.session_lifetime(false), 86400);
+        assert_eq!(accounts.session_lifetime(true), 30 * 86400);
+    }
+
+    #[test]
+    fn sessions_expire_after_their_ttl() {
+        let session = Session { user: User::default(), issued_at: Utc::now().timestamp() - 61, ttl: 60 };
+        assert!(session.is_expired());
+        assert!(!Session { ttl: 3600, ..session }.is_expired());
+    }
+
+    #[rocket::async_test]
+    async fn remembered_sessions_last_the_configured_time() {
+        let client = client(config()).await;
+        let response = client.get("/login?remember=true").dispatch().await;
+        let cookie = response.cookies().get("sku").unwrap();
+        assert_eq!(cookie.max_age(), Some(Duration::seconds(3600)));
+    }
+
+    #[rocket::async_test]
+    async fn other_sessions_end_with_the_browser() {
+        let client = client(config()).await;
+        let response = client.get("/login?remember=false").dispatch().await;
+        let cookie = response.cookies().get("sku").unwrap();
+        assert_eq!(cookie.max_age(), None);
+
+        let session: Session = serde_json::from_str(client.cookies().get_private("sku").unwrap().value()).unwrap();
+        assert_eq!(session.ttl, 60);
+    }
+
+    #[rocket::async_test]
+    async fn session_cookie_gets_the_configured_scope() {
+        let mut config = config();
+        config.cookies = serde_json::from_value(serde_json::json!({ "domain": "example.com", "path": "/app" })).unwrap();
+        let client = client(config).await;
+
+        let response = client.get("/login?remember=false").dispatch().await;
+        let cookie = response.cookies().get("sku").unwrap();
+        assert_eq!(cookie.domain(), Some("example.com"));
+        assert_eq!(cookie.path(), Some("/app"));
+    }
+}
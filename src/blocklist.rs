@@ -0,0 +1,209 @@
+//! Which email domains can register, checked at registration, including
+//! OAuth registrations.
+//!
+//! The `email_domains` config section restricts registration to some
+//! domains, e.g. a company's own, or refuses others:
+//!
+//! ```toml
+//! [default.email_domains]
+//! allow = ["example.com"]
+//! deny = ["example.net"]
+//! block_disposable = true
+//! ```
+//!
+//! With `block_disposable`, a small built-in list of disposable email
+//! providers is refused too. Whatever the config, domains from
+//! `DISPOSABLE_EMAIL_DOMAINS` (comma separated) and from the file named
+//! by `DISPOSABLE_EMAIL_DOMAINS_FILE` (one per line, `#` starts a comment)
+//! are refused, to cover providers the built-in list misses. Every list
+//! covers subdomains of its domains as well.
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+
+use rocket::form;
+use rocket::form::Error;
+use serde::{Deserialize, Serialize};
+
+/// Well known disposable email providers, refused with
+/// `block_disposable`.
+const BUILTIN_DISPOSABLE_DOMAINS: [&str; 16] = [
+    "10minutemail.com",
+    "discard.email",
+    "dispostable.com",
+    "emailondeck.com",
+    "fakeinbox.com",
+    "getnada.com",
+    "guerrillamail.com",
+    "maildrop.cc",
+    "mailinator.com",
+    "mintemail.com",
+    "mohmal.com",
+    "sharklasers.com",
+    "temp-mail.org",
+    "throwawaymail.com",
+    "trashmail.com",
+    "yopmail.com",
+];
+
+/// Shown when a domain is refused, without saying which list it's on.
+const DOMAIN_NOT_ACCEPTED_MESSAGE: &str = "email addresses from this domain are not accepted";
+
+lazy_static::lazy_static! {
+    static ref BLOCKED_DOMAINS: HashSet<String> = load();
+    static ref DISPOSABLE_DOMAINS: HashSet<String> = BUILTIN_DISPOSABLE_DOMAINS.iter()
+        .map(|domain| domain.to_string())
+        .collect();
+}
+
+/// Lowercases and converts a domain to its ASCII (punycode) form, so
+/// that e.g. "MAILINATOR.com" and unicode lookalikes are compared in
+/// the same form the blocklist is stored in.
+pub fn normalize_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('.');
+    if domain.is_empty() {
+        return None;
+    }
+
+    idna::domain_to_ascii(domain).ok()
+}
+
+fn parse_list(list: &str, separator: char) -> impl Iterator<Item = String> + '_ {
+    list.split(separator)
+        .map(|entry| entry.split('#').next().unwrap_or_default())
+        .filter_map(normalize_domain)
+}
+
+fn load() -> HashSet<String> {
+    let mut domains = HashSet::new();
+
+    if let Ok(list) = env::var("DISPOSABLE_EMAIL_DOMAINS") {
+        domains.extend(parse_list(&list, ','));
+    }
+
+    if let Ok(path) = env::var("DISPOSABLE_EMAIL_DOMAINS_FILE") {
+        match fs::read_to_string(&path) {
+            Ok(list) => domains.extend(parse_list(&list, '\n')),
+            Err(e) => rocket::error!("Could not read disposable email domains from {}: {}", path, e),
+        }
+    }
+
+    domains
+}
+
+/// A normalized domain, followed by each domain it is a subdomain of,
+/// e.g. "a.example.com", "example.com", "com".
+fn with_parents(domain: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(domain), |rest| rest.split_once('.').map(|(_, parent)| parent))
+}
+
+/// Whether a normalized domain is one of `listed`, or a subdomain of one.
+fn is_listed(domain: &str, listed: &[String]) -> bool {
+    let listed: Vec<String> = listed.iter().filter_map(|entry| normalize_domain(entry)).collect();
+    with_parents(domain).any(|candidate| listed.iter().any(|entry| entry == candidate))
+}
+
+/// Whether the domain, or any domain it is a subdomain of, is in the
+/// `DISPOSABLE_EMAIL_DOMAINS` lists.
+pub fn is_blocked_domain(domain: &str) -> bool {
+    match normalize_domain(domain) {
+        Some(domain) => with_parents(&domain).any(|candidate| BLOCKED_DOMAINS.contains(candidate)),
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EmailDomainPolicy {
+    /// If any are set, only these domains can register.
+    pub allow: Vec<String>,
+    /// Domains that can't register.
+    pub deny: Vec<String>,
+    /// Also refuse the built-in list of disposable email providers.
+    pub block_disposable: bool,
+}
+
+impl EmailDomainPolicy {
+    /// Whether addresses at `domain` can register.
+    pub fn allows(&self, domain: &str) -> bool {
+        let domain = match normalize_domain(domain) {
+            Some(domain) => domain,
+            None => return false,
+        };
+
+        if !self.allow.is_empty() && !is_listed(&domain, &self.allow) {
+            return false;
+        }
+
+        let disposable = self.block_disposable
+            && with_parents(&domain).any(|candidate| DISPOSABLE_DOMAINS.contains(candidate));
+
+        !disposable && !is_listed(&domain, &self.deny) && !is_blocked_domain(&domain)
+    }
+
+    /// Rejects email addresses at a domain that can't register. Addresses
+    /// without a domain are left to the form's other checks.
+    pub fn validate(&self, email: &str) -> form::Result<'static, ()> {
+        match email.rsplit_once('@') {
+            Some((_, domain)) if !self.allows(domain) =>
+                Err(Error::validation(DOMAIN_NOT_ACCEPTED_MESSAGE).into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str], block_disposable: bool) -> EmailDomainPolicy {
+        EmailDomainPolicy {
+            allow: allow.iter().map(|domain| domain.to_string()).collect(),
+            deny: deny.iter().map(|domain| domain.to_string()).collect(),
+            block_disposable,
+        }
+    }
+
+    #[test]
+    fn domains_are_lowercased_and_punycoded() {
+        assert_eq!(normalize_domain(" MAILINATOR.com. ").as_deref(), Some("mailinator.com"));
+        assert_eq!(normalize_domain("bücher.example").as_deref(), Some("xn--bcher-kva.example"));
+        assert_eq!(normalize_domain(""), None);
+    }
+
+    #[test]
+    fn disposable_domains_are_rejected_with_block_disposable() {
+        let policy = policy(&[], &[], true);
+        assert!(policy.validate("someone@Mailinator.COM").is_err());
+        assert!(policy.validate("someone@eu.mailinator.com").is_err());
+        assert!(policy.validate("someone@example.com").is_ok());
+    }
+
+    #[test]
+    fn disposable_domains_are_allowed_without_block_disposable() {
+        assert!(policy(&[], &[], false).validate("someone@mailinator.com").is_ok());
+    }
+
+    #[test]
+    fn denied_domains_and_their_subdomains_are_rejected() {
+        let policy = policy(&[], &["Example.NET"], false);
+        assert!(policy.validate("someone@example.net").is_err());
+        assert!(policy.validate("someone@mail.example.net").is_err());
+        assert!(policy.validate("someone@example.com").is_ok());
+        assert!(policy.validate("someone@notexample.net").is_ok());
+    }
+
+    #[test]
+    fn only_allowed_domains_register_when_any_are_set() {
+        let policy = policy(&["example.com"], &[], false);
+        assert!(policy.validate("someone@example.com").is_ok());
+        assert!(policy.validate("someone@team.example.com").is_ok());
+        assert!(policy.validate("someone@example.org").is_err());
+    }
+
+    #[test]
+    fn addresses_without_a_domain_are_left_to_other_checks() {
+        assert!(policy(&["example.com"], &[], true).validate("not an email").is_ok());
+    }
+}
@@ -0,0 +1,230 @@
+//! Optional CAPTCHA check on registration, with hCaptcha or reCAPTCHA
+//! (v2). Off unless a provider is set in the `captcha` config section:
+//!
+//! ```toml
+//! [default.captcha]
+//! provider = "hcaptcha"
+//! site_key = "10000000-ffff-ffff-ffff-000000000001"
+//! ```
+//!
+//! The secret that goes with the site key is read from `CAPTCHA_SECRET`.
+//! Launch is aborted if a provider is set without both, as every
+//! registration would then fail. The provider's widget adds its token to
+//! the registration form, which `verify` checks with the provider's
+//! `siteverify` endpoint.
+
+use std::env;
+
+use anyhow::anyhow;
+use rocket::fairing::AdHoc;
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+use crate::error;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaProvider {
+    #[default]
+    None,
+    Hcaptcha,
+    Recaptcha,
+}
+
+impl CaptchaProvider {
+    fn verify_url(&self) -> Option<&'static str> {
+        match self {
+            CaptchaProvider::None => None,
+            CaptchaProvider::Hcaptcha => Some("https://hcaptcha.com/siteverify"),
+            CaptchaProvider::Recaptcha => Some("https://www.google.com/recaptcha/api/siteverify"),
+        }
+    }
+
+    fn script_url(&self) -> Option<&'static str> {
+        match self {
+            CaptchaProvider::None => None,
+            CaptchaProvider::Hcaptcha => Some("https://js.hcaptcha.com/1/api.js"),
+            CaptchaProvider::Recaptcha => Some("https://www.google.com/recaptcha/api.js"),
+        }
+    }
+
+    /// The class of the element the widget is drawn in.
+    fn widget_class(&self) -> &'static str {
+        match self {
+            CaptchaProvider::Recaptcha => "g-recaptcha",
+            _ => "h-captcha",
+        }
+    }
+
+    /// The form field the widget puts its token in.
+    pub fn response_field(&self) -> &'static str {
+        match self {
+            CaptchaProvider::Recaptcha => "g-recaptcha-response",
+            _ => "h-captcha-response",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CaptchaConfig {
+    pub provider: CaptchaProvider,
+    /// The public key the widget is drawn with.
+    pub site_key: String,
+}
+
+impl CaptchaConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.provider != CaptchaProvider::None
+    }
+
+    /// Fails if the check is on without the site key or the secret.
+    fn validate(&self, secret: Option<&str>) -> error::Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        if self.site_key.is_empty() {
+            return Err(anyhow!("captcha.provider is set, but captcha.site_key is not").into());
+        }
+        if secret.is_none() {
+            return Err(anyhow!("captcha.provider is set, but CAPTCHA_SECRET is not").into());
+        }
+
+        Ok(())
+    }
+
+    /// What the registration template needs to draw the widget, as
+    /// `captcha`; null when the check is off.
+    pub fn widget(&self) -> serde_json::Value {
+        match self.provider.script_url() {
+            Some(script_url) => serde_json::json!({
+                "script_url": script_url,
+                "class": self.provider.widget_class(),
+                "site_key": self.site_key,
+            }),
+            None => serde_json::Value::Null,
+        }
+    }
+}
+
+/// Shown on the form when the check fails.
+pub const CAPTCHA_MESSAGE: &str = "Please complete the CAPTCHA to show you're not a robot.";
+
+#[derive(Debug, Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+// TODO: Use Figment for configuration.
+fn secret() -> Option<String> {
+    env::var("CAPTCHA_SECRET").ok().filter(|secret| !secret.is_empty())
+}
+
+/// Aborts launch if the check is on but can't work.
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("CAPTCHA", |rocket| async move {
+        let config = rocket.state::<AppConfig>()
+            .map(|config| config.captcha.clone())
+            .unwrap_or_default();
+
+        match config.validate(secret().as_deref()) {
+            Ok(()) => Ok(rocket),
+            Err(e) => {
+                rocket::error!("{}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+/// Whether the provider accepts the token from the form. Always true
+/// when the check is off. Fails closed: a missing token or secret, or a
+/// provider that can't be reached, counts as a failure.
+pub async fn verify(config: &CaptchaConfig, token: Option<&str>, remote_ip: Option<&str>) -> bool {
+    let url = match config.provider.verify_url() {
+        Some(url) => url,
+        None => return true,
+    };
+
+    let token = match token {
+        Some(token) if !token.is_empty() => token,
+        _ => return false,
+    };
+
+    let secret = match secret() {
+        Some(secret) => secret,
+        None => {
+            rocket::error!("CAPTCHA is on, but CAPTCHA_SECRET is not set");
+            return false;
+        }
+    };
+
+    let mut params = vec![("secret", secret.as_str()), ("response", token)];
+    if let Some(ip) = remote_ip {
+        params.push(("remoteip", ip));
+    }
+    let body = match serde_urlencoded::to_string(&params) {
+        Ok(body) => body,
+        Err(_) => return false,
+    };
+
+    let resp = rocket::tokio::task::spawn_blocking(move || {
+        minreq::post(url)
+            .with_header("Content-Type", "application/x-www-form-urlencoded")
+            .with_body(body)
+            .with_timeout(5)
+            .send()
+    })
+    .await;
+
+    match resp {
+        Ok(Ok(resp)) if resp.status_code == 200 => match resp.json::<SiteVerifyResponse>() {
+            Ok(verified) => verified.success,
+            Err(e) => {
+                rocket::warn!("CAPTCHA verification returned an unexpected response: {}", e);
+                false
+            },
+        },
+        Ok(Ok(resp)) => {
+            rocket::warn!("CAPTCHA verification failed with status {}", resp.status_code);
+            false
+        },
+        Ok(Err(e)) => {
+            rocket::warn!("CAPTCHA verification failed: {}", e);
+            false
+        },
+        Err(e) => {
+            rocket::warn!("CAPTCHA verification failed: {}", e);
+            false
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hcaptcha(site_key: &str) -> CaptchaConfig {
+        CaptchaConfig { provider: CaptchaProvider::Hcaptcha, site_key: site_key.to_string() }
+    }
+
+    #[test]
+    fn off_needs_no_keys() {
+        assert!(CaptchaConfig::default().validate(None).is_ok());
+        assert_eq!(CaptchaConfig::default().widget(), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn a_provider_needs_the_site_key_and_secret() {
+        assert!(hcaptcha("").validate(Some("secret")).is_err());
+        assert!(hcaptcha("site-key").validate(None).is_err());
+        assert!(hcaptcha("site-key").validate(Some("secret")).is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn missing_token_fails_verification() {
+        assert!(!verify(&hcaptcha("site-key"), None, None).await);
+        assert!(!verify(&hcaptcha("site-key"), Some(""), None).await);
+        assert!(verify(&CaptchaConfig::default(), None, None).await);
+    }
+}
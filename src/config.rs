@@ -0,0 +1,326 @@
+//! Application settings, read from the Rocket config (`Rocket.toml` or
+//! `ROCKET_` prefixed env vars) alongside Rocket's own settings, e.g.:
+//!
+//! ```toml
+//! [default.accounts]
+//! soft_delete = true
+//! email_aliases = "strip_plus"
+//!
+//! [default.captcha]
+//! provider = "hcaptcha"
+//! site_key = "..."
+//!
+//! [default.cookies]
+//! domain = "example.com"
+//!
+//! [default.csrf]
+//! allowed_origins = ["https://admin.example.com"]
+//!
+//! [[default.jobs.queues]]
+//! name = "default"
+//!
+//! [[default.jobs.queues]]
+//! name = "emails"
+//! concurrency = 4
+//!
+//! [default.email]
+//! default_locale = "en"
+//!
+//! [default.email_branding]
+//! app_name = "Example App"
+//!
+//! [default.email_domains]
+//! deny = ["example.net"]
+//! block_disposable = true
+//!
+//! [default.log]
+//! format = "json"
+//!
+//! [default.passwords]
+//! min_length = 12
+//! pattern = "ulns"
+//!
+//! [default.pii]
+//! encryption_key = "..."
+//!
+//! [default.rate_limits]
+//! authenticate = { requests = 10, window = 60 }
+//!
+//! [default.storage]
+//! backend = "local"
+//! ```
+//!
+//! Every section has defaults, so an empty config is valid.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blocklist::EmailDomainPolicy;
+use crate::captcha::CaptchaConfig;
+use crate::cookies::CookieScope;
+use crate::csrf::CsrfConfig;
+use crate::email::{EmailBranding, EmailConfig};
+use crate::jobs::DEFAULT_QUEUE;
+use crate::logging::LogConfig;
+use crate::passwords::PasswordPolicy;
+use crate::pii::PiiConfig;
+use crate::storage::StorageConfig;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub accounts: AccountsConfig,
+    pub captcha: CaptchaConfig,
+    pub cookies: CookieScope,
+    pub csrf: CsrfConfig,
+    pub email: EmailConfig,
+    pub email_branding: EmailBranding,
+    pub email_domains: EmailDomainPolicy,
+    pub jobs: JobsConfig,
+    /// Read by `logging::init` before Rocket starts.
+    pub log: LogConfig,
+    pub passwords: PasswordPolicy,
+    pub pii: PiiConfig,
+    pub rate_limits: RateLimitsConfig,
+    pub storage: StorageConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccountsConfig {
+    /// Keep deleted accounts, marked with `deleted_at`, instead of
+    /// removing the row.
+    pub soft_delete: bool,
+
+    /// Seconds a session lasts. It also ends when the browser is closed.
+    pub session_ttl: u64,
+
+    /// Seconds a "remember me" session lasts, across browser restarts.
+    pub session_remember_ttl: u64,
+
+    /// Seconds to cache each account's session fingerprint and version in
+    /// memory, to save the `User` guard's query on most requests. Revoked
+    /// sessions may then last this long. 0, the default, checks the
+    /// database on every request.
+    pub session_cache_ttl: u64,
+
+    /// Which addresses count as the same mailbox, both when checking that
+    /// an email isn't already registered and when looking an account up
+    /// by email, e.g. at login. Accounts registered before a change keep
+    /// the canonical email of the old policy, but can still be found by
+    /// their address as registered.
+    pub email_aliases: EmailAliasPolicy,
+
+    /// Reject new passwords that appear in the Have I Been Pwned breach
+    /// corpus. Only a five character prefix of the password's SHA-1 hash
+    /// is sent; if the service can't be reached, the password is allowed.
+    pub check_breached_passwords: bool,
+
+    /// Keep a log of each login's IP address and user agent, which users
+    /// can review on their settings page.
+    pub record_logins: bool,
+
+    /// Refuse password logins to accounts that haven't verified their
+    /// email, sending them to the page for a new verification link.
+    pub require_verified_email: bool,
+
+    /// Seconds before another verification email can be requested for the
+    /// same address from the resend page.
+    pub resend_verify_cooldown: u64,
+
+    /// Seconds within which a repeat verification email to the same
+    /// address is skipped by the job that sends it, however it was queued.
+    pub verify_email_cooldown: u64,
+
+    /// Days a password account has to verify its email, or log in, before
+    /// it's deleted. 0 turns purging off.
+    pub unverified_max_age_days: u32,
+
+    /// Who can register.
+    pub registration: RegistrationMode,
+
+    /// Days a password may be used before its owner is reminded to change
+    /// it. 0 turns reminders off.
+    pub password_max_age_days: u32,
+
+    /// Reminded accounts must change their password before logging in
+    /// again, by any means, and their sessions end.
+    pub enforce_password_rotation: bool,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        AccountsConfig {
+            soft_delete: false,
+            session_ttl: 86400,
+            session_remember_ttl: 30 * 86400,
+            session_cache_ttl: 0,
+            email_aliases: EmailAliasPolicy::default(),
+            check_breached_passwords: false,
+            record_logins: false,
+            require_verified_email: false,
+            resend_verify_cooldown: 60,
+            verify_email_cooldown: 300,
+            unverified_max_age_days: 7,
+            registration: RegistrationMode::default(),
+            password_max_age_days: 0,
+            enforce_password_rotation: false,
+        }
+    }
+}
+
+impl AccountsConfig {
+    /// Seconds a new session lasts; see `session_ttl` and
+    /// `session_remember_ttl`.
+    pub fn session_lifetime(&self, remember: bool) -> i64 {
+        let ttl = if remember { self.session_remember_ttl } else { self.session_ttl };
+        i64::try_from(ttl).unwrap_or(i64::MAX)
+    }
+
+    /// `password_max_age_days`, if reminders are on.
+    pub fn password_max_age(&self) -> Option<i32> {
+        i32::try_from(self.password_max_age_days).ok().filter(|days| *days > 0)
+    }
+
+    /// `unverified_max_age_days`, if purging is on.
+    pub fn unverified_max_age(&self) -> Option<i32> {
+        i32::try_from(self.unverified_max_age_days).ok().filter(|days| *days > 0)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// The job queues this server runs a worker for. Jobs pushed to a
+    /// queue no server serves wait until one does. Must include
+    /// `DEFAULT_QUEUE`, where the recurring jobs are seeded.
+    pub queues: Vec<QueueConfig>,
+    /// Seconds running jobs get to finish at shutdown.
+    pub drain_timeout: u64,
+    /// Seconds a job may stay running before it's taken to have died with
+    /// its worker, and is requeued. Keep it longer than any job takes, or
+    /// slow jobs will be run twice.
+    pub stale_job_timeout: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            queues: vec![QueueConfig { name: DEFAULT_QUEUE.to_string(), concurrency: None }],
+            drain_timeout: 30,
+            stale_job_timeout: 900,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QueueConfig {
+    pub name: String,
+    /// Jobs from this queue to run at once. Defaults to, and is capped
+    /// at, what the job database pool allows.
+    pub concurrency: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationMode {
+    /// Anyone can register.
+    #[default]
+    Open,
+    /// Only those with an `Invitation`, created by an admin, can register,
+    /// with the token from the invitation email and the address it was
+    /// sent to. OAuth logins can't register new accounts either.
+    InviteOnly,
+}
+
+impl RegistrationMode {
+    pub fn is_open(&self) -> bool {
+        *self == RegistrationMode::Open
+    }
+}
+
+/// How an email is reduced to its canonical form for the uniqueness
+/// check. The address as entered is still the one we send mail to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailAliasPolicy {
+    /// Addresses only differ by case.
+    #[default]
+    Exact,
+    /// `user+tag@example.com` is the same as `user@example.com`.
+    StripPlus,
+    /// As `StripPlus`, and dots in gmail.com addresses are ignored too,
+    /// as Gmail does.
+    Gmail,
+}
+
+impl EmailAliasPolicy {
+    pub fn canonicalize(&self, email: &str) -> String {
+        let email = email.trim().to_lowercase();
+        let (local, domain) = match email.rsplit_once('@') {
+            Some(parts) => parts,
+            None => return email,
+        };
+
+        let local = match self {
+            EmailAliasPolicy::Exact => local.to_string(),
+            EmailAliasPolicy::StripPlus => strip_plus(local).to_string(),
+            EmailAliasPolicy::Gmail if is_gmail(domain) => strip_plus(local).replace('.', ""),
+            EmailAliasPolicy::Gmail => strip_plus(local).to_string(),
+        };
+
+        format!("{}@{}", local, domain)
+    }
+}
+
+/// An email as it is stored: trimmed, with its domain, which is case
+/// insensitive, lowercased. The part before the `@` is kept as entered,
+/// since mail servers may treat its case as significant.
+pub fn normalize_email(email: &str) -> String {
+    let email = email.trim();
+    match email.rsplit_once('@') {
+        Some((local, domain)) => format!("{}@{}", local, domain.to_lowercase()),
+        None => email.to_string(),
+    }
+}
+
+fn strip_plus(local: &str) -> &str {
+    local.split('+').next().unwrap_or(local)
+}
+
+fn is_gmail(domain: &str) -> bool {
+    domain == "gmail.com" || domain == "googlemail.com"
+}
+
+/// Request rate limits per client IP, keyed by route (handler) name.
+/// Routes taking a `TokenRateLimit` are also limited per IP and account.
+/// Setting any replaces all of the defaults.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct RateLimitsConfig(pub HashMap<String, RateLimitRule>);
+
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        RateLimitsConfig(HashMap::from([
+            ("authenticate".to_string(), RateLimitRule { requests: 10, window: 60 }),
+            ("request_reset".to_string(), RateLimitRule { requests: 5, window: 300 }),
+            ("resend_link".to_string(), RateLimitRule { requests: 5, window: 300 }),
+            ("verify_with_token".to_string(), RateLimitRule { requests: 10, window: 900 }),
+            ("reset_password_with_token".to_string(), RateLimitRule { requests: 10, window: 900 }),
+            ("reset_password".to_string(), RateLimitRule { requests: 10, window: 900 }),
+            ("change_password".to_string(), RateLimitRule { requests: 10, window: 900 }),
+            ("request_magic_link".to_string(), RateLimitRule { requests: 5, window: 300 }),
+            ("magic_login".to_string(), RateLimitRule { requests: 10, window: 900 }),
+            ("passkey_login_begin".to_string(), RateLimitRule { requests: 10, window: 60 }),
+            ("passkey_login_finish".to_string(), RateLimitRule { requests: 10, window: 60 }),
+        ]))
+    }
+}
+
+/// At most `requests` requests in any `window` seconds.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct RateLimitRule {
+    pub requests: usize,
+    pub window: u64,
+}
@@ -0,0 +1,195 @@
+//! Domain and path for the cookies that should follow a user across
+//! subdomains (e.g. `www.` and `app.`): the session, the OAuth provider
+//! tokens and flash messages.
+//!
+//! Set in the `cookies` config section:
+//!
+//! ```toml
+//! [default.cookies]
+//! domain = "example.com"
+//! path = "/"
+//! ```
+//!
+//! By default cookies are host-only, on the path "/". The values are
+//! checked when the config is read at ignite, which fails if either is
+//! invalid.
+
+use std::net::IpAddr;
+
+use rocket::fairing::AdHoc;
+use rocket::http::Cookie;
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+
+/// The name of the cookie Rocket's `Flash` responder sets.
+const FLASH_COOKIE: &str = "_flash";
+
+/// The `cookies` config section, as written.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CookieSettings {
+    domain: Option<String>,
+    path: Option<String>,
+}
+
+/// The `cookies` config section, checked and normalized.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "CookieSettings")]
+pub struct CookieScope {
+    pub domain: Option<String>,
+    pub path: String,
+}
+
+impl Default for CookieScope {
+    fn default() -> Self {
+        CookieScope {
+            domain: None,
+            path: "/".to_string(),
+        }
+    }
+}
+
+impl TryFrom<CookieSettings> for CookieScope {
+    type Error = String;
+
+    fn try_from(settings: CookieSettings) -> Result<Self, String> {
+        let domain = match settings.domain {
+            Some(domain) if !domain.trim().is_empty() => Some(validate_domain(&domain)?),
+            _ => None,
+        };
+
+        let path = match settings.path {
+            Some(path) if !path.trim().is_empty() => validate_path(&path)?,
+            _ => "/".to_string(),
+        };
+
+        Ok(CookieScope { domain, path })
+    }
+}
+
+impl CookieScope {
+    pub fn apply(&self, cookie: &mut Cookie<'_>) {
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie.set_path(self.path.clone());
+    }
+
+    /// Applies the domain and path to a cookie. Use it for removals as
+    /// well, since a cookie is only removed by one with the same domain
+    /// and path.
+    pub fn scoped(&self, mut cookie: Cookie<'static>) -> Cookie<'static> {
+        self.apply(&mut cookie);
+        cookie
+    }
+}
+
+/// Checks a cookie domain and returns it in the ASCII form browsers
+/// compare against. A leading dot is dropped, as browsers ignore it.
+/// IP addresses and single label names like "localhost" are refused,
+/// since browsers won't share cookies across them anyway.
+pub fn validate_domain(domain: &str) -> Result<String, String> {
+    let trimmed = domain.trim().trim_start_matches('.');
+    if trimmed.parse::<IpAddr>().is_ok() {
+        return Err(format!("cookies.domain {:?} is an IP address", domain));
+    }
+
+    let ascii = idna::domain_to_ascii(trimmed)
+        .map_err(|_| format!("cookies.domain {:?} is not a valid domain", domain))?;
+
+    let labels: Vec<&str> = ascii.split('.').collect();
+    let valid = labels.len() >= 2
+        && ascii.len() <= 253
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+
+    if valid {
+        Ok(ascii)
+    } else {
+        Err(format!("cookies.domain {:?} is not a valid domain", domain))
+    }
+}
+
+/// Checks a cookie path: absolute, with no characters that would end
+/// the cookie attribute.
+pub fn validate_path(path: &str) -> Result<String, String> {
+    let path = path.trim();
+    if path.starts_with('/') && !path.chars().any(|c| c == ';' || c.is_control()) {
+        Ok(path.to_string())
+    } else {
+        Err(format!("cookies.path {:?} must start with '/' and can't contain ';'", path))
+    }
+}
+
+/// Rocket's `Flash` responder builds its own cookie, so the scope is
+/// applied to its `Set-Cookie` headers on the way out.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_response("Cookie scope", |req, response| Box::pin(async move {
+        let scope = match req.rocket().state::<AppConfig>() {
+            Some(config) if config.cookies != CookieScope::default() => &config.cookies,
+            _ => return,
+        };
+
+        let headers: Vec<String> = response.headers().get("Set-Cookie").map(String::from).collect();
+        if !headers.iter().any(|header| header.starts_with(FLASH_COOKIE)) {
+            return;
+        }
+
+        response.remove_header("Set-Cookie");
+        for header in headers {
+            let header = match Cookie::parse_encoded(header.clone()) {
+                Ok(mut cookie) if cookie.name() == FLASH_COOKIE => {
+                    scope.apply(&mut cookie);
+                    cookie.encoded().to_string()
+                },
+                _ => header,
+            };
+            response.adjoin_raw_header("Set-Cookie", header);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(settings: serde_json::Value) -> Result<CookieScope, serde_json::Error> {
+        serde_json::from_value(settings)
+    }
+
+    #[test]
+    fn unset_scope_is_host_only_on_the_root() {
+        assert_eq!(scope(serde_json::json!({})).unwrap(), CookieScope::default());
+        let config: AppConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert_eq!(config.cookies, CookieScope::default());
+    }
+
+    #[test]
+    fn configured_scope_is_normalized() {
+        let scope = scope(serde_json::json!({ "domain": ".Example.COM", "path": "/app" })).unwrap();
+        assert_eq!(scope.domain.as_deref(), Some("example.com"));
+        assert_eq!(scope.path, "/app");
+    }
+
+    #[test]
+    fn invalid_scope_is_refused() {
+        assert!(scope(serde_json::json!({ "domain": "localhost" })).is_err());
+        assert!(scope(serde_json::json!({ "domain": "127.0.0.1" })).is_err());
+        assert!(scope(serde_json::json!({ "path": "app" })).is_err());
+        assert!(serde_json::from_value::<AppConfig>(serde_json::json!({ "cookies": { "path": "/a;b" } })).is_err());
+    }
+
+    #[test]
+    fn scoped_cookies_get_the_domain_and_path() {
+        let scope = scope(serde_json::json!({ "domain": "example.com", "path": "/app" })).unwrap();
+        let cookie = scope.scoped(Cookie::new("sku", "session"));
+        assert_eq!(cookie.domain(), Some("example.com"));
+        assert_eq!(cookie.path(), Some("/app"));
+    }
+}
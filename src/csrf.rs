@@ -0,0 +1,280 @@
+//! Cross-site request forgery protection.
+//!
+//! Each browser gets a random token in a private cookie. Pages with forms
+//! put it in the template context as `csrf`, and forms embed it with the
+//! `csrf_field` macro:
+//!
+//! ```html
+//! {% import "macros" as m %}
+//! <form method="post" action="/accounts/logout">
+//!     {{ m::csrf_field() }}
+//! </form>
+//! ```
+//!
+//! POST handlers take a `CsrfToken` and check the submitted `csrf` field
+//! with `CsrfToken::verify`. Request guards run before the body is read,
+//! so the check can't happen in the guard itself.
+//!
+//! As a second line of defense, state-changing requests must also come
+//! from the app's own origin, or one in `csrf.allowed_origins`, going by
+//! their `Origin` or `Referer` header:
+//!
+//! ```toml
+//! [default.csrf]
+//! allowed_origins = ["https://app.example.com"]
+//! ```
+//!
+//! The `SameOrigin` guard checks this, and `CsrfToken` uses it, so
+//! handlers taking a token get the check too.
+
+use std::collections::HashSet;
+use std::env;
+
+use constant_time_eq::constant_time_eq;
+use rocket::fairing::AdHoc;
+use rocket::form::FromForm;
+use rocket::http::{Cookie, Method, Status};
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::config::AppConfig;
+
+/// Private cookie holding the token.
+pub const CSRF_COOKIE: &str = "csrf";
+
+/// Name of the hidden form field, and of the template context value.
+pub const CSRF_FIELD: &str = "csrf";
+
+/// The CSRF token for this browser, issued on first use.
+#[derive(Debug)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn value(&self) -> &str {
+        &self.0
+    }
+
+    /// Checks a submitted token, failing with `Forbidden` if it is
+    /// missing or doesn't match.
+    pub fn verify(&self, submitted: Option<&str>) -> Result<(), Status> {
+        match submitted {
+            Some(submitted) if constant_time_eq(submitted.as_bytes(), self.0.as_bytes()) => Ok(()),
+            _ => {
+                rocket::warn!("CSRF token missing or invalid");
+                Err(Status::Forbidden)
+            }
+        }
+    }
+}
+
+fn generate() -> String {
+    base64_url::encode(&rand::random::<[u8; 32]>())
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfToken {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        try_outcome!(req.guard::<SameOrigin>().await);
+
+        let cookies = req.cookies();
+        let token = match cookies.get_private(CSRF_COOKIE) {
+            Some(cookie) => cookie.value().to_string(),
+            None => {
+                let token = generate();
+                cookies.add_private(Cookie::new(CSRF_COOKIE, token.clone()));
+                token
+            }
+        };
+
+        Outcome::Success(CsrfToken(token))
+    }
+}
+
+/// The `csrf` config section.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CsrfConfig {
+    /// Origins besides the app's own that may send state-changing
+    /// requests, e.g. "https://admin.example.com".
+    pub allowed_origins: Vec<String>,
+}
+
+/// The origins that may send state-changing requests, as managed state.
+#[derive(Debug, Default)]
+pub struct AllowedOrigins(HashSet<String>);
+
+impl AllowedOrigins {
+    /// The app's own origin, from `own` (`JELLY_DOMAIN`), and those in
+    /// `csrf.allowed_origins`. Fails on any entry that isn't a URL with
+    /// an origin.
+    pub fn new(own: Option<&str>, config: &CsrfConfig) -> Result<Self, String> {
+        let mut origins = HashSet::new();
+        if let Some(origin) = own.and_then(origin_of) {
+            origins.insert(origin);
+        }
+
+        for entry in &config.allowed_origins {
+            match origin_of(entry.trim()) {
+                Some(origin) => origins.insert(origin),
+                None => return Err(format!("csrf.allowed_origins entry {:?} is not an origin", entry)),
+            };
+        }
+
+        Ok(AllowedOrigins(origins))
+    }
+
+    pub fn contains(&self, origin: &str) -> bool {
+        self.0.contains(origin)
+    }
+}
+
+/// Manages the `AllowedOrigins`, aborting launch if any configured one
+/// is invalid.
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Allowed origins", |rocket| async move {
+        let config = rocket.state::<AppConfig>()
+            .map(|config| config.csrf.clone())
+            .unwrap_or_default();
+
+        match AllowedOrigins::new(env::var("JELLY_DOMAIN").ok().as_deref(), &config) {
+            Ok(origins) => Ok(rocket.manage(origins)),
+            Err(e) => {
+                rocket::error!("{}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+/// The origin of a URL as browsers send it: scheme, host and any port
+/// that isn't the scheme's default, e.g. "https://www.example.com".
+fn origin_of(url: &str) -> Option<String> {
+    let origin = Url::parse(url).ok()?.origin();
+    if origin.is_tuple() {
+        Some(origin.ascii_serialization())
+    } else {
+        None
+    }
+}
+
+/// Request guard for requests that can change state (POST, PUT, PATCH
+/// and DELETE): fails with `Forbidden` unless the `Origin` header, or the
+/// `Referer` if there is no `Origin`, is one of the `AllowedOrigins`.
+/// Requests with neither are refused as well, as are all of them if the
+/// `AllowedOrigins` aren't managed. Other methods always pass.
+#[derive(Debug)]
+pub struct SameOrigin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SameOrigin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if !matches!(req.method(), Method::Post | Method::Put | Method::Patch | Method::Delete) {
+            return Outcome::Success(SameOrigin);
+        }
+
+        let headers = req.headers();
+        let origin = headers.get_one("Origin")
+            .or_else(|| headers.get_one("Referer"))
+            .and_then(origin_of);
+
+        let allowed = req.rocket().state::<AllowedOrigins>();
+        match (origin, allowed) {
+            (Some(origin), Some(allowed)) if allowed.contains(&origin) => Outcome::Success(SameOrigin),
+            (origin, _) => {
+                rocket::warn!("{} from disallowed origin {:?}", req.method(), origin);
+                Outcome::Failure((Status::Forbidden, ()))
+            }
+        }
+    }
+}
+
+/// Form data for POST handlers that have no fields besides the token.
+#[derive(Debug, FromForm)]
+pub struct CsrfForm<'v> {
+    pub csrf: Option<&'v str>,
+}
+
+/// The context for a blank form: the token, plus the empty fields that a
+/// `rocket::form::Context` would have, for the form macros.
+pub fn form_context(token: &CsrfToken) -> serde_json::Value {
+    serde_json::json!({
+        "csrf": token.value(),
+        "values": {},
+        "errors": {},
+        "form_errors": [],
+        "data_fields": [],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{Header, Status};
+    use rocket::local::asynchronous::Client;
+    use rocket::{get, post, routes};
+
+    use super::*;
+
+    #[get("/")]
+    fn read(_origin: SameOrigin) {}
+
+    #[post("/")]
+    fn write(_origin: SameOrigin) {}
+
+    fn allowed(origins: &[&str]) -> CsrfConfig {
+        CsrfConfig { allowed_origins: origins.iter().map(|origin| origin.to_string()).collect() }
+    }
+
+    async fn client() -> Client {
+        let origins = AllowedOrigins::new(Some("https://www.example.com"), &allowed(&["https://admin.example.com"])).unwrap();
+        Client::tracked(rocket::build().manage(origins).mount("/", routes![read, write])).await.unwrap()
+    }
+
+    #[test]
+    fn origins_are_normalized() {
+        let origins = AllowedOrigins::new(Some("https://www.example.com/path"), &allowed(&["HTTPS://Admin.Example.com:443/"])).unwrap();
+        assert!(origins.contains("https://www.example.com"));
+        assert!(origins.contains("https://admin.example.com"));
+        assert!(!origins.contains("http://admin.example.com"));
+    }
+
+    #[test]
+    fn invalid_origins_are_refused() {
+        assert!(AllowedOrigins::new(None, &allowed(&["admin.example.com"])).is_err());
+        assert!(AllowedOrigins::new(None, &allowed(&["data:text/plain,x"])).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn allowed_origins_can_post() {
+        let client = client().await;
+        for origin in ["https://www.example.com", "https://admin.example.com"] {
+            let response = client.post("/").header(Header::new("Origin", origin)).dispatch().await;
+            assert_eq!(response.status(), Status::Ok, "{}", origin);
+        }
+
+        let response = client.post("/").header(Header::new("Referer", "https://www.example.com/accounts/login")).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn other_origins_cannot_post() {
+        let client = client().await;
+        let response = client.post("/").header(Header::new("Origin", "https://evil.example.net")).dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client.post("/").dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[rocket::async_test]
+    async fn reads_pass_from_anywhere() {
+        let client = client().await;
+        let response = client.get("/").header(Header::new("Origin", "https://evil.example.net")).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
@@ -1,6 +1,14 @@
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rocket::fairing::AdHoc;
 use rocket_db_pools::{sqlx::PgPool, Connection, Database};
+use serde::Serialize;
 use sqlx::{pool::PoolConnection, Postgres, Transaction};
 
+use crate::error;
+
 pub const NAME: &str = "app_db";
 
 #[derive(Database)]
@@ -13,5 +21,215 @@ pub type PoolConn = PoolConnection<Postgres>;
 
 pub type PgTransaction<'a> = Transaction<'a, Postgres>;
 
+/// Checks that a connection can be had from the pool, and used, within
+/// `timeout`.
+pub async fn ping(pool: &PgPool, timeout: Duration) -> error::Result<()> {
+    let check = async {
+        let mut conn = pool.acquire().await?;
+        sqlx::query("SELECT 1").execute(&mut conn).await?;
+        Ok::<_, sqlx::Error>(())
+    };
+
+    match rocket::tokio::time::timeout(timeout, check).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(error::Error::from(anyhow!("database ping timed out"))),
+    }
+}
+
+/// How many connections a pool has open, and how many of those are
+/// checked out. `in_use` staying at the pool's maximum means requests or
+/// jobs are waiting for connections.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PoolStats {
+    pub size: u32,
+    pub idle: usize,
+    pub in_use: usize,
+}
+
+impl PoolStats {
+    pub fn of(pool: &PgPool) -> Self {
+        let size = pool.size();
+        let idle = pool.num_idle();
+        PoolStats {
+            size,
+            idle,
+            in_use: (size as usize).saturating_sub(idle),
+        }
+    }
+}
+
+/// The tables the app uses, with the columns it relies on, as created by
+/// the migrations.
+const REQUIRED_SCHEMA: [(&str, &[&str]); 12] = [
+    ("accounts", &[
+        "id", "name", "email", "canonical_email", "password", "profile", "plan",
+        "is_active", "is_admin", "has_verified_email", "last_login", "plan_expires_at",
+        "deleted_at", "session_version", "email_delivery_status", "password_changed_at",
+        "password_expiry_reminded_at", "must_change_password", "locale", "created", "updated",
+    ]),
+    ("identities", &[
+        "id", "account_id", "provider", "username", "name", "refresh_token", "access_token",
+        "access_token_expires_at",
+    ]),
+    ("queue", &[
+        "id", "created_at", "updated_at", "scheduled_for", "failed_attempts", "status",
+        "message", "queue_name", "priority",
+    ]),
+    ("dead_jobs", &["id", "created_at", "died_at", "failed_attempts", "message", "error"]),
+    ("email_sends", &["template", "recipient", "sent_at"]),
+    ("email_variants", &["account_id", "email_type", "variant", "send_count", "last_sent_at"]),
+    ("oauth_flows", &["state", "flow", "browser_hash", "created_at"]),
+    ("oauth_pending_tokens", &["key_hash", "tokens", "created_at"]),
+    ("login_events", &["id", "account_id", "ip", "user_agent", "created"]),
+    ("job_keys", &["key", "job_id", "expires_at"]),
+    ("webauthn_credentials", &[
+        "id", "account_id", "user_handle", "credential_id", "name", "passkey", "created", "last_used_at",
+    ]),
+    ("invitations", &[
+        "id", "email", "token_hash", "inviter_id", "created", "expires_at", "consumed_at",
+    ]),
+];
+
+/// The tables and columns in `REQUIRED_SCHEMA` that the database lacks,
+/// e.g. "table identities" or "column queue.priority".
+pub async fn missing_schema<'e, E: sqlx::PgExecutor<'e>>(executor: E) -> error::Result<Vec<String>> {
+    let rows: Vec<(String, String)> = sqlx::query_as("SELECT table_name::text, column_name::text
+        FROM information_schema.columns
+        WHERE table_schema = current_schema()")
+        .fetch_all(executor)
+        .await?;
+
+    let mut tables: BTreeMap<String, HashSet<String>> = BTreeMap::new();
+    for (table, column) in rows {
+        tables.entry(table).or_default().insert(column);
+    }
+
+    let mut missing = Vec::new();
+    for (table, columns) in REQUIRED_SCHEMA {
+        match tables.get(table) {
+            None => missing.push(format!("table {}", table)),
+            Some(found) => missing.extend(columns.iter()
+                .filter(|column| !found.contains(**column))
+                .map(|column| format!("column {}.{}", table, column))),
+        }
+    }
+
+    Ok(missing)
+}
+
+/// Runs the migrations in `migrations/` at ignite when
+/// `databases.app_db.run_migrations` is set, aborting ignition if any
+/// fails to apply. Attach after `AppDb::init()` and before
+/// `schema_check()`.
+pub fn migrations() -> AdHoc {
+    AdHoc::try_on_ignite("Database migrations", |rocket| async {
+        let run = rocket.figment()
+            .extract_inner::<bool>(&format!("databases.{}.run_migrations", NAME))
+            .unwrap_or(false);
+        if !run {
+            return Ok(rocket);
+        }
+
+        let pool = match AppDb::fetch(&rocket) {
+            Some(db) => db.0.clone(),
+            None => {
+                rocket::error!("migrations: database pool not initialized");
+                return Err(rocket);
+            }
+        };
+
+        match sqlx::migrate!().run(&pool).await {
+            Ok(()) => Ok(rocket),
+            Err(e) => {
+                rocket::error!("could not run database migrations: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+/// Aborts ignition if the schema is missing anything the app needs,
+/// usually because the migrations haven't been run. Attach after
+/// `AppDb::init()`.
+pub fn schema_check() -> AdHoc {
+    AdHoc::try_on_ignite("Database schema", |rocket| async {
+        let pool = match AppDb::fetch(&rocket) {
+            Some(db) => db.0.clone(),
+            None => {
+                rocket::error!("schema check: database pool not initialized");
+                return Err(rocket);
+            }
+        };
+
+        match missing_schema(&pool).await {
+            Ok(missing) if missing.is_empty() => Ok(rocket),
+            Ok(missing) => {
+                rocket::error!("database schema is missing {}; have the migrations been run?",
+                    missing.join(", "));
+                Err(rocket)
+            },
+            Err(e) => {
+                rocket::error!("could not check the database schema: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+/// The database that tests needing one run against. Those tests are
+/// ignored by default; run them with `DATABASE_URL` set and
+/// `cargo test -- --ignored`.
+#[cfg(test)]
+pub fn test_database_url() -> String {
+    std::env::var("DATABASE_URL")
+        .expect("DATABASE_URL must name a migrated database to run the database tests")
+}
+
+/// A connection to the test database; see `test_database_url`.
+#[cfg(test)]
+pub async fn test_connection() -> sqlx::PgConnection {
+    use sqlx::Connection;
+
+    sqlx::PgConnection::connect(&test_database_url()).await.expect("could not connect to DATABASE_URL")
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Connection;
+
+    use super::*;
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn migrated_schema_has_everything() {
+        let mut conn = test_connection().await;
+
+        assert_eq!(missing_schema(&mut conn).await.unwrap(), Vec::<String>::new());
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn missing_identities_table_is_named() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        sqlx::query("ALTER TABLE identities RENAME TO identities_hidden").execute(&mut tx).await.unwrap();
+        let missing = missing_schema(&mut tx).await.unwrap();
+        tx.rollback().await.unwrap();
+
+        assert_eq!(missing, vec!["table identities".to_string()]);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn missing_column_is_named() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
 
+        sqlx::query("ALTER TABLE queue RENAME COLUMN priority TO priority_hidden").execute(&mut tx).await.unwrap();
+        let missing = missing_schema(&mut tx).await.unwrap();
+        tx.rollback().await.unwrap();
 
+        assert_eq!(missing, vec!["column queue.priority".to_string()]);
+    }
+}
@@ -1,11 +1,8 @@
-use anyhow::anyhow;
-
 pub use tera::Context;
 
 pub mod common;
 pub use common::Configurable;
-pub use common::Email;
-use crate::error;
+pub use common::{is_bounce, Email, EmailBranding, EmailConfig, EmailError, EmailParts, EmailTemplates};
 
 #[cfg(feature = "email-mock")]
 pub mod mock;
@@ -29,24 +26,32 @@ impl Configurable for Email {
     }
 }
 
+/// Whether to fall back to the next configured provider. A bounce is
+/// about the address, so another provider would fare no better.
+fn try_next(res: &Result<(), EmailError>) -> bool {
+    matches!(res, Err(e) if !matches!(e, EmailError::HardBounce { .. }))
+}
+
 impl Email {
-    pub fn send(self) -> error::Result<()> {
+    /// Sends with each configured provider in turn, until one succeeds or
+    /// the address bounces. The error is the last provider's.
+    pub fn send(self) -> Result<(), EmailError> {
         #[allow(unused_mut)]
-        let mut res = Err(error::Error::from(anyhow!("No email provider configured")));
+        let mut res = Err(EmailError::Config("No email provider configured".to_string()));
         #[cfg(feature = "email-postmark")]
-        if res.is_err() {
+        if try_next(&res) {
             res = Email::send_via_postmark(&self, "https://api.postmarkapp.com");
         }
         #[cfg(feature = "email-sendgrid")]
-        if res.is_err() {
+        if try_next(&res) {
             res = Email::send_via_sendgrid(&self, "https://api.sendgrid.com");
         }
         #[cfg(feature = "email-smtp")]
-        if res.is_err() {
+        if try_next(&res) {
             res = Email::send_via_smtp(&self);
         }
         #[cfg(feature = "email-mock")]
-        if res.is_err() {
+        if try_next(&res) {
             res = Email::send_via_mock(&self);
         }
         res
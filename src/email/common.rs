@@ -3,11 +3,66 @@ use std::sync::{Arc, RwLock};
 
 use anyhow::anyhow;
 use chrono::{Datelike, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tera::{Context, Tera};
 
 use crate::error;
 
+/// Why sending an email failed, as far as the provider lets us tell.
+#[derive(Debug, thiserror::Error)]
+pub enum EmailError {
+    /// The address can't receive mail: it doesn't exist, has hard bounced
+    /// before, or is otherwise inactive. Retrying, or trying another
+    /// provider, won't help.
+    #[error("mail to {to} bounced: {reason}")]
+    HardBounce { to: String, reason: String },
+    /// The provider is down, overloaded or couldn't be reached. Worth
+    /// trying again later.
+    #[error("sending mail failed, may succeed later: {0}")]
+    Transient(String),
+    /// Our side is misconfigured, e.g. a bad API key or sender. Needs
+    /// fixing before any mail will go out.
+    #[error("email is misconfigured: {0}")]
+    Config(String),
+    #[error("sending mail failed: {0}")]
+    Unknown(String),
+}
+
+impl EmailError {
+    /// Whether sending the same email again might work.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, EmailError::Transient(_) | EmailError::Unknown(_))
+    }
+
+    /// Classifies an HTTP API response status, for providers that don't
+    /// say more than that.
+    pub fn from_status(status_code: i32, reason: String) -> Self {
+        match status_code {
+            401 | 403 => EmailError::Config(reason),
+            408 | 429 | 500..=599 => EmailError::Transient(reason),
+            _ => EmailError::Unknown(reason),
+        }
+    }
+}
+
+impl From<anyhow::Error> for EmailError {
+    fn from(e: anyhow::Error) -> Self {
+        EmailError::Unknown(format!("{:#}", e))
+    }
+}
+
+impl From<minreq::Error> for EmailError {
+    /// Failing to reach the provider, mostly.
+    fn from(e: minreq::Error) -> Self {
+        EmailError::Transient(e.to_string())
+    }
+}
+
+/// Whether a send failed with [`EmailError::HardBounce`].
+pub fn is_bounce(error: &error::Error) -> bool {
+    matches!(error.error.downcast_ref::<EmailError>(), Some(EmailError::HardBounce { .. }))
+}
+
 pub trait Configurable {
     /// Check that configuration is complete.
     /// This function shall be used at start up to detect misconfiguration as soon as possible
@@ -23,12 +78,130 @@ pub fn env_exists_and_not_empty(env: &str) {
     }
 }
 
+/// Locales end up in template names, so only tags like "fr" or "pt-BR"
+/// are used.
+fn is_valid_locale(locale: &str) -> bool {
+    !locale.is_empty()
+        && locale.len() <= 35
+        && locale.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The file to render `template_name` from in `locale`: e.g. for "pt-BR",
+/// `verify-account.pt-BR.html` if there is one, else
+/// `verify-account.pt.html`, else the default `verify-account.html`.
+pub fn localized_template(engine: &Tera, template_name: &str, locale: &str, extension: &str) -> String {
+    let language = locale.split(|c| c == '-' || c == '_').next().unwrap_or(locale);
+    [locale, language].iter()
+        .map(|locale| format!("{}.{}.{}", template_name, locale, extension))
+        .find(|file| engine.get_template(file).is_ok())
+        .unwrap_or_else(|| format!("{}.{}", template_name, extension))
+}
+
+/// Renders an email template. Tera's own message is usually just "Failed
+/// to render", with the cause (an undefined variable, say) further down
+/// the source chain, so the whole chain is logged along with the name of
+/// the template.
+fn render(engine: &Tera, template_name: &str, context: &Context) -> error::Result<String> {
+    engine.render(template_name, context).map_err(|e| {
+        let e = anyhow::Error::new(e).context(format!("failed to render email template '{}'", template_name));
+        tracing::error!(template = template_name, error = %format_args!("{:#}", e), "could not render email template");
+        error::Error::from(e)
+    })
+}
+
+/// Email settings, from the `email` config section.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EmailConfig {
+    /// The locale of accounts that haven't set one, and of the unsuffixed
+    /// templates.
+    pub default_locale: String,
+}
+
+impl Default for EmailConfig {
+    fn default() -> Self {
+        EmailConfig { default_locale: "en".to_string() }
+    }
+}
+
+/// Branding shown in every email, from the `email_branding` config
+/// section. Templates get it as `branding`, e.g. `{{ branding.app_name }}`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EmailBranding {
+    pub app_name: Option<String>,
+    /// An absolute URL, for the image in the email header.
+    pub logo_url: Option<String>,
+    /// Defaults to `JELLY_SUPPORT_EMAIL`.
+    pub support_email: Option<String>,
+}
+
+impl EmailBranding {
+    /// The branding, with `JELLY_` env vars filling in for settings that
+    /// aren't configured.
+    fn with_defaults(&self) -> EmailBranding {
+        let mut branding = self.clone();
+        if branding.support_email.is_none() {
+            branding.support_email = env::var("JELLY_SUPPORT_EMAIL").ok();
+        }
+        branding
+    }
+}
+
+/// The compiled email templates, along with the settings and branding
+/// every email is rendered with. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct EmailTemplates {
+    pub tera: Arc<RwLock<Tera>>,
+    pub config: Arc<EmailConfig>,
+    pub branding: Arc<EmailBranding>,
+}
+
+impl EmailTemplates {
+    pub fn new(tera: Arc<RwLock<Tera>>, config: EmailConfig, branding: EmailBranding) -> Self {
+        EmailTemplates { tera, config: Arc::new(config), branding: Arc::new(branding) }
+    }
+}
+
+/// Which bodies an email has, and so which templates it is rendered from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmailParts {
+    /// `{template}.txt` and `{template}.html`, sent as alternatives.
+    #[default]
+    Both,
+    /// Only `{template}.txt`.
+    TextOnly,
+    /// Only `{template}.html`.
+    HtmlOnly,
+}
+
+/// Formats an address with an optional display name, as in a `From`
+/// header. Names with characters that are special in headers are quoted.
+pub fn mailbox(name: Option<&str>, address: &str) -> String {
+    match name {
+        Some(name) if name.chars().any(|c| "()<>[]:;@\\,.\"".contains(c)) =>
+            format!("\"{}\" <{}>", name.replace('\\', "\\\\").replace('"', "\\\""), address),
+        Some(name) => format!("{} <{}>", name, address),
+        None => address.to_string(),
+    }
+}
+
 #[derive(Debug, Default, Serialize)]
 pub struct Email {
-    /// Who's sending this.
+    /// Who's sending this, as a mailbox: `Name <address>`, or just the
+    /// address when there's no `from_name`.
     #[serde(rename = "From")]
     pub from: String,
 
+    /// The sender's bare address, for providers that take the name and
+    /// address separately.
+    #[serde(skip)]
+    pub from_address: String,
+
+    /// The sender's display name, from `EMAIL_DEFAULT_FROM_NAME`.
+    #[serde(skip)]
+    pub from_name: Option<String>,
+
     /// Who to send to. Comma-delimited.
     #[serde(rename = "To")]
     pub to: String,
@@ -37,12 +210,12 @@ pub struct Email {
     #[serde(rename = "Subject")]
     pub subject: String,
 
-    /// What to send (plaintext)
-    #[serde(rename = "TextBody")]
+    /// What to send (plaintext). Empty if the email is HTML only.
+    #[serde(rename = "TextBody", skip_serializing_if = "String::is_empty")]
     pub body: String,
 
-    /// What to send (HTML)
-    #[serde(rename = "HtmlBody")]
+    /// What to send (HTML). Empty if the email is plaintext only.
+    #[serde(rename = "HtmlBody", skip_serializing_if = "String::is_empty")]
     pub body_html: String,
 
     /// Postmark stream to use
@@ -51,21 +224,53 @@ pub struct Email {
 }
 
 impl Email {
-    /// Construct a new `Email`.
+    /// Construct a new `Email`, with both a plaintext and an HTML body.
     ///
     /// * [`template_name`] : the template name to be used
     /// * [`to`] : an array of destinationemail addresses
     /// * [`subject`] : the mail subject line
     /// * [`context`] : the [`Context`] used to render the template
-    /// * [`templates`] : the tera templates
+    /// * [`templates`] : the tera templates and branding
+    /// * [`locale`] : the recipient's locale, if known, for picking
+    ///   localized templates (see [`localized_template`])
     pub fn new(
+        template_name: &str,
+        to: &[String],
+        subject: &str,
+        context: Context,
+        templates: EmailTemplates,
+        locale: Option<&str>,
+    ) -> error::Result<Self> {
+        Email::new_with_parts(template_name, to, subject, context, templates, locale, EmailParts::Both)
+    }
+
+    /// Construct a new `Email` with only a plaintext body, so only
+    /// `{template_name}.txt` needs to exist.
+    pub fn new_text_only(
+        template_name: &str,
+        to: &[String],
+        subject: &str,
+        context: Context,
+        templates: EmailTemplates,
+        locale: Option<&str>,
+    ) -> error::Result<Self> {
+        Email::new_with_parts(template_name, to, subject, context, templates, locale, EmailParts::TextOnly)
+    }
+
+    /// Construct a new `Email`, rendering only the templates for `parts`.
+    /// The body that isn't rendered is left empty, and providers leave it
+    /// out of the message.
+    pub fn new_with_parts(
         template_name: &str,
         to: &[String],
         subject: &str,
         mut context: Context,
-        templates: Arc<RwLock<Tera>>,
+        templates: EmailTemplates,
+        locale: Option<&str>,
+        parts: EmailParts,
     ) -> error::Result<Self> {
         let engine = templates
+            .tera
             .read()
             .map_err(|e| anyhow!("Error acquiring template read lock: {:?}", e))?;
 
@@ -74,23 +279,39 @@ impl Email {
         context.insert("year", &year.to_string());
         context.insert("subject", &subject);
 
+        let locale = locale
+            .filter(|locale| is_valid_locale(locale))
+            .unwrap_or(templates.config.default_locale.as_str())
+            .to_string();
+        context.insert("locale", &locale);
+
         for (k, v) in env::vars() {
             if k.starts_with("JELLY_") {
                 context.insert(k, &v);
             }
         }
+        context.insert("branding", &templates.branding.with_defaults());
 
-        let body_html = engine
-            .render(&(template_name.to_string() + ".html"), &context)
-            .map_err(|e| error::Error::from(anyhow!(e.to_string())))?;
-        let body = engine
-            .render(&(template_name.to_string() + ".txt"), &context)
-            .map_err(|e| error::Error::from(anyhow!(e.to_string())))?;
+        let body_html = match parts {
+            EmailParts::TextOnly => String::new(),
+            _ => render(&engine, &localized_template(&engine, template_name, &locale, "html"), &context)?,
+        };
+        let body = match parts {
+            EmailParts::HtmlOnly => String::new(),
+            _ => render(&engine, &localized_template(&engine, template_name, &locale, "txt"), &context)?,
+        };
 
         // TODO: Use Figment for configuration.
+        let from_address = env::var("EMAIL_DEFAULT_FROM").expect("EMAIL_DEFAULT_FROM not set!");
+        let from_name = env::var("EMAIL_DEFAULT_FROM_NAME").ok()
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty());
+
         Ok(Email {
             to: to.join(","),
-            from: env::var("EMAIL_DEFAULT_FROM").expect("EMAIL_DEFAULT_FROM not set!"),
+            from: mailbox(from_name.as_deref(), &from_address),
+            from_address,
+            from_name,
             body_html,
             body,
             subject: subject.to_string(),
@@ -102,3 +323,19 @@ impl Email {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_errors_name_the_template_and_the_cause() {
+        let mut engine = Tera::default();
+        engine.add_raw_template("broken.txt", "Hello {{ no_such_value }}").unwrap();
+
+        let e = render(&engine, "broken.txt", &Context::new()).unwrap_err();
+        let message = format!("{:#}", e.error);
+        assert!(message.contains("'broken.txt'"), "{}", message);
+        assert!(message.contains("no_such_value"), "{}", message);
+    }
+}
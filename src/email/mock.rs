@@ -1,13 +1,11 @@
 use std::{collections::HashMap, env, fmt};
 
-use anyhow::anyhow;
 use chrono::Utc;
 use fancy_regex::Regex;
 use serde_json;
 use uuid::Uuid;
 
-use super::common::{env_exists_and_not_empty, Email};
-use crate::error;
+use super::common::{env_exists_and_not_empty, Email, EmailError};
 
 /// Check that all needed environment variables are set and not empty.
 /// TODO: Use Figment for configuration.
@@ -52,17 +50,18 @@ fn create_response(
 
 impl Email {
     /// Send the email. Relies on you ensuring that `EMAIL_DEFAULT_FROM`,
-    /// is set in your `.env`.
+    /// is set in your `.env`. Addresses matching `EMAIL_MOCK_BOUNCE_PATTERN`
+    /// fail with `HardBounce`, the way Postmark reports inactive recipients.
     /// TODO: Use Figment for configuration.
-    pub fn send_via_mock(&self) -> error::Result<()> {
+    pub fn send_via_mock(&self) -> Result<(), EmailError> {
         let pattern = env::var("EMAIL_MOCK_BOUNCE_PATTERN").unwrap_or_else(|_| "^$".to_string());
         let re = Regex::new(&pattern).unwrap();
         let resp = match re.find(&self.to) {
-            Ok(_) => {
-                rocket::info!("Mocking hard bounce for mail to {}.", &self.to);
+            Ok(Some(_)) => {
+                tracing::info!(to = %self.to, "mocking hard bounce");
                 create_response(
-                    200,
-                    "OK",
+                    422,
+                    "Unprocessable Entity",
                     &serde_json::json!({
                         "To": self.to,
                         "SubmittedAt": Utc::now(),
@@ -84,17 +83,19 @@ impl Email {
         };
 
         if resp.status_code == 200 {
-            rocket::info!("Mail sent to {} via mock:", &self.to);
-            rocket::info!("{}", self.body);
+            let body = if self.body.is_empty() { &self.body_html } else { &self.body };
+            tracing::info!(from = %self.from, to = %self.to, %body, "mail sent via mock");
             Ok(())
+        } else if resp.body.get("ErrorCode").and_then(|code| code.as_i64()) == Some(406) {
+            Err(EmailError::HardBounce { to: self.to.clone(), reason: resp.to_string() })
         } else {
-            Err(anyhow!(
+            Err(EmailError::from_status(resp.status_code, format!(
                 "Sending mail to {} via mock failed. API call returns code {} : {} \n {} ",
                 &self.to,
                 resp.status_code,
                 resp.reason_phrase,
                 resp
-            ).into())
+            )))
         }
     }
 }
@@ -6,12 +6,18 @@
 //! send implementation in here.
 
 use std::env;
-use anyhow::{anyhow, Context};
 
-use super::common::env_exists_and_not_empty;
+use super::common::{env_exists_and_not_empty, EmailError};
 pub use super::common::Email;
 
-use crate::error;
+/// Postmark's API error codes that say the recipient address is bad:
+/// invalid, or inactive because it hard bounced, was marked as spam or
+/// unsubscribed.
+const BAD_RECIPIENT_CODES: [i64; 2] = [300, 406];
+
+/// Postmark's API error codes that say our setup is wrong: a bad server
+/// token, or a sender signature that isn't set up or confirmed.
+const CONFIG_ERROR_CODES: [i64; 3] = [10, 400, 401];
 
 /// Check that all needed environment variables are set and not empty.
 /// TODO: Use Figment for configuration.
@@ -25,26 +31,36 @@ impl Email {
     /// Send the email. Relies on you ensuring that `POSTMARK_API_KEY`
     /// is set in your `.env`.
     /// TODO: Use Figment for configuration.
-    pub fn send_via_postmark(&self, base_url_api: &str) -> error::Result<()> {
+    pub fn send_via_postmark(&self, base_url_api: &str) -> Result<(), EmailError> {
         let api_key = env::var("POSTMARK_API_KEY").expect("POSTMARK_API_KEY not set!");
 
         let resp = minreq::post(base_url_api.to_string() + "/email")
             .with_header("X-Postmark-Server-Token", api_key)
             .with_json(&self)?
-            .send()
-            .context("Posting mail via postmark API")?;
+            .send()?;
 
         if resp.status_code == 200 {
             debug!("Mail sent to {} via postmark.", &self.to);
-            Ok(())
-        } else {
-            Err(anyhow!(
-                "Sending mail to {} via postmark failed. API call returns code {} : {} \n {} ",
-                &self.to,
-                resp.status_code,
-                resp.reason_phrase,
-                resp.as_str()?
-            ))
+            return Ok(());
+        }
+
+        let reason = format!(
+            "Sending mail to {} via postmark failed. API call returns code {} : {} \n {} ",
+            &self.to,
+            resp.status_code,
+            resp.reason_phrase,
+            resp.as_str().unwrap_or_default()
+        );
+
+        // Postmark explains 422s with an `ErrorCode` in the body.
+        let error_code = resp.json::<serde_json::Value>().ok()
+            .and_then(|body| body["ErrorCode"].as_i64());
+        match (resp.status_code, error_code) {
+            (422, Some(code)) if BAD_RECIPIENT_CODES.contains(&code) =>
+                Err(EmailError::HardBounce { to: self.to.clone(), reason }),
+            (422, Some(code)) if CONFIG_ERROR_CODES.contains(&code) =>
+                Err(EmailError::Config(reason)),
+            (status_code, _) => Err(EmailError::from_status(status_code, reason)),
         }
     }
 }
@@ -1,14 +1,13 @@
-use anyhow::{anyhow, Context};
 use serde::Serialize;
 
-use super::common::env_exists_and_not_empty;
+use super::common::{env_exists_and_not_empty, EmailError};
 pub use super::common::Email;
 
-use crate::error;
-
 #[derive(Serialize, Debug)]
 struct EmailAddress<'a> {
     email: &'a String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -37,28 +36,29 @@ pub fn check_conf() {
 }
 
 impl Email {
-    /// Send the email.
-    pub fn send_via_sendgrid(&self, base_api_url: &str) -> error::Result<()> {
+    /// Send the email. SendGrid accepts mail to any well formed address
+    /// and reports bounces later, by webhook, so this never fails with
+    /// `HardBounce`.
+    pub fn send_via_sendgrid(&self, base_api_url: &str) -> Result<(), EmailError> {
         let text_plain = "text/plain".to_string();
         let text_html = "text/html".to_string();
+        // SendGrid wants text/plain first when both are sent.
+        let mut content = Vec::new();
+        if !self.body.is_empty() {
+            content.push(Content { r#type: &text_plain, value: &self.body });
+        }
+        if !self.body_html.is_empty() {
+            content.push(Content { r#type: &text_html, value: &self.body_html });
+        }
         let data = SendgridV3Data {
             personalizations: vec![Personalization {
-                to: vec![EmailAddress { email: &self.to }],
+                to: vec![EmailAddress { email: &self.to, name: None }],
             }],
-            from: EmailAddress { email: &self.from },
+            from: EmailAddress { email: &self.from_address, name: self.from_name.as_ref() },
             subject: &self.subject,
-            content: vec![
-                Content {
-                    r#type: &text_plain,
-                    value: &self.body,
-                },
-                Content {
-                    r#type: &text_html,
-                    value: &self.body_html,
-                },
-            ],
+            content,
         };
-        debug!("sendgrid payload: {}", serde_json::to_string(&data)?);
+        debug!("sendgrid payload: {}", serde_json::to_string(&data).unwrap_or_default());
 
         // TODO 106: use external server for test
         let api_key = var("SENDGRID_API_KEY").expect("SENDGRID_API_KEY not set!");
@@ -66,20 +66,20 @@ impl Email {
             .with_header("Authorization: Bearer", api_key)
             .with_json(&data)?
             .with_timeout(30)
-            .send()
-            .context("Posting mail via sendgrid API")?;
+            .send()?;
 
-        if resp.status_code == 200 {
+        // The v3 API answers 202 Accepted on success.
+        if (200..300).contains(&resp.status_code) {
             debug!("Mail sent to {} via sendgrid.", &self.to);
             Ok(())
         } else {
-            Err(anyhow!(
+            Err(EmailError::from_status(resp.status_code, format!(
                 "Sending mail to {} via sendgrid failed. API call returns code {} : {} \n {} ",
                 &self.to,
                 resp.status_code,
                 resp.reason_phrase,
-                resp.as_str()?
-            ))
+                resp.as_str().unwrap_or_default()
+            )))
         }
     }
 }
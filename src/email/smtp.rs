@@ -1,12 +1,10 @@
 use std::env;
 
-use lettre::message::MultiPart;
+use lettre::message::{MultiPart, SinglePart};
 use lettre::transport::smtp::{authentication::Credentials, client::Tls};
 use lettre::{Message, SmtpTransport, Transport};
 
-use super::common::{env_exists_and_not_empty, Email};
-
-use crate::error;
+use super::common::{env_exists_and_not_empty, Email, EmailError};
 
 /// Check that all needed environment variables are set and not empty.
 /// TODO: Use Figment for configuration.
@@ -27,40 +25,77 @@ impl Email {
     /// `EMAIL_SMTP_HOST`, `EMAIL_SMTP_USERNAME`, and `EMAIL_SMTP_PASSWORD`
     /// are set in your `.env`.
     /// TODO: Use Figment for configuration.
-    pub fn send_via_smtp(&self) -> error::Result<()> {
+    pub fn send_via_smtp(&self) -> Result<(), EmailError> {
         let host = env::var("EMAIL_SMTP_HOST").expect("EMAIL_SMTP_HOST not set!");
         let port = env::var("EMAIL_SMTP_PORT").expect("EMAIL_SMTP_PORT not set!");
         let username = env::var("EMAIL_SMTP_USERNAME").expect("EMAIL_SMTP_USERNAME not set!");
         let password = env::var("EMAIL_SMTP_PASSWORD").expect("EMAIL_SMTP_PASSWORD not set!");
         let reply_to = env::var("JELLY_SUPPORT_EMAIL").unwrap_or_else(|_| Ok(self.from.clone()));
 
-        let email = Message::builder()
-            .from(self.from.parse()?)
-            .reply_to(reply_to.parse()?)
-            .to(self.to.parse()?)
-            .subject(&self.subject)
-            .multipart(MultiPart::alternative_plain_html(
+        let builder = Message::builder()
+            .from(self.from.parse().map_err(|e| EmailError::Config(format!("invalid sender: {}", e)))?)
+            .reply_to(reply_to.parse().map_err(|e| EmailError::Config(format!("invalid reply-to: {}", e)))?)
+            .to(self.to.parse().map_err(|e| EmailError::HardBounce { to: self.to.clone(), reason: format!("{}", e) })?)
+            .subject(&self.subject);
+
+        let email = match (self.body.is_empty(), self.body_html.is_empty()) {
+            (false, true) => builder.singlepart(SinglePart::plain(self.body.clone())),
+            (true, false) => builder.singlepart(SinglePart::html(self.body_html.clone())),
+            _ => builder.multipart(MultiPart::alternative_plain_html(
                 self.body.clone(),
                 self.body_html.clone(),
-            ))?;
+            )),
+        }
+        .map_err(|e| EmailError::Unknown(e.to_string()))?;
 
         let creds = Credentials::new(username, password);
 
         // Open a remote connection to EMAIL_SMTP_HOST
-        let mut mailer_builder = SmtpTransport::relay(&host)?
-            .port(port.parse()?)
+        let mut mailer_builder = SmtpTransport::relay(&host)
+            .map_err(|e| EmailError::Config(e.to_string()))?
+            .port(port.parse().map_err(|_| EmailError::Config(format!("invalid EMAIL_SMTP_PORT {}", port)))?)
             .credentials(creds);
         if let Ok(notls) = var("EMAIL_SMTP_NOTLS").map(|v| v == "1" || v == "true") {
             if notls {
                 mailer_builder = mailer_builder.tls(Tls::None);
-                info!("Send email with no TLS");
+                tracing::info!("sending email with no TLS");
             }
         }
 
         let mailer = mailer_builder.build();
-        mailer.send(&email).context("Posting mail via sendgrid API")?;
+        match mailer.send(&email) {
+            Ok(_) => {},
+            Err(e) if e.is_transient() => return Err(EmailError::Transient(e.to_string())),
+            Err(e) if e.status().map_or(false, |code| rejects_recipient(&code.to_string())) => {
+                return Err(EmailError::HardBounce { to: self.to.clone(), reason: e.to_string() });
+            },
+            Err(e) => return Err(EmailError::Unknown(e.to_string())),
+        }
         debug!("Mail sent to {} via smtp.", &self.to);
 
         Ok(())
     }
 }
+
+/// Whether a permanent SMTP reply `code` means the mailbox itself was
+/// refused: it doesn't exist, isn't local, or its name isn't allowed.
+/// Other 5xx replies, such as to a bad login or a message the relay
+/// won't accept, are our problem rather than the recipient's.
+fn rejects_recipient(code: &str) -> bool {
+    matches!(code, "550" | "551" | "553")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_mailbox_rejections_are_hard_bounces() {
+        for code in ["550", "551", "553"] {
+            assert!(rejects_recipient(code), "{}", code);
+        }
+        for code in ["421", "450", "535", "552", "554"] {
+            assert!(!rejects_recipient(code), "{}", code);
+        }
+    }
+}
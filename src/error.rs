@@ -6,6 +6,7 @@ use rocket::http::Status;
 use rocket::request::Request;
 use rocket::response;
 use rocket::response::Responder;
+use rocket::serde::json::Json;
 
 /// Wrapper around [`anyhow::Error`]
 /// with rocket's [responder] implemented
@@ -55,12 +56,37 @@ impl Error {
     }
 }
 
+/// Clients that prefer JSON get `{ "error": <message>, "status": <code> }`;
+/// others get Rocket's default error page for the status. Server errors
+/// are logged in full, and only a generic message is sent back for them.
 impl<'r, 'o: 'r> Responder<'r, 'o> for Error {
     fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
         // log `self` to your favored error tracker, e.g.
         // sentry::capture_error(&self);
 
-        self.status.respond_to(req)
+        let is_server_error = self.status.code >= 500;
+        if is_server_error {
+            rocket::error!("{:#} ({})", self.error, self.status.code);
+        }
+
+        let wants_json = req.accept()
+            .map_or(false, |accept| accept.preferred().media_type().is_json());
+        if !wants_json {
+            return self.status.respond_to(req);
+        }
+
+        let message = if is_server_error {
+            self.status.reason().unwrap_or("Internal Server Error").to_string()
+        } else {
+            self.error.to_string()
+        };
+
+        let body = Json(serde_json::json!({
+            "error": message,
+            "status": self.status.code,
+        }));
+
+        (self.status, body).respond_to(req)
     }
 }
 
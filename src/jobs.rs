@@ -1,29 +1,55 @@
 //! Set up background jobs
 
+use std::collections::{HashSet, VecDeque};
 use std::env;
 use std::fmt::Debug;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 use anyhow::anyhow;
-use rocket::{Build, Orbit, Rocket};
+use rocket::{Build, Orbit, Rocket, Shutdown};
 use rocket::config::LogLevel;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::figment::providers::Serialized;
-use rocket::futures::StreamExt;
+use rocket::futures::{FutureExt, StreamExt};
 use rocket::http::Status;
 use rocket::request::{FromRequest, Request, Outcome};
+use rocket::tokio::task::JoinHandle;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sqlx::{ConnectOptions, PgPool, Postgres};
 use sqlx::types::{Json, Uuid};
 use tera::Tera;
 use tokio_stream::{self as stream};
+use tracing::Instrument;
 
+use crate::config::{AppConfig, JobsConfig, QueueConfig};
 use crate::database;
+use crate::email::{self, Email, EmailError, EmailTemplates};
 use crate::error;
+use crate::oauth::client::OAuthProviders;
 
+mod bulk_email;
+use bulk_email::SendBulkEmail;
+mod cleanup_stale_jobs;
+use cleanup_stale_jobs::CleanupStaleRunningJobs;
+mod cooldown;
+mod downgrade_plans;
+use downgrade_plans::DowngradeExpiredPlans;
+mod email_change;
+use email_change::{SendEmailChangeConfirmation, SendEmailChangeNotice};
+mod invitation;
+use invitation::SendInvitationEmail;
+mod magic_link;
+use magic_link::SendMagicLinkEmail;
 mod odd_registration_attempt;
 use odd_registration_attempt::SendAccountOddRegisterAttemptEmail;
+mod password_expiry;
+use password_expiry::{RemindExpiredPasswords, SendPasswordExpiryReminder};
+mod purge_unverified;
+use purge_unverified::PurgeUnverifiedAccounts;
+mod reengagement;
+use reengagement::SendReengagementEmail;
 mod reset_password;
 use reset_password::{SendPasswordWasResetEmail, SendResetPasswordEmail};
 mod verify;
@@ -31,8 +57,17 @@ use verify::SendVerifyAccountEmail;
 mod welcome;
 use welcome::SendWelcomeAccountEmail;
 
+/// The queue jobs go to unless pushed to another with `push_to`.
 pub const DEFAULT_QUEUE: &str = "default";
 
+/// The priority of jobs pushed without one. Jobs with a higher priority
+/// are pulled first; equal ones in the order they were scheduled for.
+pub const DEFAULT_PRIORITY: i32 = 0;
+
+/// For jobs a user is waiting on, like password reset emails, so they
+/// run ahead of any batch of bulk email.
+pub const HIGH_PRIORITY: i32 = 10;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
     SendResetPasswordEmail(String),
@@ -40,8 +75,106 @@ pub enum Message {
     SendAccountOddRegisterAttemptEmail(String),
     SendVerifyAccountEmail(String),
     SendWelcomeAccountEmail(String),
+    SendReengagementEmail(String),
+    SendPasswordExpiryReminder(String),
+    SendMagicLinkEmail(String),
+    SendInvitationEmail {
+        to: String,
+        token: String,
+    },
+    SendEmailChangeConfirmation {
+        email: String,
+        new_email: String,
+    },
+    SendEmailChangeNotice {
+        old_email: String,
+        new_email: String,
+    },
+    SendBulkEmail {
+        template: String,
+        recipients: Vec<String>,
+        subject: String,
+        #[serde(default)]
+        attempt: u32,
+    },
+    DowngradeExpiredPlans,
+    CleanupStaleRunningJobs,
+    RemindExpiredPasswords,
+    PurgeUnverifiedAccounts,
+}
+
+impl Message {
+    /// The name of the message's variant, e.g. "SendVerifyAccountEmail",
+    /// as `sample` takes it.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::SendResetPasswordEmail(_) => "SendResetPasswordEmail",
+            Message::SendPasswordWasResetEmail(_) => "SendPasswordWasResetEmail",
+            Message::SendAccountOddRegisterAttemptEmail(_) => "SendAccountOddRegisterAttemptEmail",
+            Message::SendVerifyAccountEmail(_) => "SendVerifyAccountEmail",
+            Message::SendWelcomeAccountEmail(_) => "SendWelcomeAccountEmail",
+            Message::SendReengagementEmail(_) => "SendReengagementEmail",
+            Message::SendPasswordExpiryReminder(_) => "SendPasswordExpiryReminder",
+            Message::SendMagicLinkEmail(_) => "SendMagicLinkEmail",
+            Message::SendInvitationEmail { .. } => "SendInvitationEmail",
+            Message::SendEmailChangeConfirmation { .. } => "SendEmailChangeConfirmation",
+            Message::SendEmailChangeNotice { .. } => "SendEmailChangeNotice",
+            Message::SendBulkEmail { .. } => "SendBulkEmail",
+            Message::DowngradeExpiredPlans => "DowngradeExpiredPlans",
+            Message::CleanupStaleRunningJobs => "CleanupStaleRunningJobs",
+            Message::RemindExpiredPasswords => "RemindExpiredPasswords",
+            Message::PurgeUnverifiedAccounts => "PurgeUnverifiedAccounts",
+        }
+    }
+
+    /// A message of the kind named by its variant, e.g.
+    /// "SendVerifyAccountEmail", with sample parameters for trying the
+    /// job out. Emails go to `to`. `RECURRING_JOBS` have none: running
+    /// one would queue another run, besides the one already waiting, and
+    /// some change data across every account.
+    pub fn sample(name: &str, to: &str) -> Option<Message> {
+        let to = to.to_string();
+        match name {
+            "SendResetPasswordEmail" => Some(Message::SendResetPasswordEmail(to)),
+            "SendPasswordWasResetEmail" => Some(Message::SendPasswordWasResetEmail(to)),
+            "SendAccountOddRegisterAttemptEmail" => Some(Message::SendAccountOddRegisterAttemptEmail(to)),
+            "SendVerifyAccountEmail" => Some(Message::SendVerifyAccountEmail(to)),
+            "SendWelcomeAccountEmail" => Some(Message::SendWelcomeAccountEmail(to)),
+            "SendReengagementEmail" => Some(Message::SendReengagementEmail(to)),
+            "SendPasswordExpiryReminder" => Some(Message::SendPasswordExpiryReminder(to)),
+            "SendMagicLinkEmail" => Some(Message::SendMagicLinkEmail(to)),
+            "SendInvitationEmail" => Some(Message::SendInvitationEmail {
+                to,
+                token: "sample-invitation-token".to_string(),
+            }),
+            "SendEmailChangeConfirmation" => Some(Message::SendEmailChangeConfirmation {
+                email: to.clone(),
+                new_email: to,
+            }),
+            "SendEmailChangeNotice" => Some(Message::SendEmailChangeNotice {
+                old_email: to.clone(),
+                new_email: to,
+            }),
+            "SendBulkEmail" => Some(Message::SendBulkEmail {
+                template: "welcome".to_string(),
+                recipients: vec![to],
+                subject: "Sample bulk email".to_string(),
+                attempt: 0,
+            }),
+            _ => None,
+        }
+    }
 }
 
+/// Jobs that reschedule themselves each time they run. One instance of
+/// each is queued at liftoff if it isn't already waiting in the queue.
+const RECURRING_JOBS: [Message; 4] = [
+    Message::DowngradeExpiredPlans,
+    Message::CleanupStaleRunningJobs,
+    Message::RemindExpiredPasswords,
+    Message::PurgeUnverifiedAccounts,
+];
+
 // We use a INT as Postgres representation for performance reasons
 #[derive(Debug, Clone, sqlx::Type, PartialEq)]
 #[repr(i32)]
@@ -59,7 +192,6 @@ struct PostgresJob {
     created_at: chrono::DateTime<chrono::Utc>,
     #[allow(dead_code)]
     updated_at: chrono::DateTime<chrono::Utc>,
-    #[allow(dead_code)]
     scheduled_for: chrono::DateTime<chrono::Utc>,
     #[allow(dead_code)]
     failed_attempts: i32,
@@ -71,6 +203,8 @@ struct PostgresJob {
 pub struct Job {
     pub id: Uuid,
     pub message: Message,
+    #[serde(with = "crate::models::rfc3339")]
+    pub scheduled_for: chrono::DateTime<chrono::Utc>,
 }
 
 impl From<PostgresJob> for Job {
@@ -78,6 +212,7 @@ impl From<PostgresJob> for Job {
         Job {
             id: item.id,
             message: item.message.0,
+            scheduled_for: item.scheduled_for,
         }
     }
 }
@@ -87,61 +222,186 @@ const CONCURRENCY: usize = 50;
 const QUEUE_EMPTY_DELAY: u64 = 500;
 const QUEUE_INTERVAL: u64 = 125;
 
+/// Connections in the queue's pool kept free of running jobs, for
+/// request handlers pushing new ones.
+const POOL_RESERVE: usize = 2;
+
+/// How many jobs to run at once: `configured`, but no more than the pool
+/// can serve while keeping `POOL_RESERVE` connections free, and at least 1.
+fn effective_concurrency(configured: usize, max_connections: usize) -> usize {
+    configured
+        .min(max_connections.saturating_sub(POOL_RESERVE))
+        .max(1)
+}
+
 #[derive(Debug, Clone)]
 pub struct PostgresQueue {
     pool: PgPool,
-    templates: Arc<RwLock<Tera>>,
+    templates: EmailTemplates,
+    /// The app's settings, for jobs that depend on them.
+    config: Arc<AppConfig>,
+    /// For jobs that delete accounts, to revoke their provider tokens.
+    providers: Arc<OAuthProviders>,
     max_attempts: i32,
+    concurrency: usize,
+    completed: CompletionLog,
 }
 
 impl PostgresQueue {
-    pub fn new(pool: PgPool, templates: Arc<RwLock<Tera>>, max_attempts: i32) -> PostgresQueue {
+    pub fn new(
+        pool: PgPool,
+        templates: Arc<RwLock<Tera>>,
+        config: AppConfig,
+        providers: OAuthProviders,
+        max_attempts: i32,
+        max_connections: usize,
+    ) -> PostgresQueue {
+        let concurrency = effective_concurrency(CONCURRENCY, max_connections);
+        if concurrency < CONCURRENCY {
+            tracing::warn!(requested = CONCURRENCY, max_connections, concurrency,
+                "job concurrency is more than the pool's connections allow");
+        }
+
         PostgresQueue {
             pool,
-            templates,
+            templates: EmailTemplates::new(templates, config.email.clone(), config.email_branding.clone()),
+            config: Arc::new(config),
+            providers: Arc::new(providers),
             max_attempts,
+            concurrency,
+            completed: CompletionLog::default(),
         }
     }
 
+    /// Pushes a job to the `DEFAULT_QUEUE`, to run at `date`, or now,
+    /// with `priority`, or `DEFAULT_PRIORITY`.
     pub async fn push(
         &self,
         job: Message,
         date: Option<chrono::DateTime<chrono::Utc>>,
+        priority: Option<i32>,
     ) -> error::Result<()> {
-        let scheduled_for = date.unwrap_or_else(chrono::Utc::now);
-        let failed_attempts: i32 = 0;
-        let message = Json(job.clone());
-        let status = PostgresJobStatus::Queued;
-        let now = chrono::Utc::now();
+        self.push_to(DEFAULT_QUEUE, job, date, priority).await
+    }
 
+    /// Pushes a job to the named queue. Only servers with a worker for
+    /// that queue, in `jobs.queues`, will run it.
+    pub async fn push_to(
+        &self,
+        queue_name: &str,
+        job: Message,
+        date: Option<chrono::DateTime<chrono::Utc>>,
+        priority: Option<i32>,
+    ) -> error::Result<()> {
         // ULID to UUID. We use Ulid so that job_ids are ordered by creation time.
         let job_id: Uuid = ulid::Ulid::new().into();
+        insert_job(&self.pool, job_id, queue_name, job, date, priority).await
+    }
+
+    /// Pushes a job to the `DEFAULT_QUEUE` now, unless one was pushed
+    /// under the same idempotency `key` within the last `window` seconds,
+    /// in which case that job's id is returned and nothing is queued.
+    /// Keys name the job's purpose and subject, e.g.
+    /// "verify-account:user@example.com", so that a retried request
+    /// doesn't queue the same job twice.
+    pub async fn push_idempotent(&self, key: &str, window: i64, job: Message) -> error::Result<Uuid> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM job_keys WHERE expires_at <= now()")
+            .execute(&mut tx)
+            .await?;
+
+        let job_id: Uuid = ulid::Ulid::new().into();
+        let claimed = sqlx::query("INSERT INTO job_keys (key, job_id, expires_at)
+            VALUES ($1, $2, now() + make_interval(secs => $3))
+            ON CONFLICT (key) DO NOTHING")
+            .bind(key)
+            .bind(job_id)
+            .bind(window as f64)
+            .execute(&mut tx)
+            .await?;
+
+        if claimed.rows_affected() == 0 {
+            let (existing,): (Uuid,) = sqlx::query_as("SELECT job_id FROM job_keys WHERE key = $1")
+                .bind(key)
+                .fetch_one(&mut tx)
+                .await?;
+            tx.commit().await?;
+            tracing::info!(job_id = %existing, key, "job already pushed under key");
+            return Ok(existing);
+        }
+
+        insert_job(&mut tx, job_id, DEFAULT_QUEUE, job, None, None).await?;
+        tx.commit().await?;
+        Ok(job_id)
+    }
+
+    /// Pushes a job now, unless one was pushed under the same `key` for
+    /// `to` within the last `window` seconds. Returns whether it was. For
+    /// jobs anyone can request, like resending a verification email, so
+    /// the request can't be used to flood someone's inbox.
+    pub async fn push_with_cooldown(&self, key: &str, to: &str, window: i64, job: Message) -> error::Result<bool> {
+        if !cooldown::claim(&self.pool, key, to, window).await? {
+            return Ok(false);
+        }
+
+        if let Err(e) = self.push(job, None, None).await {
+            cooldown::release(&self.pool, key, to).await?;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Pushes many jobs to the `DEFAULT_QUEUE` with one INSERT, each to run
+    /// at its date, or now. Either all are queued or none are. Returns
+    /// the new job ids, in the order given.
+    pub async fn push_batch(
+        &self,
+        jobs: Vec<(Message, Option<chrono::DateTime<chrono::Utc>>)>,
+    ) -> error::Result<Vec<Uuid>> {
+        if jobs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let now = chrono::Utc::now();
+        let mut job_ids: Vec<Uuid> = Vec::with_capacity(jobs.len());
+        let mut scheduled_for = Vec::with_capacity(jobs.len());
+        let mut messages: Vec<serde_json::Value> = Vec::with_capacity(jobs.len());
+        for (job, date) in jobs {
+            job_ids.push(ulid::Ulid::new().into());
+            scheduled_for.push(date.unwrap_or(now));
+            messages.push(serde_json::to_value(job)?);
+        }
+
         let query = "INSERT INTO queue
-            (id, created_at, updated_at, scheduled_for, failed_attempts, status, message)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)";
+            (id, created_at, updated_at, scheduled_for, failed_attempts, status, message, queue_name, priority)
+            SELECT id, $1, $1, scheduled_for, 0, $2, message, $3, $4
+            FROM UNNEST($5::uuid[], $6::timestamptz[], $7::jsonb[]) AS jobs (id, scheduled_for, message)";
 
+        let mut tx = self.pool.begin().await?;
         let query_result = sqlx::query(query)
-            .bind(job_id)
             .bind(now)
-            .bind(now)
-            .bind(scheduled_for)
-            .bind(failed_attempts)
-            .bind(status)
-            .bind(message)
-            .execute(&self.pool)
+            .bind(PostgresJobStatus::Queued)
+            .bind(DEFAULT_QUEUE)
+            .bind(DEFAULT_PRIORITY)
+            .bind(&job_ids)
+            .bind(&scheduled_for)
+            .bind(&messages)
+            .execute(&mut tx)
             .await?;
 
-        if query_result.rows_affected() > 0 {
-            rocket::info!("pushed job {}", job_id);
-            Ok(())
-        } else {
-            rocket::error!("failed to push job {}", job_id);
-            Err(anyhow!("job insertion error").into())
+        if query_result.rows_affected() as usize != job_ids.len() {
+            tracing::error!(count = job_ids.len(), "failed to push batch of jobs");
+            return Err(anyhow!("job batch insertion error").into());
         }
+        tx.commit().await?;
+
+        tracing::info!(count = job_ids.len(), "pushed batch of jobs");
+        Ok(job_ids)
     }
 
-    /// pull fetches at most `number_of_jobs` from the queue.
-    pub async fn pull(&self, number_of_jobs: u32) -> error::Result<Vec<Job>> {
+    /// pull fetches at most `number_of_jobs` from the named queue.
+    pub async fn pull(&self, queue_name: &str, number_of_jobs: u32) -> error::Result<Vec<Job>> {
         let now = chrono::Utc::now();
 
         // Note use of UPDATE SKIP LOCKED for performance
@@ -150,8 +410,8 @@ impl PostgresQueue {
             WHERE id IN (
                 SELECT id
                 FROM queue
-                WHERE status = $3 AND scheduled_for <= $4 AND failed_attempts < $5
-                ORDER BY scheduled_for
+                WHERE queue_name = $7 AND status = $3 AND scheduled_for <= $4 AND failed_attempts < $5
+                ORDER BY priority DESC, scheduled_for ASC
                 FOR UPDATE SKIP LOCKED
                 LIMIT $6
             )
@@ -164,6 +424,7 @@ impl PostgresQueue {
             .bind(now)
             .bind(self.max_attempts)
             .bind(number_of_jobs)
+            .bind(queue_name)
             .fetch_all(&self.pool)
             .await?;
 
@@ -177,16 +438,216 @@ impl PostgresQueue {
         Ok(())
     }
 
+    /// Moves a job that can't succeed to the `dead_jobs` table, with the
+    /// error that killed it.
+    pub async fn bury_job(&self, job_id: Uuid, error: &str) -> error::Result<()> {
+        let query = "WITH dead AS (
+                DELETE FROM queue WHERE id = $1
+                RETURNING id, created_at, failed_attempts, message
+            )
+            INSERT INTO dead_jobs (id, created_at, failed_attempts, message, error)
+            SELECT id, created_at, failed_attempts + 1, message, $2 FROM dead";
+
+        sqlx::query(query)
+            .bind(job_id)
+            .bind(error)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Requeues a job to be retried, or marks it `Failed` once it has
+    /// used up `max_attempts`, or if it is a recurring job whose next run
+    /// is already queued.
     pub async fn fail_job(&self, job_id: Uuid) -> error::Result<()> {
         let now = chrono::Utc::now();
         let query = "UPDATE queue
-            SET status = $1, updated_at = $2, failed_attempts = failed_attempts + 1
-            WHERE id = $3";
+            SET status = CASE WHEN failed_attempts + 1 >= $1 OR EXISTS (
+                    SELECT 1 FROM queue AS next
+                    WHERE next.message = queue.message AND next.status = $3 AND jsonb_typeof(next.message) = 'string'
+                ) THEN $2 ELSE $3 END,
+                updated_at = $4, failed_attempts = failed_attempts + 1
+            WHERE id = $5";
 
         sqlx::query(query)
+            .bind(self.max_attempts)
+            .bind(PostgresJobStatus::Failed)
+            .bind(PostgresJobStatus::Queued)
+            .bind(now)
+            .bind(job_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Renders an email template as `Email::new` would for a job, without
+    /// sending it. Fails with `NotFound` if there's no such template.
+    pub fn preview_email(&self, template_name: &str, context: tera::Context, locale: Option<&str>) -> error::Result<Email> {
+        let exists = self.templates.tera.read()
+            .map(|tera| tera.get_template(&format!("{}.html", template_name)).is_ok())
+            .unwrap_or(false);
+        if !exists {
+            return Err(error::Error::with_status(
+                anyhow!("no email template named {}", template_name), Status::NotFound));
+        }
+
+        let subject = format!("Preview of {}", template_name);
+        Email::new(template_name, &[], &subject, context, self.templates.clone(), locale)
+    }
+
+    /// Usage of the job database pool, which is separate from `AppDb`'s.
+    pub fn pool_stats(&self) -> database::PoolStats {
+        database::PoolStats::of(&self.pool)
+    }
+
+    /// Counts of jobs by state, and how far behind the queue is, across
+    /// all queues. Completions are only those of this server's workers.
+    pub async fn stats(&self) -> error::Result<QueueStats> {
+        let query = "SELECT
+                count(*) FILTER (WHERE status = $1 AND failed_attempts < $4),
+                count(*) FILTER (WHERE status = $2),
+                count(*) FILTER (WHERE status = $3 OR (status = $1 AND failed_attempts >= $4)),
+                min(scheduled_for) FILTER (WHERE status = $1 AND failed_attempts < $4)
+            FROM queue";
+
+        let (queued, running, failed, oldest_queued): (i64, i64, i64, Option<chrono::DateTime<chrono::Utc>>) =
+            sqlx::query_as(query)
+                .bind(PostgresJobStatus::Queued)
+                .bind(PostgresJobStatus::Running)
+                .bind(PostgresJobStatus::Failed)
+                .bind(self.max_attempts)
+                .fetch_one(&self.pool)
+                .await?;
+
+        let (dead,): (i64,) = sqlx::query_as("SELECT count(*) FROM dead_jobs")
+            .fetch_one(&self.pool)
+            .await?;
+
+        // Recurring jobs wait in the queue scheduled for later, so only
+        // a job that is past due counts as lag.
+        let lag_seconds = oldest_queued
+            .map_or(0, |oldest| (chrono::Utc::now() - oldest).num_seconds().max(0));
+
+        Ok(QueueStats {
+            queued,
+            running,
+            failed,
+            dead,
+            oldest_queued,
+            lag_seconds,
+            completed: self.completed.count(),
+            completed_window_seconds: COMPLETED_WINDOW,
+        })
+    }
+
+    /// Requeues jobs that have been `Running` for more than `timeout`
+    /// seconds, whose worker presumably died. This counts as a failed
+    /// attempt, so jobs that keep killing their worker end up `Failed`
+    /// once they reach `max_attempts`, as do recurring jobs whose next
+    /// run is already queued. Returns how many were recovered.
+    pub async fn recover_stale_jobs(&self, timeout: u64) -> error::Result<u64> {
+        let now = chrono::Utc::now();
+        let query = "UPDATE queue
+            SET status = CASE WHEN failed_attempts + 1 >= $1 OR EXISTS (
+                    SELECT 1 FROM queue AS next
+                    WHERE next.message = queue.message AND next.status = $3 AND jsonb_typeof(next.message) = 'string'
+                ) THEN $2 ELSE $3 END,
+                updated_at = $4, failed_attempts = failed_attempts + 1
+            WHERE status = $5 AND updated_at < $4 - make_interval(secs => $6)";
+
+        let result = sqlx::query(query)
+            .bind(self.max_attempts)
+            .bind(PostgresJobStatus::Failed)
             .bind(PostgresJobStatus::Queued)
             .bind(now)
+            .bind(PostgresJobStatus::Running)
+            .bind(timeout as f64)
+            .execute(&self.pool)
+            .await?;
+
+        let count = result.rows_affected();
+        if count > 0 {
+            tracing::warn!(count, "recovered stale running jobs");
+        }
+        Ok(count)
+    }
+
+    /// The number of jobs waiting to run.
+    pub async fn count_queued(&self) -> error::Result<i64> {
+        let query = "SELECT count(*) FROM queue WHERE status = $1";
+
+        let (count,): (i64,) = sqlx::query_as(query)
+            .bind(PostgresJobStatus::Queued)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count)
+    }
+
+    /// Runs `job` in the calling task, bypassing the queue.
+    pub async fn run_now(&self, job: Message) -> error::Result<()> {
+        run_message(job, self).await
+    }
+
+    /// Seeds one of the `RECURRING_JOBS` to run now, unless a live copy
+    /// of it is queued or running. Copies that used up their attempts
+    /// don't count, and are marked `Failed` so they can't block it.
+    pub async fn push_if_absent(&self, job: Message) -> error::Result<()> {
+        let message = Json(job);
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("UPDATE queue SET status = $1
+            WHERE message = $2 AND status = $3 AND failed_attempts >= $4")
+            .bind(PostgresJobStatus::Failed)
+            .bind(&message)
+            .bind(PostgresJobStatus::Queued)
+            .bind(self.max_attempts)
+            .execute(&mut tx)
+            .await?;
+
+        // The unique index on queued recurring jobs makes this a no-op
+        // when another server seeds the same job first.
+        let now = chrono::Utc::now();
+        let query = "INSERT INTO queue
+            (id, created_at, updated_at, scheduled_for, failed_attempts, status, message, queue_name, priority)
+            SELECT $1, $2, $2, $2, 0, $3, $4, $5, $6
+            WHERE NOT EXISTS (SELECT 1 FROM queue WHERE message = $4 AND status = $7)
+            ON CONFLICT DO NOTHING";
+
+        let job_id: Uuid = ulid::Ulid::new().into();
+        sqlx::query(query)
+            .bind(job_id)
+            .bind(now)
+            .bind(PostgresJobStatus::Queued)
+            .bind(&message)
+            .bind(DEFAULT_QUEUE)
+            .bind(DEFAULT_PRIORITY)
+            .bind(PostgresJobStatus::Running)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Schedules the next run of one of the `RECURRING_JOBS`, from the
+    /// run before it, unless one was seeded meanwhile.
+    pub async fn push_recurring(&self, job: Message, date: chrono::DateTime<chrono::Utc>) -> error::Result<()> {
+        let now = chrono::Utc::now();
+        let query = "INSERT INTO queue
+            (id, created_at, updated_at, scheduled_for, failed_attempts, status, message, queue_name, priority)
+            VALUES ($1, $2, $2, $3, 0, $4, $5, $6, $7)
+            ON CONFLICT DO NOTHING";
+
+        let job_id: Uuid = ulid::Ulid::new().into();
+        sqlx::query(query)
             .bind(job_id)
+            .bind(now)
+            .bind(date)
+            .bind(PostgresJobStatus::Queued)
+            .bind(Json(job))
+            .bind(DEFAULT_QUEUE)
+            .bind(DEFAULT_PRIORITY)
             .execute(&self.pool)
             .await?;
         Ok(())
@@ -200,18 +661,159 @@ impl PostgresQueue {
     }
 }
 
+/// When the worker loop last went round, as a unix timestamp, kept in
+/// Rocket managed state so that `/readyz` can tell if the worker died.
+#[derive(Debug, Clone, Default)]
+pub struct WorkerHeartbeat(Arc<AtomicI64>);
+
+impl WorkerHeartbeat {
+    fn beat(&self) {
+        self.0.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    /// Seconds since the last beat, or None if the worker never started.
+    pub fn age(&self) -> Option<i64> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            last => Some(chrono::Utc::now().timestamp() - last),
+        }
+    }
+}
+
+/// Seconds over which `QueueStats` counts completed jobs.
+const COMPLETED_WINDOW: i64 = 300;
+
+/// When this server's workers completed jobs, as unix timestamps, for
+/// the last `COMPLETED_WINDOW` seconds.
+#[derive(Debug, Clone, Default)]
+struct CompletionLog(Arc<Mutex<VecDeque<i64>>>);
+
+impl CompletionLog {
+    fn record(&self) {
+        let now = chrono::Utc::now().timestamp();
+        let mut log = self.0.lock().unwrap();
+        log.push_back(now);
+        Self::expire(&mut log, now);
+    }
+
+    fn count(&self) -> u64 {
+        let mut log = self.0.lock().unwrap();
+        Self::expire(&mut log, chrono::Utc::now().timestamp());
+        log.len() as u64
+    }
+
+    fn expire(log: &mut VecDeque<i64>, now: i64) {
+        while log.front().map_or(false, |completed| *completed <= now - COMPLETED_WINDOW) {
+            log.pop_front();
+        }
+    }
+}
+
+/// A snapshot of the job queue, for monitoring.
+#[derive(Debug, Serialize)]
+pub struct QueueStats {
+    /// Jobs waiting to run, now or later.
+    pub queued: i64,
+    pub running: i64,
+    /// Jobs that used up their attempts and are left in the queue.
+    pub failed: i64,
+    /// Jobs moved to `dead_jobs` as not worth retrying.
+    pub dead: i64,
+    /// When the longest waiting job was scheduled for.
+    pub oldest_queued: Option<chrono::DateTime<chrono::Utc>>,
+    /// Seconds the oldest job is past due, or 0.
+    pub lag_seconds: i64,
+    /// Jobs this server completed in the last `completed_window_seconds`.
+    pub completed: u64,
+    pub completed_window_seconds: i64,
+}
+
+/// Why a job failed, and whether running it again could help. Email
+/// errors say for themselves; anything else is assumed worth a retry.
+#[derive(Debug)]
+pub struct JobError {
+    pub error: error::Error,
+    pub retryable: bool,
+}
+
+impl From<error::Error> for JobError {
+    fn from(error: error::Error) -> Self {
+        let retryable = error.error.downcast_ref::<EmailError>()
+            .map_or(true, EmailError::is_retryable);
+        JobError { error, retryable }
+    }
+}
+
+impl std::fmt::Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.error, if self.retryable { "" } else { " (not retryable)" })
+    }
+}
+
 /// From background_jobs crate
 #[rocket::async_trait]
 pub trait JobRun: 'static + Serialize + DeserializeOwned {
     async fn run(self, state: &PostgresQueue) -> error::Result<()>;
 }
 
-async fn run_worker(queue: PostgresQueue) {
+/// The worker tasks spawned at liftoff, managed as Rocket state so they
+/// can be waited on once the server has shut down.
+#[derive(Debug, Clone)]
+pub struct Workers {
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    drain_timeout: Duration,
+}
+
+impl Workers {
+    fn new(drain_timeout: Duration) -> Self {
+        Workers { handles: Arc::default(), drain_timeout }
+    }
+
+    fn add(&self, handle: JoinHandle<()>) {
+        self.handles.lock().unwrap().push(handle);
+    }
+
+    /// Waits, up to the drain timeout, for the workers to finish the jobs
+    /// they were running when shutdown was signaled, so no email is left
+    /// half sent. Jobs still running after that are left `Running`, for
+    /// the stale job recovery to requeue.
+    pub async fn drain(&self) {
+        let handles: Vec<JoinHandle<()>> = self.handles.lock().unwrap().drain(..).collect();
+        if handles.is_empty() {
+            return;
+        }
+
+        tracing::info!(timeout_secs = self.drain_timeout.as_secs(), "waiting for running jobs to finish");
+        let finished = rocket::tokio::time::timeout(self.drain_timeout, rocket::futures::future::join_all(handles)).await;
+        if finished.is_err() {
+            tracing::warn!(timeout_secs = self.drain_timeout.as_secs(),
+                "jobs still running, leaving them for stale job recovery");
+        }
+    }
+}
+
+/// Runs the jobs in one named queue, up to `concurrency` at a time, until
+/// shutdown is signaled. Jobs already running when it is are finished;
+/// see `Workers::drain`.
+async fn run_worker(
+    queue: PostgresQueue,
+    queue_name: String,
+    concurrency: usize,
+    heartbeat: WorkerHeartbeat,
+    shutdown: Shutdown,
+) {
     loop {
-        let jobs = match queue.pull(CONCURRENCY as u32).await {
+        heartbeat.beat();
+
+        // Pulled jobs are marked running, so the pull isn't interrupted.
+        if shutdown.clone().now_or_never().is_some() {
+            break;
+        }
+
+        let jobs = match queue.pull(&queue_name, concurrency as u32).await {
             Ok(jobs) => jobs,
             Err(err) => {
-                rocket::error!("error pulling jobs: {}", err);
+                tracing::error!(queue = %queue_name, error = %err, "could not pull jobs");
                 rocket::tokio::time::sleep(Duration::from_millis(QUEUE_EMPTY_DELAY)).await;
                 Vec::new()
             }
@@ -219,39 +821,97 @@ async fn run_worker(queue: PostgresQueue) {
 
         let number_of_jobs = jobs.len();
         if number_of_jobs > 0 {
-            rocket::info!("fetched {} jobs", number_of_jobs);
+            tracing::info!(queue = %queue_name, count = number_of_jobs, "fetched jobs");
         }
 
         stream::iter(jobs)
-            .for_each_concurrent(CONCURRENCY, |job| async {
-                let job_id = job.id;
-                let res = match handle_job(job, &queue).await {
-                    Ok(_) => {
-                        rocket::info!("job({}) was handled successfully", job_id);
-                        queue.delete_job(job_id).await
-                    },
-                    Err(err) => {
-                        rocket::error!("error handling job({}): {}", job_id, &err);
-                        queue.fail_job(job_id).await
-                    }
-                };
+            .for_each_concurrent(concurrency, |job| {
+                // Everything logged while the job runs carries its id.
+                let span = tracing::info_span!("job", job_id = %job.id, queue = %queue_name, kind = job.message.kind());
+                async {
+                    let job_id = job.id;
+                    let res = match handle_job(job, &queue).await {
+                        Ok(_) => {
+                            tracing::info!("job handled");
+                            queue.completed.record();
+                            queue.delete_job(job_id).await
+                        },
+                        Err(err) if !err.retryable => {
+                            tracing::error!(error = %err, "job failed, giving up");
+                            queue.bury_job(job_id, &format!("{:#}", err.error.error)).await
+                        },
+                        Err(err) => {
+                            tracing::error!(error = %err, "job failed, will retry");
+                            queue.fail_job(job_id).await
+                        }
+                    };
 
-                match res {
-                    Ok(_) => {}
-                    Err(err) => {
-                        rocket::error!("error deleting / failing job: {}", &err);
+                    if let Err(err) = res {
+                        tracing::error!(error = %err, "could not delete or fail job");
                     }
                 }
+                .instrument(span)
             })
             .await;
 
         // sleep not to overload our database
-        rocket::tokio::time::sleep(Duration::from_millis(QUEUE_INTERVAL)).await;
+        rocket::tokio::select! {
+            _ = shutdown.clone() => break,
+            _ = rocket::tokio::time::sleep(Duration::from_millis(QUEUE_INTERVAL)) => {},
+        }
+    }
+
+    tracing::info!(queue = %queue_name, "worker stopped");
+}
+
+/// Inserts a job into the named queue, as `job_id`.
+async fn insert_job<'e, E: sqlx::PgExecutor<'e>>(
+    executor: E,
+    job_id: Uuid,
+    queue_name: &str,
+    job: Message,
+    date: Option<chrono::DateTime<chrono::Utc>>,
+    priority: Option<i32>,
+) -> error::Result<()> {
+    let scheduled_for = date.unwrap_or_else(chrono::Utc::now);
+    let priority = priority.unwrap_or(DEFAULT_PRIORITY);
+    let failed_attempts: i32 = 0;
+    let message = Json(job);
+    let status = PostgresJobStatus::Queued;
+    let now = chrono::Utc::now();
+
+    let query = "INSERT INTO queue
+        (id, created_at, updated_at, scheduled_for, failed_attempts, status, message, queue_name, priority)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)";
+
+    let query_result = sqlx::query(query)
+        .bind(job_id)
+        .bind(now)
+        .bind(now)
+        .bind(scheduled_for)
+        .bind(failed_attempts)
+        .bind(status)
+        .bind(message)
+        .bind(queue_name)
+        .bind(priority)
+        .execute(executor)
+        .await?;
+
+    if query_result.rows_affected() > 0 {
+        tracing::info!(%job_id, queue = queue_name, "pushed job");
+        Ok(())
+    } else {
+        tracing::error!(%job_id, queue = queue_name, "failed to push job");
+        Err(anyhow!("job insertion error").into())
     }
 }
 
-async fn handle_job(job: Job, state: &PostgresQueue) -> error::Result<()> {
-    match job.message {
+async fn handle_job(job: Job, state: &PostgresQueue) -> Result<(), JobError> {
+    run_message(job.message, state).await.map_err(JobError::from)
+}
+
+async fn run_message(message: Message, state: &PostgresQueue) -> error::Result<()> {
+    match message {
         Message::SendResetPasswordEmail(email) =>
             SendResetPasswordEmail { to: email }.run(state).await,
         Message::SendPasswordWasResetEmail(email) =>
@@ -262,18 +922,75 @@ async fn handle_job(job: Job, state: &PostgresQueue) -> error::Result<()> {
             SendVerifyAccountEmail { to: email }.run(state).await,
         Message::SendWelcomeAccountEmail(email) =>
             SendWelcomeAccountEmail { to: email }.run(state).await,
+        Message::SendReengagementEmail(email) =>
+            SendReengagementEmail { to: email }.run(state).await,
+        Message::SendPasswordExpiryReminder(email) =>
+            SendPasswordExpiryReminder { to: email }.run(state).await,
+        Message::SendMagicLinkEmail(email) =>
+            SendMagicLinkEmail { to: email }.run(state).await,
+        Message::SendInvitationEmail { to, token } =>
+            SendInvitationEmail { to, token }.run(state).await,
+        Message::SendEmailChangeConfirmation { email, new_email } =>
+            SendEmailChangeConfirmation { email, new_email }.run(state).await,
+        Message::SendEmailChangeNotice { old_email, new_email } =>
+            SendEmailChangeNotice { old_email, new_email }.run(state).await,
+        Message::SendBulkEmail { template, recipients, subject, attempt } =>
+            SendBulkEmail { template, recipients, subject, attempt }.run(state).await,
+        Message::DowngradeExpiredPlans =>
+            DowngradeExpiredPlans.run(state).await,
+        Message::CleanupStaleRunningJobs =>
+            CleanupStaleRunningJobs.run(state).await,
+        Message::RemindExpiredPasswords =>
+            RemindExpiredPasswords.run(state).await,
+        Message::PurgeUnverifiedAccounts =>
+            PurgeUnverifiedAccounts.run(state).await,
     }
 }
 
+/// Email templates the jobs send, each an `.html` and `.txt` pair. Bulk
+/// emails name their template when queued, so can't be checked here.
+const REQUIRED_TEMPLATES: [&str; 10] = [
+    "verify-account",
+    "welcome",
+    "reset-password",
+    "password-was-reset",
+    "password-expiry",
+    "confirm-email-change",
+    "email-changed",
+    "magic-link",
+    "invitation",
+    "odd-registration-attempt",
+];
+
+/// The files of required templates that `tera` doesn't have.
+fn missing_templates(tera: &Tera) -> Vec<String> {
+    let loaded: HashSet<&str> = tera.get_template_names().collect();
+    REQUIRED_TEMPLATES.into_iter()
+        .chain(reengagement::templates())
+        .flat_map(|name| [format!("{}.html", name), format!("{}.txt", name)])
+        .filter(|file| !loaded.contains(file.as_str()))
+        .collect()
+}
+
 /// Loads a glob of Tera templates (for sending emails) into memory behind an `Arc<RwLock<>>`.
 /// Note: As opposed to the Rocket dyn_templates crate, these templates do not have
 /// the ".tera" extension, because we have ".txt" and ".html" templates.
+/// Fails if any template a job needs is missing, rather than when that job runs.
 fn load_templates() -> error::Result<Arc<RwLock<Tera>>> {
     // TODO: Use Figment to specify the location.
     let templates_glob = env::var("EMAIL_TEMPLATES_GLOB").expect("EMAIL_TEMPLATES_GLOB not set!");
-    let tera = Tera::new(&templates_glob)
+    load_templates_from(&templates_glob)
+}
+
+fn load_templates_from(templates_glob: &str) -> error::Result<Arc<RwLock<Tera>>> {
+    let tera = Tera::new(templates_glob)
         .map_err(|e| error::Error::from(anyhow!("failed to compile templates {}", e)))?;
 
+    let missing = missing_templates(&tera);
+    if !missing.is_empty() {
+        return Err(anyhow!("missing email templates in {}: {}", templates_glob, missing.join(", ")).into());
+    }
+
     Ok(Arc::new(RwLock::new(tera)))
 }
 
@@ -317,7 +1034,8 @@ pub struct PoolConfig {
 
 type PgConnectOptions = <<Postgres as sqlx::Database>::Connection as sqlx::Connection>::Options;
 
-async fn create_database_pool(rocket: &Rocket<Build>) -> error::Result<PgPool> {
+/// Returns the pool along with its `max_connections`.
+async fn create_database_pool(rocket: &Rocket<Build>) -> error::Result<(PgPool, usize)> {
     let workers: usize = rocket.figment()
         .extract_inner(rocket::Config::WORKERS)
         .unwrap_or_else(|_| rocket::Config::default().workers);
@@ -331,11 +1049,28 @@ async fn create_database_pool(rocket: &Rocket<Build>) -> error::Result<PgPool> {
         .parse::<PgConnectOptions>()
         .map_err(|e| error::Error::from(anyhow!("invalid pg connection options {}", e)))?;
 
+    // Statements taking longer than `slow_statement_ms` are logged as
+    // warnings. Without it, debug logging includes every statement. This
+    // is only for the worker's pool; rocket_db_pools sets up `AppDb`'s
+    // and doesn't read the setting.
+    let slow_threshold = figment.extract_inner::<u64>("slow_statement_ms")
+        .ok()
+        .map(Duration::from_millis);
+
     opts.disable_statement_logging();
     if let Ok(level) = figment.extract_inner::<LogLevel>(rocket::Config::LOG_LEVEL) {
-        if !matches!(level, LogLevel::Normal | LogLevel::Off) {
-            opts.log_statements(level.into())
-                .log_slow_statements(level.into(), Duration::default());
+        match (level, slow_threshold) {
+            (LogLevel::Off, _) => {},
+            (LogLevel::Normal, Some(threshold)) => {
+                opts.log_slow_statements(log::LevelFilter::Warn, threshold);
+            },
+            (LogLevel::Normal, None) => {},
+            (_, threshold) => {
+                opts.log_statements(level.into())
+                    .log_slow_statements(
+                        threshold.map_or(level.into(), |_| log::LevelFilter::Warn),
+                        threshold.unwrap_or_default());
+            },
         }
     }
 
@@ -346,6 +1081,7 @@ async fn create_database_pool(rocket: &Rocket<Build>) -> error::Result<PgPool> {
         .min_connections(config.min_connections.unwrap_or_default())
         .connect_with(opts)
         .await
+        .map(|pool| (pool, config.max_connections))
         .map_err(|e| error::Error::from(anyhow!("could not connect pool to db {}", e)))
 }
 
@@ -380,20 +1116,35 @@ impl Fairing for BackgroundQueue {
     ///
     /// The default implementation of this method simply returns `Ok(rocket)`.
     async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        let serves_default = rocket.state::<AppConfig>()
+            .map_or(true, |config| config.jobs.queues.iter().any(|queue| queue.name == DEFAULT_QUEUE));
+        if !serves_default {
+            tracing::error!(queue = DEFAULT_QUEUE, "jobs.queues must include the default queue");
+            return Err(rocket);
+        }
+
         match create_database_pool(&rocket).await {
-            Ok(pool) =>
+            Ok((pool, max_connections)) =>
                 match load_templates() {
                     Ok(templates) => {
-                        let queue = PostgresQueue::new(pool, templates, 5);
-                        Ok(rocket.manage(queue))
+                        let config = rocket.state::<AppConfig>().cloned().unwrap_or_default();
+                        // Built separately from the routes' providers, which
+                        // are set up by their own fairing.
+                        let providers = OAuthProviders::from_figment(rocket.figment());
+                        let queue = PostgresQueue::new(pool, templates, config, providers, 5, max_connections);
+                        let drain_timeout = rocket.state::<AppConfig>()
+                            .map_or(JobsConfig::default().drain_timeout, |config| config.jobs.drain_timeout);
+                        Ok(rocket.manage(queue)
+                            .manage(WorkerHeartbeat::default())
+                            .manage(Workers::new(Duration::from_secs(drain_timeout))))
                     },
                     Err(e) => {
-                        rocket::error!("background_jobs failed to load templates: {}", e);
+                        tracing::error!(error = %e, "background jobs could not load templates");
                         Err(rocket)
                     }
             },
             Err(e) => {
-                rocket::error!("background_jobs failed to connect to db: {}", e);
+                tracing::error!(error = %e, "background jobs could not connect to the database");
                 Err(rocket)
             }
         }
@@ -403,13 +1154,41 @@ impl Fairing for BackgroundQueue {
     async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
         match rocket.state::<PostgresQueue>() {
             Some(queue) => {
-                // queue is an Arc pointer, so this just copies the reference
-                let worker_queue = queue.clone();
-                let _queue_task_handle = rocket::tokio::spawn(async move { run_worker(worker_queue).await });
-                rocket::info!("job queue worker task spawned");
+                // Jobs a previous run of the worker died in the middle of.
+                if let Err(e) = queue.recover_stale_jobs(queue.config.jobs.stale_job_timeout).await {
+                    tracing::error!(error = %e, "could not recover stale jobs");
+                }
+
+                for job in RECURRING_JOBS {
+                    if let Err(e) = queue.push_if_absent(job).await {
+                        tracing::error!(error = %e, "could not schedule recurring job");
+                    }
+                }
+
+                let queues = rocket.state::<AppConfig>()
+                    .map(|config| config.jobs.queues.clone())
+                    .unwrap_or_else(|| JobsConfig::default().queues);
+                let heartbeat = rocket.state::<WorkerHeartbeat>().cloned().unwrap_or_default();
+                let workers = rocket.state::<Workers>();
+
+                for QueueConfig { name, concurrency } in queues {
+                    let concurrency = concurrency.unwrap_or(queue.concurrency).min(queue.concurrency).max(1);
+
+                    // queue is an Arc pointer, so this just copies the reference
+                    let worker_queue = queue.clone();
+                    let heartbeat = heartbeat.clone();
+                    let shutdown = rocket.shutdown();
+                    tracing::info!(queue = %name, concurrency, "spawning job worker");
+                    let queue_task_handle = rocket::tokio::spawn(async move {
+                        run_worker(worker_queue, name, concurrency, heartbeat, shutdown).await
+                    });
+                    if let Some(workers) = workers {
+                        workers.add(queue_task_handle);
+                    }
+                }
             }
             None => {
-                rocket::error!("could not fetch job queue");
+                tracing::error!("could not fetch job queue");
             }
         }
     }
@@ -425,10 +1204,324 @@ impl<'r> FromRequest<'r> for PostgresQueue {
                 Outcome::Success(queue.clone())
             }
             None => {
-                rocket::error!("could not fetch job queue");
+                tracing::error!("could not fetch job queue");
                 Outcome::Failure((Status::InternalServerError,
                     error::Error::from(anyhow!("could not fetch job queue"))))
             }
         }
     }
 }
+
+/// A queue on the test database, without templates, for tests that push
+/// jobs; see `database::test_database_url`.
+#[cfg(test)]
+pub async fn test_queue() -> PostgresQueue {
+    let pool = PgPool::connect(&crate::database::test_database_url()).await
+        .expect("could not connect to DATABASE_URL");
+    PostgresQueue::new(
+        pool,
+        Arc::new(RwLock::new(Tera::default())),
+        AppConfig::default(),
+        OAuthProviders::default(),
+        5,
+        1,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+
+    #[test]
+    fn the_shipped_email_templates_load() {
+        assert!(load_templates_from("email_templates/*.{html,txt}").is_ok());
+    }
+
+    #[test]
+    fn a_missing_welcome_txt_fails_to_load() {
+        let dir = env::temp_dir().join(format!("email-templates-{}", ulid::Ulid::new()));
+        fs::create_dir(&dir).unwrap();
+        for entry in fs::read_dir("email_templates").unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap();
+            if name != "welcome.txt" {
+                fs::copy(&path, dir.join(name)).unwrap();
+            }
+        }
+
+        let result = load_templates_from(&format!("{}/*.{{html,txt}}", dir.display()));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let error = result.err().expect("templates without welcome.txt loaded").to_string();
+        assert!(error.contains("missing email templates"), "{}", error);
+        assert!(error.contains("welcome.txt"), "{}", error);
+        assert!(!error.contains("welcome.html"), "{}", error);
+    }
+
+    /// A worker that sets `done` after `delay`.
+    fn spawn_worker(workers: &Workers, delay: Duration) -> Arc<AtomicBool> {
+        let done = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&done);
+        workers.add(rocket::tokio::spawn(async move {
+            rocket::tokio::time::sleep(delay).await;
+            flag.store(true, Ordering::SeqCst);
+        }));
+        done
+    }
+
+    #[rocket::async_test]
+    async fn drain_lets_running_jobs_finish() {
+        let workers = Workers::new(Duration::from_secs(5));
+        let done = spawn_worker(&workers, Duration::from_millis(50));
+
+        workers.drain().await;
+
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    #[rocket::async_test]
+    async fn drain_gives_up_after_its_timeout() {
+        let workers = Workers::new(Duration::from_millis(50));
+        let done = spawn_worker(&workers, Duration::from_secs(60));
+
+        let started = std::time::Instant::now();
+        workers.drain().await;
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!done.load(Ordering::SeqCst));
+    }
+
+    #[rocket::async_test]
+    async fn drain_without_workers_returns_at_once() {
+        Workers::new(Duration::from_secs(60)).drain().await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn a_worker_finishes_its_running_job_after_shutdown() {
+        use crate::models::{Account, EmailDeliveryStatus};
+        use crate::routes::accounts::NewAccount;
+
+        let queue = test_queue().await;
+        env::set_var("EMAIL_DEFAULT_FROM", "app@example.com");
+        env::set_var("JELLY_DOMAIN", "https://example.com");
+        queue.templates.tera.write().unwrap().add_raw_templates(vec![
+            ("verify-account.html", "<a href=\"{{ action_url }}\">Verify</a>"),
+            ("verify-account.txt", "Verify: {{ action_url }}"),
+        ]).unwrap();
+
+        let suffix = ulid::Ulid::new().to_string().to_lowercase();
+        let email = format!("drain-{}@example.com", suffix);
+        let queue_name = format!("drain-{}", suffix);
+        let aliases = queue.config.accounts.email_aliases;
+        let mut conn = queue.pool.acquire().await.unwrap();
+        let new_account = NewAccount { name: "Drain", email: &email, password: "a long test password" };
+        Account::register(&new_account, aliases, &mut conn).await.unwrap();
+
+        // The job's cooldown claim waits on this uncommitted one, which
+        // keeps the job running until it's rolled back.
+        let mut held = queue.pool.begin().await.unwrap();
+        sqlx::query("INSERT INTO email_sends (template, recipient) VALUES ('verify-account', lower($1))")
+            .bind(&email)
+            .execute(&mut held)
+            .await
+            .unwrap();
+
+        let job = Message::SendVerifyAccountEmail(email.clone());
+        queue.push_to(&queue_name, job.clone(), None, None).await.unwrap();
+
+        let shutdown = rocket::build().ignite().await.unwrap().shutdown();
+        let workers = Workers::new(Duration::from_secs(10));
+        workers.add(rocket::tokio::spawn(
+            run_worker(queue.clone(), queue_name, 1, WorkerHeartbeat::default(), shutdown.clone())));
+
+        for _ in 0..250 {
+            if statuses_of(&queue, job.clone()).await == vec![PostgresJobStatus::Running as i32] {
+                break;
+            }
+            rocket::tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(statuses_of(&queue, job.clone()).await, vec![PostgresJobStatus::Running as i32]);
+
+        shutdown.notify();
+        let (_, released) = rocket::tokio::join!(workers.drain(), async {
+            rocket::tokio::time::sleep(Duration::from_millis(200)).await;
+            held.rollback().await
+        });
+        released.unwrap();
+
+        let remaining = statuses_of(&queue, job.clone()).await;
+        let status = Account::get_by_email(&email, aliases, &mut conn).await.unwrap().email_delivery_status;
+        delete_all(&queue, job).await;
+        cooldown::release(&queue.pool, "verify-account", &email).await.unwrap();
+        sqlx::query("DELETE FROM accounts WHERE email = $1")
+            .bind(crate::pii::seal_email(&email))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        assert!(remaining.is_empty(), "{:?}", remaining);
+        assert_eq!(status, EmailDeliveryStatus::Sent);
+    }
+
+    #[test]
+    fn recurring_jobs_have_no_sample() {
+        for job in RECURRING_JOBS {
+            assert!(Message::sample(job.kind(), "me@example.com").is_none(), "{} has a sample", job.kind());
+        }
+    }
+
+    #[test]
+    fn concurrency_is_clamped_to_the_pool_size() {
+        assert_eq!(effective_concurrency(50, 10), 10 - POOL_RESERVE);
+        assert_eq!(effective_concurrency(4, 10), 4);
+        assert_eq!(effective_concurrency(50, 100), 50);
+    }
+
+    #[test]
+    fn concurrency_is_at_least_one() {
+        assert_eq!(effective_concurrency(50, POOL_RESERVE), 1);
+        assert_eq!(effective_concurrency(50, 0), 1);
+        assert_eq!(effective_concurrency(0, 10), 1);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn queue_concurrency_follows_its_pool() {
+        let queue = test_queue().await;
+        assert_eq!(queue.concurrency, 1);
+    }
+
+    async fn statuses_of(queue: &PostgresQueue, job: Message) -> Vec<i32> {
+        sqlx::query_as::<_, (i32,)>("SELECT status FROM queue WHERE message = $1 ORDER BY status")
+            .bind(Json(job))
+            .fetch_all(&queue.pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(status,)| status)
+            .collect()
+    }
+
+    async fn delete_all(queue: &PostgresQueue, job: Message) {
+        sqlx::query("DELETE FROM queue WHERE message = $1")
+            .bind(Json(job))
+            .execute(&queue.pool)
+            .await
+            .unwrap();
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn recurring_jobs_are_seeded_once() {
+        let queue = test_queue().await;
+        delete_all(&queue, Message::DowngradeExpiredPlans).await;
+
+        let (first, second) = rocket::tokio::join!(
+            queue.push_if_absent(Message::DowngradeExpiredPlans),
+            queue.push_if_absent(Message::DowngradeExpiredPlans),
+        );
+        first.unwrap();
+        second.unwrap();
+        queue.push_recurring(Message::DowngradeExpiredPlans, chrono::Utc::now()).await.unwrap();
+
+        assert_eq!(statuses_of(&queue, Message::DowngradeExpiredPlans).await, vec![0]);
+        delete_all(&queue, Message::DowngradeExpiredPlans).await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn a_running_recurring_job_is_not_seeded_again() {
+        let queue = test_queue().await;
+        delete_all(&queue, Message::RemindExpiredPasswords).await;
+
+        queue.push_if_absent(Message::RemindExpiredPasswords).await.unwrap();
+        sqlx::query("UPDATE queue SET status = 1 WHERE message = $1")
+            .bind(Json(Message::RemindExpiredPasswords))
+            .execute(&queue.pool)
+            .await
+            .unwrap();
+        queue.push_if_absent(Message::RemindExpiredPasswords).await.unwrap();
+        assert_eq!(statuses_of(&queue, Message::RemindExpiredPasswords).await, vec![1]);
+
+        // The run it is in reschedules the next.
+        queue.push_recurring(Message::RemindExpiredPasswords, chrono::Utc::now()).await.unwrap();
+        assert_eq!(statuses_of(&queue, Message::RemindExpiredPasswords).await, vec![0, 1]);
+        delete_all(&queue, Message::RemindExpiredPasswords).await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn a_recurring_job_out_of_attempts_does_not_block_seeding() {
+        let queue = test_queue().await;
+        delete_all(&queue, Message::PurgeUnverifiedAccounts).await;
+
+        queue.push_if_absent(Message::PurgeUnverifiedAccounts).await.unwrap();
+        sqlx::query("UPDATE queue SET failed_attempts = $1 WHERE message = $2")
+            .bind(queue.max_attempts)
+            .bind(Json(Message::PurgeUnverifiedAccounts))
+            .execute(&queue.pool)
+            .await
+            .unwrap();
+        queue.push_if_absent(Message::PurgeUnverifiedAccounts).await.unwrap();
+
+        assert_eq!(statuses_of(&queue, Message::PurgeUnverifiedAccounts).await, vec![0, 2]);
+        delete_all(&queue, Message::PurgeUnverifiedAccounts).await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn stale_running_jobs_are_requeued() {
+        let queue = test_queue().await;
+
+        let suffix = ulid::Ulid::new().to_string().to_lowercase();
+        let stale = Message::SendWelcomeAccountEmail(format!("stale-{}@example.com", suffix));
+        let fresh = Message::SendWelcomeAccountEmail(format!("fresh-{}@example.com", suffix));
+        for (job, age) in [(&stale, 3600), (&fresh, 0)] {
+            queue.push(job.clone(), None, None).await.unwrap();
+            sqlx::query("UPDATE queue SET status = $1, updated_at = now() - make_interval(secs => $2)
+                WHERE message = $3")
+                .bind(PostgresJobStatus::Running)
+                .bind(age as f64)
+                .bind(Json(job.clone()))
+                .execute(&queue.pool)
+                .await
+                .unwrap();
+        }
+
+        assert!(queue.recover_stale_jobs(60).await.unwrap() >= 1);
+
+        let attempts: (i32,) = sqlx::query_as("SELECT failed_attempts FROM queue WHERE message = $1")
+            .bind(Json(stale.clone()))
+            .fetch_one(&queue.pool)
+            .await
+            .unwrap();
+        let stale_statuses = statuses_of(&queue, stale.clone()).await;
+        let fresh_statuses = statuses_of(&queue, fresh.clone()).await;
+        delete_all(&queue, stale).await;
+        delete_all(&queue, fresh).await;
+
+        assert_eq!(stale_statuses, vec![0]);
+        assert_eq!(attempts, (1,));
+        assert_eq!(fresh_statuses, vec![1]);
+    }
+
+    #[rocket::async_test]
+    async fn ignition_fails_without_the_default_queue() {
+        let mut config = AppConfig::default();
+        config.jobs.queues = vec![QueueConfig { name: "bulk".to_string(), concurrency: None }];
+        let rocket = rocket::build().manage(config).attach(BackgroundQueue::fairing());
+
+        assert!(rocket.ignite().await.is_err());
+    }
+
+    #[test]
+    fn samples_are_of_the_named_kind() {
+        let message = Message::sample("SendWelcomeAccountEmail", "me@example.com").unwrap();
+        assert_eq!(message.kind(), "SendWelcomeAccountEmail");
+        assert!(Message::sample("NoSuchJob", "me@example.com").is_none());
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::anyhow;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tera::Context;
+
+use crate::email::{is_bounce, Email};
+use crate::error;
+use crate::jobs::{JobRun, Message, PostgresQueue};
+use crate::models::Account;
+
+/// How many times delivery is attempted for each recipient, and how long
+/// to wait, in seconds, before retrying the recipients that failed.
+const MAX_BULK_ATTEMPTS: u32 = 3;
+const BULK_RETRY_DELAY: i64 = 300;
+
+/// Sends the same email to many recipients, e.g. an announcement.
+/// Each recipient gets their own copy, so addresses aren't exposed to
+/// each other. Failures are tracked per recipient: only the recipients
+/// that failed are retried, in a new job, so nobody gets a duplicate.
+/// Addresses that hard bounce aren't retried.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendBulkEmail {
+    pub template: String,
+    pub recipients: Vec<String>,
+    pub subject: String,
+    pub attempt: u32,
+}
+
+#[rocket::async_trait]
+impl JobRun for SendBulkEmail {
+    async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        let mut conn_result = state.pool.acquire().await;
+        let conn = conn_result
+            .as_mut()
+            .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
+
+        let mut failed = Vec::new();
+        for to in self.recipients.iter() {
+            let mut context = Context::new();
+            context.insert("email", to);
+
+            // Recipients needn't have an account; they get the default.
+            let locale = Account::fetch_locale_from_email(to, state.config.accounts.email_aliases, conn).await.ok().flatten();
+
+            let result = Email::new(
+                &self.template,
+                &[to.clone()],
+                &self.subject,
+                context,
+                state.templates.clone(),
+                locale.as_deref(),
+            )
+            .and_then(|email| email.send().map_err(error::Error::from));
+
+            match result {
+                Ok(()) => {},
+                // Retrying a bounced address would only bounce again.
+                Err(e) if is_bounce(&e) => tracing::warn!(template = %self.template, error = %e, "bulk email bounced"),
+                Err(e) => {
+                    tracing::error!(template = %self.template, error = %e, "bulk email failed");
+                    failed.push(to.clone());
+                },
+            }
+        }
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+
+        tracing::info!(template = %self.template, failed = failed.len(), recipients = self.recipients.len(),
+            "bulk email failed for some recipients");
+
+        let attempt = self.attempt + 1;
+        if attempt < MAX_BULK_ATTEMPTS {
+            state
+                .push(
+                    Message::SendBulkEmail {
+                        template: self.template,
+                        recipients: failed,
+                        subject: self.subject,
+                        attempt,
+                    },
+                    Some(Utc::now() + Duration::seconds(BULK_RETRY_DELAY)),
+                    None,
+                )
+                .await
+        } else {
+            tracing::error!(template = %self.template, failed = failed.len(), "giving up on bulk email");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::jobs::test_queue;
+
+    /// A bulk job's retries, found by its unique subject, and removed.
+    async fn take_retries(queue: &PostgresQueue, subject: &str) -> Vec<(serde_json::Value,)> {
+        let retries = sqlx::query_as("SELECT message->'SendBulkEmail' FROM queue
+            WHERE message->'SendBulkEmail'->>'subject' = $1")
+            .bind(subject)
+            .fetch_all(&queue.pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM queue WHERE message->'SendBulkEmail'->>'subject' = $1")
+            .bind(subject)
+            .execute(&queue.pool)
+            .await
+            .unwrap();
+        retries
+    }
+
+    /// A queue whose "bulk-test" template renders for every address but
+    /// those starting with "fail-", and whose mock mailer bounces those
+    /// starting with "bounce-bulk-".
+    async fn queue() -> PostgresQueue {
+        let queue = test_queue().await;
+        env::set_var("EMAIL_DEFAULT_FROM", "app@example.com");
+        env::set_var("EMAIL_MOCK_BOUNCE_PATTERN", "^bounce-bulk-");
+        queue.templates.tera.write().unwrap().add_raw_templates(vec![
+            ("bulk-test.html", "<p>Hello {{ email }}</p>"),
+            ("bulk-test.txt", "Hello {% if email is starting_with(\"fail-\") %}{{ no_such_value }}{% else %}{{ email }}{% endif %}"),
+        ]).unwrap();
+        queue
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn only_failed_recipients_are_retried() {
+        let queue = queue().await;
+
+        let suffix = ulid::Ulid::new().to_string().to_lowercase();
+        let failed = format!("fail-{}@example.com", suffix);
+        let subject = format!("Bulk test {}", suffix);
+        let job = SendBulkEmail {
+            template: "bulk-test".to_string(),
+            recipients: vec![
+                format!("sent-{}@example.com", suffix),
+                format!("bounce-bulk-{}@example.com", suffix),
+                failed.clone(),
+            ],
+            subject: subject.clone(),
+            attempt: 0,
+        };
+
+        job.run(&queue).await.unwrap();
+        let retries = take_retries(&queue, &subject).await;
+
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries[0].0["recipients"], serde_json::json!([failed]));
+        assert_eq!(retries[0].0["attempt"], 1);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn nothing_is_retried_when_every_recipient_is_sent() {
+        let queue = queue().await;
+
+        let suffix = ulid::Ulid::new().to_string().to_lowercase();
+        let subject = format!("Bulk test {}", suffix);
+        let job = SendBulkEmail {
+            template: "bulk-test".to_string(),
+            recipients: vec![format!("one-{}@example.com", suffix), format!("two-{}@example.com", suffix)],
+            subject: subject.clone(),
+            attempt: 0,
+        };
+
+        job.run(&queue).await.unwrap();
+
+        assert!(take_retries(&queue, &subject).await.is_empty());
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn failures_are_dropped_after_the_last_attempt() {
+        let queue = queue().await;
+
+        let suffix = ulid::Ulid::new().to_string().to_lowercase();
+        let subject = format!("Bulk test {}", suffix);
+        let job = SendBulkEmail {
+            template: "bulk-test".to_string(),
+            recipients: vec![format!("fail-{}@example.com", suffix)],
+            subject: subject.clone(),
+            attempt: MAX_BULK_ATTEMPTS - 1,
+        };
+
+        job.run(&queue).await.unwrap();
+
+        assert!(take_retries(&queue, &subject).await.is_empty());
+    }
+}
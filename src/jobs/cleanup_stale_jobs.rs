@@ -0,0 +1,24 @@
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error;
+use crate::jobs::{JobRun, Message, PostgresQueue};
+
+/// How often, in seconds, stale jobs are looked for.
+pub const CLEANUP_INTERVAL: i64 = 300;
+
+/// A recurring job that puts jobs left `Running` by a crashed worker back
+/// in the queue, after `jobs.stale_job_timeout`, then reschedules itself.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CleanupStaleRunningJobs;
+
+#[rocket::async_trait]
+impl JobRun for CleanupStaleRunningJobs {
+    async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        state.recover_stale_jobs(state.config.jobs.stale_job_timeout).await?;
+
+        state
+            .push_recurring(Message::CleanupStaleRunningJobs, Utc::now() + Duration::seconds(CLEANUP_INTERVAL))
+            .await
+    }
+}
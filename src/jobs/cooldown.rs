@@ -0,0 +1,45 @@
+//! Suppresses repeat sends of the same email to the same address within
+//! a cooldown window.
+
+use sqlx::PgPool;
+
+use crate::error;
+
+/// Claims the right to send `template` to `to`, returning false if it was
+/// already sent within the last `window` seconds. A claim that isn't
+/// followed by a successful send should be given back with `release`.
+pub async fn claim(pool: &PgPool, template: &str, to: &str, window: i64) -> error::Result<bool> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM email_sends
+        WHERE template = $1 AND recipient = lower($2)
+            AND sent_at <= now() - make_interval(secs => $3)")
+        .bind(template)
+        .bind(to)
+        .bind(window as f64)
+        .execute(&mut tx)
+        .await?;
+
+    // Of two claims at once, the second waits on the unique index for the
+    // first to commit, then inserts nothing.
+    let result = sqlx::query("INSERT INTO email_sends (template, recipient)
+        VALUES ($1, lower($2))
+        ON CONFLICT (template, recipient) DO NOTHING")
+        .bind(template)
+        .bind(to)
+        .execute(&mut tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Gives back a claim after the send failed, so a retry isn't suppressed.
+pub async fn release(pool: &PgPool, template: &str, to: &str) -> error::Result<()> {
+    sqlx::query("DELETE FROM email_sends WHERE template = $1 AND recipient = lower($2)")
+        .bind(template)
+        .bind(to)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use anyhow::anyhow;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error;
+use crate::jobs::{JobRun, Message, PostgresQueue};
+use crate::models::Account;
+
+/// How often, in seconds, expired plans are checked for.
+pub const DOWNGRADE_INTERVAL: i64 = 3600;
+
+/// A recurring job that downgrades accounts whose paid plan has
+/// passed its `plan_expires_at` date, then reschedules itself.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DowngradeExpiredPlans;
+
+#[rocket::async_trait]
+impl JobRun for DowngradeExpiredPlans {
+    async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        let mut conn_result = state.pool.acquire().await;
+        let conn = conn_result
+            .as_mut()
+            .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
+
+        let count = Account::downgrade_expired_plans(conn)
+            .await
+            .map_err(|e| anyhow!("Error downgrading expired plans: {:?}", e))?;
+        if count > 0 {
+            tracing::info!(count, "downgraded expired plans");
+        }
+
+        state
+            .push_recurring(Message::DowngradeExpiredPlans, Utc::now() + Duration::seconds(DOWNGRADE_INTERVAL))
+            .await
+    }
+}
@@ -0,0 +1,112 @@
+use std::env;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tera::Context;
+
+use crate::email::Email;
+use crate::error;
+use crate::jobs::reset_password::reset_url;
+use crate::jobs::{JobRun, PostgresQueue};
+use crate::models::{Account, EmailChange};
+use crate::token::OneTimeUseTokenGenerator;
+
+/// Sends the link confirming a change of email to the new address, so
+/// that the change only goes through for an address the user can read.
+/// The account is found by its current address.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendEmailChangeConfirmation {
+    pub email: String,
+    pub new_email: String,
+}
+
+/// The link that makes the change, with the new address alongside the
+/// token that covers it.
+pub fn confirm_url(account: &Account, new_email: &str) -> error::Result<String> {
+    let domain = env::var("JELLY_DOMAIN").expect("No JELLY_DOMAIN value set!");
+
+    Ok(format!(
+        "{}/accounts/settings/email/{}-{}?email={}",
+        domain,
+        base64_url::encode(&format!("{}", account.id)),
+        EmailChange { account, new_email }
+            .create_reset_token()
+            .map_err(|e| { anyhow!("Error creating email change token: {:?}", e) })?,
+        url::form_urlencoded::byte_serialize(new_email.as_bytes()).collect::<String>()
+    ))
+}
+
+#[rocket::async_trait]
+impl JobRun for SendEmailChangeConfirmation {
+    async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        let mut conn_result = state.pool.acquire().await;
+        let conn = conn_result
+            .as_mut()
+            .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
+
+        let account = Account::get_by_email(&self.email, state.config.accounts.email_aliases, conn)
+            .await
+            .map_err(|e| anyhow!("Error fetching account for email change: {:?}", e))?;
+
+        let email = Email::new(
+            "confirm-email-change",
+            &[self.new_email.clone()],
+            "Confirm your new email address",
+            build_context(&account.name, &self.new_email, &confirm_url(&account, &self.new_email)?),
+            state.templates.clone(),
+            account.locale.as_deref(),
+        );
+
+        email?.send()?;
+
+        Ok(())
+    }
+}
+
+/// Tells the previous address of an account that its email was changed,
+/// so that a hijacked account gets noticed. Queue it once the change is
+/// confirmed; the account is found by its new address.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendEmailChangeNotice {
+    pub old_email: String,
+    pub new_email: String,
+}
+
+pub fn build_context(name: &str, new_email: &str, action_url: &str) -> Context {
+    let mut context = Context::new();
+    context.insert("name", name);
+    context.insert("new_email", new_email);
+    context.insert("action_url", action_url);
+    context
+}
+
+#[rocket::async_trait]
+impl JobRun for SendEmailChangeNotice {
+    async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        let mut conn_result = state.pool.acquire().await;
+        let conn = conn_result
+            .as_mut()
+            .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
+
+        let account = Account::get_by_email(&self.new_email, state.config.accounts.email_aliases, conn)
+            .await
+            .map_err(|e| anyhow!("Error fetching account for email change notice: {:?}", e))?;
+
+        // Resetting the password logs out every other session, which is
+        // how the owner takes the account back.
+        let action_url = reset_url(&account)?;
+
+        let email = Email::new(
+            "email-changed",
+            &[self.old_email],
+            "Your email address was changed",
+            build_context(&account.name, &self.new_email, &action_url),
+            state.templates.clone(),
+            account.locale.as_deref(),
+        );
+
+        email?.send()?;
+
+        Ok(())
+    }
+}
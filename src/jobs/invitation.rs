@@ -0,0 +1,47 @@
+use std::env;
+
+use serde::{Deserialize, Serialize};
+use tera::Context;
+
+use crate::email::Email;
+use crate::error;
+use crate::jobs::{JobRun, PostgresQueue};
+
+/// Emails an invitation to register. Queue it when the `Invitation` is
+/// created, with its token, which isn't stored anywhere else.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendInvitationEmail {
+    pub to: String,
+    pub token: String,
+}
+
+pub fn build_context(email: &str, register_url: &str) -> Context {
+    let mut context = Context::new();
+    context.insert("email", email);
+    context.insert("action_url", register_url);
+    context
+}
+
+/// The registration form, carrying the invitation token.
+pub fn register_url(token: &str) -> String {
+    let domain = env::var("JELLY_DOMAIN").expect("No JELLY_DOMAIN value set!");
+    format!("{}/accounts/register?invite={}", domain, token)
+}
+
+#[rocket::async_trait]
+impl JobRun for SendInvitationEmail {
+    async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        let email = Email::new(
+            "invitation",
+            &[self.to.clone()],
+            "You're invited",
+            build_context(&self.to, &register_url(&self.token)),
+            state.templates.clone(),
+            None,
+        );
+
+        email?.send()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use std::env;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tera::Context;
+
+use crate::email::Email;
+use crate::error;
+use crate::jobs::{JobRun, PostgresQueue};
+use crate::models::Account;
+use crate::token::OneTimeUseTokenGenerator;
+
+/// Emails a link that logs the account in once, for users who can't sign
+/// in any other way, e.g. because they forgot which provider they used.
+/// Addresses without an active account get nothing, and the form that
+/// queues this says the same thing either way.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendMagicLinkEmail {
+    pub to: String,
+}
+
+pub fn build_context(name: &str, login_url: &str) -> Context {
+    let mut context = Context::new();
+    context.insert("name", name);
+    context.insert("action_url", login_url);
+    context
+}
+
+/// The one time login link. The token is the same kind as the reset
+/// link's, so it stops working once the account logs in.
+pub fn login_url(account: &Account) -> error::Result<String> {
+    let domain = env::var("JELLY_DOMAIN").expect("No JELLY_DOMAIN value set!");
+
+    Ok(format!(
+        "{}/accounts/magic/{}-{}",
+        domain,
+        base64_url::encode(&format!("{}", account.id)),
+        account
+            .create_reset_token()
+            .map_err(|e| { anyhow!("Error creating login token: {:?}", e) })?
+    ))
+}
+
+#[rocket::async_trait]
+impl JobRun for SendMagicLinkEmail {
+    async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        let mut conn_result = state.pool.acquire().await;
+        let conn = conn_result
+            .as_mut()
+            .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
+
+        let account = match Account::get_by_email(&self.to, state.config.accounts.email_aliases, conn).await {
+            Ok(account) if account.is_active => account,
+            _ => {
+                tracing::info!("no active account for login link");
+                return Ok(());
+            }
+        };
+
+        let email = Email::new(
+            "magic-link",
+            &[account.email.clone()],
+            "Your login link",
+            build_context(&account.name, &login_url(&account)?),
+            state.templates.clone(),
+            account.locale.as_deref(),
+        );
+
+        email?.send()?;
+
+        Ok(())
+    }
+}
@@ -44,7 +44,7 @@ impl JobRun for SendAccountOddRegisterAttemptEmail {
             .as_mut()
             .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
 
-        let name = Account::fetch_name_from_email(&self.to, conn)
+        let name = Account::fetch_name_from_email(&self.to, state.config.accounts.email_aliases, conn)
             .await
             .map_err(|e| {
                 anyhow!(
@@ -52,6 +52,7 @@ impl JobRun for SendAccountOddRegisterAttemptEmail {
                     e
                 )
             })?;
+        let locale = Account::fetch_locale_from_email(&self.to, state.config.accounts.email_aliases, conn).await?;
 
         let email = Email::new(
             "odd-registration-attempt",
@@ -59,6 +60,7 @@ impl JobRun for SendAccountOddRegisterAttemptEmail {
             "Did you want to reset your password?",
             build_context(&name),
             state.templates.clone(),
+            locale.as_deref(),
         );
 
         email?.send()?;
@@ -0,0 +1,95 @@
+use anyhow::anyhow;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tera::Context;
+
+use crate::email::Email;
+use crate::error;
+use crate::jobs::reset_password::reset_url;
+use crate::jobs::{JobRun, Message, PostgresQueue};
+use crate::models::Account;
+
+/// How often, in seconds, expired passwords are looked for.
+pub const REMINDER_INTERVAL: i64 = 3600;
+
+/// A recurring job that queues a reminder for each account whose password
+/// has passed `accounts.password_max_age_days`, then reschedules itself.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemindExpiredPasswords;
+
+#[rocket::async_trait]
+impl JobRun for RemindExpiredPasswords {
+    async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        let accounts = &state.config.accounts;
+        if let Some(max_age_days) = accounts.password_max_age() {
+            let mut conn_result = state.pool.acquire().await;
+            let conn = conn_result
+                .as_mut()
+                .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
+
+            let emails = Account::remind_expired_passwords(max_age_days, accounts.enforce_password_rotation, conn)
+                .await
+                .map_err(|e| anyhow!("Error finding expired passwords: {:?}", e))?;
+            if !emails.is_empty() {
+                tracing::info!(count = emails.len(), "reminding accounts to change their password");
+                let jobs = emails.into_iter()
+                    .map(|email| (Message::SendPasswordExpiryReminder(email), None))
+                    .collect();
+                state.push_batch(jobs).await?;
+            }
+        }
+
+        state
+            .push_recurring(Message::RemindExpiredPasswords, Utc::now() + Duration::seconds(REMINDER_INTERVAL))
+            .await
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendPasswordExpiryReminder {
+    pub to: String,
+}
+
+pub fn build_context(name: &str, action_url: &str, max_age_days: i32, enforced: bool) -> Context {
+    let mut context = Context::new();
+    context.insert("name", name);
+    context.insert("action_url", action_url);
+    context.insert("max_age_days", &max_age_days);
+    context.insert("enforced", &enforced);
+    context
+}
+
+#[rocket::async_trait]
+impl JobRun for SendPasswordExpiryReminder {
+    async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        let mut conn_result = state.pool.acquire().await;
+        let conn = conn_result
+            .as_mut()
+            .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
+
+        let account = Account::get_by_email(&self.to, state.config.accounts.email_aliases, conn)
+            .await
+            .map_err(|e| anyhow!("Error fetching account for password expiry: {:?}", e))?;
+
+        if !account.email_delivery_status.is_deliverable() {
+            tracing::info!(account_id = account.id, email_type = "password expiry", "skipping email, address is undeliverable");
+            return Ok(());
+        }
+
+        let enforced = Account::must_change_password(account.id, conn).await?;
+        let action_url = reset_url(&account)?;
+
+        let email = Email::new(
+            "password-expiry",
+            &[account.email],
+            "Time to change your password",
+            build_context(&account.name, &action_url, state.config.accounts.password_max_age().unwrap_or_default(), enforced),
+            state.templates.clone(),
+            account.locale.as_deref(),
+        );
+
+        email?.send()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use anyhow::anyhow;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error;
+use crate::jobs::{JobRun, Message, PostgresQueue};
+use crate::models::Account;
+
+/// How often, in seconds, unverified accounts are looked for.
+pub const PURGE_INTERVAL: i64 = 3600;
+
+/// A recurring job that deletes accounts registered with a password that
+/// never verified their email or logged in within
+/// `accounts.unverified_max_age_days`, then reschedules itself. They
+/// go through `Account::delete`, so `accounts.soft_delete` applies and any
+/// provider tokens are revoked.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurgeUnverifiedAccounts;
+
+#[rocket::async_trait]
+impl JobRun for PurgeUnverifiedAccounts {
+    async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        if let Some(max_age_days) = state.config.accounts.unverified_max_age() {
+            let mut conn_result = state.pool.acquire().await;
+            let conn = conn_result
+                .as_mut()
+                .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
+
+            let ids = Account::never_verified(max_age_days, conn)
+                .await
+                .map_err(|e| anyhow!("Error finding unverified accounts: {:?}", e))?;
+
+            let mut deleted = 0;
+            for id in ids {
+                match Account::delete(id, state.config.accounts.soft_delete, &state.providers, conn).await {
+                    Ok(()) => deleted += 1,
+                    Err(e) => tracing::error!(account_id = id, error = ?e, "could not delete unverified account"),
+                }
+            }
+            if deleted > 0 {
+                tracing::info!(count = deleted, "deleted accounts that never verified their email");
+            }
+        }
+
+        state
+            .push_recurring(Message::PurgeUnverifiedAccounts, Utc::now() + Duration::seconds(PURGE_INTERVAL))
+            .await
+    }
+}
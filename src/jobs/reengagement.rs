@@ -0,0 +1,177 @@
+use std::env::var;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tera::Context;
+
+use crate::email::Email;
+use crate::error;
+use crate::jobs::{JobRun, PostgresQueue};
+use crate::models::Account;
+
+/// The email type the variants are recorded under.
+const EMAIL_TYPE: &str = "reengagement";
+
+/// Template variants under test, each a pair of `.html` and `.txt`
+/// templates, with their subject lines.
+const VARIANTS: [(&str, &str); 2] = [
+    ("reengagement-a", "We miss you"),
+    ("reengagement-b", "Here's what's new since your last visit"),
+];
+
+/// The templates the variants use, for checking at startup.
+pub fn templates() -> impl Iterator<Item = &'static str> {
+    VARIANTS.iter().map(|(template, _)| *template)
+}
+
+/// A job for nudging an inactive account to come back. Which of the
+/// `VARIANTS` is sent is an A/B test, assigned once per account.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendReengagementEmail {
+    pub to: String,
+}
+
+/// Picks a variant index for an account. This is a stable hash of the
+/// account id and email type, so the split is even and doesn't change
+/// between releases.
+pub fn choose_variant(account_id: i32, email_type: &str, variants: usize) -> usize {
+    let digest = Sha256::digest(format!("{}:{}", email_type, account_id).as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(bytes) % variants as u64) as usize
+}
+
+/// The variant the account was assigned, assigning `chosen` if it has
+/// none yet.
+async fn assigned_variant(pool: &PgPool, account_id: i32, chosen: &str) -> error::Result<String> {
+    sqlx::query("INSERT INTO email_variants (account_id, email_type, variant)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (account_id, email_type) DO NOTHING")
+        .bind(account_id)
+        .bind(EMAIL_TYPE)
+        .bind(chosen)
+        .execute(pool)
+        .await?;
+
+    let (variant,): (String,) = sqlx::query_as("SELECT variant FROM email_variants
+        WHERE account_id = $1 AND email_type = $2")
+        .bind(account_id)
+        .bind(EMAIL_TYPE)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(variant)
+}
+
+async fn record_send(pool: &PgPool, account_id: i32) -> error::Result<()> {
+    sqlx::query("UPDATE email_variants
+        SET send_count = send_count + 1, last_sent_at = now()
+        WHERE account_id = $1 AND email_type = $2")
+        .bind(account_id)
+        .bind(EMAIL_TYPE)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub fn build_context(name: &str) -> Context {
+    let mut context = Context::new();
+    context.insert("name", name);
+    context.insert(
+        "help_url",
+        &var("JELLY_HELP_URL").expect("JELLY_HELP_URL not set?"),
+    );
+    context
+}
+
+#[rocket::async_trait]
+impl JobRun for SendReengagementEmail {
+    async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        let mut conn_result = state.pool.acquire().await;
+        let conn = conn_result
+            .as_mut()
+            .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
+
+        let account = Account::get_by_email(&self.to, state.config.accounts.email_aliases, conn)
+            .await
+            .map_err(|e| anyhow!("Error fetching user name/email: {:?}", e))?;
+
+        if !account.email_delivery_status.is_deliverable() {
+            tracing::info!(account_id = account.id, email_type = EMAIL_TYPE, "skipping email, address is undeliverable");
+            return Ok(());
+        }
+
+        let chosen = VARIANTS[choose_variant(account.id, EMAIL_TYPE, VARIANTS.len())];
+        let variant = assigned_variant(&state.pool, account.id, chosen.0).await?;
+
+        // An assignment to a variant that has since been retired falls
+        // back to the freshly chosen one.
+        let (template, subject) = VARIANTS.iter()
+            .find(|(template, _)| *template == variant)
+            .copied()
+            .unwrap_or(chosen);
+
+        let email = Email::new(
+            template,
+            &[account.email],
+            subject,
+            build_context(&account.name),
+            state.templates.clone(),
+            account.locale.as_deref(),
+        );
+
+        email?.send()?;
+        record_send(&state.pool, account.id).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::test_queue;
+    use crate::routes::accounts::NewAccount;
+
+    #[test]
+    fn variants_are_stable_per_account() {
+        for account_id in [1, 42, 1_000_000] {
+            let variant = choose_variant(account_id, EMAIL_TYPE, VARIANTS.len());
+            assert!(variant < VARIANTS.len());
+            assert_eq!(choose_variant(account_id, EMAIL_TYPE, VARIANTS.len()), variant);
+        }
+    }
+
+    #[test]
+    fn variants_spread_across_accounts() {
+        let mut counts = [0usize; 2];
+        for account_id in 1..=1000 {
+            counts[choose_variant(account_id, EMAIL_TYPE, counts.len())] += 1;
+        }
+        // Close to an even split.
+        assert!(counts.iter().all(|count| (400..=600).contains(count)), "{:?}", counts);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn the_first_assignment_is_kept() {
+        let queue = test_queue().await;
+
+        let email = format!("variant-{}@example.com", ulid::Ulid::new().to_string().to_lowercase());
+        let new_account = NewAccount { name: "Variant Test", email: &email, password: "a long test password" };
+        let aliases = queue.config.accounts.email_aliases;
+        let mut conn = queue.pool.acquire().await.unwrap();
+        Account::register(&new_account, aliases, &mut conn).await.unwrap();
+        let id = Account::id_by_email(&email, aliases, &mut conn).await.unwrap();
+
+        let first = assigned_variant(&queue.pool, id, VARIANTS[0].0).await.unwrap();
+        let again = assigned_variant(&queue.pool, id, VARIANTS[1].0).await.unwrap();
+        sqlx::query("DELETE FROM email_variants WHERE account_id = $1").bind(id).execute(&mut conn).await.unwrap();
+        sqlx::query("DELETE FROM accounts WHERE id = $1").bind(id).execute(&mut conn).await.unwrap();
+
+        assert_eq!(first, VARIANTS[0].0);
+        assert_eq!(again, VARIANTS[0].0);
+    }
+}
@@ -21,6 +21,20 @@ pub fn build_context(verify_url: &str) -> Context {
     context
 }
 
+/// The link for choosing a new password, with a one time token.
+pub fn reset_url(account: &Account) -> error::Result<String> {
+    let domain = env::var("JELLY_DOMAIN").expect("No JELLY_DOMAIN value set!");
+
+    Ok(format!(
+        "{}/accounts/reset/{}-{}",
+        domain,
+        base64_url::encode(&format!("{}", account.id)),
+        account
+            .create_reset_token()
+            .map_err(|e| { anyhow!("Error creating verification token: {:?}", e) })?
+    ))
+}
+
 #[rocket::async_trait]
 impl JobRun for SendResetPasswordEmail {
     async fn run(self, state: &PostgresQueue) -> error::Result<()> {
@@ -29,20 +43,11 @@ impl JobRun for SendResetPasswordEmail {
             .as_mut()
             .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
 
-        let account = Account::get_by_email(&self.to, conn)
+        let account = Account::get_by_email(&self.to, state.config.accounts.email_aliases, conn)
             .await
             .map_err(|e| anyhow!("Error fetching account for password reset: {:?}", e))?;
 
-        let domain = env::var("JELLY_DOMAIN").expect("No JELLY_DOMAIN value set!");
-
-        let verify_url = format!(
-            "{}/accounts/reset/{}-{}",
-            domain,
-            base64_url::encode(&format!("{}", account.id)),
-            account
-                .create_reset_token()
-                .map_err(|e| { anyhow!("Error creating verification token: {:?}", e) })?
-        );
+        let verify_url = reset_url(&account)?;
 
         let email = Email::new(
             "reset-password",
@@ -50,6 +55,7 @@ impl JobRun for SendResetPasswordEmail {
             "Reset your account password",
             build_context(&verify_url),
             state.templates.clone(),
+            account.locale.as_deref(),
         );
 
         email?.send()?;
@@ -66,12 +72,20 @@ pub struct SendPasswordWasResetEmail {
 #[rocket::async_trait]
 impl JobRun for SendPasswordWasResetEmail {
     async fn run(self, state: &PostgresQueue) -> error::Result<()> {
+        let mut conn_result = state.pool.acquire().await;
+        let conn = conn_result
+            .as_mut()
+            .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
+
+        let locale = Account::fetch_locale_from_email(&self.to, state.config.accounts.email_aliases, conn).await?;
+
         let email = Email::new(
             "password-was-reset",
             &[self.to],
             "Your Password Was Reset",
             Context::new(),
             state.templates.clone(),
+            locale.as_deref(),
         );
 
         email?.send()?;
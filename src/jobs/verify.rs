@@ -4,10 +4,10 @@ use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use tera::Context;
 
-use crate::email::Email;
+use crate::email::{is_bounce, Email};
 use crate::error;
-use crate::jobs::{JobRun, PostgresQueue};
-use crate::models::Account;
+use crate::jobs::{cooldown, JobRun, PostgresQueue};
+use crate::models::{Account, EmailDeliveryStatus};
 use crate::token::OneTimeUseTokenGenerator;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,11 +29,19 @@ impl JobRun for SendVerifyAccountEmail {
             .as_mut()
             .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
 
-        let account = Account::get_by_email(&self.to, conn)
+        let account = Account::get_by_email(&self.to, state.config.accounts.email_aliases, conn)
             .await
             .map_err(|e| anyhow!("Error fetching account for verification: {:?}", e))?;
 
+        // Already verified accounts get nothing, so resending can't be used
+        // to mint a fresh link for them.
         if !account.has_verified_email {
+            let window = i64::try_from(state.config.accounts.verify_email_cooldown).unwrap_or(i64::MAX);
+            if !cooldown::claim(&state.pool, "verify-account", &account.email, window).await? {
+                tracing::info!(account_id = account.id, "skipping verify email, sent recently");
+                return Ok(());
+            }
+
             let domain = env::var("JELLY_DOMAIN").expect("No JELLY_DOMAIN value set!");
 
             let verify_url = format!(
@@ -47,15 +55,111 @@ impl JobRun for SendVerifyAccountEmail {
 
             let email = Email::new(
                 "verify-account",
-                &[account.email],
+                &[account.email.clone()],
                 "Verify your new account",
                 build_context(&verify_url),
                 state.templates.clone(),
+                account.locale.as_deref(),
             );
 
-            email?.send()?;
+            match email.and_then(|email| email.send().map_err(error::Error::from)) {
+                Ok(()) => {
+                    Account::set_email_delivery_status(&account.email, EmailDeliveryStatus::Sent, state.config.accounts.email_aliases, conn).await?;
+                },
+                // Retrying won't help, so record it and finish the job.
+                Err(e) if is_bounce(&e) => {
+                    tracing::warn!(account_id = account.id, error = %e, "verify email bounced");
+                    Account::set_email_delivery_status(&account.email, EmailDeliveryStatus::Bounced, state.config.accounts.email_aliases, conn).await?;
+                },
+                Err(e) => {
+                    cooldown::release(&state.pool, "verify-account", &account.email).await?;
+                    return Err(e);
+                },
+            }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::test_queue;
+    use crate::routes::accounts::NewAccount;
+
+    /// The account's delivery status, then set back to `Queued`, so that
+    /// the next send can be told apart.
+    async fn take_delivery_status(queue: &PostgresQueue, email: &str) -> EmailDeliveryStatus {
+        let mut conn = queue.pool.acquire().await.unwrap();
+        let aliases = queue.config.accounts.email_aliases;
+        let status = Account::get_by_email(email, aliases, &mut conn).await.unwrap().email_delivery_status;
+        Account::set_email_delivery_status(email, EmailDeliveryStatus::Queued, aliases, &mut conn).await.unwrap();
+        status
+    }
+
+    /// A queue that can render the verify email, and sends it with the
+    /// mock mailer.
+    async fn queue() -> PostgresQueue {
+        let queue = test_queue().await;
+        env::set_var("EMAIL_DEFAULT_FROM", "app@example.com");
+        env::set_var("JELLY_DOMAIN", "https://example.com");
+        queue.templates.tera.write().unwrap().add_raw_templates(vec![
+            ("verify-account.html", "<a href=\"{{ action_url }}\">Verify</a>"),
+            ("verify-account.txt", "Verify: {{ action_url }}"),
+        ]).unwrap();
+        queue
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn a_second_verify_job_within_the_cooldown_sends_nothing() {
+        let queue = queue().await;
+
+        let email = format!("verify-twice-{}@example.com", ulid::Ulid::new().to_string().to_lowercase());
+        let new_account = NewAccount { name: "Verify Twice", email: &email, password: "a long test password" };
+        let mut conn = queue.pool.acquire().await.unwrap();
+        Account::register(&new_account, queue.config.accounts.email_aliases, &mut conn).await.unwrap();
+
+        SendVerifyAccountEmail { to: email.clone() }.run(&queue).await.unwrap();
+        let first = take_delivery_status(&queue, &email).await;
+        SendVerifyAccountEmail { to: email.clone() }.run(&queue).await.unwrap();
+        let second = take_delivery_status(&queue, &email).await;
+
+        cooldown::release(&queue.pool, "verify-account", &email).await.unwrap();
+        sqlx::query("DELETE FROM accounts WHERE email = $1")
+            .bind(crate::pii::seal_email(&email))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        assert_eq!(first, EmailDeliveryStatus::Sent);
+        assert_eq!(second, EmailDeliveryStatus::Queued);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn verified_accounts_are_sent_nothing() {
+        let queue = queue().await;
+
+        let email = format!("verified-{}@example.com", ulid::Ulid::new().to_string().to_lowercase());
+        let new_account = NewAccount { name: "Verified", email: &email, password: "a long test password" };
+        let aliases = queue.config.accounts.email_aliases;
+        let mut conn = queue.pool.acquire().await.unwrap();
+        Account::register(&new_account, aliases, &mut conn).await.unwrap();
+        let id = Account::id_by_email(&email, aliases, &mut conn).await.unwrap();
+        Account::mark_verified(id, &mut conn).await.unwrap();
+
+        SendVerifyAccountEmail { to: email.clone() }.run(&queue).await.unwrap();
+        let status = take_delivery_status(&queue, &email).await;
+        let (claims,): (i64,) = sqlx::query_as("SELECT count(*) FROM email_sends WHERE template = 'verify-account' AND recipient = lower($1)")
+            .bind(&email)
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM accounts WHERE id = $1").bind(id).execute(&mut conn).await.unwrap();
+
+        assert_eq!(status, EmailDeliveryStatus::Queued);
+        assert_eq!(claims, 0);
+    }
+}
@@ -34,16 +34,22 @@ impl JobRun for SendWelcomeAccountEmail {
             .as_mut()
             .map_err(|_| error::Error::from(anyhow!("failed to acquire connection")))?;
 
-        let account = Account::get_by_email(&self.to, conn)
+        let account = Account::get_by_email(&self.to, state.config.accounts.email_aliases, conn)
             .await
             .map_err(|e| anyhow!("Error fetching user name/email: {:?}", e))?;
 
+        if !account.email_delivery_status.is_deliverable() {
+            tracing::info!(account_id = account.id, email_type = "welcome", "skipping email, address is undeliverable");
+            return Ok(());
+        }
+
         let email = Email::new(
             "welcome",
             &[account.email],
             "Welcome to the service",
             build_context(&account.name),
             state.templates.clone(),
+            account.locale.as_deref(),
         );
 
         email?.send()?;
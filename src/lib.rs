@@ -1,37 +1,136 @@
-use rocket::{routes, Build, Rocket};
+use rocket::{catchers, routes, Build, Rocket};
+use rocket::fairing::AdHoc;
 use rocket_db_pools::Database;
 use rocket_dyn_templates::Template;
 
 pub mod auth;
+pub mod blocklist;
+pub mod captcha;
+pub mod config;
+pub mod cookies;
+pub mod csrf;
 pub mod database;
 pub mod email;
 pub mod error;
 pub mod jobs;
+pub mod limits;
+pub mod logging;
 pub mod models;
 #[cfg(feature = "oauth")]
 pub mod oauth;
 pub mod response;
 pub mod routes;
 pub mod passwords;
+pub mod pii;
+pub mod ratelimit;
+pub mod storage;
 pub mod token;
+#[cfg(feature = "webauthn")]
+pub mod webauthn;
 
 use email::common::Configurable;
 
 pub fn rocket() -> Rocket<Build> {
     email::Email::check_conf();
 
-    rocket::build()
+    let figment = rocket::Config::figment()
+        .join(("limits", limits::defaults()));
+
+    let rocket = rocket::custom(figment)
+        .attach(logging::RequestLogger)
+        .attach(AdHoc::config::<config::AppConfig>())
         .attach(database::AppDb::init())
+        .attach(database::migrations())
+        .attach(database::schema_check())
+        .attach(pii::fairing())
         .attach(Template::fairing())
+        .attach(cookies::fairing())
+        .attach(csrf::fairing())
+        .attach(captcha::fairing())
         .attach(jobs::BackgroundQueue::fairing())
-        .mount("/accounts", routes![
+        .attach(storage::fairing())
+        .manage(ratelimit::RateLimiter::default())
+        .manage(routes::webhooks::EmailWebhookSecret::from_env())
+        .mount("/accounts", logging::traced(routes![
             routes::accounts::registration_form,
             routes::accounts::create_account,
+            routes::accounts::password_strength,
             routes::accounts::login_form,
             routes::accounts::authenticate,
             routes::accounts::logout,
+            routes::accounts::logout_all,
             routes::accounts::verify_with_token,
-            routes::accounts::verify
-        ])
-        .mount("/", routes![routes::home::home])
+            routes::accounts::verify,
+            routes::accounts::resend_link_form,
+            routes::accounts::resend_link,
+            routes::accounts::reset_password_form,
+            routes::accounts::request_reset,
+            routes::accounts::reset_password_with_token,
+            routes::accounts::magic_link_form,
+            routes::accounts::request_magic_link,
+            routes::accounts::magic_login,
+            routes::accounts::reset_password,
+            routes::accounts::settings_form,
+            routes::accounts::update_settings,
+            routes::accounts::upload_avatar,
+            routes::accounts::change_password,
+            routes::accounts::change_email,
+            routes::accounts::confirm_email_change,
+            routes::accounts::export_data,
+            routes::accounts::unlink_identity,
+            routes::accounts::delete_account
+        ]))
+        .mount("/api", logging::traced(routes![routes::api::description]))
+        .mount("/admin", logging::traced(routes![
+            routes::admin::list_accounts,
+            routes::admin::resend_welcome,
+            routes::admin::deactivate_account,
+            routes::admin::reactivate_account,
+            routes::admin::create_invitation,
+            routes::admin::queue_stats,
+            routes::admin::pool_stats,
+            routes::admin::preview_email,
+            routes::admin::preview_email_text
+        ]))
+        .mount("/", logging::traced(routes![
+            routes::home::home,
+            routes::dashboard::dashboard,
+            routes::health::healthz,
+            routes::health::readyz
+        ]))
+        .mount("/webhooks", logging::traced(routes![routes::webhooks::postmark_event]))
+        .mount("/dashboard", logging::traced(routes![routes::guests::get]))
+        .mount("/accounts/settings", logging::traced(routes![routes::guests::get, routes::guests::post]))
+        .mount("/accounts/logout-all", logging::traced(routes![routes::guests::post]))
+        .mount("/admin", logging::traced(routes![routes::guests::get, routes::guests::post]))
+        .register("/", catchers![routes::catchers::unauthorized]);
+
+    let rocket = if rocket.figment().profile() == rocket::Config::DEBUG_PROFILE {
+        rocket.mount("/dev", logging::traced(routes![routes::dev::run_job]))
+    } else {
+        rocket
+    };
+
+    #[cfg(feature = "oauth")]
+    let rocket = rocket.attach(oauth::client::fairing()).mount("/oauth", logging::traced(routes![
+        routes::oauth::login_form,
+        routes::oauth::login,
+        routes::oauth::callback,
+        routes::oauth::confirm
+    ]));
+
+    #[cfg(feature = "webauthn")]
+    let rocket = rocket.attach(webauthn::fairing()).mount("/webauthn", logging::traced(routes![
+        routes::webauthn::passkey_login_form,
+        routes::webauthn::passkey_login_begin,
+        routes::webauthn::passkey_login_finish,
+        routes::webauthn::passkey_register_begin,
+        routes::webauthn::passkey_register_begin_guest,
+        routes::webauthn::passkey_register_finish,
+        routes::webauthn::passkey_register_finish_guest,
+        routes::webauthn::delete_passkey,
+        routes::webauthn::delete_passkey_guest
+    ]));
+
+    rocket
 }
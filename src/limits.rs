@@ -0,0 +1,107 @@
+//! Request body limits.
+//!
+//! Rocket reads data limits from the `limits` config table. We provide
+//! tighter defaults than Rocket's for forms, which can be overridden in
+//! `Rocket.toml` or via `ROCKET_LIMITS`:
+//!
+//! ```toml
+//! [default.limits]
+//! form = "32 KiB"
+//! auth-form = "4 KiB"
+//! "auth-form/create_account" = "8 KiB"
+//! ```
+//!
+//! The `auth-form` limit applies to the login, registration and password
+//! reset forms, and can be overridden per route using the handler's name.
+
+use anyhow::anyhow;
+use rocket::data::{ByteUnit, Limits, ToByteUnit};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+use crate::error;
+
+/// Limit name for the account forms.
+pub const AUTH_FORM: &str = "auth-form";
+
+/// Fallback for the `auth-form` limit if it isn't configured.
+pub const AUTH_FORM_DEFAULT: ByteUnit = ByteUnit::Kibibyte(4);
+
+/// Default limits, joined into the config so that any configured
+/// values take priority.
+pub fn defaults() -> Limits {
+    Limits::default()
+        .limit("form", 32.kibibytes())
+        .limit("data-form", 2.mebibytes())
+        .limit(AUTH_FORM, AUTH_FORM_DEFAULT)
+}
+
+/// Request guard that rejects auth form submissions whose declared
+/// `Content-Length` exceeds the `auth-form` limit for the route with
+/// `PayloadTooLarge`. Bodies without a `Content-Length` are still
+/// capped by the global `form` limit when the form is parsed.
+pub struct AuthFormLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthFormLimit {
+    type Error = error::Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let route_name = req.route()
+            .and_then(|route| route.name.as_deref())
+            .unwrap_or_default();
+        let limit = req.limits()
+            .find([AUTH_FORM, route_name])
+            .unwrap_or(AUTH_FORM_DEFAULT);
+
+        let length = req.headers()
+            .get_one("Content-Length")
+            .and_then(|v| v.parse::<u64>().ok());
+
+        match length {
+            Some(length) if length > limit.as_u64() =>
+                Outcome::Failure((Status::PayloadTooLarge, error::Error::with_status(
+                    anyhow!("request body of {} bytes exceeds limit of {}", length, limit),
+                    Status::PayloadTooLarge))),
+            _ => Outcome::Success(AuthFormLimit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use rocket::{post, routes};
+
+    use super::*;
+
+    #[post("/submit", data = "<body>")]
+    fn submit(_limit: AuthFormLimit, body: String) -> String {
+        body
+    }
+
+    async fn status_for(body: &str, limits: Limits) -> Status {
+        let figment = rocket::Config::figment().merge(("limits", limits));
+        let client = Client::tracked(rocket::custom(figment).mount("/", routes![submit])).await.unwrap();
+        client.post("/submit")
+            .header(Header::new("Content-Length", body.len().to_string()))
+            .body(body)
+            .dispatch()
+            .await
+            .status()
+    }
+
+    #[rocket::async_test]
+    async fn over_limit_bodies_are_too_large() {
+        let limits = defaults().limit(AUTH_FORM, 16.bytes());
+        assert_eq!(status_for("short", limits.clone()).await, Status::Ok);
+        assert_eq!(status_for(&"x".repeat(32), limits).await, Status::PayloadTooLarge);
+    }
+
+    #[rocket::async_test]
+    async fn routes_can_raise_their_limit() {
+        let limits = defaults().limit(AUTH_FORM, 16.bytes()).limit("auth-form/submit", 64.bytes());
+        assert_eq!(status_for(&"x".repeat(32), limits).await, Status::Ok);
+    }
+}
@@ -0,0 +1,234 @@
+//! Logging, through `tracing`.
+//!
+//! `init` installs a subscriber that also takes the `log` records from
+//! Rocket, sqlx and the `rocket::info!` style macros, so everything comes
+//! out in one format. `RUST_LOG` filters it, as it did with env_logger,
+//! defaulting to "info". The `log` config section picks the format:
+//!
+//! ```toml
+//! [default.log]
+//! format = "json"
+//! ```
+//!
+//! With "json", each event is written as a line of JSON, with its fields
+//! and those of its spans, for log collectors.
+//!
+//! Every request gets an id: the `X-Request-Id` header set by a proxy in
+//! front of the app, if it looks like one, or a new ULID. It is sent back
+//! in the response's `X-Request-Id`, and logged with the request's route,
+//! status and duration. Routes mounted through `traced` run in a span
+//! carrying the id, so whatever their handlers and guards log has it too.
+//! Background jobs log within a span carrying the job id; see
+//! `jobs::run_worker`.
+
+use std::time::Instant;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::route::{self, Handler, Route};
+use rocket::{Data, Response};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+/// Header carrying the request id, both ways.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+const DEFAULT_FILTER: &str = "info";
+
+/// The longest request id taken from a proxy.
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// The `log` config section.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// A line of text per event.
+    #[default]
+    Text,
+    /// A JSON object per event, with fields such as `request_id` and
+    /// `job_id`.
+    Json,
+}
+
+/// Installs the subscriber, with the `log` section of the Rocket config.
+/// Call it once, before building Rocket, so that Rocket's own logger
+/// doesn't claim the `log` records.
+pub fn init() {
+    let config = rocket::Config::figment()
+        .extract_inner::<LogConfig>("log")
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid log config, using the defaults: {}", e);
+            LogConfig::default()
+        });
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    let result = match config.format {
+        LogFormat::Json => builder.json().try_init(),
+        LogFormat::Text => builder.try_init(),
+    };
+
+    if let Err(e) = result {
+        eprintln!("Could not set up logging: {}", e);
+    }
+}
+
+/// The id of the current request.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn of(req: &Request<'_>) -> &RequestId {
+        req.local_cache(|| {
+            let forwarded = req.headers()
+                .get_one(REQUEST_ID_HEADER)
+                .filter(|id| is_valid_request_id(id));
+
+            RequestId(forwarded.map_or_else(|| ulid::Ulid::new().to_string(), String::from))
+        })
+    }
+}
+
+/// The span a request is handled in.
+struct RequestSpan(tracing::Span);
+
+impl RequestSpan {
+    fn of(req: &Request<'_>) -> tracing::Span {
+        req.local_cache(|| {
+            let id = RequestId::of(req);
+            RequestSpan(tracing::info_span!("request", request_id = %id.0, method = %req.method()))
+        })
+        .0
+        .clone()
+    }
+}
+
+/// Ids from a proxy are logged as given, so only short ones made of
+/// letters, digits and a little punctuation are taken.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(req).clone())
+    }
+}
+
+/// A route's handler, run within its request's span.
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        self.0.handle(req, data).instrument(RequestSpan::of(req)).await
+    }
+}
+
+/// Runs the handlers of `routes`, and their guards, within the span of
+/// each request, so that their events carry its id.
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Traced(route.handler));
+            route
+        })
+        .collect()
+}
+
+/// When the request came in, for its duration.
+struct RequestStart(Instant);
+
+/// Assigns request ids, and logs each request as it's answered.
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+        RequestSpan::of(req);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let id = RequestId::of(req);
+        let duration = req.local_cache(|| RequestStart(Instant::now())).0.elapsed();
+
+        // The route rather than the path, which may hold a token.
+        let route = req.route().map_or_else(|| "-".to_string(), |route| route.uri.to_string());
+        tracing::info!(
+            parent: &RequestSpan::of(req),
+            route = %route,
+            status = res.status().code,
+            duration_ms = duration.as_millis() as u64,
+            "request"
+        );
+
+        res.set_header(Header::new(REQUEST_ID_HEADER, id.0.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::asynchronous::Client;
+    use rocket::{get, routes};
+
+    use super::*;
+
+    #[get("/")]
+    fn index() -> &'static str {
+        "ok"
+    }
+
+    async fn client() -> Client {
+        let rocket = rocket::build().attach(RequestLogger).mount("/", traced(routes![index]));
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[test]
+    fn only_short_plain_request_ids_are_taken() {
+        assert!(is_valid_request_id("01G3Z5-abc_1.2"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("a b"));
+        assert!(!is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1)));
+    }
+
+    #[rocket::async_test]
+    async fn traced_routes_answer_with_the_proxys_request_id() {
+        let client = client().await;
+        let res = client.get("/").header(Header::new(REQUEST_ID_HEADER, "abc-123")).dispatch().await;
+
+        assert_eq!(res.headers().get_one(REQUEST_ID_HEADER), Some("abc-123"));
+        assert_eq!(res.into_string().await.as_deref(), Some("ok"));
+    }
+
+    #[rocket::async_test]
+    async fn requests_without_an_id_get_a_new_one() {
+        let client = client().await;
+        let res = client.get("/").header(Header::new(REQUEST_ID_HEADER, "not valid")).dispatch().await;
+
+        let id = res.headers().get_one(REQUEST_ID_HEADER).unwrap();
+        assert!(id.parse::<ulid::Ulid>().is_ok());
+    }
+}
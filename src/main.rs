@@ -4,11 +4,24 @@
 async fn main() {
     // Load .env files
     dotenv::dotenv().ok();
-    pretty_env_logger::init();
+    mainlib::logging::init();
 
-    if let Err(e) = mainlib::rocket().launch().await {
-        println!("Whoops! Rocket didn't launch!");
-        // We drop the error to get a Rocket-formatted panic.
-        drop(e);
+    let rocket = match mainlib::rocket().ignite().await {
+        Ok(rocket) => rocket,
+        Err(e) => {
+            tracing::error!(error = %e, "could not ignite Rocket");
+            return;
+        }
     };
+
+    // Launch consumes the instance, so take the workers out beforehand.
+    let workers = rocket.state::<mainlib::jobs::Workers>().cloned();
+
+    if let Err(e) = rocket.launch().await {
+        tracing::error!(error = %e, "could not launch Rocket");
+    };
+
+    if let Some(workers) = workers {
+        workers.drain().await;
+    }
 }
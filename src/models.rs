@@ -2,19 +2,64 @@
 // users, along with welcome email and verification.
 
 use anyhow::anyhow;
+use constant_time_eq::constant_time_eq;
 use sqlx::types::chrono::{DateTime, Utc};
-use djangohashers as hasher;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 use sqlx::{types::Json, Acquire, FromRow};
 
 use rocket::http::Status;
 
+use crate::config::{normalize_email, EmailAliasPolicy};
 use crate::database::{AppDbConnection, PgTransaction};
 use crate::error;
+use crate::passwords;
+use crate::oauth::{self, ProviderTokens};
+use crate::oauth::client::OAuthProviders;
+use crate::pii;
 use crate::routes::accounts::{LoginData, NewAccount};
 use crate::routes::oauth::{LinkIdentityData};
 use crate::token::{OneTimeUseTokenGenerator, UserToken};
 
+/// Serde helpers that write timestamps as RFC 3339 in UTC with second
+/// precision, e.g. `2022-04-18T09:00:00Z`, so API clients get one
+/// stable format. Use with `#[serde(with = "rfc3339")]`.
+pub mod rfc3339 {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use sqlx::types::chrono::{DateTime, SecondsFormat, Utc};
+
+    pub fn serialize<S: Serializer>(ts: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&ts.to_rfc3339_opts(SecondsFormat::Secs, true))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        DateTime::parse_from_rfc3339(&value)
+            .map(|ts| ts.with_timezone(&Utc))
+            .map_err(de::Error::custom)
+    }
+
+    /// The same, for optional timestamps.
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use sqlx::types::chrono::{DateTime, Utc};
+
+        pub fn serialize<S: Serializer>(ts: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+            match ts {
+                Some(ts) => super::serialize(ts, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+            #[derive(Deserialize)]
+            struct Wrapper(#[serde(with = "super")] DateTime<Utc>);
+
+            Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(ts)| ts))
+        }
+    }
+}
+
 /// A smaller, serialize-able instance of an Account
 /// that can be used to avoid a database hit.
 #[derive(Debug, Serialize, Deserialize)]
@@ -23,6 +68,13 @@ pub struct User {
     pub name: String,
     pub is_admin: bool,
     pub is_anonymous: bool,
+    /// Derived from the account's password when the session was issued,
+    /// so sessions can be revoked by changing the password.
+    #[serde(default)]
+    pub fingerprint: String,
+    /// The account's `session_version` when the session was issued.
+    #[serde(default)]
+    pub session_version: i32,
 }
 
 impl Default for User {
@@ -33,7 +85,28 @@ impl Default for User {
             name: String::new(),
             is_admin: false,
             is_anonymous: true,
+            fingerprint: String::new(),
+            session_version: 0,
+        }
+    }
+}
+
+impl User {
+    /// Fetches the full account behind this session, for handlers that
+    /// need more than the cookie carries. Anonymous users have none, and
+    /// get `Unauthorized`; an account deleted since login is `NotFound`.
+    pub async fn load_account(&self, conn: &mut sqlx::PgConnection) -> error::Result<Account> {
+        if self.is_anonymous {
+            return Err(error::Error::with_status(anyhow!("anonymous user has no account"), Status::Unauthorized));
         }
+
+        Account::get(self.id, conn).await.map_err(|e| {
+            if matches!(e.error.downcast_ref::<sqlx::Error>(), Some(sqlx::Error::RowNotFound)) {
+                error::Error::with_status(e.error, Status::NotFound)
+            } else {
+                e
+            }
+        })
     }
 }
 
@@ -41,24 +114,241 @@ struct UserPass {
     id: i32,
     name: String,
     password: Option<String>,
+    is_active: bool,
     is_admin: bool,
+    has_verified_email: bool,
+    session_version: i32,
+}
+
+lazy_static::lazy_static! {
+    /// A hash to check passwords against when there is no account, or the
+    /// account has no password, so that a failed login takes as long as a
+    /// wrong password would and doesn't reveal whether the email exists.
+    static ref DUMMY_PASSWORD_HASH: String = passwords::hash("not the password you are looking for")
+        .expect("could not hash dummy password");
+}
+
+/// A short digest of an account's password hash, stored in the session
+/// cookie. Changing (or removing) the password changes the fingerprint.
+pub fn session_fingerprint(id: i32, password: Option<&str>) -> String {
+    let digest = Sha256::digest(format!("{}{}", id, password.unwrap_or("NoPassword")).as_bytes());
+    format!("{:x}", digest)[..16].to_string()
+}
+
+/// What a session must carry to still be valid for its account.
+#[derive(Debug, Clone)]
+pub struct SessionState {
+    pub fingerprint: String,
+    pub session_version: i32,
+    /// Past the password rotation deadline, no session is valid.
+    pub must_change_password: bool,
+}
+
+impl SessionState {
+    pub fn matches(&self, user: &User) -> bool {
+        constant_time_eq(self.fingerprint.as_bytes(), user.fingerprint.as_bytes())
+            && self.session_version == user.session_version
+            && !self.must_change_password
+    }
+}
+
+/// Runs a password check that can never succeed, for timing purposes.
+fn dummy_check_password(password: &str) {
+    let _ignore = passwords::verify(password, &DUMMY_PASSWORD_HASH);
 }
 
 impl UserPass {
+    /// A corrupt stored hash is an `InternalServerError`, logged, rather
+    /// than passing for a wrong password, so it gets noticed.
     fn check_password(&self, password: &str) -> error::Result<bool> {
-        self.password
-            .as_ref()
-            .ok_or_else(|| error::Error::from(anyhow!("no password for account")))
-            .and_then(|encoded|
-                hasher::check_password(password, encoded)
-                    .map_err(|_| error::Error::from(anyhow!("password invalid"))))
+        match &self.password {
+            Some(encoded) => passwords::verify(password, encoded).map_err(|e| {
+                rocket::error!("corrupt password hash for account {}: {}", self.id, e.error);
+                error::Error::with_status(e.error, Status::InternalServerError)
+            }),
+            None => {
+                dummy_check_password(password);
+                Err(error::Error::with_status(anyhow!("no password for account"), Status::Unauthorized))
+            }
+        }
     }
 }
 
+/// The current shape of `Profile`. Bump it whenever the fields change,
+/// and add an arm to `upgrade_profile` that brings the previous shape up
+/// to the new one.
+pub const PROFILE_VERSION: u64 = 2;
+
 /// Personalized profile data that is a pain to make a needless JOIN
 /// for; just shove it in a jsonb field.
+///
+/// Stored profiles may be of any earlier version, so reading one first
+/// upgrades it to the current shape. If it still won't deserialize, the
+/// default profile is used and a warning logged, rather than failing to
+/// load the account.
 #[derive(Debug, Serialize, Deserialize, FromRow)]
-pub struct Profile {}
+#[serde(remote = "Self")]
+pub struct Profile {
+    pub version: u64,
+    /// Shown in place of the account name, if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    /// An IANA time zone name, e.g. "Europe/Paris".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            version: PROFILE_VERSION,
+            display_name: None,
+            avatar_url: None,
+            bio: None,
+            timezone: None,
+        }
+    }
+}
+
+/// A single `Profile` field, for updating one without touching the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileField {
+    DisplayName,
+    AvatarUrl,
+    Bio,
+    Timezone,
+}
+
+impl Profile {
+    /// Sets one field, or clears it with `None`.
+    pub fn set(&mut self, field: ProfileField, value: Option<String>) {
+        let slot = match field {
+            ProfileField::DisplayName => &mut self.display_name,
+            ProfileField::AvatarUrl => &mut self.avatar_url,
+            ProfileField::Bio => &mut self.bio,
+            ProfileField::Timezone => &mut self.timezone,
+        };
+        *slot = value;
+    }
+}
+
+/// Changes a stored profile from `version` to `version + 1`.
+fn upgrade_profile(version: u64, _profile: &mut serde_json::Map<String, serde_json::Value>) {
+    match version {
+        // Version 0 is a profile from before versioning, always empty.
+        0 => {},
+        // Version 2 added the optional display_name, avatar_url, bio and
+        // timezone, which are simply missing from older profiles.
+        1 => {},
+        _ => {},
+    }
+}
+
+impl<'de> Deserialize<'de> for Profile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut profile = match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::Object(profile) => profile,
+            other => {
+                rocket::warn!("profile is not an object, using the default: {}", other);
+                return Ok(Profile::default());
+            }
+        };
+
+        let stored_version = profile.get("version").and_then(serde_json::Value::as_u64).unwrap_or(0);
+        for version in stored_version..PROFILE_VERSION {
+            upgrade_profile(version, &mut profile);
+        }
+        profile.insert("version".to_string(), PROFILE_VERSION.into());
+
+        // Calls the derived (remote) deserializer, not this one.
+        Ok(Profile::deserialize(serde_json::Value::Object(profile)).unwrap_or_else(|e| {
+            rocket::warn!("could not read profile of version {}, using the default: {}", stored_version, e);
+            Profile::default()
+        }))
+    }
+}
+
+impl Serialize for Profile {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Profile::serialize(self, serializer)
+    }
+}
+
+/// Subscription plans. Stored in the `accounts.plan` integer column.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[repr(i32)]
+pub enum Plan {
+    #[default]
+    Free = 0,
+    Pro = 1,
+    Enterprise = 2,
+}
+
+impl TryFrom<i32> for Plan {
+    type Error = error::Error;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Plan::Free),
+            1 => Ok(Plan::Pro),
+            2 => Ok(Plan::Enterprise),
+            _ => Err(error::Error::from(anyhow!("invalid plan {}", value))),
+        }
+    }
+}
+
+/// Whether mail to the account's address, the verification email in
+/// particular, gets through. Stored in the `accounts.email_delivery_status`
+/// integer column.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[repr(i32)]
+pub enum EmailDeliveryStatus {
+    #[default]
+    Queued = 0,
+    Sent = 1,
+    /// The provider reported the address as inactive or rejecting mail.
+    Bounced = 2,
+    /// The recipient marked our mail as spam.
+    Complained = 3,
+}
+
+impl EmailDeliveryStatus {
+    /// Whether mail to the address should still be sent. Bounced
+    /// addresses would only bounce again, and sending to addresses that
+    /// complained hurts our sender reputation.
+    pub fn is_deliverable(&self) -> bool {
+        !matches!(self, EmailDeliveryStatus::Bounced | EmailDeliveryStatus::Complained)
+    }
+}
+
+impl TryFrom<i32> for EmailDeliveryStatus {
+    type Error = error::Error;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(EmailDeliveryStatus::Queued),
+            1 => Ok(EmailDeliveryStatus::Sent),
+            2 => Ok(EmailDeliveryStatus::Bounced),
+            3 => Ok(EmailDeliveryStatus::Complained),
+            _ => Err(error::Error::from(anyhow!("invalid email delivery status {}", value))),
+        }
+    }
+}
+
+/// A successful login, as shown to the user on the settings page.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginEvent {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    #[serde(with = "rfc3339")]
+    pub created: DateTime<Utc>,
+}
 
 /// A user Account.
 /// Note: `password` can be None if authenticating via OAuth.
@@ -69,15 +359,83 @@ pub struct Account {
     pub email: String,
     pub password: Option<String>,
     pub profile: Json<Profile>,
-    pub plan: i32,
+    pub plan: Plan,
+    pub is_active: bool,
+    pub is_admin: bool,
+    pub has_verified_email: bool,
+    #[serde(with = "rfc3339::option")]
+    pub last_login: Option<DateTime<Utc>>,
+    #[serde(with = "rfc3339::option")]
+    pub plan_expires_at: Option<DateTime<Utc>>,
+    pub session_version: i32,
+    pub email_delivery_status: EmailDeliveryStatus,
+    /// The language to email the account in; `None` for the default.
+    pub locale: Option<String>,
+    #[serde(with = "rfc3339")]
+    pub created: DateTime<Utc>,
+    #[serde(with = "rfc3339")]
+    pub updated: DateTime<Utc>,
+}
+
+/// Everything stored about an account, for its owner to download. The
+/// password hash, session version, OAuth tokens and passkeys' public
+/// keys are left out.
+#[derive(Debug, Serialize)]
+pub struct AccountExport {
+    #[serde(with = "rfc3339")]
+    pub exported_at: DateTime<Utc>,
+    pub account: AccountData,
+    pub identities: Vec<Identity>,
+    pub passkeys: Vec<WebauthnCredential>,
+    pub logins: Vec<LoginEvent>,
+}
+
+/// The fields of an `Account` included in an export.
+#[derive(Debug, Serialize)]
+pub struct AccountData {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub has_password: bool,
+    pub profile: Profile,
+    pub plan: Plan,
     pub is_active: bool,
     pub is_admin: bool,
     pub has_verified_email: bool,
+    #[serde(with = "rfc3339::option")]
     pub last_login: Option<DateTime<Utc>>,
+    #[serde(with = "rfc3339::option")]
+    pub plan_expires_at: Option<DateTime<Utc>>,
+    pub email_delivery_status: EmailDeliveryStatus,
+    pub locale: Option<String>,
+    #[serde(with = "rfc3339")]
     pub created: DateTime<Utc>,
+    #[serde(with = "rfc3339")]
     pub updated: DateTime<Utc>,
 }
 
+impl From<Account> for AccountData {
+    fn from(account: Account) -> Self {
+        AccountData {
+            id: account.id,
+            name: account.name,
+            email: account.email,
+            has_password: account.password.is_some(),
+            profile: account.profile.0,
+            plan: account.plan,
+            is_active: account.is_active,
+            is_admin: account.is_admin,
+            has_verified_email: account.has_verified_email,
+            last_login: account.last_login,
+            plan_expires_at: account.plan_expires_at,
+            email_delivery_status: account.email_delivery_status,
+            locale: account.locale,
+            created: account.created,
+            updated: account.updated,
+        }
+    }
+}
+
 impl crate::token::OneTimeUseTokenGenerator for Account {
     fn hash_value(&self) -> String {
         format!(
@@ -93,34 +451,183 @@ impl crate::token::OneTimeUseTokenGenerator for Account {
     }
 }
 
+/// A change of an account's email to `new_email`, waiting on the link
+/// sent to the new address. The token covers the new address, so the
+/// link can't be used for another, and the current one, so it stops
+/// working once the change is made.
+pub struct EmailChange<'a> {
+    pub account: &'a Account,
+    pub new_email: &'a str,
+}
+
+impl OneTimeUseTokenGenerator for EmailChange<'_> {
+    fn hash_value(&self) -> String {
+        format!("{}{}", self.account.hash_value(), normalize_email(self.new_email))
+    }
+}
+
+/// What a soft deleted account's email is replaced with: unique, and at
+/// a reserved domain no mail can go to.
+pub fn deleted_email(id: i32) -> String {
+    format!("deleted-{}@deleted.invalid", id)
+}
+
+/// The account id in a verify or reset-password URL, if it decodes.
+fn token_account_id(token: &UserToken) -> Option<i32> {
+    token.uidb64.as_ref()
+        .and_then(|uidb64| base64_url::decode(uidb64).ok())
+        .and_then(|uid_bytes| String::from_utf8(uid_bytes).ok())
+        .and_then(|uid_str| uid_str.parse::<i32>().ok())
+}
+
+/// The sealed values an account is looked up by email with: its canonical
+/// form under `aliases`, `accounts.email_aliases`, which matches any alias
+/// of the address, and the address normalized, which matches accounts
+/// registered under another policy or without a canonical email. Queries
+/// prefer an exact match.
+fn email_lookup(email: &str, aliases: EmailAliasPolicy) -> (String, String) {
+    (
+        pii::seal_email(&aliases.canonicalize(email)),
+        pii::seal_email(&normalize_email(email)),
+    )
+}
+
 impl Account {
-        /// Decodes the pieces used in verify and reset-password URL structures,
-        /// and validates them. If they're valid, it will return the Account in
-        /// question - if not, it will raise a generic error.
-        ///
-        /// Flows should silence this error and display a generic message to
-        /// the user to avoid leaking information.
+    /// Decrypts the personal fields of a row as loaded; see `pii`.
+    fn decrypt(mut self) -> error::Result<Self> {
+        self.name = pii::open(&self.name)?;
+        self.email = pii::open(&self.email)?;
+        Ok(self)
+    }
+
+    pub fn session_fingerprint(&self) -> String {
+        session_fingerprint(self.id, self.password.as_deref())
+    }
+
+    /// What sessions for this account should currently carry, or None if
+    /// the account is gone or inactive.
+    pub async fn current_session(id: i32, conn: &mut sqlx::PgConnection) -> error::Result<Option<SessionState>> {
+        Ok(sqlx::query!(
+            "
+            SELECT password, session_version, must_change_password
+            FROM accounts WHERE id = $1 AND is_active AND deleted_at IS NULL
+        ",
+            id
+        )
+        .fetch_optional(conn)
+        .await?
+        .map(|r| SessionState {
+            fingerprint: session_fingerprint(id, r.password.as_deref()),
+            session_version: r.session_version,
+            must_change_password: r.must_change_password,
+        }))
+    }
+
+    /// Deactivates or reactivates an account. Inactive accounts can't log
+    /// in, and have no `current_session`, so the `User` guard ends their
+    /// sessions. Fails with `NotFound` if there's no such account.
+    pub async fn set_active(id: i32, active: bool, conn: &mut sqlx::PgConnection) -> error::Result<()> {
+        let result = sqlx::query!(
+            "
+            UPDATE accounts
+            SET is_active = $2,
+                session_version = session_version + CASE WHEN $2 THEN 0 ELSE 1 END
+            WHERE id = $1 AND deleted_at IS NULL
+        ",
+            id,
+            active
+        )
+        .execute(conn)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(error::Error::with_status(anyhow!("no account {}", id), Status::NotFound));
+        }
+        Ok(())
+    }
+
+    /// Invalidates every existing session for the account. Returns the
+    /// new version, for the session that asked for it.
+    pub async fn bump_session_version(id: i32, conn: &mut sqlx::PgConnection) -> error::Result<i32> {
+        Ok(sqlx::query!(
+            "
+            UPDATE accounts
+            SET session_version = session_version + 1
+            WHERE id = $1
+            RETURNING session_version
+        ",
+            id
+        )
+        .fetch_one(conn)
+        .await?
+        .session_version)
+    }
+
+    /// Decodes the pieces used in verify and reset-password URL structures,
+    /// and validates them. If they're valid, it will return the Account in
+    /// question - if not, it will raise a generic error.
+    ///
+    /// Flows should silence this error and display a generic message to
+    /// the user to avoid leaking information. Tokens for accounts that
+    /// don't exist are checked against a dummy value, so that they take
+    /// as long to refuse as a wrong token for a real account; see
+    /// `OneTimeUseTokenGenerator::is_token_valid`. If the account can't
+    /// be loaded, the error is an `InternalServerError` instead, which
+    /// flows should report as one.
     pub async fn validate_token(
         token: &UserToken,
         conn: &mut sqlx::PgConnection,
     ) -> error::Result<Self> {
-        if let Some(uidb64) = &token.uidb64 {
-            if let Ok(uid_bytes) = base64_url::decode(&uidb64) {
-                if let Ok(uid_str) = std::str::from_utf8(&uid_bytes) {
-                    if let Ok(uid) = uid_str.parse::<i32>() {
-                        if let Ok(account) = Self::get(uid, conn).await {
-                            if account.is_token_valid(&token.as_anonymous_string()) {
-                                return Ok(account);
-                            }
-                        }
-                    }
-                }
-            }
+        let account = Self::token_account(token, conn).await?;
+
+        let anonymous_token = token.as_anonymous_string();
+        match account {
+            Some(account) if account.is_token_valid(&anonymous_token) => return Ok(account),
+            Some(_) => {},
+            None => {
+                crate::token::dummy_check(&anonymous_token);
+            },
         }
 
         Err(error::Error::with_status(anyhow!("invalid token"), Status::BadRequest))
     }
 
+    /// As `validate_token`, for a link confirming a change of the
+    /// account's email to `new_email`; see `EmailChange`.
+    pub async fn validate_email_change_token(
+        token: &UserToken,
+        new_email: &str,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<Self> {
+        let account = Self::token_account(token, conn).await?;
+
+        let anonymous_token = token.as_anonymous_string();
+        match account {
+            Some(account) if (EmailChange { account: &account, new_email }).is_token_valid(&anonymous_token) =>
+                return Ok(account),
+            Some(_) => {},
+            None => {
+                crate::token::dummy_check(&anonymous_token);
+            },
+        }
+
+        Err(error::Error::with_status(anyhow!("invalid token"), Status::BadRequest))
+    }
+
+    /// The account `token` names, if there is one.
+    async fn token_account(token: &UserToken, conn: &mut sqlx::PgConnection) -> error::Result<Option<Self>> {
+        let uid = match token_account_id(token) {
+            Some(uid) => uid,
+            None => return Ok(None),
+        };
+
+        match Self::get(uid, conn).await {
+            Ok(account) => Ok(Some(account)),
+            Err(e) if matches!(e.error.downcast_ref::<sqlx::Error>(), Some(sqlx::Error::RowNotFound)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
     pub async fn count(conn: &mut sqlx::PgConnection) -> error::Result<i64> {
         Ok(sqlx::query!(
             "
@@ -135,113 +642,336 @@ impl Account {
         .unwrap())
     }
 
+    /// A page of accounts, oldest first.
+    pub async fn list(offset: i64, limit: i64, conn: &mut sqlx::PgConnection) -> error::Result<Vec<Self>> {
+        sqlx::query_as_unchecked!(
+            Account,
+            "
+            SELECT
+                id, name, email, password, profile, plan,
+                is_active, is_admin, has_verified_email,
+                last_login, plan_expires_at, session_version, email_delivery_status, locale, created, updated
+            FROM accounts
+            ORDER BY created, id
+            OFFSET $1 LIMIT $2
+        ",
+            offset,
+            limit
+        )
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(Account::decrypt)
+        .collect()
+    }
+
     pub async fn get(id: i32, conn: &mut sqlx::PgConnection) -> error::Result<Self> {
-        Ok(sqlx::query_as_unchecked!(
+        sqlx::query_as_unchecked!(
             Account,
             "
             SELECT
                 id, name, email, password, profile, plan,
                 is_active, is_admin, has_verified_email,
-                last_login, created, updated
+                last_login, plan_expires_at, session_version, email_delivery_status, locale, created, updated
             FROM accounts WHERE id = $1
         ",
             id
         )
         .fetch_one(conn)
-        .await?)
+        .await?
+        .decrypt()
     }
 
-    pub async fn get_by_email(email: &str, conn: &mut sqlx::PgConnection) -> error::Result<Self> {
-        Ok(sqlx::query_as_unchecked!(
+    pub async fn get_by_email(email: &str, aliases: EmailAliasPolicy, conn: &mut sqlx::PgConnection) -> error::Result<Self> {
+        let (canonical, normalized) = email_lookup(email, aliases);
+        sqlx::query_as_unchecked!(
             Account,
             "
             SELECT
                 id, name, email, password, profile, plan,
                 is_active, is_admin, has_verified_email,
-                last_login, created, updated
-            FROM accounts WHERE email = $1
+                last_login, plan_expires_at, session_version, email_delivery_status, locale, created, updated
+            FROM accounts WHERE (canonical_email = $1 OR email = $2) AND deleted_at IS NULL
+            ORDER BY email = $2 DESC
+            LIMIT 1
         ",
-            email
+            canonical,
+            normalized
         )
         .fetch_one(conn)
-        .await?)
+        .await?
+        .decrypt()
     }
 
-    pub async fn id_by_email(email: &str, conn: &mut sqlx::PgConnection) -> error::Result<i32> {
+    pub async fn id_by_email(email: &str, aliases: EmailAliasPolicy, conn: &mut sqlx::PgConnection) -> error::Result<i32> {
+        let (canonical, normalized) = email_lookup(email, aliases);
         Ok(sqlx::query!(
             "
             SELECT id
-            FROM accounts WHERE email = $1
+            FROM accounts WHERE (canonical_email = $1 OR email = $2)
+            ORDER BY email = $2 DESC
+            LIMIT 1
         ",
-            email
+            canonical,
+            normalized
         )
         .fetch_one(conn)
         .await?
         .id)
     }
 
-    pub async fn authenticate(form: &LoginData<'_>, conn: &mut sqlx::PgConnection) -> error::Result<User> {
+    /// Checks a login. An unknown email, a wrong password and an inactive
+    /// account all fail with `Unauthorized`. With `require_verified_email`,
+    /// accounts that haven't verified their email fail with `Forbidden`,
+    /// once the password has checked out. Any other status is a problem
+    /// on our side, such as a corrupt password hash.
+    pub async fn authenticate(
+        form: &LoginData<'_>,
+        require_verified_email: bool,
+        aliases: EmailAliasPolicy,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<User> {
+        let (canonical, normalized) = email_lookup(form.email, aliases);
         let user = sqlx::query_as_unchecked!(
             UserPass,
             "
             SELECT
-                id, name, password, is_admin
-            FROM accounts WHERE email = $1
+                id, name, password, is_active, is_admin, has_verified_email, session_version
+            FROM accounts WHERE (canonical_email = $1 OR email = $2) AND deleted_at IS NULL
+            ORDER BY email = $2 DESC
+            LIMIT 1
         ",
-            form.email
+            canonical,
+            normalized
         )
-        .fetch_one(conn)
+        .fetch_optional(&mut *conn)
         .await?;
 
-        user.check_password(form.password)?;
+        let mut user = match user {
+            Some(user) => user,
+            None => {
+                dummy_check_password(form.password);
+                return Err(error::Error::with_status(anyhow!("no such account"), Status::Unauthorized));
+            }
+        };
+
+        if !user.check_password(form.password)? {
+            return Err(error::Error::with_status(anyhow!("password invalid"), Status::Unauthorized));
+        }
+
+        // Checked after the password, so that failing it doesn't tell
+        // anyone who lacks the password that the account exists.
+        if !user.is_active {
+            return Err(error::Error::with_status(anyhow!("account {} is inactive", user.id), Status::Unauthorized));
+        }
+
+        if require_verified_email && !user.has_verified_email {
+            return Err(error::Error::with_status(anyhow!("account {} has not verified its email", user.id), Status::Forbidden));
+        }
+
+        // Move legacy hashes over to the current scheme while we have the
+        // password. This changes the session fingerprint, so other sessions
+        // for the account end, once.
+        if user.password.as_deref().map_or(false, passwords::needs_rehash) {
+            match Account::rehash_password(user.id, form.password, conn).await {
+                Ok(encoded) => user.password = Some(encoded),
+                Err(e) => rocket::error!("could not rehash password for account {}: {:?}", user.id, e),
+            }
+        }
 
         Ok(User {
             id: user.id,
-            name: user.name,
+            fingerprint: session_fingerprint(user.id, user.password.as_deref()),
+            session_version: user.session_version,
+            name: pii::open(&user.name)?,
             is_admin: user.is_admin,
             is_anonymous: false,
         })
     }
 
-    pub async fn fetch_name_from_email(email: &str, conn: &mut sqlx::PgConnection) -> error::Result<String> {
+    pub async fn fetch_name_from_email(
+        email: &str,
+        aliases: EmailAliasPolicy,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<String> {
+        let (canonical, normalized) = email_lookup(email, aliases);
         let data = sqlx::query!(
             "
-            SELECT name FROM accounts WHERE email = $1
+            SELECT name FROM accounts WHERE (canonical_email = $1 OR email = $2) AND deleted_at IS NULL
+            ORDER BY email = $2 DESC
+            LIMIT 1
         ",
-            email
+            canonical,
+            normalized
         )
         .fetch_one(conn)
         .await?;
 
-        Ok(data.name)
+        pii::open(&data.name)
+    }
+
+    /// The locale to email an address in, if it belongs to an account
+    /// that has one.
+    pub async fn fetch_locale_from_email(
+        email: &str,
+        aliases: EmailAliasPolicy,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<Option<String>> {
+        let (canonical, normalized) = email_lookup(email, aliases);
+        let data = sqlx::query!(
+            "
+            SELECT locale FROM accounts WHERE (canonical_email = $1 OR email = $2) AND deleted_at IS NULL
+            ORDER BY email = $2 DESC
+            LIMIT 1
+        ",
+            canonical,
+            normalized
+        )
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(data.and_then(|data| data.locale))
     }
 
     // pub async fn register(form: &NewAccount, mut db: AppDbConnection) -> error::Result<i32> {
-    pub async fn register<'a>(account: &NewAccount<'a>, conn: &mut sqlx::PgConnection) -> error::Result<String> {
+    /// Registration fails if another account has the same canonical email
+    /// under `aliases`, even if the address as entered differs. The email
+    /// is stored normalized; see `config::normalize_email`.
+    pub async fn register<'a>(
+        account: &NewAccount<'a>,
+        aliases: EmailAliasPolicy,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<String> {
         // TODO 101: return InvalidPassword if password is empty
-        let password = hasher::make_password(account.password);
+        let password = passwords::hash(account.password)?;
 
-        Ok(sqlx::query!(
+        let email = sqlx::query!(
             "
-            INSERT INTO accounts (name, email, password)
-            VALUES ($1, $2, $3)
+            INSERT INTO accounts (name, email, canonical_email, password)
+            VALUES ($1, $2, $3, $4)
             RETURNING email
         ",
-            account.name,
-            account.email,
+            pii::seal(account.name),
+            pii::seal_email(&normalize_email(account.email)),
+            pii::seal_email(&aliases.canonicalize(account.email)),
             password
         )
         .fetch_one(conn)
         .await?
-        .email)
+        .email;
+
+        pii::open(&email)
+    }
+
+    /// As `register`, using up the invitation `token` for the account's
+    /// email in the same transaction. Fails with `Forbidden`, registering
+    /// nothing, if the invitation isn't valid.
+    pub async fn register_invited<'a>(
+        account: &NewAccount<'a>,
+        token: &str,
+        aliases: EmailAliasPolicy,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<String> {
+        let mut tx = conn.begin().await?;
+        Invitation::consume(token, account.email, &mut tx).await?;
+        let email = Account::register(account, aliases, &mut tx).await?;
+        tx.commit().await?;
+        Ok(email)
     }
 
+    /// Changes the account's email to a confirmed `new_email`, stored as
+    /// `register` stores it and counted as verified. Fails if another
+    /// account has it under `aliases`, or `NotFound` if the account is
+    /// gone. Returns the previous address.
+    pub async fn change_email(
+        id: i32,
+        new_email: &str,
+        aliases: EmailAliasPolicy,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<String> {
+        let mut tx = conn.begin().await?;
+
+        let previous = sqlx::query!(
+            "
+            SELECT email FROM accounts WHERE id = $1 AND deleted_at IS NULL
+            FOR UPDATE
+        ",
+            id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or_else(|| error::Error::with_status(anyhow!("no account {}", id), Status::NotFound))?
+        .email;
+
+        sqlx::query!(
+            "
+            UPDATE accounts
+            SET email = $2, canonical_email = $3, has_verified_email = true, email_delivery_status = $4
+            WHERE id = $1
+        ",
+            id,
+            pii::seal_email(&normalize_email(new_email)),
+            pii::seal_email(&aliases.canonicalize(new_email)),
+            EmailDeliveryStatus::Queued as i32
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        pii::open(&previous)
+    }
+
+    /// Checks the password of a logged in user, for changes that need it.
+    /// Fails with `Unauthorized` if it's wrong, or if the account has no
+    /// password.
+    pub async fn check_password(id: i32, password: &str, conn: &mut sqlx::PgConnection) -> error::Result<()> {
+        let user = sqlx::query_as_unchecked!(
+            UserPass,
+            "
+            SELECT
+                id, name, password, is_active, is_admin, has_verified_email, session_version
+            FROM accounts WHERE id = $1 AND deleted_at IS NULL
+        ",
+            id
+        )
+        .fetch_optional(conn)
+        .await?
+        .ok_or_else(|| error::Error::with_status(anyhow!("no account {}", id), Status::NotFound))?;
+
+        if !user.check_password(password)? {
+            return Err(error::Error::with_status(anyhow!("password invalid"), Status::Unauthorized));
+        }
+        Ok(())
+    }
+
+    /// Replaces the stored hash of an unchanged password, returning the new
+    /// hash.
+    async fn rehash_password(id: i32, password: &str, conn: &mut sqlx::PgConnection) -> error::Result<String> {
+        let encoded = passwords::hash(password)?;
+
+        sqlx::query!(
+            "
+            UPDATE accounts
+            SET password = $2
+            WHERE id = $1
+        ",
+            id,
+            encoded
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(encoded)
+    }
+
+    /// Marks the account verified. Already verified accounts are left
+    /// alone, so that an old link can't be used to touch `last_login`.
     pub async fn mark_verified(id: i32, conn: &mut sqlx::PgConnection) -> error::Result<()> {
         sqlx::query!(
             "
             UPDATE accounts
             SET has_verified_email = true, last_login = now()
-            WHERE id = $1
+            WHERE id = $1 AND NOT has_verified_email
         ",
             id
         )
@@ -251,6 +981,36 @@ impl Account {
         Ok(())
     }
 
+    /// Records what happened to the last email sent to `email`, on the
+    /// account it would be looked up by.
+    pub async fn set_email_delivery_status(
+        email: &str,
+        status: EmailDeliveryStatus,
+        aliases: EmailAliasPolicy,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<()> {
+        let (canonical, normalized) = email_lookup(email, aliases);
+        sqlx::query!(
+            "
+            UPDATE accounts
+            SET email_delivery_status = $3
+            WHERE id = (
+                SELECT id FROM accounts
+                WHERE (canonical_email = $1 OR email = $2) AND deleted_at IS NULL
+                ORDER BY email = $2 DESC
+                LIMIT 1
+            )
+        ",
+            canonical,
+            normalized,
+            status as i32
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn update_last_login(id: i32, conn: &mut sqlx::PgConnection) -> error::Result<()> {
         sqlx::query!(
             "
@@ -266,18 +1026,103 @@ impl Account {
         Ok(())
     }
 
+    /// Adds a login to the account's audit log.
+    pub async fn record_login(
+        id: i32,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<()> {
+        sqlx::query!(
+            "
+            INSERT INTO login_events (account_id, ip, user_agent)
+            VALUES ($1, $2, $3)
+        ",
+            id,
+            ip,
+            user_agent
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The account's latest `limit` logins, newest first.
+    pub async fn recent_logins(id: i32, limit: i64, conn: &mut sqlx::PgConnection) -> error::Result<Vec<LoginEvent>> {
+        Ok(sqlx::query_as!(
+            LoginEvent,
+            "
+            SELECT ip, user_agent, created FROM login_events
+            WHERE account_id = $1
+            ORDER BY created DESC
+            LIMIT $2
+        ",
+            id,
+            limit
+        )
+        .fetch_all(conn)
+        .await?)
+    }
+
+    /// Gathers the account, its linked identities and passkeys, and its
+    /// whole login history for the owner to download.
+    pub async fn export_data(id: i32, conn: &mut sqlx::PgConnection) -> error::Result<AccountExport> {
+        let account = Account::get(id, conn).await?;
+
+        let identities = sqlx::query_as_unchecked!(
+            Identity,
+            "
+            SELECT
+                id, account_id, provider, username, name,
+                refresh_token, access_token, access_token_expires_at, created, updated
+            FROM identities WHERE account_id = $1
+            ORDER BY created
+        ",
+            id
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(Identity::decrypt)
+        .collect::<error::Result<Vec<_>>>()?;
+
+        let passkeys = WebauthnCredential::for_account(id, conn).await?;
+
+        let logins = sqlx::query_as!(
+            LoginEvent,
+            "
+            SELECT ip, user_agent, created FROM login_events
+            WHERE account_id = $1
+            ORDER BY created DESC
+        ",
+            id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        Ok(AccountExport {
+            exported_at: Utc::now(),
+            account: account.into(),
+            identities,
+            passkeys,
+            logins,
+        })
+    }
+
     pub async fn update_password_and_last_login(
         id: i32,
         password: &str,
         conn: &mut sqlx::PgConnection,
     ) -> error::Result<()> {
         // TODO 101: return InvalidPassword if password is empty
-        let password = hasher::make_password(password);
+        let password = passwords::hash(password)?;
 
         sqlx::query!(
             "
             UPDATE accounts
-            SET password = $2, last_login = now()
+            SET password = $2, last_login = now(),
+                password_changed_at = now(), must_change_password = false
             WHERE id = $1
         ",
             id,
@@ -289,26 +1134,348 @@ impl Account {
         Ok(())
     }
 
+    /// Changes the password of a logged in user, who has to give the
+    /// current one. Fails with `Unauthorized` if it's wrong, or if the
+    /// account has no password, as with ones registered through an OAuth
+    /// provider; those can set one with the reset flow.
+    ///
+    /// The new password changes the session fingerprint, so the `User`
+    /// guard ends other sessions.
+    pub async fn change_password(
+        id: i32,
+        current: &str,
+        new: &str,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<()> {
+        let mut tx = conn.begin().await?;
+
+        let user = sqlx::query_as_unchecked!(
+            UserPass,
+            "
+            SELECT
+                id, name, password, is_active, is_admin, has_verified_email, session_version
+            FROM accounts WHERE id = $1 AND deleted_at IS NULL
+            FOR UPDATE
+        ",
+            id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or_else(|| error::Error::with_status(anyhow!("no account {}", id), Status::NotFound))?;
+
+        if !user.check_password(current)? {
+            return Err(error::Error::with_status(anyhow!("password invalid"), Status::Unauthorized));
+        }
+
+        let password = passwords::hash(new)?;
+        sqlx::query!(
+            "
+            UPDATE accounts
+            SET password = $2, password_changed_at = now(), must_change_password = false
+            WHERE id = $1
+        ",
+            id,
+            password
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Updates the user-editable parts of an account.
+    pub async fn update_profile(
+        id: i32,
+        name: &str,
+        profile: &Profile,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<()> {
+        let profile = serde_json::to_value(profile)?;
+
+        sqlx::query!(
+            "
+            UPDATE accounts
+            SET name = $2, profile = $3
+            WHERE id = $1
+        ",
+            id,
+            pii::seal(name),
+            profile
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Sets one profile field, or clears it with `None`, leaving the
+    /// others as they are. The profile is read, upgraded to the current
+    /// version and written back in one transaction, with the row locked,
+    /// so concurrent updates to other fields aren't lost.
+    pub async fn update_profile_field(
+        id: i32,
+        field: ProfileField,
+        value: Option<String>,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<Profile> {
+        let mut tx = conn.begin().await?;
+
+        let row = sqlx::query!(
+            "
+            SELECT profile FROM accounts WHERE id = $1 AND deleted_at IS NULL FOR UPDATE
+        ",
+            id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or_else(|| error::Error::with_status(anyhow!("no account {}", id), Status::NotFound))?;
+
+        let mut profile: Profile = serde_json::from_value(row.profile)?;
+        profile.set(field, value);
+
+        sqlx::query!(
+            "
+            UPDATE accounts SET profile = $2 WHERE id = $1
+        ",
+            id,
+            serde_json::to_value(&profile)?
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(profile)
+    }
+
+    pub async fn set_plan(id: i32, plan: Plan, conn: &mut sqlx::PgConnection) -> error::Result<()> {
+        sqlx::query!(
+            "
+            UPDATE accounts
+            SET plan = $2
+            WHERE id = $1
+        ",
+            id,
+            plan as i32
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Schedules the account's plan to be downgraded to `Plan::Free` at `at`,
+    /// e.g. at the end of a cancelled subscription's billing period.
+    pub async fn schedule_downgrade(
+        id: i32,
+        at: DateTime<Utc>,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<()> {
+        sqlx::query!(
+            "
+            UPDATE accounts
+            SET plan_expires_at = $2
+            WHERE id = $1
+        ",
+            id,
+            at
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Downgrades every account whose `plan_expires_at` has passed,
+    /// returning the number of accounts that were downgraded.
+    pub async fn downgrade_expired_plans(conn: &mut sqlx::PgConnection) -> error::Result<u64> {
+        Ok(sqlx::query!(
+            "
+            UPDATE accounts
+            SET plan = $1, plan_expires_at = NULL
+            WHERE plan_expires_at <= now()
+        ",
+            Plan::Free as i32
+        )
+        .execute(conn)
+        .await?
+        .rows_affected())
+    }
+
+    /// Accounts registered with a password more than `older_than_days`
+    /// ago that have neither verified their email nor ever logged in, for
+    /// the purge job to delete. Accounts without a password registered
+    /// through an OAuth provider, which never sets the flag, or were soft
+    /// deleted; accounts that have logged in are in use, verified or not.
+    pub async fn never_verified(older_than_days: i32, conn: &mut sqlx::PgConnection) -> error::Result<Vec<i32>> {
+        Ok(sqlx::query!(
+            "
+            SELECT id FROM accounts
+            WHERE NOT has_verified_email AND last_login IS NULL
+                AND password IS NOT NULL AND deleted_at IS NULL
+                AND created < now() - make_interval(days => $1)
+            ORDER BY id
+        ",
+            older_than_days
+        )
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect())
+    }
+
+    /// Marks accounts whose password is more than `max_age_days` old, and
+    /// whose owners haven't been reminded since it was set, as reminded.
+    /// With `enforce`, they must also change it before logging in again.
+    /// Returns their emails.
+    pub async fn remind_expired_passwords(
+        max_age_days: i32,
+        enforce: bool,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<Vec<String>> {
+        sqlx::query!(
+            "
+            UPDATE accounts
+            SET password_expiry_reminded_at = now(),
+                must_change_password = must_change_password OR $2
+            WHERE password IS NOT NULL AND is_active AND deleted_at IS NULL
+                AND password_changed_at <= now() - make_interval(days => $1)
+                AND (password_expiry_reminded_at IS NULL
+                    OR password_expiry_reminded_at < password_changed_at)
+            RETURNING email
+        ",
+            max_age_days,
+            enforce
+        )
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|row| pii::open(&row.email))
+        .collect()
+    }
+
+    /// Whether the account must change its password before logging in.
+    pub async fn must_change_password(id: i32, conn: &mut sqlx::PgConnection) -> error::Result<bool> {
+        Ok(sqlx::query!(
+            "
+            SELECT must_change_password FROM accounts WHERE id = $1
+        ",
+            id
+        )
+        .fetch_one(conn)
+        .await?
+        .must_change_password)
+    }
+
+    /// Deletes an account along with its linked identities and any queued
+    /// jobs addressed to it. With `soft` set, the account row is kept but
+    /// marked with `deleted_at`, deactivated and stripped of its password,
+    /// so that it can no longer log in. Its email is replaced with a
+    /// tombstone address, see `deleted_email`, so the address can register
+    /// again. The identities' provider tokens are revoked once the
+    /// deletion is committed.
+    pub async fn delete(
+        id: i32,
+        soft: bool,
+        providers: &OAuthProviders,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<()> {
+        let mut tx = conn.begin().await?;
+
+        let email = pii::open(&sqlx::query!(
+            "
+            SELECT email FROM accounts WHERE id = $1
+        ",
+            id
+        )
+        .fetch_one(&mut tx)
+        .await?
+        .email)?;
+
+        let identities = sqlx::query!(
+            "
+            DELETE FROM identities WHERE account_id = $1
+            RETURNING provider, refresh_token, access_token
+        ",
+            id
+        )
+        .fetch_all(&mut tx)
+        .await?;
+
+        if soft {
+            sqlx::query!(
+                "
+                UPDATE accounts
+                SET deleted_at = now(), is_active = false, password = NULL,
+                    email = $2, canonical_email = NULL
+                WHERE id = $1
+            ",
+                id,
+                deleted_email(id)
+            )
+            .execute(&mut tx)
+            .await?;
+        } else {
+            sqlx::query!(
+                "
+                DELETE FROM accounts WHERE id = $1
+            ",
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        // Jobs reference accounts by email, e.g. `{"SendWelcomeAccountEmail": "..."}`.
+        // Only jobs that are still queued are removed.
+        sqlx::query!(
+            "
+            DELETE FROM queue
+            WHERE status = 0
+            AND jsonb_path_exists(message, '$.* ? (@ == $email)', jsonb_build_object('email', $1::text))
+        ",
+            email
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        for identity in identities {
+            Identity::revoke_tokens(providers, &identity.provider, identity.refresh_token, identity.access_token).await;
+        }
+
+        Ok(())
+    }
+
+    /// Without `allow_registration`, an identity that is neither linked
+    /// nor being linked to the current account fails with `Forbidden`
+    /// instead of registering a new account.
     pub async fn merge_identity_and_login(
         form: LinkIdentityData,
-        refresh_token: Option<String>,
+        tokens: Option<ProviderTokens>,
         current_account_id: Option<i32>,
+        allow_registration: bool,
+        aliases: EmailAliasPolicy,
         conn: &mut sqlx::PgConnection,
     ) -> error::Result<User> {
         let transaction = conn.begin().await?;
-        handle_merge(form, refresh_token, current_account_id, transaction).await
+        handle_merge(form, tokens, current_account_id, allow_registration, aliases, transaction).await
     }
 }
 
 async fn handle_merge(form: LinkIdentityData,
-    refresh_token: Option<String>,
+    tokens: Option<ProviderTokens>,
     current_account_id: Option<i32>,
+    allow_registration: bool,
+    aliases: EmailAliasPolicy,
     mut tx: PgTransaction<'_>) ->  error::Result<User> {
     let linked_account_id = sqlx::query!(
         "
         SELECT account_id
         FROM identities
-        WHERE provider = $1 AND username = $2
+        WHERE provider = $1 AND lower(username) = lower($2)
     ",
         form.provider,
         form.username,
@@ -317,18 +1484,67 @@ async fn handle_merge(form: LinkIdentityData,
     .await?
     .map(|r| r.account_id);
 
+    if linked_account_id.is_some() {
+        refresh_identity(&form, tokens.as_ref(), &mut tx).await?;
+    }
+
     match (linked_account_id, current_account_id) {
         (Some(linked_id), None) =>
             login_with_linked_account(linked_id, tx).await,
+        (None, None) if !allow_registration =>
+            Err(error::Error::with_status(anyhow!("registration is not allowed for this identity"), Status::Forbidden)),
         (None, None) =>
-            register_oauth_user(form, refresh_token, tx).await,
+            register_oauth_user(form, tokens, aliases, tx).await,
         (Some(linked_id), Some(account_id)) =>
             merge_linked_account(account_id, linked_id, form, tx).await,
         (None, Some(account_id)) =>
-            link_additional_identity(account_id, form, refresh_token, tx).await,
+            link_additional_identity(account_id, form, tokens, tx).await,
     }
 }
 
+/// Provider display names (and the casing of usernames) can change over
+/// time, so the stored identity is brought up to date on every login,
+/// along with its tokens.
+async fn refresh_identity(
+    form: &LinkIdentityData,
+    tokens: Option<&ProviderTokens>,
+    conn: &mut sqlx::PgConnection,
+) -> error::Result<()> {
+    sqlx::query!(
+        "
+        UPDATE identities
+        SET name = $3, username = $2
+        WHERE provider = $1 AND lower(username) = lower($2)
+            AND (name IS DISTINCT FROM $3 OR username <> $2)
+    ",
+        form.provider,
+        form.username,
+        form.name,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    if let Some(tokens) = tokens {
+        sqlx::query!(
+            "
+            UPDATE identities
+            SET access_token = $3, access_token_expires_at = $4,
+                refresh_token = COALESCE($5, refresh_token)
+            WHERE provider = $1 AND lower(username) = lower($2)
+        ",
+            form.provider,
+            form.username,
+            pii::seal(&tokens.access_token),
+            tokens.expires_at,
+            tokens.refresh_token.as_deref().map(pii::seal),
+        )
+        .execute(conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
 async fn login_with_linked_account(linked_id: i32, mut tx: PgTransaction<'_>) -> error::Result<User> {
     // The account is linked to a local account and
     //    no session cookie is present --> Login
@@ -341,54 +1557,72 @@ async fn login_with_linked_account(linked_id: i32, mut tx: PgTransaction<'_>) ->
         RETURNING
             id, name, email, password, profile, plan,
             is_active, is_admin, has_verified_email,
-            last_login, created, updated
+            last_login, plan_expires_at, session_version, email_delivery_status, locale, created, updated
     ",
         linked_id
     )
     .fetch_one(&mut tx)
-    .await?;
+    .await?
+    .decrypt()?;
+
+    // Dropping the transaction leaves last_login as it was.
+    if !user.is_active {
+        return Err(error::Error::with_status(anyhow!("account {} is inactive", user.id), Status::Unauthorized));
+    }
 
     tx.commit().await?;
 
     Ok(User {
         id: user.id,
+        fingerprint: user.session_fingerprint(),
+        session_version: user.session_version,
         name: user.name,
         is_admin: user.is_admin,
         is_anonymous: false,
     })
 }
 
-async fn register_oauth_user(form: LinkIdentityData, refresh_token: Option<String>, mut tx: PgTransaction<'_>) -> error::Result<User> {
+async fn register_oauth_user(
+    form: LinkIdentityData,
+    tokens: Option<ProviderTokens>,
+    aliases: EmailAliasPolicy,
+    mut tx: PgTransaction<'_>,
+) -> error::Result<User> {
     // The account is not linked to a local account and
     //    no session cookie is present --> Register
     let user = sqlx::query_as_unchecked!(
         Account,
         "
-        INSERT INTO accounts (name, email, password, last_login)
-        VALUES ($1, $2, $3, now())
+        INSERT INTO accounts (name, email, canonical_email, password, last_login)
+        VALUES ($1, $2, $3, $4, now())
         RETURNING
             id, name, email, password, profile, plan,
             is_active, is_admin, has_verified_email,
-            last_login, created, updated
+            last_login, plan_expires_at, session_version, email_delivery_status, locale, created, updated
     ",
-        form.name,
-        form.email,
+        pii::seal(&form.name),
+        pii::seal_email(&normalize_email(&form.email)),
+        pii::seal_email(&aliases.canonicalize(&form.email)),
         None as Option<String>,
     )
     .fetch_one(&mut tx)
-    .await?;
+    .await?
+    .decrypt()?;
 
     let _identity_id = sqlx::query!(
         "
-        INSERT INTO identities (account_id, provider, username, name, refresh_token)
-        VALUES ($1, $2, $3, $4, $5)
+        INSERT INTO identities
+            (account_id, provider, username, name, refresh_token, access_token, access_token_expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
         RETURNING id
     ",
         user.id,
         form.provider,
         form.username,
         form.name,
-        refresh_token,
+        tokens.as_ref().and_then(|tokens| tokens.refresh_token.as_deref()).map(pii::seal),
+        tokens.as_ref().map(|tokens| pii::seal(&tokens.access_token)),
+        tokens.as_ref().and_then(|tokens| tokens.expires_at),
     )
     .fetch_one(&mut tx)
     .await?
@@ -398,6 +1632,8 @@ async fn register_oauth_user(form: LinkIdentityData, refresh_token: Option<Strin
 
     Ok(User {
         id: user.id,
+        fingerprint: user.session_fingerprint(),
+        session_version: user.session_version,
         name: user.name,
         is_admin: user.is_admin,
         is_anonymous: false,
@@ -420,25 +1656,28 @@ async fn merge_linked_account(account_id: i32, linked_id: i32, form: LinkIdentit
         RETURNING
             id, name, email, password, profile, plan,
             is_active, is_admin, has_verified_email,
-            last_login, created, updated
+            last_login, plan_expires_at, session_version, email_delivery_status, locale, created, updated
     ",
-        form.name,
+        pii::seal(&form.name),
         account_id
     )
     .fetch_one(&mut tx)
-    .await?;
+    .await?
+    .decrypt()?;
 
     tx.commit().await?;
 
     Ok(User {
         id: user.id,
+        fingerprint: user.session_fingerprint(),
+        session_version: user.session_version,
         name: user.name,
         is_admin: user.is_admin,
         is_anonymous: false,
     })
 }
 
-async fn link_additional_identity(account_id: i32, form: LinkIdentityData, refresh_token: Option<String>, mut tx: PgTransaction<'_>) -> error::Result<User> {
+async fn link_additional_identity(account_id: i32, form: LinkIdentityData, tokens: Option<ProviderTokens>, mut tx: PgTransaction<'_>) -> error::Result<User> {
     // The account is not linked to a local account and
     //    a session cookie is present --> Linking Additional account
     let user = sqlx::query_as_unchecked!(
@@ -450,24 +1689,28 @@ async fn link_additional_identity(account_id: i32, form: LinkIdentityData, refre
         RETURNING
             id, name, email, password, profile, plan,
             is_active, is_admin, has_verified_email,
-            last_login, created, updated
+            last_login, plan_expires_at, session_version, email_delivery_status, locale, created, updated
     ",
         account_id
     )
     .fetch_one(&mut tx)
-    .await?;
+    .await?
+    .decrypt()?;
 
     let _identity_id = sqlx::query!(
         "
-        INSERT INTO identities (account_id, provider, username, name, refresh_token)
-        VALUES ($1, $2, $3, $4, $5)
+        INSERT INTO identities
+            (account_id, provider, username, name, refresh_token, access_token, access_token_expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
         RETURNING id
     ",
         account_id,
         form.provider,
         form.username,
         form.name,
-        refresh_token,
+        tokens.as_ref().and_then(|tokens| tokens.refresh_token.as_deref()).map(pii::seal),
+        tokens.as_ref().map(|tokens| pii::seal(&tokens.access_token)),
+        tokens.as_ref().and_then(|tokens| tokens.expires_at),
     )
     .fetch_one(&mut tx)
     .await?
@@ -477,6 +1720,8 @@ async fn link_additional_identity(account_id: i32, form: LinkIdentityData, refre
 
     Ok(User {
         id: user.id,
+        fingerprint: user.session_fingerprint(),
+        session_version: user.session_version,
         name: user.name,
         is_admin: user.is_admin,
         is_anonymous: false,
@@ -484,6 +1729,10 @@ async fn link_additional_identity(account_id: i32, form: LinkIdentityData, refre
 }
 
 
+/// Seconds before it expires that an access token is refreshed, so it
+/// doesn't run out while in use.
+const ACCESS_TOKEN_MARGIN: i64 = 60;
+
 /// Oauth identities
 /// From https://stackoverflow.com/questions/6666267/architecture-for-merging-multiple-user-accounts-together
 ///
@@ -558,25 +1807,40 @@ pub struct Identity {
     pub provider: String,
     pub username: String,
     pub name: Option<String>,
+    #[serde(skip)]
     pub refresh_token: Option<String>,
+    #[serde(skip)]
+    pub access_token: Option<String>,
+    #[serde(with = "rfc3339::option")]
+    pub access_token_expires_at: Option<DateTime<Utc>>,
+    #[serde(with = "rfc3339")]
     pub created: DateTime<Utc>,
+    #[serde(with = "rfc3339")]
     pub updated: DateTime<Utc>,
 }
 
 impl Identity {
+    /// Decrypts the tokens of a row as loaded; see `pii`.
+    fn decrypt(mut self) -> error::Result<Self> {
+        self.refresh_token = self.refresh_token.as_deref().map(pii::open).transpose()?;
+        self.access_token = self.access_token.as_deref().map(pii::open).transpose()?;
+        Ok(self)
+    }
+
     pub async fn get(id: i32, mut db: AppDbConnection) -> error::Result<Self> {
-        Ok(sqlx::query_as_unchecked!(
+        sqlx::query_as_unchecked!(
             Identity,
             "
             SELECT
                 id, account_id, provider, username, name,
-                refresh_token, created, updated
+                refresh_token, access_token, access_token_expires_at, created, updated
             FROM identities WHERE id = $1
         ",
             id
         )
         .fetch_one(&mut *db)
-        .await?)
+        .await?
+        .decrypt()
     }
 
     pub async fn get_by_provider_username(
@@ -584,12 +1848,12 @@ impl Identity {
         username: &str,
         mut db: AppDbConnection,
     ) -> error::Result<Self> {
-        Ok(sqlx::query_as_unchecked!(
+        sqlx::query_as_unchecked!(
             Identity,
             "
             SELECT
                 id, account_id, provider, username, name,
-                refresh_token, created, updated
+                refresh_token, access_token, access_token_expires_at, created, updated
             FROM identities
             WHERE provider = $1 AND username = $2
         ",
@@ -597,21 +1861,723 @@ impl Identity {
             username,
         )
         .fetch_one(&mut *db)
-        .await?)
+        .await?
+        .decrypt()
     }
 
     pub async fn linked_to_account_id(account_id: i32, mut db: AppDbConnection) -> error::Result<Vec<Self>> {
-        Ok(sqlx::query_as_unchecked!(
+        sqlx::query_as_unchecked!(
             Identity,
             "
             SELECT
                 id, account_id, provider, username, name,
-                refresh_token, created, updated
+                refresh_token, access_token, access_token_expires_at, created, updated
             FROM identities WHERE account_id = $1
         ",
             account_id
         )
         .fetch_all(&mut *db)
+        .await?
+        .into_iter()
+        .map(Identity::decrypt)
+        .collect()
+    }
+
+    /// The identity's access token for the provider's API. An expired
+    /// one is first refreshed with the stored refresh token, and the new
+    /// tokens saved. Fails with `Unauthorized` if that can't be done, in
+    /// which case the user has to log in with the provider again.
+    pub async fn valid_access_token(
+        &self,
+        providers: &OAuthProviders,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<String> {
+        let expiring = self.access_token_expires_at
+            .map_or(false, |expires_at| expires_at <= Utc::now() + chrono::Duration::seconds(ACCESS_TOKEN_MARGIN));
+        if let (Some(access_token), false) = (&self.access_token, expiring) {
+            return Ok(access_token.clone());
+        }
+
+        let refresh_token = self.refresh_token.clone().ok_or_else(|| error::Error::with_status(
+            anyhow!("no refresh token for {} identity {}", self.provider, self.id), Status::Unauthorized))?;
+        let client = providers.client(&self.provider)?;
+
+        let tokens = rocket::tokio::task::spawn_blocking(move || oauth::refresh_tokens(&client, &refresh_token))
+            .await
+            .map_err(|e| anyhow!("token refresh task failed: {}", e))?
+            .map_err(|e| error::Error::with_status(e.error, Status::Unauthorized))?;
+
+        sqlx::query!(
+            "
+            UPDATE identities
+            SET access_token = $2, access_token_expires_at = $3, refresh_token = $4
+            WHERE id = $1
+        ",
+            self.id,
+            pii::seal(&tokens.access_token),
+            tokens.expires_at,
+            tokens.refresh_token.as_deref().map(pii::seal),
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(tokens.access_token)
+    }
+
+    /// Revokes a removed identity's tokens, as stored, with the provider,
+    /// if it supports revocation. The refresh token is preferred, as
+    /// revoking it covers the access token too. Failures are only logged,
+    /// since the identity is already gone on our side.
+    async fn revoke_tokens(
+        providers: &OAuthProviders,
+        provider: &str,
+        refresh_token: Option<String>,
+        access_token: Option<String>,
+    ) {
+        let token = match refresh_token.or(access_token).as_deref().map(pii::open) {
+            Some(Ok(token)) => token,
+            Some(Err(e)) => {
+                rocket::warn!("not revoking {} tokens: {}", provider, e.error);
+                return;
+            },
+            None => return,
+        };
+        let client = match providers.client(provider) {
+            Ok(client) => client,
+            Err(e) => {
+                rocket::warn!("not revoking tokens: {}", e.error);
+                return;
+            }
+        };
+
+        let revoked = rocket::tokio::task::spawn_blocking(move || oauth::revoke_token(&client, &token)).await;
+        match revoked {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => rocket::warn!("could not revoke {} tokens: {}", provider, e.error),
+            Err(e) => rocket::warn!("token revocation task failed: {}", e),
+        }
+    }
+
+    /// Removes the account's identity for `provider`. Refuses to remove
+    /// the last identity of an account without a password, since the
+    /// user would have no way left to sign in. The identity's provider
+    /// tokens are revoked afterwards.
+    pub async fn unlink(
+        account_id: i32,
+        provider: &str,
+        providers: &OAuthProviders,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<()> {
+        let mut tx = conn.begin().await?;
+
+        let logins = sqlx::query!(
+            "
+            SELECT
+                password IS NOT NULL AS \"has_password!\",
+                (SELECT count(*) FROM identities WHERE account_id = $1) AS \"identities!\"
+            FROM accounts WHERE id = $1
+        ",
+            account_id
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        if !logins.has_password && logins.identities <= 1 {
+            return Err(error::Error::with_status(
+                anyhow!("set a password before unlinking your only sign-in provider"),
+                Status::BadRequest));
+        }
+
+        let deleted = sqlx::query!(
+            "
+            DELETE FROM identities
+            WHERE account_id = $1 AND provider = $2
+            RETURNING refresh_token, access_token
+        ",
+            account_id,
+            provider
+        )
+        .fetch_all(&mut tx)
+        .await?;
+
+        if deleted.is_empty() {
+            return Err(error::Error::with_status(
+                anyhow!("no linked {} account", provider), Status::NotFound));
+        }
+
+        tx.commit().await?;
+
+        for identity in deleted {
+            Identity::revoke_tokens(providers, provider, identity.refresh_token, identity.access_token).await;
+        }
+
+        Ok(())
+    }
+}
+
+/// A passkey registered to an account; see `webauthn`. `passkey` is the
+/// credential as the webauthn library serializes it, public key included,
+/// and is left out when this is serialized.
+#[derive(Debug, Serialize)]
+pub struct WebauthnCredential {
+    pub id: i32,
+    pub account_id: i32,
+    #[serde(skip)]
+    pub user_handle: sqlx::types::Uuid,
+    pub credential_id: String,
+    pub name: String,
+    #[serde(skip)]
+    pub passkey: serde_json::Value,
+    #[serde(with = "rfc3339")]
+    pub created: DateTime<Utc>,
+    #[serde(with = "rfc3339::option")]
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl WebauthnCredential {
+    pub async fn for_account(account_id: i32, conn: &mut sqlx::PgConnection) -> error::Result<Vec<Self>> {
+        Ok(sqlx::query_as_unchecked!(
+            WebauthnCredential,
+            "
+            SELECT id, account_id, user_handle, credential_id, name, passkey, created, last_used_at
+            FROM webauthn_credentials WHERE account_id = $1
+            ORDER BY created
+        ",
+            account_id
+        )
+        .fetch_all(conn)
+        .await?)
+    }
+
+    /// The passkeys of the active account with this email, if any.
+    pub async fn for_email(email: &str, aliases: EmailAliasPolicy, conn: &mut sqlx::PgConnection) -> error::Result<Vec<Self>> {
+        let (canonical, normalized) = email_lookup(email, aliases);
+        Ok(sqlx::query_as_unchecked!(
+            WebauthnCredential,
+            "
+            SELECT
+                c.id, c.account_id, c.user_handle, c.credential_id, c.name, c.passkey,
+                c.created, c.last_used_at
+            FROM webauthn_credentials c
+            WHERE c.account_id = (
+                SELECT id FROM accounts
+                WHERE (canonical_email = $1 OR email = $2) AND is_active AND deleted_at IS NULL
+                ORDER BY email = $2 DESC
+                LIMIT 1
+            )
+            ORDER BY c.created
+        ",
+            canonical,
+            normalized
+        )
+        .fetch_all(conn)
         .await?)
     }
+
+    pub async fn find(account_id: i32, credential_id: &str, conn: &mut sqlx::PgConnection) -> error::Result<Self> {
+        sqlx::query_as_unchecked!(
+            WebauthnCredential,
+            "
+            SELECT id, account_id, user_handle, credential_id, name, passkey, created, last_used_at
+            FROM webauthn_credentials WHERE account_id = $1 AND credential_id = $2
+        ",
+            account_id,
+            credential_id
+        )
+        .fetch_optional(conn)
+        .await?
+        .ok_or_else(|| error::Error::with_status(anyhow!("no such passkey"), Status::NotFound))
+    }
+
+    pub async fn create(
+        account_id: i32,
+        user_handle: sqlx::types::Uuid,
+        credential_id: &str,
+        name: &str,
+        passkey: serde_json::Value,
+        conn: &mut sqlx::PgConnection,
+    ) -> error::Result<i32> {
+        Ok(sqlx::query!(
+            "
+            INSERT INTO webauthn_credentials (account_id, user_handle, credential_id, name, passkey)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id
+        ",
+            account_id,
+            user_handle,
+            credential_id,
+            name,
+            passkey
+        )
+        .fetch_one(conn)
+        .await?
+        .id)
+    }
+
+    /// Notes a login with the passkey, saving it again if the login
+    /// changed it, e.g. its signature counter.
+    pub async fn record_use(id: i32, passkey: Option<serde_json::Value>, conn: &mut sqlx::PgConnection) -> error::Result<()> {
+        sqlx::query!(
+            "
+            UPDATE webauthn_credentials
+            SET last_used_at = now(), passkey = coalesce($2, passkey)
+            WHERE id = $1
+        ",
+            id,
+            passkey
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Fails with `NotFound` unless the account has a passkey with this id.
+    pub async fn delete(account_id: i32, id: i32, conn: &mut sqlx::PgConnection) -> error::Result<()> {
+        let result = sqlx::query!(
+            "
+            DELETE FROM webauthn_credentials WHERE id = $1 AND account_id = $2
+        ",
+            id,
+            account_id
+        )
+        .execute(conn)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(error::Error::with_status(anyhow!("no such passkey"), Status::NotFound));
+        }
+        Ok(())
+    }
+}
+
+/// Days an invitation can be used for.
+const INVITATION_TTL_DAYS: i32 = 14;
+
+/// An invitation to register, for `AccountsConfig::registration` set to
+/// `invite_only`. Only a hash of its token is stored, so the token for
+/// the invitation email is only known when it's created.
+#[derive(Debug, Serialize)]
+pub struct Invitation {
+    pub id: i32,
+    pub email: String,
+    pub inviter_id: Option<i32>,
+    #[serde(with = "rfc3339")]
+    pub created: DateTime<Utc>,
+    #[serde(with = "rfc3339")]
+    pub expires_at: DateTime<Utc>,
+    #[serde(with = "rfc3339::option")]
+    pub consumed_at: Option<DateTime<Utc>>,
+}
+
+fn invitation_token_hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// How an invited email is stored. Invitations match the address the
+/// invitee registers with, ignoring case.
+fn invitation_email(email: &str) -> String {
+    pii::seal_email(&email.trim().to_lowercase())
+}
+
+impl Invitation {
+    /// Invites `email` to register, returning the invitation and the
+    /// token to send with it.
+    pub async fn create(email: &str, inviter_id: i32, conn: &mut sqlx::PgConnection) -> error::Result<(Self, String)> {
+        let token = base64_url::encode(&rand::random::<[u8; 32]>());
+
+        let mut invitation = sqlx::query_as_unchecked!(
+            Invitation,
+            "
+            INSERT INTO invitations (email, token_hash, inviter_id, expires_at)
+            VALUES ($1, $2, $3, now() + make_interval(days => $4))
+            RETURNING id, email, inviter_id, created, expires_at, consumed_at
+        ",
+            invitation_email(email),
+            invitation_token_hash(&token),
+            inviter_id,
+            INVITATION_TTL_DAYS
+        )
+        .fetch_one(conn)
+        .await?;
+
+        invitation.email = pii::open(&invitation.email)?;
+        Ok((invitation, token))
+    }
+
+    /// Whether `token` is an unused, unexpired invitation for `email`.
+    pub async fn is_valid(token: &str, email: &str, conn: &mut sqlx::PgConnection) -> error::Result<bool> {
+        Ok(sqlx::query!(
+            "
+            SELECT id FROM invitations
+            WHERE token_hash = $1 AND email = $2 AND consumed_at IS NULL AND expires_at > now()
+        ",
+            invitation_token_hash(token),
+            invitation_email(email)
+        )
+        .fetch_optional(conn)
+        .await?
+        .is_some())
+    }
+
+    /// Uses up the invitation, failing with `Forbidden` unless it
+    /// `is_valid`. Run it in the transaction that registers the account,
+    /// so that each invitation registers one.
+    pub async fn consume(token: &str, email: &str, conn: &mut sqlx::PgConnection) -> error::Result<()> {
+        let result = sqlx::query!(
+            "
+            UPDATE invitations
+            SET consumed_at = now()
+            WHERE token_hash = $1 AND email = $2 AND consumed_at IS NULL AND expires_at > now()
+        ",
+            invitation_token_hash(token),
+            invitation_email(email)
+        )
+        .execute(conn)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(error::Error::with_status(anyhow!("invalid or used invitation"), Status::Forbidden));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_connection;
+
+    fn new_account(email: &str) -> NewAccount<'_> {
+        NewAccount { name: "Test Account", email, password: "a long test password" }
+    }
+
+    fn user_pass(password: Option<&str>) -> UserPass {
+        UserPass {
+            id: 1,
+            name: "Test Account".to_string(),
+            password: password.map(String::from),
+            is_active: true,
+            is_admin: false,
+            has_verified_email: true,
+            session_version: 0,
+        }
+    }
+
+    fn profile(json: serde_json::Value) -> Profile {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn v1_profile_is_upgraded() {
+        let upgraded = profile(serde_json::json!({ "version": 1 }));
+        assert_eq!(upgraded.version, PROFILE_VERSION);
+        assert_eq!(upgraded.display_name, None);
+        assert_eq!(upgraded.timezone, None);
+    }
+
+    #[test]
+    fn unversioned_profile_is_upgraded() {
+        assert_eq!(profile(serde_json::json!({})).version, PROFILE_VERSION);
+    }
+
+    #[test]
+    fn current_profile_round_trips() {
+        let mut current = Profile::default();
+        current.set(ProfileField::DisplayName, Some("Ann".to_string()));
+        current.set(ProfileField::Timezone, Some("Europe/Paris".to_string()));
+
+        let read = profile(serde_json::to_value(&current).unwrap());
+        assert_eq!(read.version, PROFILE_VERSION);
+        assert_eq!(read.display_name.as_deref(), Some("Ann"));
+        assert_eq!(read.timezone.as_deref(), Some("Europe/Paris"));
+    }
+
+    #[test]
+    fn unreadable_profiles_are_the_default() {
+        for json in [serde_json::json!(null), serde_json::json!([1, 2]), serde_json::json!({ "version": 1, "bio": 5 })] {
+            let read = profile(json.clone());
+            assert_eq!(read.version, PROFILE_VERSION, "{}", json);
+            assert_eq!(read.bio, None, "{}", json);
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Stamped {
+        #[serde(with = "rfc3339")]
+        at: DateTime<Utc>,
+        #[serde(with = "rfc3339::option")]
+        until: Option<DateTime<Utc>>,
+    }
+
+    #[test]
+    fn timestamps_are_rfc3339_in_utc_to_the_second() {
+        let at = DateTime::parse_from_rfc3339("2022-04-18T11:00:00.123456+02:00").unwrap().with_timezone(&Utc);
+        let json = serde_json::to_value(Stamped { at, until: None }).unwrap();
+        assert_eq!(json, serde_json::json!({ "at": "2022-04-18T09:00:00Z", "until": null }));
+
+        let read: Stamped = serde_json::from_value(serde_json::json!({
+            "at": "2022-04-18T09:00:00Z",
+            "until": "2022-04-18T11:00:00+02:00",
+        })).unwrap();
+        assert_eq!(read.at, read.until.unwrap());
+        assert!(serde_json::from_value::<Stamped>(serde_json::json!({ "at": "18/04/2022", "until": null })).is_err());
+    }
+
+    #[test]
+    fn right_and_wrong_passwords_are_checked() {
+        let hash = passwords::hash("a long test password").unwrap();
+        let user = user_pass(Some(&hash));
+        assert!(user.check_password("a long test password").unwrap());
+        assert!(!user.check_password("another password").unwrap());
+    }
+
+    #[test]
+    fn a_corrupt_hash_is_a_server_error() {
+        for corrupt in ["$argon2id$v=19$m=4096,t=3,p=1$not-base64!$", "$argon2id$truncated", "not-a-hash"] {
+            let error = user_pass(Some(corrupt)).check_password("a long test password").unwrap_err();
+            assert_eq!(error.status, Status::InternalServerError, "{}", corrupt);
+        }
+    }
+
+    #[test]
+    fn no_password_is_unauthorized() {
+        let error = user_pass(None).check_password("a long test password").unwrap_err();
+        assert_eq!(error.status, Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn changed_provider_names_are_saved_on_login() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let suffix = ulid::Ulid::new().to_string().to_lowercase();
+        let identity = |username: &str, name: &str| LinkIdentityData {
+            provider: "github".to_string(),
+            username: username.to_string(),
+            name: name.to_string(),
+            email: format!("octo-{}@example.com", suffix),
+        };
+        let username = format!("octo-{}", suffix);
+
+        let first = Account::merge_identity_and_login(
+            identity(&username, "Octo Cat"), None, None, true, EmailAliasPolicy::default(), &mut tx,
+        ).await.unwrap();
+        let again = Account::merge_identity_and_login(
+            identity(&username.to_uppercase(), "Octo Renamed"), None, None, true, EmailAliasPolicy::default(), &mut tx,
+        ).await.unwrap();
+        assert_eq!(again.id, first.id);
+
+        let stored: (String, Option<String>) = sqlx::query_as(
+            "SELECT username, name FROM identities WHERE provider = 'github' AND lower(username) = $1")
+            .bind(&username)
+            .fetch_one(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(stored, (username.to_uppercase(), Some("Octo Renamed".to_string())));
+    }
+
+    fn verify_calls() -> usize {
+        passwords::VERIFY_CALLS.with(|calls| calls.get())
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn unknown_emails_are_hashed_like_wrong_passwords() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let suffix = ulid::Ulid::new().to_string().to_lowercase();
+        let email = format!("timing-{}@example.com", suffix);
+        let unknown = format!("unknown-{}@example.com", suffix);
+        Account::register(&new_account(&email), EmailAliasPolicy::default(), &mut tx).await.unwrap();
+
+        for email in [&email, &unknown] {
+            let login = LoginData { email, password: "the wrong password", remember: false };
+            let before = verify_calls();
+            let e = Account::authenticate(&login, false, EmailAliasPolicy::default(), &mut tx).await.unwrap_err();
+            assert_eq!(e.status, Status::Unauthorized, "{}", email);
+            assert_eq!(verify_calls() - before, 1, "{}", email);
+        }
+    }
+
+    #[test]
+    fn deleted_emails_are_unique_and_undeliverable() {
+        assert_ne!(deleted_email(1), deleted_email(2));
+        assert!(deleted_email(1).ends_with("@deleted.invalid"));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn soft_deleted_email_can_register_again() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let email = format!("deleted-{}@example.com", ulid::Ulid::new().to_string().to_lowercase());
+        Account::register(&new_account(&email), EmailAliasPolicy::default(), &mut tx).await.unwrap();
+        let id = Account::id_by_email(&email, EmailAliasPolicy::default(), &mut tx).await.unwrap();
+
+        Account::delete(id, true, &OAuthProviders::default(), &mut tx).await.unwrap();
+
+        let deleted = Account::get(id, &mut tx).await.unwrap();
+        assert_eq!(deleted.email, deleted_email(id));
+        assert!(!deleted.is_active);
+
+        Account::register(&new_account(&email), EmailAliasPolicy::default(), &mut tx).await.unwrap();
+        let registered = Account::get_by_email(&email, EmailAliasPolicy::default(), &mut tx).await.unwrap();
+        assert_ne!(registered.id, id);
+    }
+
+    /// Whether `email` can register, in a savepoint so that a refused
+    /// one doesn't abort `conn`'s transaction.
+    async fn registers(email: &str, aliases: EmailAliasPolicy, conn: &mut sqlx::PgConnection) -> bool {
+        let mut attempt = conn.begin().await.unwrap();
+        let registered = Account::register(&new_account(email), aliases, &mut attempt).await.is_ok();
+        if registered {
+            attempt.commit().await.unwrap();
+        }
+        registered
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn plus_aliases_collide_only_when_stripped() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        for (aliases, collide) in [(EmailAliasPolicy::Exact, false), (EmailAliasPolicy::StripPlus, true)] {
+            let local = format!("alias-{}", ulid::Ulid::new().to_string().to_lowercase());
+            assert!(registers(&format!("{}+one@example.com", local), aliases, &mut tx).await);
+            assert_eq!(registers(&format!("{}+two@example.com", local), aliases, &mut tx).await, !collide, "{:?}", aliases);
+        }
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn delivery_status_is_set_by_alias() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let local = format!("status-{}", ulid::Ulid::new().to_string().to_lowercase());
+        let email = format!("{}@example.com", local);
+        let alias = format!("{}+news@Example.com", local);
+        Account::register(&new_account(&email), EmailAliasPolicy::StripPlus, &mut tx).await.unwrap();
+
+        Account::set_email_delivery_status(&alias, EmailDeliveryStatus::Bounced, EmailAliasPolicy::StripPlus, &mut tx)
+            .await
+            .unwrap();
+
+        let account = Account::get_by_email(&email, EmailAliasPolicy::StripPlus, &mut tx).await.unwrap();
+        assert_eq!(account.email_delivery_status, EmailDeliveryStatus::Bounced);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn hard_deleted_email_can_register_again() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let email = format!("deleted-{}@example.com", ulid::Ulid::new().to_string().to_lowercase());
+        Account::register(&new_account(&email), EmailAliasPolicy::default(), &mut tx).await.unwrap();
+        let id = Account::id_by_email(&email, EmailAliasPolicy::default(), &mut tx).await.unwrap();
+
+        Account::delete(id, false, &OAuthProviders::default(), &mut tx).await.unwrap();
+        assert!(Account::get(id, &mut tx).await.is_err());
+
+        Account::register(&new_account(&email), EmailAliasPolicy::default(), &mut tx).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn only_expired_plans_are_downgraded() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let suffix = ulid::Ulid::new().to_string().to_lowercase();
+        let mut ids = Vec::new();
+        for (name, offset) in [("past", -1), ("future", 1)] {
+            let email = format!("plan-{}-{}@example.com", name, suffix);
+            Account::register(&new_account(&email), EmailAliasPolicy::default(), &mut tx).await.unwrap();
+            let id = Account::id_by_email(&email, EmailAliasPolicy::default(), &mut tx).await.unwrap();
+            Account::set_plan(id, Plan::Pro, &mut tx).await.unwrap();
+            Account::schedule_downgrade(id, Utc::now() + chrono::Duration::hours(offset), &mut tx).await.unwrap();
+            ids.push(id);
+        }
+
+        assert!(Account::downgrade_expired_plans(&mut tx).await.unwrap() >= 1);
+
+        let past = Account::get(ids[0], &mut tx).await.unwrap();
+        assert_eq!(past.plan, Plan::Free);
+        assert!(past.plan_expires_at.is_none());
+        let future = Account::get(ids[1], &mut tx).await.unwrap();
+        assert_eq!(future.plan, Plan::Pro);
+        assert!(future.plan_expires_at.is_some());
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn only_passwords_past_the_max_age_are_reminded() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let suffix = ulid::Ulid::new().to_string().to_lowercase();
+        let mut accounts = Vec::new();
+        for (name, age_days) in [("old", 100), ("new", 10)] {
+            let email = format!("expiry-{}-{}@example.com", name, suffix);
+            Account::register(&new_account(&email), EmailAliasPolicy::default(), &mut tx).await.unwrap();
+            let id = Account::id_by_email(&email, EmailAliasPolicy::default(), &mut tx).await.unwrap();
+            sqlx::query("UPDATE accounts SET password_changed_at = now() - make_interval(days => $2) WHERE id = $1")
+                .bind(id)
+                .bind(age_days)
+                .execute(&mut tx)
+                .await
+                .unwrap();
+            accounts.push((id, email));
+        }
+        let (old_id, old_email) = &accounts[0];
+        let (new_id, new_email) = &accounts[1];
+
+        let reminded = Account::remind_expired_passwords(90, true, &mut tx).await.unwrap();
+        assert!(reminded.contains(old_email));
+        assert!(!reminded.contains(new_email));
+        assert!(Account::must_change_password(*old_id, &mut tx).await.unwrap());
+        assert!(!Account::must_change_password(*new_id, &mut tx).await.unwrap());
+
+        // Not again, until the password is changed.
+        let reminded = Account::remind_expired_passwords(90, true, &mut tx).await.unwrap();
+        assert!(!reminded.contains(old_email));
+    }
+
+    fn user(id: i32) -> User {
+        User { id, is_anonymous: false, ..User::default() }
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn missing_account_loads_as_not_found() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let email = format!("load-{}@example.com", ulid::Ulid::new().to_string().to_lowercase());
+        Account::register(&new_account(&email), EmailAliasPolicy::default(), &mut tx).await.unwrap();
+        let id = Account::id_by_email(&email, EmailAliasPolicy::default(), &mut tx).await.unwrap();
+        assert!(user(id).load_account(&mut tx).await.is_ok());
+
+        Account::delete(id, false, &OAuthProviders::default(), &mut tx).await.unwrap();
+        let e = user(id).load_account(&mut tx).await.unwrap_err();
+        assert_eq!(e.status, Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn failed_account_load_is_a_server_error() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        // Aborts the transaction, so the next query fails.
+        assert!(sqlx::query("SELECT 1 / 0").execute(&mut tx).await.is_err());
+
+        let e = user(1).load_account(&mut tx).await.unwrap_err();
+        assert_eq!(e.status, Status::InternalServerError);
+    }
 }
@@ -1,24 +1,112 @@
 //! URL dispatcher for oauth related API endpoints.
 
 use std::str;
+use std::sync::Arc;
 
 use anyhow::anyhow;
 use oauth2::basic::{BasicClient, BasicTokenResponse};
 use oauth2::http::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 use oauth2::http::method::Method;
 use oauth2::reqwest::http_client;
+use chrono::{DateTime, Utc};
+use constant_time_eq::constant_time_eq;
 use oauth2::{
-    url, AccessToken, AuthorizationCode, AuthorizationRequest, CsrfToken, PkceCodeChallenge,
-    PkceCodeVerifier, Scope, TokenResponse,
+    url, AccessToken, AuthorizationCode, AuthorizationRequest, ConfigurationError, CsrfToken,
+    PkceCodeChallenge, PkceCodeVerifier, RefreshToken, Scope, StandardRevocableToken, TokenResponse,
 };
-use rocket::http::{Cookie, CookieJar};
 use serde::{Deserialize, Serialize};
 use serde_json;
+use sha2::{Digest, Sha256};
 
 use crate::error;
+use crate::pii;
 
 pub mod client;
 
+/// Private cookie holding the key the provider's `ProviderTokens` are
+/// kept under between the callback and the confirmation form. The tokens
+/// themselves never leave the server.
+pub const TOKENS_COOKIE: &str = "oauth_tokens";
+
+/// Private cookie holding the nonce that ties a flow stored in the
+/// database to the browser that started it.
+pub const BROWSER_COOKIE: &str = "oauth_browser";
+
+/// A new nonce for `BROWSER_COOKIE` or `TOKENS_COOKIE`.
+pub fn browser_nonce() -> String {
+    base64_url::encode(&rand::random::<[u8; 32]>())
+}
+
+/// How a browser nonce is stored with its flow, so the table alone can't
+/// be used to complete someone else's login.
+fn browser_hash(nonce: &str) -> String {
+    format!("{:x}", Sha256::digest(nonce.as_bytes()))
+}
+
+/// The tokens a provider issued, kept on the identity for calling the
+/// provider's API later.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProviderTokens {
+    pub access_token: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub refresh_token: Option<String>,
+}
+
+impl ProviderTokens {
+    fn from_response(response: &BasicTokenResponse) -> Self {
+        ProviderTokens {
+            access_token: response.access_token().secret().clone(),
+            expires_at: response.expires_in()
+                .and_then(|expires_in| chrono::Duration::from_std(expires_in).ok())
+                .map(|expires_in| Utc::now() + expires_in),
+            refresh_token: response.refresh_token().map(|token| token.secret().clone()),
+        }
+    }
+
+    /// Keeps the tokens until the user confirms their details, clearing
+    /// out expired ones on the way. Returns the key, for `TOKENS_COOKIE`,
+    /// they can be loaded with.
+    pub async fn stash(&self, conn: &mut sqlx::PgConnection) -> error::Result<String> {
+        sqlx::query("DELETE FROM oauth_pending_tokens WHERE created_at < now() - make_interval(secs => $1)")
+            .bind(FLOW_TTL as f64)
+            .execute(&mut *conn)
+            .await?;
+
+        let key = browser_nonce();
+        sqlx::query("INSERT INTO oauth_pending_tokens (key_hash, tokens) VALUES ($1, $2)")
+            .bind(browser_hash(&key))
+            .bind(pii::seal(&serde_json::to_string(self)?))
+            .execute(conn)
+            .await?;
+        Ok(key)
+    }
+
+    /// The tokens stashed under `key`, if they haven't expired.
+    pub async fn load(key: &str, conn: &mut sqlx::PgConnection) -> error::Result<Option<ProviderTokens>> {
+        let tokens: Option<(String,)> = sqlx::query_as(
+            "SELECT tokens FROM oauth_pending_tokens
+            WHERE key_hash = $1 AND created_at >= now() - make_interval(secs => $2)")
+            .bind(browser_hash(key))
+            .bind(FLOW_TTL as f64)
+            .fetch_optional(conn)
+            .await?;
+
+        match tokens {
+            Some((tokens,)) => Ok(Some(serde_json::from_str(&pii::open(&tokens)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the tokens stashed under `key`, once they are saved.
+    pub async fn discard(key: &str, conn: &mut sqlx::PgConnection) -> error::Result<()> {
+        sqlx::query("DELETE FROM oauth_pending_tokens WHERE key_hash = $1")
+            .bind(browser_hash(key))
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct OAuthFlow {
     pub provider: String,
@@ -33,6 +121,63 @@ impl OAuthFlow {
         self.authorization_code = code.to_string();
         self
     }
+
+    /// Stores the flow under its state nonce, for `FlowStorage::Database`,
+    /// clearing out expired flows on the way. Only the browser holding
+    /// `browser`, from `browser_nonce`, can take it back.
+    pub async fn save(&self, browser: &str, conn: &mut sqlx::PgConnection) -> error::Result<()> {
+        sqlx::query("DELETE FROM oauth_flows WHERE created_at < now() - make_interval(secs => $1)")
+            .bind(FLOW_TTL as f64)
+            .execute(&mut *conn)
+            .await?;
+
+        sqlx::query("INSERT INTO oauth_flows (state, flow, browser_hash) VALUES ($1, $2, $3)")
+            .bind(&self.csrf_token_secret)
+            .bind(sqlx::types::Json(self))
+            .bind(browser_hash(browser))
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Removes and returns the flow stored under `state` by the browser
+    /// holding `browser`, if there is one and it hasn't expired. Each flow
+    /// can only be taken once, and a callback opened in another browser
+    /// leaves it in place.
+    pub async fn take(state: &str, browser: &str, conn: &mut sqlx::PgConnection) -> error::Result<Option<OAuthFlow>> {
+        let flow: Option<(sqlx::types::Json<OAuthFlow>, bool)> = sqlx::query_as(
+            "DELETE FROM oauth_flows WHERE state = $1 AND browser_hash = $2
+            RETURNING flow, created_at >= now() - make_interval(secs => $3)")
+            .bind(state)
+            .bind(browser_hash(browser))
+            .bind(FLOW_TTL as f64)
+            .fetch_optional(conn)
+            .await?;
+
+        Ok(flow.filter(|(_, fresh)| *fresh).map(|(flow, _)| flow.0))
+    }
+
+    /// Whether `state`, as the provider returned it, is the one this flow
+    /// sent.
+    pub fn matches_state(&self, state: &str) -> bool {
+        constant_time_eq(state.as_bytes(), self.csrf_token_secret.as_bytes())
+    }
+}
+
+/// Seconds a user has to authorize with the provider before a stored
+/// flow expires.
+pub const FLOW_TTL: i64 = 600;
+
+/// Where the `OAuthFlow` is kept between the login and the callback, from
+/// `oauth.flow_storage`: a private cookie, the default, or the
+/// `oauth_flows` table, for deployments where the cookie can't be relied
+/// on or the state should be checked server side.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowStorage {
+    #[default]
+    Cookie,
+    Database,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -65,8 +210,21 @@ pub struct ScopedClient {
     pub user_info_request: UserInfoRequest,
 }
 
+impl ScopedClient {
+    /// Finalizes a PKCE authorization request into the URL to redirect
+    /// the user to. The CSRF token and PKCE verifier must be kept (e.g. in
+    /// an `OAuthFlow`) to complete the exchange in the callback.
+    pub fn authorize_url(&self, login_hint: Option<&str>) -> (String, CsrfToken, PkceCodeVerifier) {
+        let (authorization_request, pkce_code_verifier) =
+            pkce_authorization_request(self, login_hint);
+        let (url, csrf_token) = authorization_request.url();
+
+        (url.to_string(), csrf_token, pkce_code_verifier)
+    }
+}
+
 pub struct ClientFlow {
-    pub client: ScopedClient,
+    pub client: Arc<ScopedClient>,
     pub flow: OAuthFlow,
 }
 
@@ -133,25 +291,56 @@ pub fn request_token(client_flow: ClientFlow) -> error::Result<TokenInfo> {
             response,
             provider: client_flow.flow.provider,
             email: client_flow.flow.email,
-            user_info_request: client_flow.client.user_info_request,
+            user_info_request: client_flow.client.user_info_request.clone(),
         })
         .map_err(|_| error::Error::from(anyhow!("provider failed to exchange token")))
 }
 
-pub async fn fetch_user_info(
-    jar: &CookieJar<'_>,
-    token_info: TokenInfo,
-) -> error::Result<UserInfo> {
-    match token_info.response.refresh_token() {
-        Some(token) => jar.add_private(Cookie::new("rfrsh", token.secret().clone())),
-        None => jar.remove_private(Cookie::named("rfrsh")),
-    }
+/// Exchanges a refresh token for a new access token. This blocks, so
+/// run it with `spawn_blocking`.
+pub fn refresh_tokens(client: &ScopedClient, refresh_token: &str) -> error::Result<ProviderTokens> {
+    let response = client
+        .inner
+        .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
+        .request(http_client)
+        .map_err(|_| error::Error::from(anyhow!("provider failed to refresh token")))?;
+
+    // Providers that don't rotate refresh tokens leave them out.
+    let mut tokens = ProviderTokens::from_response(&response);
+    tokens.refresh_token.get_or_insert_with(|| refresh_token.to_string());
+    Ok(tokens)
+}
+
+/// Revokes a token with the provider, so it can't be used once the user
+/// has unlinked the provider or deleted their account. The token is sent
+/// as a refresh token, since revoking one also revokes its access tokens
+/// at most providers; they look up other token types as well. Providers
+/// without a `revoke_url` are skipped. This blocks, so run it with
+/// `spawn_blocking`.
+pub fn revoke_token(client: &ScopedClient, token: &str) -> error::Result<()> {
+    let token = StandardRevocableToken::RefreshToken(RefreshToken::new(token.to_string()));
+    let request = match client.inner.revoke_token(token) {
+        Ok(request) => request,
+        Err(ConfigurationError::MissingUrl(_)) => return Ok(()),
+        Err(e) => return Err(error::Error::from(anyhow!("can not revoke token: {}", e))),
+    };
+
+    request
+        .request(http_client)
+        .map_err(|_| error::Error::from(anyhow!("provider failed to revoke token")))
+}
+
+/// Fetches the user's profile with the access token, returning it along
+/// with the tokens the provider issued.
+pub async fn fetch_user_info(token_info: TokenInfo) -> error::Result<(UserInfo, ProviderTokens)> {
+    let tokens = ProviderTokens::from_response(&token_info.response);
 
     let access_token = token_info.response.access_token();
     let user_info_request = get_user_info_request(access_token, &token_info.user_info_request);
     http_client(user_info_request)
         .map_err(|_| error::Error::from(anyhow!("failed to fetch user profile")))
         .and_then(|response| token_info.parse_user_info_response(&response))
+        .map(|user_info| (user_info, tokens))
 }
 
 fn get_user_info_request<'a>(
@@ -186,3 +375,134 @@ fn get_user_info_request<'a>(
         body,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Connection;
+
+    use super::*;
+    use crate::database::test_connection;
+
+    fn flow() -> OAuthFlow {
+        OAuthFlow {
+            provider: "google".to_string(),
+            email: "user@example.com".to_string(),
+            authorization_code: String::new(),
+            csrf_token_secret: browser_nonce(),
+            pkce_verifier_secret: "verifier".to_string(),
+        }
+    }
+
+    #[test]
+    fn browser_hash_does_not_store_the_nonce() {
+        let nonce = browser_nonce();
+        assert_ne!(browser_hash(&nonce), nonce);
+        assert_eq!(browser_hash(&nonce), browser_hash(&nonce));
+        assert_ne!(browser_hash(&nonce), browser_hash(&browser_nonce()));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn stored_flow_round_trips_once() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let flow = flow();
+        let browser = browser_nonce();
+        flow.save(&browser, &mut tx).await.unwrap();
+
+        let taken = OAuthFlow::take(&flow.csrf_token_secret, &browser, &mut tx).await.unwrap();
+        assert_eq!(taken.map(|taken| taken.pkce_verifier_secret), Some(flow.pkce_verifier_secret.clone()));
+        assert!(OAuthFlow::take(&flow.csrf_token_secret, &browser, &mut tx).await.unwrap().is_none());
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn stored_flow_is_tied_to_its_browser() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let flow = flow();
+        let browser = browser_nonce();
+        flow.save(&browser, &mut tx).await.unwrap();
+
+        assert!(OAuthFlow::take(&flow.csrf_token_secret, &browser_nonce(), &mut tx).await.unwrap().is_none());
+        assert!(OAuthFlow::take(&flow.csrf_token_secret, &browser, &mut tx).await.unwrap().is_some());
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn stored_flow_expires() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let flow = flow();
+        let browser = browser_nonce();
+        flow.save(&browser, &mut tx).await.unwrap();
+        sqlx::query("UPDATE oauth_flows SET created_at = now() - make_interval(secs => $1) WHERE state = $2")
+            .bind((FLOW_TTL + 1) as f64)
+            .bind(&flow.csrf_token_secret)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+
+        assert!(OAuthFlow::take(&flow.csrf_token_secret, &browser, &mut tx).await.unwrap().is_none());
+    }
+
+    fn tokens() -> ProviderTokens {
+        ProviderTokens {
+            access_token: "access".to_string(),
+            expires_at: None,
+            refresh_token: Some("refresh".to_string()),
+        }
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn stashed_tokens_load_by_key_until_discarded() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let key = tokens().stash(&mut tx).await.unwrap();
+        assert!(ProviderTokens::load(&browser_nonce(), &mut tx).await.unwrap().is_none());
+
+        let loaded = ProviderTokens::load(&key, &mut tx).await.unwrap().unwrap();
+        assert_eq!(loaded.access_token, "access");
+        assert_eq!(loaded.refresh_token.as_deref(), Some("refresh"));
+
+        ProviderTokens::discard(&key, &mut tx).await.unwrap();
+        assert!(ProviderTokens::load(&key, &mut tx).await.unwrap().is_none());
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn stashed_tokens_are_not_stored_as_given() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let key = tokens().stash(&mut tx).await.unwrap();
+        let stored: Vec<(String,)> = sqlx::query_as("SELECT key_hash FROM oauth_pending_tokens WHERE key_hash = $1")
+            .bind(&key)
+            .fetch_all(&mut tx)
+            .await
+            .unwrap();
+        assert!(stored.is_empty());
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn stashed_tokens_expire() {
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+
+        let key = tokens().stash(&mut tx).await.unwrap();
+        sqlx::query("UPDATE oauth_pending_tokens SET created_at = now() - make_interval(secs => $1) WHERE key_hash = $2")
+            .bind((FLOW_TTL + 1) as f64)
+            .bind(browser_hash(&key))
+            .execute(&mut tx)
+            .await
+            .unwrap();
+
+        assert!(ProviderTokens::load(&key, &mut tx).await.unwrap().is_none());
+    }
+}
@@ -1,13 +1,30 @@
-use lazy_static::lazy_static;
+//! OAuth providers, loaded at ignition from `[oauth.providers.<name>]`
+//! tables in the Rocket configuration. Each names the `kind` of profile
+//! its user info endpoint returns, one of the built-in deserializers, so
+//! a provider speaking a known format can be added without code changes.
+//! See Rocket.example.toml.
+//!
+//! The clients are kept in `OAuthProviders`, which the fairing manages as
+//! Rocket state.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::anyhow;
 use oauth2::basic::BasicClient;
-use oauth2::{url, AuthUrl, ClientId, ClientSecret, RedirectUrl, RevocationUrl, TokenUrl};
+use oauth2::http::header::{HeaderName, HeaderValue};
+use oauth2::{url, AuthType, AuthUrl, ClientId, ClientSecret, RedirectUrl, RevocationUrl, TokenUrl};
+use rocket::fairing::AdHoc;
+use rocket::figment::value::Value;
+use rocket::http::Status;
+use rocket::figment::Figment;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::HashMap;
-use std::env;
-use std::sync::{Arc, Mutex};
 
-use crate::oauth::{ScopedClient, UserInfo, UserInfoDeserializer, UserInfoRequest};
+use crate::error;
+use crate::oauth::{FlowStorage, ScopedClient, UserInfo, UserInfoDeserializer, UserInfoRequest};
 
 pub const DEFAULT_PROVIDER: &str = "google";
 
@@ -16,214 +33,256 @@ pub struct ProviderHints {
     pub uses_email_hint: bool,
 }
 
-type HintMap = HashMap<&'static str, ProviderHints>;
-
-type ClientMap = HashMap<String, Option<ScopedClient>>;
-
-// TODO 105: use once_cell get_or_init and/or once_cell:sync::Lazy
-lazy_static! {
-    static ref LOGIN_HINTS: Arc<Mutex<HintMap>> = Arc::new(Mutex::new(build_hints()));
-    static ref CLIENTS: Arc<Mutex<ClientMap>> = Arc::new(Mutex::new(HashMap::new()));
-}
-
-fn build_hints() -> HintMap {
-    let mut hints = HashMap::new();
-    hints.insert(
-        "google",
-        ProviderHints {
-            uses_email_hint: true,
-        },
-    );
-    hints.insert(
-        "twitter",
-        ProviderHints {
-            uses_email_hint: false,
-        },
-    );
-    hints.insert(
-        "github",
-        ProviderHints {
-            uses_email_hint: false,
-        },
-    );
-    hints.insert(
-        "facebook",
-        ProviderHints {
-            uses_email_hint: false,
-        },
-    );
-    hints
+/// The profile format a provider's user info endpoint returns.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    Google,
+    Twitter,
+    Github,
+    Facebook,
+    Microsoft,
 }
 
-pub fn valid_provider(provider: &str) -> bool {
-    LOGIN_HINTS.lock().unwrap().contains_key(provider)
+impl ProviderKind {
+    fn deserializer(self) -> UserInfoDeserializer {
+        match self {
+            ProviderKind::Google => deserialize_google,
+            ProviderKind::Twitter => deserialize_twitter,
+            ProviderKind::Github => deserialize_github,
+            ProviderKind::Facebook => deserialize_facebook,
+            ProviderKind::Microsoft => deserialize_microsoft,
+        }
+    }
 }
 
-pub fn provider_hints(provider: &str) -> Option<ProviderHints> {
-    LOGIN_HINTS.lock().unwrap().get(provider).copied()
+/// How a confidential client authenticates to the token endpoint.
+/// Public clients, those without a secret, always send just their id in
+/// the request body.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenAuth {
+    /// HTTP basic auth with the client id and secret, the OAuth 2.0
+    /// default, and the only one Twitter accepts.
+    #[default]
+    Basic,
+    /// The id and secret as `client_id` and `client_secret` parameters.
+    RequestBody,
 }
 
-pub fn client_for(provider: &str) -> Option<ScopedClient> {
-    if valid_provider(provider) {
-        let mut provider_map = CLIENTS.lock().unwrap();
-        if !provider_map.contains_key(provider) {
-            // Important: the root domain host cannot have a numeric IP address.
-            let root_domain = env::var("JELLY_DOMAIN").expect("JELLY_DOMAIN not set!");
-            // Important: the redirect_uri must have the trailing slash,
-            // and it must be registered with the OAuth provider.
-            let redirect_uri = format!("{}/oauth/callback", root_domain);
-            let client = build_client(provider, &redirect_uri);
-            provider_map.insert(provider.to_string(), client);
-        }
-        match provider_map.get(provider) {
-            // TODO 104: can we avoid client.clone() ?
-            Some(Some(client)) => Some(client.clone()),
-            _ => None,
+impl From<TokenAuth> for AuthType {
+    fn from(auth: TokenAuth) -> Self {
+        match auth {
+            TokenAuth::Basic => AuthType::BasicAuth,
+            TokenAuth::RequestBody => AuthType::RequestBody,
         }
-    } else {
-        None
     }
 }
 
-struct ClientConfig<'a> {
-    redirect_uri: &'a str,
-    client_id_env: &'a str,
-    client_secret_env: Option<&'a str>,
-    auth_url: &'a str,
-    token_url: &'a str,
-    revoke_url: Option<&'a str>,
-    scopes: &'a [&'a str],
-    login_hint_key: Option<&'a str>,
-    user_info_uri: &'a str,
-    user_info_params: &'a [(&'a str, &'a str)],
-    user_info_headers: &'a [(&'a [u8], &'a str)],
-    user_info_deserializer: UserInfoDeserializer,
+/// One `[oauth.providers.<name>]` table. The client id and secret are
+/// given either directly or as the name of an environment variable that
+/// holds them, to keep them out of the configuration file. Without a
+/// secret the provider is used as a public client, relying on PKCE.
+#[derive(Deserialize)]
+pub struct ProviderConfig {
+    pub kind: ProviderKind,
+    pub client_id: Option<String>,
+    pub client_id_env: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_secret_env: Option<String>,
+    /// How the secret is sent when exchanging and refreshing tokens.
+    #[serde(default)]
+    pub token_auth: TokenAuth,
+    pub auth_url: String,
+    pub token_url: String,
+    pub revoke_url: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// The authorization URL parameter the login email is passed in.
+    pub login_hint_key: Option<String>,
+    /// Whether the login form asks for an email to pass as a hint.
+    #[serde(default)]
+    pub uses_email_hint: bool,
+    pub user_info_url: String,
+    #[serde(default)]
+    pub user_info_params: BTreeMap<String, String>,
+    #[serde(default)]
+    pub user_info_headers: BTreeMap<String, String>,
 }
 
-impl<'a> From<ClientConfig<'a>> for ScopedClient {
-    fn from(cfg: ClientConfig<'a>) -> Self {
-        let client_id = ClientId::new(
-            env::var(cfg.client_id_env)
-                .unwrap_or_else(|_| panic!("Missing the {} environment variable.", cfg.client_id_env)),
-        );
-        let client_secret = cfg.client_secret_env.map(|secret_env| {
-            ClientSecret::new(
-                env::var(secret_env)
-                    .unwrap_or_else(|_| panic!("Missing the {} environment variable.", secret_env)),
-            )
-        });
-        let auth_url =
-            AuthUrl::new(cfg.auth_url.to_string()).expect("Invalid authorization endpoint URL");
-        let token_url = TokenUrl::new(cfg.token_url.to_string()).expect("Invalid token endpoint URL");
-
-        let mut inner = BasicClient::new(client_id, client_secret, auth_url, Some(token_url))
-            .set_redirect_uri(
-                RedirectUrl::new(cfg.redirect_uri.to_string()).expect("Invalid redirect URL"),
-            );
-
-        if let Some(revoke_url) = cfg.revoke_url {
-            let revocation_url =
-                RevocationUrl::new(revoke_url.to_string()).expect("Invalid revocation endpoint URL");
+/// A setting given directly, or else read from the named environment
+/// variable.
+fn setting(value: &Option<String>, env_name: &Option<String>, what: &str) -> error::Result<Option<String>> {
+    match (value, env_name) {
+        (Some(value), _) => Ok(Some(value.clone())),
+        (None, Some(name)) => env::var(name)
+            .map(Some)
+            .map_err(|_| anyhow!("{} environment variable {} is not set", what, name).into()),
+        (None, None) => Ok(None),
+    }
+}
+
+impl ProviderConfig {
+    /// Redirect URI must match exactly with registered.
+    fn build(&self, redirect_uri: &str) -> error::Result<ScopedClient> {
+        let client_id = setting(&self.client_id, &self.client_id_env, "client id")?
+            .ok_or_else(|| anyhow!("neither client_id nor client_id_env is set"))?;
+        let client_secret = setting(&self.client_secret, &self.client_secret_env, "client secret")?;
+
+        let auth_url = AuthUrl::new(self.auth_url.clone())
+            .map_err(|e| anyhow!("invalid auth_url: {}", e))?;
+        let token_url = TokenUrl::new(self.token_url.clone())
+            .map_err(|e| anyhow!("invalid token_url: {}", e))?;
+        let redirect_url = RedirectUrl::new(redirect_uri.to_string())
+            .map_err(|e| anyhow!("invalid redirect URL {}: {}", redirect_uri, e))?;
+
+        let mut inner = BasicClient::new(
+            ClientId::new(client_id),
+            client_secret.map(ClientSecret::new),
+            auth_url,
+            Some(token_url),
+        )
+        .set_redirect_uri(redirect_url)
+        .set_auth_type(self.token_auth.into());
+
+        if let Some(revoke_url) = &self.revoke_url {
+            let revocation_url = RevocationUrl::new(revoke_url.clone())
+                .map_err(|e| anyhow!("invalid revoke_url: {}", e))?;
             inner = inner.set_revocation_uri(revocation_url);
         }
 
-        Self {
+        // Checked here, as the user info request unwraps them.
+        url::Url::parse(&self.user_info_url)
+            .map_err(|e| anyhow!("invalid user_info_url: {}", e))?;
+        for (name, value) in self.user_info_headers.iter() {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| anyhow!("invalid user info header name {:?}", name))?;
+            HeaderValue::from_str(value)
+                .map_err(|_| anyhow!("invalid value for user info header {}", name))?;
+        }
+
+        Ok(ScopedClient {
             inner,
-            scopes: array_str_to_vec(cfg.scopes),
-            login_hint_key: cfg.login_hint_key.map(|key| key.to_string()),
+            scopes: self.scopes.clone(),
+            login_hint_key: self.login_hint_key.clone(),
             user_info_request: UserInfoRequest {
-                uri: cfg.user_info_uri.to_string(),
-                params: array_tuple_str_to_vec(cfg.user_info_params),
-                headers: array_tuple_u8_to_vec(cfg.user_info_headers),
-                deserializer: cfg.user_info_deserializer,
+                uri: self.user_info_url.clone(),
+                params: self.user_info_params.clone().into_iter().collect(),
+                headers: self.user_info_headers.iter()
+                    .map(|(name, value)| (name.as_bytes().to_vec(), value.clone()))
+                    .collect(),
+                deserializer: self.kind.deserializer(),
             },
-        }
+        })
     }
 }
 
-fn array_str_to_vec(a: &[&str]) -> Vec<String> {
-    a.iter().map(|&x| x.into()).collect()
+struct Provider {
+    client: Arc<ScopedClient>,
+    hints: ProviderHints,
+}
+
+/// The OAuth providers that could be set up, by name.
+#[derive(Default)]
+pub struct OAuthProviders(HashMap<String, Provider>);
+
+impl fmt::Debug for OAuthProviders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.keys()).finish()
+    }
 }
 
-fn array_tuple_str_to_vec(a: &[(&str, &str)]) -> Vec<(String, String)> {
-    a.iter().map(|&(k, v)| (k.into(), v.into())).collect()
+impl OAuthProviders {
+    /// Builds the providers in `oauth.providers`. One that can't be built
+    /// is logged and left out, so the rest still work.
+    pub fn from_figment(figment: &Figment) -> Self {
+        OAuthProviders(load(figment))
+    }
+
+    pub fn is_valid(&self, provider: &str) -> bool {
+        self.0.contains_key(provider)
+    }
+
+    pub fn hints(&self, provider: &str) -> Option<ProviderHints> {
+        self.0.get(provider).map(|provider| provider.hints)
+    }
+
+    /// The client for a provider, failing with `NotFound` if it isn't
+    /// configured. Clients are shared, not copied per request.
+    pub fn client(&self, provider: &str) -> error::Result<Arc<ScopedClient>> {
+        self.0.get(provider)
+            .map(|provider| Arc::clone(&provider.client))
+            .ok_or_else(|| error::Error::with_status(
+                anyhow!("OAuth provider {} is not configured", provider), Status::NotFound))
+    }
 }
 
-fn array_tuple_u8_to_vec(a: &[(&[u8], &str)]) -> Vec<(Vec<u8>, String)> {
-    a.iter().map(|&(k, v)| (k.into(), v.into())).collect()
+/// The URL providers redirect back to, under `oauth.domain`, or else
+/// `JELLY_DOMAIN`.
+fn redirect_uri(figment: &Figment) -> error::Result<String> {
+    let root_domain = match figment.extract_inner::<String>("oauth.domain") {
+        Ok(domain) => domain,
+        Err(_) => env::var("JELLY_DOMAIN")
+            .map_err(|_| anyhow!("neither oauth.domain nor JELLY_DOMAIN is set"))?,
+    };
+    // Important: the root domain host cannot have a numeric IP address.
+    // Important: the redirect_uri must have the trailing slash,
+    // and it must be registered with the OAuth provider.
+    Ok(format!("{}/oauth/callback", root_domain.trim_end_matches('/')))
 }
 
-/// Redirect URI must match exactly with registered.
-fn build_client<'a>(provider: &'a str, redirect_uri: &'a str) -> Option<ScopedClient> {
-    match provider {
-        "google" => Some(ClientConfig {
-            redirect_uri,
-            client_id_env: "GOOGLE_CLIENT_ID",
-            client_secret_env: Some("GOOGLE_CLIENT_SECRET"),
-            auth_url: "https://accounts.google.com/o/oauth2/v2/auth",
-            token_url: "https://oauth2.googleapis.com/token",
-            revoke_url: Some("https://oauth2.googleapis.com/revoke"),
-            scopes: &[
-                "https://www.googleapis.com/auth/userinfo.email",
-                "https://www.googleapis.com/auth/userinfo.profile",
-            ],
-            login_hint_key: Some("login_hint"),
-            user_info_uri: "https://www.googleapis.com/oauth2/v3/userinfo",
-            user_info_params: &[],
-            user_info_headers: &[(b"Accept", "application/json")],
-            user_info_deserializer: deserialize_google,
-        }),
-        "twitter" => Some(ClientConfig {
-            redirect_uri,
-            client_id_env: "TWITTER_CLIENT_ID",
-            client_secret_env: None,
-            auth_url: "https://twitter.com/i/oauth2/authorize",
-            token_url: "https://api.twitter.com/2/oauth2/token",
-            revoke_url: Some("https://api.twitter.com/2/oauth2/revoke"),
-            scopes: &["tweet.read", "users.read"],
-            login_hint_key: None,
-            user_info_uri: "https://api.twitter.com/2/users/me",
-            user_info_params: &[(
-                "user.fields",
-                "id,name,username,verified,url,profile_image_url",
-            )],
-            user_info_headers: &[(b"Accept", "application/json")],
-            user_info_deserializer: deserialize_twitter,
-        }),
-        "github" => Some(ClientConfig {
-            redirect_uri,
-            client_id_env: "GITHUB_CLIENT_ID",
-            client_secret_env: Some("GITHUB_CLIENT_SECRET"),
-            auth_url: "https://github.com/login/oauth/authorize",
-            token_url: "https://github.com/login/oauth/access_token",
-            revoke_url: None,
-            scopes: &["read:user"],
-            login_hint_key: Some("login"),
-            user_info_uri: "https://api.github.com/user",
-            user_info_params: &[],
-            user_info_headers: &[
-                (b"Accept", "application/vnd.github.v3+json"),
-                (b"User-Agent", "Zingg-Starter-App"),
-            ],
-            user_info_deserializer: deserialize_github,
-        }),
-        "facebook" => Some(ClientConfig {
-            redirect_uri,
-            client_id_env: "FACEBOOK_CLIENT_ID",
-            client_secret_env: Some("FACEBOOK_CLIENT_SECRET"),
-            auth_url: "https://www.facebook.com/v13.0/dialog/oauth",
-            token_url: "https://graph.facebook.com/v13.0/oauth/access_token",
-            revoke_url: None,
-            scopes: &["public_profile", "email"],
-            login_hint_key: None,
-            user_info_uri: "https://graph.facebook.com/v13.0/me",
-            user_info_params: &[],
-            user_info_headers: &[(b"Accept", "application/json")],
-            user_info_deserializer: deserialize_facebook,
-        }),
-        _ => None,
+fn load(figment: &Figment) -> HashMap<String, Provider> {
+    let mut providers = HashMap::new();
+
+    let tables = match figment.extract_inner::<BTreeMap<String, Value>>("oauth.providers") {
+        Ok(tables) => tables,
+        Err(e) if e.missing() => return providers,
+        Err(e) => {
+            rocket::error!("invalid oauth.providers configuration: {}", e);
+            return providers;
+        }
+    };
+
+    let redirect_uri = match redirect_uri(figment) {
+        Ok(redirect_uri) => redirect_uri,
+        Err(e) => {
+            rocket::error!("OAuth login disabled: {}", e);
+            return providers;
+        }
+    };
+
+    for (name, table) in tables {
+        let built = table.deserialize::<ProviderConfig>()
+            .map_err(error::Error::from)
+            .and_then(|config| Ok((config.build(&redirect_uri)?, config.uses_email_hint)));
+
+        match built {
+            Ok((client, uses_email_hint)) => {
+                let hints = ProviderHints { uses_email_hint };
+                providers.insert(name, Provider { client: Arc::new(client), hints });
+            },
+            Err(e) => rocket::error!("OAuth provider {} disabled: {}", name, e),
+        }
     }
-    .map(|cfg| cfg.into())
+
+    providers
+}
+
+/// Loads the `OAuthProviders` from the configuration at ignition, and
+/// manages them along with the `FlowStorage` from `oauth.flow_storage`.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_ignite("OAuth providers", |rocket| async move {
+        let providers = OAuthProviders::from_figment(rocket.figment());
+        rocket::info!("OAuth providers: {:?}", providers.0.keys().collect::<Vec<_>>());
+
+        let storage = rocket.figment().extract_inner::<FlowStorage>("oauth.flow_storage").unwrap_or_else(|e| {
+            if !e.missing() {
+                rocket::error!("invalid oauth.flow_storage, using cookies: {}", e);
+            }
+            FlowStorage::default()
+        });
+        rocket.manage(providers).manage(storage)
+    })
 }
 
 fn deserialize_google(json_body: &str, email: &str) -> serde_json::Result<UserInfo> {
@@ -242,6 +301,10 @@ fn deserialize_facebook(json_body: &str, email: &str) -> serde_json::Result<User
     parse_user_info::<FacebookUserInfo>(json_body, email)
 }
 
+fn deserialize_microsoft(json_body: &str, email: &str) -> serde_json::Result<UserInfo> {
+    parse_user_info::<MicrosoftUserInfo>(json_body, email)
+}
+
 fn parse_user_info<'de, T: Deserialize<'de> + Into<UserInfo>>(
     json_body: &'de str,
     email: &str,
@@ -354,3 +417,161 @@ impl From<FacebookUserInfo> for UserInfo {
         }
     }
 }
+
+/// Microsoft Graph `me` endpoint
+/// See https://docs.microsoft.com/en-us/graph/api/user-get
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MicrosoftUserInfo {
+    id: String,
+    display_name: String,
+    user_principal_name: String,
+    mail: Option<String>,
+}
+
+impl From<MicrosoftUserInfo> for UserInfo {
+    fn from(microsoft: MicrosoftUserInfo) -> Self {
+        UserInfo {
+            provider: "microsoft",
+            id: microsoft.id,
+            name: microsoft.display_name,
+            username: Some(microsoft.user_principal_name),
+            provider_email: microsoft.mail,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use oauth2::http::header::AUTHORIZATION;
+    use oauth2::{AuthorizationCode, HttpRequest, HttpResponse};
+    use rocket::figment::providers::{Format, Toml};
+
+    use super::*;
+
+    const PROVIDERS: &str = r#"
+        [oauth]
+        domain = "https://example.com"
+
+        [oauth.providers.github]
+        kind = "github"
+        client_id = "github-id"
+        client_secret = "github-secret"
+        auth_url = "https://github.com/login/oauth/authorize"
+        token_url = "https://github.com/login/oauth/access_token"
+        user_info_url = "https://api.github.com/user"
+    "#;
+
+    fn providers(toml: &str) -> OAuthProviders {
+        OAuthProviders::from_figment(&Figment::from(Toml::string(toml)))
+    }
+
+    /// A Twitter provider, with `settings` added to its table.
+    fn twitter(settings: &str) -> Arc<ScopedClient> {
+        let toml = format!(r#"
+            [oauth]
+            domain = "https://example.com"
+
+            [oauth.providers.twitter]
+            kind = "twitter"
+            client_id = "twitter-id"
+            auth_url = "https://twitter.com/i/oauth2/authorize"
+            token_url = "https://api.twitter.com/2/oauth2/token"
+            user_info_url = "https://api.twitter.com/2/users/me"
+            {}
+        "#, settings);
+        providers(&toml).client("twitter").unwrap()
+    }
+
+    /// The request `client` makes to exchange a code for tokens, captured
+    /// instead of sent.
+    fn token_request(client: &ScopedClient) -> (Option<String>, String) {
+        let mut captured = None;
+        let _ = client.inner
+            .exchange_code(AuthorizationCode::new("the-code".to_string()))
+            .request(|request: HttpRequest| -> Result<HttpResponse, io::Error> {
+                captured = Some(request);
+                Err(io::Error::new(io::ErrorKind::Other, "not sent"))
+            });
+
+        let request = captured.expect("no token request was made");
+        let authorization = request.headers.get(AUTHORIZATION)
+            .map(|value| value.to_str().unwrap().to_string());
+        (authorization, String::from_utf8(request.body).unwrap())
+    }
+
+    #[test]
+    fn configured_providers_are_loaded() {
+        let providers = providers(PROVIDERS);
+        assert!(providers.is_valid("github"));
+        assert!(!providers.is_valid("google"));
+        assert_eq!(providers.client("google").err().map(|e| e.status), Some(Status::NotFound));
+    }
+
+    #[test]
+    fn concurrent_lookups_share_one_client() {
+        let providers = providers(PROVIDERS);
+        let first = providers.client("github").unwrap();
+
+        std::thread::scope(|scope| {
+            let lookups: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| providers.client("github").unwrap()))
+                .collect();
+            for lookup in lookups {
+                assert!(Arc::ptr_eq(&first, &lookup.join().unwrap()));
+            }
+        });
+    }
+
+    #[test]
+    fn public_twitter_clients_send_only_their_id() {
+        let (authorization, body) = token_request(&twitter(""));
+        assert_eq!(authorization, None);
+        assert!(body.contains("client_id=twitter-id"), "{}", body);
+        assert!(!body.contains("client_secret"), "{}", body);
+    }
+
+    #[test]
+    fn confidential_twitter_clients_use_basic_auth() {
+        let (authorization, body) = token_request(&twitter(r#"client_secret = "twitter-secret""#));
+        // base64 of "twitter-id:twitter-secret"
+        assert_eq!(authorization.as_deref(), Some("Basic dHdpdHRlci1pZDp0d2l0dGVyLXNlY3JldA=="));
+        assert!(!body.contains("client_secret"), "{}", body);
+        assert!(body.contains("code=the-code"), "{}", body);
+    }
+
+    #[test]
+    fn authorize_urls_carry_the_flow_parameters() {
+        let client = twitter(r#"
+            scopes = ["tweet.read", "users.read"]
+            login_hint_key = "login_hint"
+        "#);
+        let (url, csrf_token, _) = client.authorize_url(Some("me@example.com"));
+
+        let (base, query) = url.split_once('?').unwrap();
+        assert_eq!(base, "https://twitter.com/i/oauth2/authorize");
+        let params: HashMap<String, String> = serde_urlencoded::from_str(query).unwrap();
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["client_id"], "twitter-id");
+        assert_eq!(params["redirect_uri"], "https://example.com/oauth/callback");
+        assert_eq!(params["scope"], "tweet.read users.read");
+        assert_eq!(params["state"], *csrf_token.secret());
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert!(!params["code_challenge"].is_empty());
+        assert_eq!(params["login_hint"], "me@example.com");
+    }
+
+    #[test]
+    fn request_body_auth_sends_the_secret_as_parameters() {
+        let (authorization, body) = token_request(&twitter(r#"
+            client_secret = "twitter-secret"
+            token_auth = "request_body"
+        "#));
+        assert_eq!(authorization, None);
+        assert!(body.contains("client_id=twitter-id"), "{}", body);
+        assert!(body.contains("client_secret=twitter-secret"), "{}", body);
+    }
+}
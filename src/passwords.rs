@@ -1,18 +1,77 @@
-//! Password strength checks
+//! Password hashing, strength and breach checks
 
 use std::collections::HashSet;
+use std::convert::TryFrom;
 
+use anyhow::anyhow;
+use argon2::password_hash::{self, rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use djangohashers as legacy;
 use fancy_regex::Regex;
 use rocket::form;
 use rocket::form::{Error, Errors, FromFormField};
 use serde::{Deserialize, Serialize};
-use zxcvbn::zxcvbn;
+use sha1::{Digest, Sha1};
+use zxcvbn::{zxcvbn, Entropy, ZxcvbnError};
+
+use crate::error;
+
+#[cfg(test)]
+thread_local! {
+    /// How many times `verify` ran on this thread, for tests that check
+    /// a code path pays for a hash check.
+    pub static VERIFY_CALLS: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
+/// Hashes a password for storage, with Argon2id.
+pub fn hash(password: &str) -> error::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| error::Error::from(anyhow!("could not hash password: {}", e)))
+}
+
+/// Checks a password against a stored hash. Besides our own Argon2
+/// hashes, this accepts the Django-style hashes that passwords used to
+/// be stored as; see `needs_rehash`. A wrong password is `Ok(false)`;
+/// an error means the stored hash itself is corrupt or unsupported.
+pub fn verify(password: &str, encoded: &str) -> error::Result<bool> {
+    #[cfg(test)]
+    VERIFY_CALLS.with(|calls| calls.set(calls.get() + 1));
+
+    if encoded.starts_with('$') {
+        let parsed = PasswordHash::new(encoded)
+            .map_err(|e| error::Error::from(anyhow!("invalid password hash: {}", e)))?;
+        match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(error::Error::from(anyhow!("invalid password hash: {}", e))),
+        }
+    } else {
+        legacy::check_password(password, encoded)
+            .map_err(|_| error::Error::from(anyhow!("invalid password hash")))
+    }
+}
+
+/// Whether a stored hash is in a legacy format, or uses weaker Argon2
+/// parameters than we do now, and should be replaced with a fresh hash
+/// the next time we see the password.
+pub fn needs_rehash(encoded: &str) -> bool {
+    match PasswordHash::new(encoded) {
+        Ok(parsed) => {
+            parsed.algorithm != argon2::Algorithm::Argon2id.ident()
+                || argon2::Params::try_from(&parsed).map_or(true, |params| params != argon2::Params::default())
+        },
+        Err(_) => true,
+    }
+}
 
 /// For validating passwords. [`pattern`] is the regex that the
 /// password must match, and [`message`] is the user-facing
 /// error message that will be presented if the password does
 /// match the regex.
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct RegexConfig {
     pattern: Regex,
     message: String,
@@ -49,6 +108,109 @@ impl Default for RegexConfig {
     }
 }
 
+/// The pattern a password policy requires: `"anh"` or `"ulns"` for
+/// [`REGEX_ANH`] or [`REGEX_ULNS`], or else a regex of its own. Invalid
+/// regexes fail when the config is loaded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PasswordPattern {
+    source: String,
+    config: RegexConfig,
+}
+
+impl PasswordPattern {
+    pub fn config(&self) -> &RegexConfig {
+        &self.config
+    }
+}
+
+impl Default for PasswordPattern {
+    fn default() -> Self {
+        PasswordPattern {
+            source: "anh".to_string(),
+            config: REGEX_ANH.clone(),
+        }
+    }
+}
+
+impl TryFrom<String> for PasswordPattern {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let config = match source.as_str() {
+            "anh" => REGEX_ANH.clone(),
+            "ulns" => REGEX_ULNS.clone(),
+            pattern => RegexConfig {
+                pattern: Regex::new(pattern).map_err(|e| format!("invalid password pattern: {}", e))?,
+                message: "does not match the required pattern.".to_string(),
+            },
+        };
+
+        Ok(PasswordPattern { source, config })
+    }
+}
+
+impl From<PasswordPattern> for String {
+    fn from(pattern: PasswordPattern) -> Self {
+        pattern.source
+    }
+}
+
+/// The rules new passwords must follow, from the `passwords` section of
+/// the app config.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub pattern: PasswordPattern,
+    /// Shown instead of the pattern's own message when the password
+    /// doesn't match; useful with a custom pattern.
+    pub pattern_message: Option<String>,
+    pub min_score: PasswordScore,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            pattern: PasswordPattern::default(),
+            pattern_message: None,
+            min_score: PasswordScore::SafelyUnguessable,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// The policy's rules, for the API description.
+    pub fn constraints(&self) -> serde_json::Value {
+        serde_json::json!({
+            "min_length": self.min_length,
+            "pattern": self.pattern.config().pattern.as_str(),
+            "min_score": self.min_score.clone() as u8,
+        })
+    }
+
+    /// Checks length, then pattern, then strength, stopping at the first
+    /// rule that fails. `user_inputs` are the user's name, email and the
+    /// like, which the password shouldn't be built from.
+    pub fn validate<'v, T: AsRef<str>>(&self, password: &'v str, user_inputs: &[T]) -> form::Result<'v, ()> {
+        if password.chars().count() < self.min_length {
+            return Err(Error::validation(format!("must be at least {} characters.", self.min_length)).into());
+        }
+
+        match self.pattern.config().pattern.is_match(password) {
+            Ok(true) => {},
+            Ok(false) => {
+                let message = self.pattern_message.as_ref().unwrap_or(&self.pattern.config().message);
+                return Err(Error::validation(message.clone()).into());
+            },
+            Err(_) => return Err(Error::validation("bad pattern").into()),
+        }
+
+        validate_strength(password, self.min_score.clone(), user_inputs)
+    }
+}
+
 /// The mininum score of password attackability, as determined
 /// by the `zxcvbn` algorithm.
 #[repr(u8)]
@@ -110,21 +272,26 @@ pub fn validate_pattern<'v>(
     }
 }
 
-/// Validate password strength using zxcvbn algorithm.
-pub fn validate_strength<'v, T: AsRef<str>>(
-    password: &'v str,
-    strength: PasswordScore,
-    user_inputs: &[T],
-) -> form::Result<'v, ()> {
+/// Runs zxcvbn with the user's inputs added to its dictionary.
+fn estimate<T: AsRef<str>>(password: &str, user_inputs: &[T]) -> Result<Entropy, ZxcvbnError> {
     let words = split_inputs(user_inputs);
-    match zxcvbn(
+    zxcvbn(
         password,
         words
             .iter()
             .map(|s| s.as_ref())
             .collect::<Vec<&str>>()
             .as_slice(),
-    ) {
+    )
+}
+
+/// Validate password strength using zxcvbn algorithm.
+pub fn validate_strength<'v, T: AsRef<str>>(
+    password: &'v str,
+    strength: PasswordScore,
+    user_inputs: &[T],
+) -> form::Result<'v, ()> {
+    match estimate(password, user_inputs) {
         Err(_) => Err(Error::validation("cannot be blank").into()),
         Ok(estimate) if estimate.score() >= strength as u8 => Ok(()),
         Ok(estimate) => {
@@ -150,3 +317,107 @@ pub fn validate_strength<'v, T: AsRef<str>>(
         }
     }
 }
+
+/// Message for a password that `is_breached`.
+pub const BREACHED_MESSAGE: &str = "has appeared in a data breach, please choose another.";
+
+/// Asks the Have I Been Pwned range API whether the password is in a
+/// known breach. Only the first five characters of its SHA-1 hash leave
+/// the server (k-anonymity), and responses are padded so their size
+/// doesn't give the rest away. Returns `None` if the API can't be reached
+/// or answers with an error, so that callers can fail open.
+pub async fn is_breached(password: &str) -> Option<bool> {
+    let digest = format!("{:X}", Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = digest.split_at(5);
+    let url = format!("https://api.pwnedpasswords.com/range/{}", prefix);
+
+    let resp = rocket::tokio::task::spawn_blocking(move || {
+        minreq::get(url)
+            .with_header("Add-Padding", "true")
+            .with_header("User-Agent", "rocket-starterapp")
+            .with_timeout(5)
+            .send()
+    })
+    .await;
+
+    let resp = match resp {
+        Ok(Ok(resp)) if resp.status_code == 200 => resp,
+        Ok(Ok(resp)) => {
+            rocket::warn!("Breached password check failed with status {}", resp.status_code);
+            return None;
+        },
+        Ok(Err(e)) => {
+            rocket::warn!("Breached password check failed: {}", e);
+            return None;
+        },
+        Err(e) => {
+            rocket::warn!("Breached password check failed: {}", e);
+            return None;
+        },
+    };
+
+    // Each line is "<hash suffix>:<count>"; padding lines have a count of 0.
+    let body = resp.as_str().ok()?;
+    Some(body.lines().any(|line| match line.trim().split_once(':') {
+        Some((candidate, count)) => {
+            candidate.eq_ignore_ascii_case(suffix) && count.parse::<u64>().map_or(false, |n| n > 0)
+        },
+        None => false,
+    }))
+}
+
+/// How strong a password is, for showing to the user while they choose
+/// one.
+#[derive(Debug, Clone, Serialize)]
+pub struct PasswordFeedback {
+    /// The zxcvbn score, 0 to 4; see [`PasswordScore`].
+    pub score: u8,
+    /// Roughly how long an offline attack on a slow hash, at 10^4 guesses
+    /// a second, would take to crack it, e.g. "3 hours" or "centuries".
+    pub crack_time_display: String,
+    pub warning: Option<String>,
+    pub suggestions: Vec<String>,
+}
+
+/// zxcvbn's estimate for a password, with `user_inputs` as for
+/// [`validate_strength`]. A blank password gets the lowest score.
+pub fn password_feedback<T: AsRef<str>>(password: &str, user_inputs: &[T]) -> PasswordFeedback {
+    match estimate(password, user_inputs) {
+        Ok(estimate) => PasswordFeedback {
+            score: estimate.score(),
+            crack_time_display: estimate.crack_times().offline_slow_hashing_1e4_per_second().to_string(),
+            warning: estimate.feedback().as_ref()
+                .and_then(|feedback| feedback.warning())
+                .map(|warning| warning.to_string()),
+            suggestions: estimate.feedback().as_ref()
+                .map(|feedback| feedback.suggestions().iter().map(|s| s.to_string()).collect())
+                .unwrap_or_default(),
+        },
+        Err(_) => PasswordFeedback {
+            score: PasswordScore::TooGuessable as u8,
+            crack_time_display: "less than a second".to_string(),
+            warning: None,
+            suggestions: Vec::new(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weak_and_strong_passwords_get_different_estimates() {
+        let no_inputs: [&str; 0] = [];
+        let weak = password_feedback("password", &no_inputs);
+        let strong = password_feedback("Xq7#vL9!pR2@mZ4$wK8^tB", &no_inputs);
+
+        assert_eq!(weak.score, PasswordScore::TooGuessable as u8);
+        assert_eq!(weak.crack_time_display, "less than a second");
+        assert!(weak.warning.is_some());
+
+        assert_eq!(strong.score, PasswordScore::VeryUnguessable as u8);
+        assert_eq!(strong.crack_time_display, "centuries");
+        assert!(strong.warning.is_none());
+    }
+}
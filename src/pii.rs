@@ -0,0 +1,365 @@
+//! Optional encryption of personal data: the `email`, `canonical_email`
+//! and `name` columns of `accounts`, invited emails, and the provider
+//! tokens of `identities`.
+//!
+//! With the `pii-encryption` feature, values are encrypted with AES-SIV
+//! under the key in the `pii` config section (64 bytes, base64url encoded):
+//!
+//! ```toml
+//! [default.pii]
+//! encryption_key = "..."
+//! ```
+//!
+//! Ignition is aborted if the feature is on and the key is missing or
+//! unusable. Emails are encrypted deterministically, so an address always
+//! gives the same ciphertext and lookups like `WHERE email = $1`, and the
+//! unique indexes, keep working; the cost is that rows with the same email
+//! can be told apart from others. Names and tokens get a random nonce.
+//!
+//! Encrypted values are stored as `enc:` followed by base64url. Values
+//! stored before the feature was turned on are encrypted at ignite; until
+//! then, values without the prefix are read as plaintext.
+//!
+//! Without the feature, values pass through unchanged.
+
+use std::fmt;
+
+use rocket::fairing::AdHoc;
+use rocket::{Build, Rocket};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+use crate::error;
+
+/// Marks an encrypted value.
+const PREFIX: &str = "enc:";
+
+/// The `pii` config section.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PiiConfig {
+    /// With the `pii-encryption` feature, the key personal data is
+    /// encrypted with: 64 random bytes, base64url encoded. Changing it
+    /// makes existing accounts unreadable.
+    #[serde(skip_serializing)]
+    pub encryption_key: Option<String>,
+}
+
+impl fmt::Debug for PiiConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PiiConfig")
+            .field("encryption_key", &self.encryption_key.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+#[cfg(feature = "pii-encryption")]
+mod cipher {
+    use std::sync::OnceLock;
+
+    use aes_siv::aead::{Aead, NewAead};
+    use aes_siv::{Aes256SivAead, Nonce};
+    use anyhow::anyhow;
+
+    use super::PREFIX;
+    use crate::error;
+
+    const NONCE_LEN: usize = 16;
+
+    /// The key, as set at ignite, and its cipher.
+    static CIPHER: OnceLock<(Vec<u8>, Aes256SivAead)> = OnceLock::new();
+
+    /// Sets the key values are encrypted with. Fails if it isn't usable,
+    /// or another key is already in use.
+    pub fn set_key(key: &str) -> error::Result<()> {
+        let key = base64_url::decode(key)
+            .map_err(|_| anyhow!("pii.encryption_key is not valid base64url"))?;
+        let cipher = Aes256SivAead::new_from_slice(&key)
+            .map_err(|_| anyhow!("pii.encryption_key must be 64 bytes"))?;
+
+        let (current, _) = CIPHER.get_or_init(|| (key.clone(), cipher));
+        if *current != key {
+            return Err(anyhow!("another PII encryption key is already in use").into());
+        }
+
+        Ok(())
+    }
+
+    fn cipher() -> &'static Aes256SivAead {
+        &CIPHER.get().expect("the PII encryption key is set by pii::fairing at ignite").1
+    }
+
+    pub fn encrypt(plaintext: &str, nonce: [u8; NONCE_LEN]) -> String {
+        let ciphertext = cipher()
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .expect("AES-SIV encryption can't fail");
+        format!("{}{}", PREFIX, base64_url::encode(&[&nonce[..], &ciphertext].concat()))
+    }
+
+    pub fn decrypt(encoded: &str) -> error::Result<String> {
+        let bytes = base64_url::decode(encoded)
+            .map_err(|_| anyhow!("encrypted value is not valid base64url"))?;
+        if bytes.len() < NONCE_LEN {
+            return Err(anyhow!("encrypted value is too short").into());
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plaintext = cipher()
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("could not decrypt value, wrong pii.encryption_key?"))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+/// Sets up encryption from the `pii` config section and encrypts what
+/// was stored before it was turned on, aborting ignition if either
+/// fails. Attach after `database::migrations()`.
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("PII encryption", |rocket| async move {
+        let config = rocket.state::<AppConfig>()
+            .map(|config| config.pii.clone())
+            .unwrap_or_default();
+
+        set_up(rocket, config).await
+    })
+}
+
+#[cfg(not(feature = "pii-encryption"))]
+async fn set_up(rocket: Rocket<Build>, config: PiiConfig) -> rocket::fairing::Result {
+    if config.encryption_key.is_some() {
+        rocket::warn!("pii.encryption_key is set, but the pii-encryption feature is not enabled");
+    }
+
+    Ok(rocket)
+}
+
+#[cfg(feature = "pii-encryption")]
+async fn set_up(rocket: Rocket<Build>, config: PiiConfig) -> rocket::fairing::Result {
+    use rocket_db_pools::Database;
+
+    use crate::database::AppDb;
+
+    let key = match config.encryption_key {
+        Some(key) => key,
+        None => {
+            rocket::error!("the pii-encryption feature is enabled, but pii.encryption_key is not set");
+            return Err(rocket);
+        }
+    };
+    if let Err(e) = cipher::set_key(&key) {
+        rocket::error!("{}", e.error);
+        return Err(rocket);
+    }
+
+    let pool = match AppDb::fetch(&rocket) {
+        Some(db) => db.0.clone(),
+        None => {
+            rocket::error!("PII encryption: database pool not initialized");
+            return Err(rocket);
+        }
+    };
+
+    match encrypt_existing(&pool).await {
+        Ok(0) => Ok(rocket),
+        Ok(rows) => {
+            rocket::info!("encrypted the personal data of {} rows stored without encryption", rows);
+            Ok(rocket)
+        },
+        Err(e) => {
+            rocket::error!("could not encrypt existing personal data: {}", e.error);
+            Err(rocket)
+        }
+    }
+}
+
+/// `stored` sealed with `seal`, unless it already is.
+#[cfg(feature = "pii-encryption")]
+fn seal_plaintext(stored: &str, seal: fn(&str) -> String) -> String {
+    if stored.starts_with(PREFIX) {
+        stored.to_string()
+    } else {
+        seal(stored)
+    }
+}
+
+/// Encrypts the values of rows written before encryption was turned on,
+/// returning how many rows were changed.
+#[cfg(feature = "pii-encryption")]
+async fn encrypt_existing(pool: &sqlx::PgPool) -> error::Result<u64> {
+    let mut tx = pool.begin().await?;
+    let mut rows = 0;
+
+    let accounts = sqlx::query!(
+        "
+        SELECT id, name, email, canonical_email FROM accounts
+        WHERE name NOT LIKE 'enc:%' OR email NOT LIKE 'enc:%' OR canonical_email NOT LIKE 'enc:%'
+    "
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    for account in accounts {
+        sqlx::query!(
+            "
+            UPDATE accounts SET name = $2, email = $3, canonical_email = $4 WHERE id = $1
+        ",
+            account.id,
+            seal_plaintext(&account.name, seal),
+            seal_plaintext(&account.email, seal_email),
+            account.canonical_email.as_deref().map(|email| seal_plaintext(email, seal_email)),
+        )
+        .execute(&mut tx)
+        .await?;
+        rows += 1;
+    }
+
+    let identities = sqlx::query!(
+        "
+        SELECT id, refresh_token, access_token FROM identities
+        WHERE refresh_token NOT LIKE 'enc:%' OR access_token NOT LIKE 'enc:%'
+    "
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    for identity in identities {
+        sqlx::query!(
+            "
+            UPDATE identities SET refresh_token = $2, access_token = $3 WHERE id = $1
+        ",
+            identity.id,
+            identity.refresh_token.as_deref().map(|token| seal_plaintext(token, seal)),
+            identity.access_token.as_deref().map(|token| seal_plaintext(token, seal)),
+        )
+        .execute(&mut tx)
+        .await?;
+        rows += 1;
+    }
+
+    let invitations = sqlx::query!(
+        "
+        SELECT id, email FROM invitations WHERE email NOT LIKE 'enc:%'
+    "
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    for invitation in invitations {
+        sqlx::query!(
+            "
+            UPDATE invitations SET email = $2 WHERE id = $1
+        ",
+            invitation.id,
+            seal_email(&invitation.email),
+        )
+        .execute(&mut tx)
+        .await?;
+        rows += 1;
+    }
+
+    tx.commit().await?;
+    Ok(rows)
+}
+
+/// An email as it is stored, and as it must be given to queries that
+/// compare it with the `email` or `canonical_email` columns.
+pub fn seal_email(email: &str) -> String {
+    #[cfg(feature = "pii-encryption")]
+    return cipher::encrypt(email, [0; 16]);
+
+    #[cfg(not(feature = "pii-encryption"))]
+    email.to_string()
+}
+
+/// Any other personal value, such as a name or a token, as it is stored.
+pub fn seal(value: &str) -> String {
+    #[cfg(feature = "pii-encryption")]
+    return cipher::encrypt(value, rand::random());
+
+    #[cfg(not(feature = "pii-encryption"))]
+    value.to_string()
+}
+
+/// A stored value as plaintext.
+pub fn open(stored: &str) -> error::Result<String> {
+    match stored.strip_prefix(PREFIX) {
+        #[cfg(feature = "pii-encryption")]
+        Some(encoded) => cipher::decrypt(encoded),
+        #[cfg(not(feature = "pii-encryption"))]
+        Some(_) => Err(anyhow::anyhow!("found an encrypted value, but the pii-encryption feature is off").into()),
+        None => Ok(stored.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plaintext_is_read_as_is() {
+        assert_eq!(open("user@example.com").unwrap(), "user@example.com");
+    }
+
+    #[test]
+    fn key_is_redacted_from_debug_output() {
+        let config = PiiConfig { encryption_key: Some("secret".to_string()) };
+        assert!(!format!("{:?}", config).contains("secret"));
+    }
+
+    #[cfg(feature = "pii-encryption")]
+    #[test]
+    fn unusable_keys_are_refused() {
+        assert!(cipher::set_key("not base64!").is_err());
+        assert!(cipher::set_key(&base64_url::encode(&[0u8; 16])).is_err());
+    }
+
+    #[cfg(feature = "pii-encryption")]
+    #[test]
+    fn values_round_trip() {
+        cipher::set_key(&base64_url::encode(&[7u8; 64])).unwrap();
+
+        let email = seal_email("user@example.com");
+        assert!(email.starts_with(PREFIX));
+        assert_eq!(email, seal_email("user@example.com"));
+        assert_eq!(open(&email).unwrap(), "user@example.com");
+
+        let name = seal("Ann");
+        assert_ne!(name, seal("Ann"));
+        assert_eq!(open(&name).unwrap(), "Ann");
+        assert_eq!(seal_plaintext(&name, seal), name);
+    }
+
+    #[cfg(feature = "pii-encryption")]
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn emails_are_stored_encrypted_and_found_by_plaintext() {
+        use sqlx::Acquire;
+
+        use crate::config::EmailAliasPolicy;
+        use crate::database::test_connection;
+        use crate::models::Account;
+        use crate::routes::accounts::NewAccount;
+
+        let mut conn = test_connection().await;
+        let mut tx = conn.begin().await.unwrap();
+        cipher::set_key(&base64_url::encode(&[7u8; 64])).unwrap();
+
+        let email = format!("pii-{}@example.com", ulid::Ulid::new().to_string().to_lowercase());
+        let new_account = NewAccount { name: "Pii Test", email: &email, password: "a long test password" };
+        Account::register(&new_account, EmailAliasPolicy::default(), &mut tx).await.unwrap();
+
+        let account = Account::get_by_email(&email, EmailAliasPolicy::default(), &mut tx).await.unwrap();
+        assert_eq!(account.email, email);
+        assert_eq!(account.name, "Pii Test");
+
+        let (stored_email, stored_name): (String, String) =
+            sqlx::query_as("SELECT email, name FROM accounts WHERE id = $1")
+                .bind(account.id)
+                .fetch_one(&mut tx)
+                .await
+                .unwrap();
+        assert!(stored_email.starts_with(PREFIX));
+        assert!(!stored_email.contains(&email));
+        assert!(stored_name.starts_with(PREFIX));
+    }
+}
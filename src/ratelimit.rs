@@ -0,0 +1,192 @@
+//! In-memory request rate limiting, per client IP and route.
+//!
+//! Handlers opt in by taking a `RateLimit` guard, or a `TokenRateLimit`
+//! for the routes that check emailed tokens; the limits for each route
+//! come from the `rate_limits` config section (see `config`). Counts are
+//! kept per process, so several app servers each allow the full rate.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use rocket::http::Status;
+use rocket::request::{FromParam, FromRequest, Outcome, Request};
+
+use crate::config::{AppConfig, RateLimitRule};
+use crate::error;
+use crate::token::UserToken;
+
+/// Past this many tracked clients, idle entries are swept out.
+const SWEEP_THRESHOLD: usize = 10_000;
+
+/// Recent request times by client and route, managed as Rocket state.
+/// Clients are usually IP addresses, but can be any key, e.g. an account.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    hits: Mutex<HashMap<(String, String), VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    /// Records a request, returning false if it exceeds the rule over
+    /// the sliding window. Rejected requests aren't counted.
+    pub fn check(&self, client: &str, route: &str, rule: RateLimitRule) -> bool {
+        let now = Instant::now();
+        let window = Duration::from_secs(rule.window);
+        let mut hits = self.hits.lock().unwrap();
+
+        if hits.len() > SWEEP_THRESHOLD {
+            hits.retain(|_, times| times.back().map_or(false, |last| now.duration_since(*last) < window));
+        }
+
+        let times = hits.entry((client.to_string(), route.to_string())).or_default();
+        while times.front().map_or(false, |first| now.duration_since(*first) >= window) {
+            times.pop_front();
+        }
+
+        if times.len() >= rule.requests {
+            return false;
+        }
+
+        times.push_back(now);
+        true
+    }
+}
+
+/// Request guard that fails with `TooManyRequests` when the client has
+/// exceeded the limit configured for the route. Routes without a limit,
+/// and requests without a known client IP, always pass.
+pub struct RateLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimit {
+    type Error = error::Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let route_name = match req.route().and_then(|route| route.name.as_deref()) {
+            Some(name) => name,
+            None => return Outcome::Success(RateLimit),
+        };
+
+        let rule = req.rocket().state::<AppConfig>()
+            .and_then(|config| config.rate_limits.0.get(route_name).copied());
+        let limiter = req.rocket().state::<RateLimiter>();
+
+        match (rule, limiter, req.client_ip()) {
+            (Some(rule), Some(limiter), Some(ip)) if !limiter.check(&ip.to_string(), route_name, rule) => {
+                rocket::warn!("rate limit exceeded for {} on {}", ip, route_name);
+                Outcome::Failure((Status::TooManyRequests, error::Error::with_status(
+                    anyhow!("too many requests"), Status::TooManyRequests)))
+            },
+            _ => Outcome::Success(RateLimit),
+        }
+    }
+}
+
+/// The clients a token check counts against: the IP, and the IP together
+/// with the account the token is for. The account is never counted on
+/// its own, so no one can lock its owner out of their emailed links by
+/// guessing from elsewhere. Requests without a known IP aren't counted.
+fn token_clients(ip: Option<IpAddr>, uid: Option<&str>) -> Vec<String> {
+    let ip = match ip {
+        Some(ip) => ip.to_string(),
+        None => return vec![],
+    };
+
+    match uid {
+        Some(uid) => vec![format!("{}/uid:{}", ip, uid), ip],
+        None => vec![ip],
+    }
+}
+
+/// Request guard for routes ending in a `UserToken`, so that tokens can't
+/// be guessed by brute force. The route's limit applies per client IP,
+/// and per IP and account, going by the token's uid; see `token_clients`.
+/// It never fails: when the limit is exceeded the handler should respond
+/// as it does to an invalid token.
+pub struct TokenRateLimit {
+    pub exceeded: bool,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TokenRateLimit {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let route_name = match req.route().and_then(|route| route.name.as_deref()) {
+            Some(name) => name,
+            None => return Outcome::Success(TokenRateLimit { exceeded: false }),
+        };
+
+        let rule = req.rocket().state::<AppConfig>()
+            .and_then(|config| config.rate_limits.0.get(route_name).copied());
+        let (rule, limiter) = match (rule, req.rocket().state::<RateLimiter>()) {
+            (Some(rule), Some(limiter)) => (rule, limiter),
+            _ => return Outcome::Success(TokenRateLimit { exceeded: false }),
+        };
+
+        let uid = req.uri().path().segments().last()
+            .and_then(|segment| UserToken::from_param(segment).ok())
+            .and_then(|token| token.uidb64);
+
+        let exceeded = token_clients(req.client_ip(), uid.as_deref())
+            .iter()
+            .any(|client| !limiter.check(client, route_name, rule));
+
+        if exceeded {
+            rocket::warn!("token rate limit exceeded for {:?} on {}", req.client_ip(), route_name);
+        }
+        Outcome::Success(TokenRateLimit { exceeded })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULE: RateLimitRule = RateLimitRule { requests: 2, window: 60 };
+
+    fn ip(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([192, 0, 2, last]))
+    }
+
+    /// Whether a token check from `ip` for `uid` passes, as the guard
+    /// counts it.
+    fn token_check(limiter: &RateLimiter, ip: Option<IpAddr>, uid: &str) -> bool {
+        !token_clients(ip, Some(uid)).iter().any(|client| !limiter.check(client, "reset_password", RULE))
+    }
+
+    #[test]
+    fn requests_over_the_limit_are_refused() {
+        let limiter = RateLimiter::default();
+        assert!(limiter.check("192.0.2.1", "authenticate", RULE));
+        assert!(limiter.check("192.0.2.1", "authenticate", RULE));
+        assert!(!limiter.check("192.0.2.1", "authenticate", RULE));
+        assert!(limiter.check("192.0.2.2", "authenticate", RULE));
+        assert!(limiter.check("192.0.2.1", "request_reset", RULE));
+    }
+
+    #[test]
+    fn repeated_token_guesses_for_an_account_are_throttled() {
+        let limiter = RateLimiter::default();
+        assert!(token_check(&limiter, ip(1), "MQ"));
+        assert!(token_check(&limiter, ip(1), "MQ"));
+        assert!(!token_check(&limiter, ip(1), "MQ"));
+    }
+
+    #[test]
+    fn token_guesses_elsewhere_do_not_lock_the_account_out() {
+        let limiter = RateLimiter::default();
+        while token_check(&limiter, ip(1), "MQ") {}
+
+        assert!(token_check(&limiter, ip(2), "MQ"));
+    }
+
+    #[test]
+    fn token_clients_need_an_ip() {
+        assert!(token_clients(None, Some("MQ")).is_empty());
+        assert_eq!(token_clients(ip(1), None), vec!["192.0.2.1".to_string()]);
+        assert_eq!(token_clients(ip(1), Some("MQ")), vec!["192.0.2.1/uid:MQ".to_string(), "192.0.2.1".to_string()]);
+    }
+}
@@ -1,4 +1,11 @@
-use rocket::response::{Redirect, Responder};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+
+use rocket::form::Context;
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Flash, Redirect, Responder, Response};
+use rocket::serde::json::Json;
 use rocket_dyn_templates::Template;
 use serde::{Deserialize, Serialize};
 
@@ -6,6 +13,9 @@ use serde::{Deserialize, Serialize};
 pub enum RenderOrRedirect {
     Template(Template),
     Redirect(Redirect),
+    Flash(Flash<Redirect>),
+    Status(Status),
+    Json((Status, Json<serde_json::Value>)),
 }
 
 impl From<Template> for RenderOrRedirect {
@@ -20,6 +30,124 @@ impl From<Redirect> for RenderOrRedirect {
     }
 }
 
+impl From<Flash<Redirect>> for RenderOrRedirect {
+    fn from(f: Flash<Redirect>) -> Self {
+        Self::Flash(f)
+    }
+}
+
+impl From<Status> for RenderOrRedirect {
+    fn from(s: Status) -> Self {
+        Self::Status(s)
+    }
+}
+
+impl RenderOrRedirect {
+    pub fn json(status: Status, value: serde_json::Value) -> Self {
+        Self::Json((status, Json(value)))
+    }
+}
+
+/// The longest `next` path carried through a login.
+const MAX_NEXT_LENGTH: usize = 1024;
+
+/// Returns `next` if it is a path on this site, safe to redirect to.
+/// Anything a browser could resolve to another origin is refused:
+/// absolute and scheme-relative ("//host") URLs, backslashes, which some
+/// browsers treat as slashes, and whitespace or control characters.
+pub fn safe_next(next: &str) -> Option<&str> {
+    let safe = next.starts_with('/')
+        && !next.starts_with("//")
+        && next.len() <= MAX_NEXT_LENGTH
+        && !next.chars().any(|c| c == '\\' || c.is_whitespace() || c.is_control());
+
+    if safe { Some(next) } else { None }
+}
+
+/// Redirects to the login form, carrying the page the user asked for in
+/// a `next` query parameter so `authenticate` can send them back to it.
+/// An unsafe `next` is dropped.
+#[derive(Debug)]
+pub struct LoginRedirect(Option<String>);
+
+impl LoginRedirect {
+    pub fn new(next: Option<&str>) -> Self {
+        LoginRedirect(next.and_then(safe_next).map(String::from))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for LoginRedirect {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let query = self.0.and_then(|next| serde_urlencoded::to_string([("next", next)]).ok());
+        let redirect = match query {
+            Some(query) => Redirect::to(format!("/accounts/login?{}", query)),
+            None => Redirect::to("/accounts/login"),
+        };
+        redirect.respond_to(req)
+    }
+}
+
+/// A JSON document that browsers save as `filename` rather than show.
+#[derive(Debug)]
+pub struct JsonDownload<T> {
+    pub filename: String,
+    pub body: T,
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for JsonDownload<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let body = serde_json::to_string_pretty(&self.body).map_err(|e| {
+            rocket::error!("Could not serialize {}: {}", self.filename, e);
+            Status::InternalServerError
+        })?;
+
+        Response::build_from(body.respond_to(req)?)
+            .header(ContentType::JSON)
+            .raw_header("Content-Disposition", format!("attachment; filename=\"{}\"", self.filename))
+            .ok()
+    }
+}
+
+/// Request guard for the response format the client asked for. Clients
+/// that prefer `application/json` in their `Accept` header get JSON from
+/// the handlers that support it; everyone else gets HTML.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    pub fn is_json(&self) -> bool {
+        *self == Format::Json
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Format {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let json = req.accept()
+            .map_or(false, |accept| accept.preferred().media_type().is_json());
+
+        Outcome::Success(if json { Format::Json } else { Format::Html })
+    }
+}
+
+/// The validation errors in a form context, by field name, for JSON
+/// responses. Unlike the context itself, this leaves out the submitted
+/// values.
+pub fn form_errors(context: &Context<'_>) -> serde_json::Value {
+    let mut errors: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for error in context.errors() {
+        let name = error.name.as_ref().map(|name| name.to_string()).unwrap_or_default();
+        errors.entry(name).or_default().push(error.to_string());
+    }
+
+    serde_json::json!(errors)
+}
+
 /// A `FlashMessage` is a generic message that can be shoved into the Session
 /// between requests. This isn't particularly useful for JSON-based workflows, but
 /// for the traditional webapp side it works well.
@@ -29,13 +157,47 @@ struct FlashMessage {
     message: String,
 }
 
+/// The `flash_messages` for a template, for pages whose context is JSON
+/// rather than a `tera::Context`.
+pub fn flash_messages(flash: Option<rocket::request::FlashMessage>) -> serde_json::Value {
+    let messages: Vec<FlashMessage> = flash.into_iter()
+        .map(|msg| {
+            let (kind, message) = msg.into_inner();
+            FlashMessage { kind, message }
+        })
+        .collect();
+    serde_json::json!(messages)
+}
+
 pub fn flash_context(flash: Option<rocket::request::FlashMessage>) -> tera::Context {
     let mut context = tera::Context::new();
-    let mut messages: Vec<FlashMessage> = Vec::new();
-    if let Some(msg) = flash {
-        let (kind, message) = msg.into_inner();
-        messages.push(FlashMessage { kind, message });
-    }
-    context.insert("flash_messages", &messages);
+    context.insert("flash_messages", &flash_messages(flash));
     context
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_on_this_site_are_safe() {
+        assert_eq!(safe_next("/dashboard"), Some("/dashboard"));
+        assert_eq!(safe_next("/accounts/settings?tab=email"), Some("/accounts/settings?tab=email"));
+    }
+
+    #[test]
+    fn anything_that_can_leave_the_site_is_unsafe() {
+        for next in [
+            "https://evil.example.com/",
+            "//evil.example.com/",
+            "/\\evil.example.com",
+            "evil.example.com",
+            "/ /evil.example.com",
+            "/\tpath",
+            "",
+        ] {
+            assert_eq!(safe_next(next), None, "{:?}", next);
+        }
+        assert_eq!(safe_next(&format!("/{}", "a".repeat(MAX_NEXT_LENGTH))), None);
+    }
+}
@@ -1,6 +1,16 @@
 //! Rocket route handers
 
 pub mod accounts;
+pub mod admin;
+pub mod api;
+pub mod catchers;
+pub mod dashboard;
+pub mod dev;
+pub mod guests;
+pub mod health;
 #[cfg(feature = "oauth")]
 pub mod oauth;
 pub mod home;
+#[cfg(feature = "webauthn")]
+pub mod webauthn;
+pub mod webhooks;
@@ -1,100 +1,283 @@
 //! Accounts routes, mounted at "/accounts"
 
-use rocket::form::{Context, Contextual, Form, FromForm};
-use rocket::http::CookieJar;
+use rocket::data::ByteUnit;
+use rocket::form::{self, Context, Contextual, Error, Form, FromForm};
+use rocket::fs::TempFile;
+use rocket::http::{CookieJar, Status};
 use rocket::request::FlashMessage;
-use rocket::response::Redirect;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::json::Json;
 use rocket::uri;
-use rocket::{get, post};
+use rocket::tokio::io::AsyncReadExt;
+use rocket::{get, post, State};
 use rocket_db_pools::Connection;
 use rocket_dyn_templates::Template;
 use serde::{Deserialize, Serialize};
 
 use crate::auth;
+use crate::auth::{ClientInfo, PASSWORD_EXPIRED_MESSAGE};
+use crate::captcha::{self, CAPTCHA_MESSAGE};
+use crate::config::{AppConfig, RegistrationMode};
+use crate::csrf::{self, CsrfForm, CsrfToken, CSRF_FIELD};
 use crate::database::AppDb;
-use crate::jobs::{Message, PostgresQueue};
-use crate::models::{Account, User};
-use crate::passwords::{validate_pattern, validate_strength, REGEX_ANH,
-    PasswordScore::SafelyUnguessable};
-use crate::response::RenderOrRedirect;
+use crate::jobs::{Message, PostgresQueue, HIGH_PRIORITY};
+use crate::limits::AuthFormLimit;
+use crate::models::{Account, AccountExport, Identity, Invitation, ProfileField, User, WebauthnCredential};
+use crate::oauth::client::OAuthProviders;
+use crate::passwords;
+use crate::ratelimit::{RateLimit, TokenRateLimit};
+use crate::response::{flash_messages, form_errors, safe_next, Format, JsonDownload, RenderOrRedirect};
+use crate::routes::api::{Describe, FieldDescription};
+use crate::storage::{self, FileStorage};
 use crate::token::UserToken;
 
 #[derive(Clone, Debug, Default, Deserialize, FromForm, Serialize)]
 pub struct NewAccount<'v> {
     #[field(validate = len(1..))]
     pub name: &'v str,
+    /// Checked against `AppConfig::email_domains` by the handler.
     #[field(validate = contains('@').or_else(msg!("invalid email address")))]
     pub email: &'v str,
-    #[field(validate = len(8..))]
-    #[field(validate = validate_pattern(&REGEX_ANH))]
-    #[field(validate = validate_strength(SafelyUnguessable, vec![self.name, self.email].as_slice()))]
+    /// Checked against `AppConfig::passwords` by the handler.
     pub password: &'v str,
 }
 
 #[derive(Debug, FromForm)]
 pub struct NewAccountSubmit<'v> {
     account: NewAccount<'v>,
+    /// The invitation token, with `registration = "invite_only"`.
+    invite: Option<&'v str>,
 }
 
-/// Show the registration form.
-#[get("/register")]
+impl Describe for NewAccountSubmit<'_> {
+    fn describe(config: &AppConfig) -> Vec<FieldDescription> {
+        let mut email = serde_json::json!({ "contains": "@", "disposable_domain": false });
+        if !config.email_domains.allow.is_empty() {
+            email["allowed_domains"] = serde_json::json!(config.email_domains.allow);
+        }
+
+        let mut fields = vec![
+            FieldDescription::string("account.name", serde_json::json!({ "min_length": 1 })),
+            FieldDescription::string("account.email", email),
+            FieldDescription::string("account.password", password_constraints(config)),
+        ];
+        if !config.accounts.registration.is_open() {
+            fields.push(FieldDescription::string("invite", serde_json::json!({ "invitation_for_email": true })));
+        }
+        fields
+    }
+}
+
+/// Shown, with `registration = "invite_only"`, when the form has no
+/// invitation for its email.
+const INVALID_INVITATION_MESSAGE: &str =
+    "this invitation is invalid, used or expired, or was sent to another address";
+
+/// The parts of a user that API clients see.
+fn user_json(user: &User) -> serde_json::Value {
+    serde_json::json!({
+        "id": user.id,
+        "name": user.name,
+        "is_admin": user.is_admin,
+    })
+}
+
+/// Checks a submitted password against the configured policy and, if
+/// `check_breached_passwords` is on, against known breaches. This runs in
+/// the handlers rather than as field validators, which can't read the
+/// config or make requests.
+async fn validate_password<'v>(config: &AppConfig, password: &'v str, user_inputs: &[&str]) -> form::Result<'v, ()> {
+    config.passwords.validate(password, user_inputs)?;
+
+    if config.accounts.check_breached_passwords && passwords::is_breached(password).await == Some(true) {
+        return Err(Error::validation(passwords::BREACHED_MESSAGE).into());
+    }
+
+    Ok(())
+}
+
+/// What `validate_password` checks, for the API description.
+fn password_constraints(config: &AppConfig) -> serde_json::Value {
+    let mut constraints = config.passwords.constraints();
+    constraints["not_breached"] = config.accounts.check_breached_passwords.into();
+    constraints
+}
+
+/// Show the registration form, carrying the `invite` token from an
+/// invitation link. JSON clients get the CSRF token to submit with it.
+#[get("/register?<invite>")]
 pub async fn registration_form<'a>(
     // flash: Option<FlashMessage<'_>>,
     cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
+    format: Format,
+    config: &State<AppConfig>,
+    invite: Option<&str>,
 ) -> RenderOrRedirect {
+    if format.is_json() {
+        return RenderOrRedirect::json(Status::Ok, serde_json::json!({ "csrf": csrf.value() }));
+    }
+
     if auth::is_authenticated(cookies) {
         return Redirect::to(uri!("/dashboard")).into();
     }
 
-    let context = Context::default();
-    Template::render("accounts/register", &context).into()
+    let mut context = csrf::form_context(&csrf);
+    if let Some(invite) = invite {
+        context["values"]["invite"] = serde_json::json!([invite]);
+    }
+    Template::render("accounts/register", register_context(context, config)).into()
+}
+
+/// The registration form's context, plus the CAPTCHA widget, if it's on,
+/// and whether an invitation is needed.
+fn register_context(form: serde_json::Value, config: &AppConfig) -> serde_json::Value {
+    let mut context = form;
+    context["captcha"] = config.captcha.widget();
+    context["invite_only"] = (!config.accounts.registration.is_open()).into();
+    context
 }
 
-/// POST-handler for registering a new account.
+/// Seconds within which a repeated registration for the same address
+/// doesn't queue another email.
+const REGISTRATION_JOB_WINDOW: i64 = 600;
+
+/// POST-handler for registering a new account. JSON clients get
+/// `{ "user": null }` with `Accepted` on success, since the account
+/// isn't usable until verified, or `{ "errors": ... }`.
 #[post("/register", data = "<form>")]
 pub async fn create_account<'a>(
+    _limit: AuthFormLimit,
     // flash: Option<FlashMessage<'_>>,
     cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
+    format: Format,
     mut db: Connection<AppDb>,
-    form: Form<Contextual<'a, NewAccountSubmit<'a>>>,
+    config: &State<AppConfig>,
+    client: ClientInfo,
+    mut form: Form<Contextual<'a, NewAccountSubmit<'a>>>,
     queue: PostgresQueue,
 ) -> RenderOrRedirect {
+    if let Err(status) = csrf.verify(form.context.field_value(CSRF_FIELD)) {
+        return status.into();
+    }
+
     if auth::is_authenticated(cookies) {
         return Redirect::to(uri!("/dashboard")).into();
     }
 
+    let email = form.value.as_ref().map(|value| value.account.email);
+    if let Some(Err(errors)) = email.map(|email| config.email_domains.validate(email)) {
+        form.context.push_errors(errors.with_name("account.email"));
+        form.value = None;
+    }
+
+    let submitted = form.value.as_ref()
+        .map(|value| (value.account.password, [value.account.name, value.account.email]));
+    if let Some((password, user_inputs)) = submitted {
+        if let Err(errors) = validate_password(config, password, &user_inputs).await {
+            form.context.push_errors(errors.with_name("account.password"));
+            form.value = None;
+        }
+    }
+
+    let invited = form.value.as_ref()
+        .map(|value| (value.invite.unwrap_or_default(), value.account.email));
+    if let (RegistrationMode::InviteOnly, Some((token, email))) = (config.accounts.registration, invited) {
+        if !Invitation::is_valid(token, email, db.as_mut()).await.unwrap_or(false) {
+            form.context.push_error(Error::validation(INVALID_INVITATION_MESSAGE).with_name("invite"));
+            form.value = None;
+        }
+    }
+
+    // Only checked for otherwise valid forms, to spare the provider.
+    if form.value.is_some() && config.captcha.is_enabled() {
+        let token = form.context.field_value(config.captcha.provider.response_field());
+        if !captcha::verify(&config.captcha, token, client.ip.as_deref()).await {
+            form.context.push_error(Error::validation(CAPTCHA_MESSAGE));
+            form.value = None;
+        }
+    }
+
     match &form.value {
         // Form parsed successfully. value is the `NewAccountSubmit`.
         Some(value) => {
             let conn: &mut sqlx::PgConnection = db.as_mut();
-            let _ignore = match Account::register(&value.account, conn).await {
-                Ok(email) => queue.push(Message::SendVerifyAccountEmail(email), None).await,
+            let aliases = config.accounts.email_aliases;
+            let registered = match config.accounts.registration {
+                RegistrationMode::Open => Account::register(&value.account, aliases, conn).await,
+                RegistrationMode::InviteOnly =>
+                    Account::register_invited(&value.account, value.invite.unwrap_or_default(), aliases, conn).await,
+            };
+            // Keyed by address, so a retried submit doesn't queue a second email.
+            let _ignore = match registered {
+                Ok(email) => queue.push_idempotent(
+                    &format!("verify-account:{}", email.to_lowercase()),
+                    REGISTRATION_JOB_WINDOW,
+                    Message::SendVerifyAccountEmail(email),
+                ).await,
                 Err(e) => {
-                    rocket::error!("Error with registering: {:?}", e);
-                    queue
-                        .push(
-                            Message::SendAccountOddRegisterAttemptEmail(
-                                value.account.email.to_string(),
-                            ),
-                            None,
-                        )
-                        .await
+                    tracing::error!(error = ?e, "could not register account");
+                    queue.push_idempotent(
+                        &format!("odd-registration-attempt:{}", value.account.email.to_lowercase()),
+                        REGISTRATION_JOB_WINDOW,
+                        Message::SendAccountOddRegisterAttemptEmail(value.account.email.to_string()),
+                    ).await
                 }
             };
 
             // No matter what, just appear as if it worked.
+            if format.is_json() {
+                return RenderOrRedirect::json(Status::Accepted, serde_json::json!({ "user": null }));
+            }
             Redirect::to(uri!("/accounts/verify")).into()
         }
-        None => Template::render("accounts/register", &form.context).into(),
+        None if format.is_json() =>
+            RenderOrRedirect::json(Status::UnprocessableEntity, serde_json::json!({ "errors": form_errors(&form.context) })),
+        None => {
+            let context = serde_json::to_value(&form.context).unwrap_or_default();
+            Template::render("accounts/register", register_context(context, config)).into()
+        },
     }
 }
 
+#[derive(Debug, FromForm)]
+pub struct StrengthCheck<'v> {
+    password: &'v str,
+    name: Option<&'v str>,
+    email: Option<&'v str>,
+}
+
+impl Describe for StrengthCheck<'_> {
+    fn describe(_config: &AppConfig) -> Vec<FieldDescription> {
+        vec![
+            FieldDescription::string("password", serde_json::json!({})),
+            FieldDescription::string("name", serde_json::json!({})).optional(),
+            FieldDescription::string("email", serde_json::json!({})).optional(),
+        ]
+    }
+}
+
+/// Rates a password while it's being typed, for the registration and
+/// reset forms. Nothing is stored or changed, so no CSRF token is needed.
+#[post("/password-strength", data = "<form>")]
+pub async fn password_strength<'a>(
+    _limit: AuthFormLimit,
+    form: Form<StrengthCheck<'a>>,
+) -> Json<passwords::PasswordFeedback> {
+    let user_inputs: Vec<&str> = form.name.into_iter().chain(form.email).collect();
+    Json(passwords::password_feedback(form.password, &user_inputs))
+}
+
 #[derive(Clone, Debug, Default, Deserialize, FromForm, Serialize)]
 pub struct LoginData<'v> {
     #[field(validate = contains('@').or_else(msg!("invalid email address")))]
     pub email: &'v str,
     #[field(validate = len(1..))]
     pub password: &'v str,
+    /// Keep the session across browser restarts, for
+    /// `accounts.session_remember_ttl`.
+    pub remember: bool,
 }
 
 #[derive(Debug, FromForm)]
@@ -102,53 +285,186 @@ pub struct LoginSubmit<'v> {
     account: LoginData<'v>,
 }
 
-/// Show the login form.
-#[get("/login")]
+impl Describe for LoginSubmit<'_> {
+    fn describe(_config: &AppConfig) -> Vec<FieldDescription> {
+        vec![
+            FieldDescription::string("account.email", serde_json::json!({ "contains": "@" })),
+            FieldDescription::string("account.password", serde_json::json!({ "min_length": 1 })),
+            FieldDescription::boolean("account.remember"),
+        ]
+    }
+}
+
+/// Shown when the email or password is wrong, without saying which, so
+/// the form can't be used to find out who has an account.
+const INVALID_LOGIN_MESSAGE: &str = "invalid email or password";
+
+/// Shown, with `require_verified_email`, when the account's email isn't
+/// verified yet.
+const UNVERIFIED_EMAIL_MESSAGE: &str = "please verify your email before logging in; you can have the link sent again here";
+
+/// Where to send a user who just logged in: back to the `next` page
+/// they asked for, if it is safe, or else the dashboard.
+fn after_login(next: Option<&str>) -> Redirect {
+    match next.and_then(safe_next) {
+        Some(next) => Redirect::to(next.to_string()),
+        None => Redirect::to(uri!("/dashboard")),
+    }
+}
+
+/// Show the login form. JSON clients get the CSRF token to submit
+/// with it. A safe `next` path is passed along in a hidden field.
+#[get("/login?<next>")]
 pub async fn login_form<'a>(
-    // flash: Option<FlashMessage<'_>>,
+    flash: Option<FlashMessage<'_>>,
     cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
+    format: Format,
+    next: Option<&str>,
 ) -> RenderOrRedirect {
+    if format.is_json() {
+        return RenderOrRedirect::json(Status::Ok, serde_json::json!({ "csrf": csrf.value() }));
+    }
+
     if auth::is_authenticated(cookies) {
-        return Redirect::to(uri!("/dashboard")).into();
+        return after_login(next).into();
     }
 
-    let context = Context::default();
-    Template::render("accounts/login", &context).into()
+    let mut context = csrf::form_context(&csrf);
+    if let Some(next) = next.and_then(safe_next) {
+        context["values"]["next"] = serde_json::json!([next]);
+    }
+    context["flash_messages"] = flash_messages(flash);
+
+    Template::render("accounts/login", context).into()
 }
 
-/// POST-handler for logging in.
+/// POST-handler for logging in. Browsers are redirected to the `next`
+/// field's path, if safe, or the dashboard. JSON clients get
+/// `{ "user": ... }`, or `{ "errors": ... }` with `Unauthorized`,
+/// `Forbidden` (the password must be changed) or `UnprocessableEntity`.
 #[post("/login", data = "<form>")]
 pub async fn authenticate<'a>(
+    _limit: AuthFormLimit,
+    _rate: RateLimit,
     // flash: Option<FlashMessage<'_>>,
     cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
+    format: Format,
     mut db: Connection<AppDb>,
-    form: Form<Contextual<'a, LoginSubmit<'a>>>,
+    config: &State<AppConfig>,
+    client: ClientInfo,
+    mut form: Form<Contextual<'a, LoginSubmit<'a>>>,
+    queue: PostgresQueue,
 ) -> RenderOrRedirect {
+    if let Err(status) = csrf.verify(form.context.field_value(CSRF_FIELD)) {
+        return status.into();
+    }
+
+    let next = form.context.field_value("next");
+
     if auth::is_authenticated(cookies) {
-        return Redirect::to(uri!("/dashboard")).into();
+        if format.is_json() {
+            if let Ok(user) = auth::user(cookies) {
+                return RenderOrRedirect::json(Status::Ok, serde_json::json!({ "user": user_json(&user) }));
+            }
+        }
+        return after_login(next).into();
     }
 
     if let Some(value) = &form.value {
         // Form parsed successfully. value is the `LoginSubmit`.
         let conn: &mut sqlx::PgConnection = db.as_mut();
-        if let Ok(user) = Account::authenticate(&value.account, conn).await {
+        let authenticated = match Account::authenticate(&value.account, config.accounts.require_verified_email, config.accounts.email_aliases, conn).await {
+            Err(e) if e.status == Status::Forbidden => {
+                if format.is_json() {
+                    return RenderOrRedirect::json(Status::Forbidden, serde_json::json!({
+                        "errors": { "account.email": [UNVERIFIED_EMAIL_MESSAGE] },
+                    }));
+                }
+                return Flash::error(Redirect::to(uri!("/accounts/resend")), UNVERIFIED_EMAIL_MESSAGE).into();
+            },
+            Err(e) if e.status != Status::Unauthorized => return e.status.into(),
+            result => result,
+        };
+        if let Ok(user) = authenticated {
+            // Past the password rotation deadline, the password is only
+            // good for having another link to set a new one sent.
+            match auth::password_expired(user.id, &queue, conn).await {
+                Ok(false) => {},
+                Ok(true) => {
+                    if format.is_json() {
+                        return RenderOrRedirect::json(Status::Forbidden, serde_json::json!({
+                            "errors": { "account.password": [PASSWORD_EXPIRED_MESSAGE] },
+                        }));
+                    }
+                    form.context.push_error(Error::validation(PASSWORD_EXPIRED_MESSAGE).with_name("account.password"));
+                    return Template::render("accounts/login", &form.context).into();
+                },
+                Err(_) => return Status::InternalServerError.into(),
+            }
+
             let _ignore = Account::update_last_login(user.id, conn).await;
-            auth::set_user(cookies, user);
-            return Redirect::to(uri!("/dashboard")).into();
+            client.record_login(config, user.id, conn).await;
+            let body = serde_json::json!({ "user": user_json(&user) });
+            auth::set_user(cookies, config, user, value.account.remember);
+            if format.is_json() {
+                return RenderOrRedirect::json(Status::Ok, body);
+            }
+            return Flash::success(after_login(next), "You are logged in.").into();
+        }
+
+        if format.is_json() {
+            return RenderOrRedirect::json(Status::Unauthorized, serde_json::json!({
+                "errors": { "account": [INVALID_LOGIN_MESSAGE] },
+            }));
         }
+        form.context.push_error(Error::validation(INVALID_LOGIN_MESSAGE));
+    }
+
+    if format.is_json() {
+        return RenderOrRedirect::json(Status::UnprocessableEntity, serde_json::json!({ "errors": form_errors(&form.context) }));
     }
 
     Template::render("accounts/login", &form.context).into()
 }
 
 /// Just renders a standard "Check your email and verify" page.
-#[post("/logout")]
+#[post("/logout", data = "<form>")]
 pub async fn logout<'a>(
-    // flash: Option<FlashMessage<'_>>,
     cookies: &CookieJar<'a>,
-) -> Redirect {
-    auth::clear_user(cookies);
-    Redirect::to(uri!("/"))
+    csrf: CsrfToken,
+    config: &State<AppConfig>,
+    form: Form<CsrfForm<'a>>,
+) -> Result<Flash<Redirect>, Status> {
+    csrf.verify(form.csrf)?;
+    auth::clear_user(cookies, config);
+    Ok(Flash::success(Redirect::to(uri!("/")), "You have been logged out."))
+}
+
+/// Logs the user out of every session, on every device, by bumping the
+/// account's session version. Other sessions are rejected on their next
+/// request, or once `accounts.session_cache_ttl` has passed.
+#[post("/logout-all", data = "<form>")]
+pub async fn logout_all<'a>(
+    user: User,
+    cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
+    mut db: Connection<AppDb>,
+    config: &State<AppConfig>,
+    form: Form<CsrfForm<'a>>,
+) -> Result<Flash<Redirect>, Status> {
+    csrf.verify(form.csrf)?;
+
+    let conn: &mut sqlx::PgConnection = db.as_mut();
+    if let Err(e) = Account::bump_session_version(user.id, conn).await {
+        tracing::error!(account_id = user.id, error = ?e, "could not log out account everywhere");
+        return Err(Status::InternalServerError);
+    }
+
+    auth::forget_session(user.id);
+    auth::clear_user(cookies, config);
+    Ok(Flash::success(Redirect::to(uri!("/")), "You have been logged out everywhere."))
 }
 
 /// Just renders a standard "Check your email and verify" page.
@@ -164,27 +480,53 @@ pub async fn verify<'a>(
 /// token and user, signs them in, and redirects to the dashboard.
 ///
 /// In general, we do not want to leak information, so any errors here
-/// should simply report as "invalid or expired".
+/// should simply report as "invalid or expired". That includes too many
+/// attempts, per `TokenRateLimit`.
 #[get("/verify/<token>")]
 pub async fn verify_with_token<'a>(
+    rate: TokenRateLimit,
     // flash: Option<FlashMessage<'_>>,
     cookies: &CookieJar<'a>,
     mut db: Connection<AppDb>,
+    config: &State<AppConfig>,
     token: UserToken,
+    queue: PostgresQueue,
 ) -> RenderOrRedirect {
+    if rate.exceeded {
+        return Template::render("accounts/invalid_token", &Context::default()).into();
+    }
+
     let conn: &mut sqlx::PgConnection = db.as_mut();
     match Account::validate_token(&token, conn).await {
+        // A verify link is only good once; treat a link for an account
+        // that's already verified like any other bad link.
+        Ok(account) if account.has_verified_email => {
+            let context = Context::default();
+            Template::render("accounts/invalid_token", &context).into()
+        },
         Ok(account) => {
             let _ignore = Account::mark_verified(account.id, conn).await;
 
-            auth::set_user(cookies, User {
+            match auth::password_expired(account.id, &queue, conn).await {
+                Ok(false) => {},
+                Ok(true) => return Flash::error(Redirect::to(uri!("/accounts/login")), PASSWORD_EXPIRED_MESSAGE).into(),
+                Err(_) => return Status::InternalServerError.into(),
+            }
+
+            auth::set_user(cookies, config, User {
                 id: account.id,
+                fingerprint: account.session_fingerprint(),
+                session_version: account.session_version,
                 name: account.name,
                 is_admin: account.is_admin,
                 is_anonymous: false,
-            });
+            }, false);
 
-            Redirect::to(uri!("/dashboard")).into()
+            Flash::success(Redirect::to(uri!("/dashboard")), "Your email address was verified.").into()
+        },
+        Err(e) if e.status == Status::InternalServerError => {
+            tracing::error!(error = ?e, "could not check verify token");
+            Status::InternalServerError.into()
         },
         Err(_) => {
            let context = Context::default();
@@ -204,50 +546,67 @@ pub struct SendLinkSubmit<'v> {
     pub account: SendLinkData<'v>,
 }
 
+impl Describe for SendLinkSubmit<'_> {
+    fn describe(_config: &AppConfig) -> Vec<FieldDescription> {
+        vec![FieldDescription::string("account.email", serde_json::json!({ "contains": "@" }))]
+    }
+}
+
 /// Just renders a standard "Enter Your Email" password reset page.
 #[get("/resend")]
 pub async fn resend_link_form<'a>(
-    // flash: Option<FlashMessage<'_>>
+    flash: Option<FlashMessage<'_>>,
+    csrf: CsrfToken,
 ) -> Template {
-    let context = Context::default();
-    Template::render("accounts/resend_link/index", &context)
+    let mut context = csrf::form_context(&csrf);
+    context["flash_messages"] = flash_messages(flash);
+    Template::render("accounts/resend_link/index", context)
 }
 
 /// Processes the reset password request, which ultimately just passes
 /// it to a background worker to execute - we do this to avoid any timing
-/// attacks re: leaking user existence.
+/// attacks re: leaking user existence. Repeat requests for an address
+/// within `accounts.resend_verify_cooldown` are dropped, with the same
+/// response.
 #[post("/resend", data = "<form>")]
 pub async fn resend_link<'a>(
+    _limit: AuthFormLimit,
+    _rate: RateLimit,
+    csrf: CsrfToken,
+    config: &State<AppConfig>,
     queue: PostgresQueue,
     form: Form<Contextual<'a, SendLinkSubmit<'a>>>
-) -> Template {
-    match &form.value {
-        Some(value) => {
+) -> Result<Template, Status> {
+    csrf.verify(form.context.field_value(CSRF_FIELD))?;
 
-            let _ignore = queue
-                .push(
-                    Message::SendVerifyAccountEmail(
-                        value.account.email.to_string(),
-                    ),
-                    None,
-                )
-                .await;
+    Ok(match &form.value {
+        Some(value) => {
+            let email = value.account.email;
+            let cooldown = i64::try_from(config.accounts.resend_verify_cooldown).unwrap_or(i64::MAX);
+            match queue.push_with_cooldown("resend-verify", email, cooldown,
+                Message::SendVerifyAccountEmail(email.to_string())).await {
+                Ok(true) => {},
+                Ok(false) => tracing::info!("verification email resent recently, not queueing another"),
+                Err(e) => tracing::error!(error = %e, "could not queue verification email"),
+            }
 
             let context = Context::default();
             Template::render("accounts/resend_link/requested", &context)
         },
         None =>
             Template::render("accounts/resend_link/index", &form.context),
-    }
+    })
 }
 
 /// Just renders a standard "Enter Your Email" password reset page.
 #[get("/reset")]
 pub async fn reset_password_form<'a>(
-    // flash: Option<FlashMessage<'_>>
+    flash: Option<FlashMessage<'_>>,
+    csrf: CsrfToken,
 ) -> Template {
-    let context = Context::default();
-    Template::render("accounts/reset_password/index", &context)
+    let mut context = csrf::form_context(&csrf);
+    context["flash_messages"] = flash_messages(flash);
+    Template::render("accounts/reset_password/index", context)
 }
 
 /// Processes the reset password request, which ultimately just passes
@@ -255,10 +614,15 @@ pub async fn reset_password_form<'a>(
 /// attacks re: leaking user existence.
 #[post("/reset", data = "<form>")]
 pub async fn request_reset<'a>(
+    _limit: AuthFormLimit,
+    _rate: RateLimit,
+    csrf: CsrfToken,
     queue: PostgresQueue,
     form: Form<Contextual<'a, SendLinkSubmit<'a>>>
-) -> Template {
-    match &form.value {
+) -> Result<Template, Status> {
+    csrf.verify(form.context.field_value(CSRF_FIELD))?;
+
+    Ok(match &form.value {
         Some(value) => {
             let _ignore = queue
                 .push(
@@ -266,6 +630,7 @@ pub async fn request_reset<'a>(
                         value.account.email.to_string(),
                     ),
                     None,
+                    Some(HIGH_PRIORITY),
                 )
                 .await;
 
@@ -274,16 +639,628 @@ pub async fn request_reset<'a>(
         },
         None =>
             Template::render("accounts/reset_password/index", &form.context),
+    })
+}
+
+/// Renders the "Need help signing in?" page, which emails a login link.
+#[get("/magic-link")]
+pub async fn magic_link_form(
+    flash: Option<FlashMessage<'_>>,
+    csrf: CsrfToken,
+) -> Template {
+    let mut context = csrf::form_context(&csrf);
+    context["flash_messages"] = flash_messages(flash);
+    Template::render("accounts/magic_link/index", context)
+}
+
+/// Queues an email with a one time login link. As with password resets,
+/// the page is the same whether or not the address has an account, and
+/// the job works that out, so the form can't be used to find accounts.
+#[post("/magic-link", data = "<form>")]
+pub async fn request_magic_link<'a>(
+    _limit: AuthFormLimit,
+    _rate: RateLimit,
+    csrf: CsrfToken,
+    queue: PostgresQueue,
+    form: Form<Contextual<'a, SendLinkSubmit<'a>>>
+) -> Result<Template, Status> {
+    csrf.verify(form.context.field_value(CSRF_FIELD))?;
+
+    Ok(match &form.value {
+        Some(value) => {
+            let _ignore = queue
+                .push(
+                    Message::SendMagicLinkEmail(value.account.email.to_string()),
+                    None,
+                    Some(HIGH_PRIORITY),
+                )
+                .await;
+
+            Template::render("accounts/magic_link/requested", &Context::default())
+        },
+        None =>
+            Template::render("accounts/magic_link/index", &form.context),
+    })
+}
+
+/// Logs in with a link from `request_magic_link` and goes to the
+/// settings page, where the user can see how they normally sign in. The
+/// login changes the account's last login, which is part of the token,
+/// so the link only works once. Bad, used or rate limited links get the
+/// same "invalid or expired" page.
+#[get("/magic/<token>")]
+pub async fn magic_login<'a>(
+    rate: TokenRateLimit,
+    cookies: &CookieJar<'a>,
+    client: ClientInfo,
+    config: &State<AppConfig>,
+    mut db: Connection<AppDb>,
+    token: UserToken,
+    queue: PostgresQueue,
+) -> RenderOrRedirect {
+    if rate.exceeded {
+        return Template::render("accounts/invalid_token", &Context::default()).into();
+    }
+
+    let conn: &mut sqlx::PgConnection = db.as_mut();
+    match Account::validate_token(&token, conn).await {
+        Ok(account) if account.is_active => {
+            match auth::password_expired(account.id, &queue, conn).await {
+                Ok(false) => {},
+                Ok(true) => return Flash::error(Redirect::to(uri!("/accounts/login")), PASSWORD_EXPIRED_MESSAGE).into(),
+                Err(_) => return Status::InternalServerError.into(),
+            }
+
+            if Account::update_last_login(account.id, conn).await.is_err() {
+                return Status::InternalServerError.into();
+            }
+            // Following the link shows the address is the user's.
+            if !account.has_verified_email {
+                let _ignore = Account::mark_verified(account.id, conn).await;
+            }
+            client.record_login(config, account.id, conn).await;
+
+            auth::set_user(cookies, config, User {
+                id: account.id,
+                fingerprint: account.session_fingerprint(),
+                session_version: account.session_version,
+                name: account.name,
+                is_admin: account.is_admin,
+                is_anonymous: false,
+            }, false);
+
+            Flash::success(Redirect::to(uri!("/accounts/settings")), "You are logged in.").into()
+        },
+        Err(e) if e.status == Status::InternalServerError => {
+            tracing::error!(error = ?e, "could not check magic link token");
+            Status::InternalServerError.into()
+        },
+        _ => Template::render("accounts/invalid_token", &Context::default()).into(),
     }
 }
 
+#[derive(Clone, Debug, Default, Deserialize, FromForm, Serialize)]
+pub struct SettingsData<'v> {
+    #[field(validate = len(1..))]
+    pub name: &'v str,
+}
+
+#[derive(Debug, FromForm)]
+pub struct SettingsSubmit<'v> {
+    pub account: SettingsData<'v>,
+}
+
+impl Describe for SettingsSubmit<'_> {
+    fn describe(_config: &AppConfig) -> Vec<FieldDescription> {
+        vec![FieldDescription::string("account.name", serde_json::json!({ "min_length": 1 }))]
+    }
+}
+
+/// Logins shown on the settings page, when they're recorded.
+const RECENT_LOGINS: i64 = 10;
+
+/// Show the account settings form, along with when the account was
+/// created and last logged in, and its recent logins if they're recorded.
+#[get("/settings")]
+pub async fn settings_form(
+    user: User,
+    flash: Option<FlashMessage<'_>>,
+    csrf: CsrfToken,
+    config: &State<AppConfig>,
+    db: Connection<AppDb>,
+) -> Template {
+    let mut context = settings_context(&user, config, db).await;
+    context.insert("flash_messages", &flash_messages(flash));
+    context.insert("errors", &serde_json::json!([]));
+    context.insert("csrf", csrf.value());
+
+    Template::render("accounts/settings", &context.into_json())
+}
+
+/// Everything on the settings page but the form state.
+async fn settings_context(user: &User, config: &AppConfig, mut db: Connection<AppDb>) -> tera::Context {
+    let conn: &mut sqlx::PgConnection = db.as_mut();
+    let account = user.load_account(conn).await.ok();
+    let recent_logins = if config.accounts.record_logins {
+        Account::recent_logins(user.id, RECENT_LOGINS, conn).await.unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let passkeys = WebauthnCredential::for_account(user.id, conn).await.unwrap_or_default();
+
+    let identities = Identity::linked_to_account_id(user.id, db)
+        .await
+        .unwrap_or_default();
+
+    let mut context = tera::Context::new();
+    context.insert("values", &serde_json::json!({ "account.name": [user.name] }));
+    context.insert("identities", &identities);
+    if let Some(account) = account {
+        context.insert("created", &account.created.to_rfc3339());
+        context.insert("last_login", &account.last_login.map(|last_login| last_login.to_rfc3339()));
+        context.insert("avatar_url", &account.profile.avatar_url);
+    }
+    context.insert("recent_logins", &recent_logins);
+    context.insert("passkeys_enabled", &cfg!(feature = "webauthn"));
+    context.insert("passkeys", &passkeys);
+    context
+}
+
+/// POST-handler for updating account settings. The session cookie is
+/// refreshed so that the new name shows up right away.
+#[post("/settings", data = "<form>")]
+pub async fn update_settings<'a>(
+    user: User,
+    cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
+    mut db: Connection<AppDb>,
+    config: &State<AppConfig>,
+    form: Form<Contextual<'a, SettingsSubmit<'a>>>,
+) -> RenderOrRedirect {
+    if let Err(status) = csrf.verify(form.context.field_value(CSRF_FIELD)) {
+        return status.into();
+    }
+
+    match &form.value {
+        Some(value) => {
+            let conn: &mut sqlx::PgConnection = db.as_mut();
+            let result = match user.load_account(conn).await {
+                Ok(account) =>
+                    Account::update_profile(account.id, value.account.name, &account.profile, conn).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(_) => {
+                    auth::set_user(cookies, config, User {
+                        name: value.account.name.to_string(),
+                        ..user
+                    }, false);
+
+                    Redirect::to(uri!("/accounts/settings")).into()
+                },
+                Err(e) => {
+                    tracing::error!(error = ?e, "could not update settings");
+                    render_settings_errors(&user, &csrf, config, db, &form.context).await
+                }
+            }
+        },
+        None => render_settings_errors(&user, &csrf, config, db, &form.context).await,
+    }
+}
+
+/// The largest avatar image accepted. Rocket's `file` limit, 1 MiB
+/// unless configured, must be at least this.
+const AVATAR_MAX_SIZE: ByteUnit = ByteUnit::Mebibyte(1);
+
+const NOT_AN_IMAGE: &str = "must be a PNG, JPEG, GIF or WebP image";
+
+#[derive(Debug, FromForm)]
+pub struct AvatarSubmit<'v> {
+    #[field(validate = len(..=AVATAR_MAX_SIZE).or_else(msg!("images can be at most 1 MiB")))]
+    #[field(validate = validate_image_upload())]
+    pub avatar: TempFile<'v>,
+}
+
+/// Checks the type the browser gave the upload. The contents are checked
+/// as well once the file is read, by `save_avatar`.
+fn validate_image_upload<'v>(file: &TempFile<'_>) -> form::Result<'v, ()> {
+    let is_image = file.content_type()
+        .map_or(false, |ct| ct.is_png() || ct.is_jpeg() || ct.is_gif() || ct.is_webp());
+
+    if is_image {
+        Ok(())
+    } else {
+        Err(Error::validation(NOT_AN_IMAGE).into())
+    }
+}
+
+/// Stores an uploaded avatar, points the profile at it and removes the
+/// previous one. Returns `None` if the file isn't really an image.
+async fn save_avatar(
+    user: &User,
+    file: &TempFile<'_>,
+    storage: &FileStorage,
+    conn: &mut sqlx::PgConnection,
+) -> crate::error::Result<Option<String>> {
+    let mut bytes = Vec::new();
+    let reader = file.open().await?;
+    rocket::tokio::pin!(reader);
+    reader.read_to_end(&mut bytes).await?;
+
+    let (content_type, extension) = match storage::image_type(&bytes) {
+        Some(image_type) => image_type,
+        None => return Ok(None),
+    };
+
+    let account = user.load_account(conn).await?;
+    let key = format!("avatars/{}-{}.{}", account.id, ulid::Ulid::new().to_string().to_lowercase(), extension);
+    storage.put(&key, &content_type, bytes).await?;
+    let url = storage.url_for(&key);
+
+    Account::update_profile_field(account.id, ProfileField::AvatarUrl, Some(url.clone()), conn).await?;
+
+    if let Some(previous) = account.profile.avatar_url.as_deref().and_then(|url| storage.key_for(url)) {
+        if let Err(e) = storage.delete(&previous).await {
+            tracing::warn!(key = %previous, error = ?e, "could not remove old avatar");
+        }
+    }
+
+    Ok(Some(url))
+}
+
+/// Multipart POST-handler for a new avatar image. Uploads that aren't
+/// images, or are too large, get the settings page back with an error
+/// on the `avatar` field.
+#[post("/settings/avatar", data = "<form>")]
+pub async fn upload_avatar<'a>(
+    user: User,
+    csrf: CsrfToken,
+    config: &State<AppConfig>,
+    storage: &State<FileStorage>,
+    mut db: Connection<AppDb>,
+    form: Form<Contextual<'a, AvatarSubmit<'a>>>,
+) -> RenderOrRedirect {
+    let mut form = form.into_inner();
+    if let Err(status) = csrf.verify(form.context.field_value(CSRF_FIELD)) {
+        return status.into();
+    }
+
+    if let Some(value) = &form.value {
+        let redirect = Redirect::to(uri!("/accounts/settings"));
+        match save_avatar(&user, &value.avatar, storage, db.as_mut()).await {
+            Ok(Some(_)) => return Flash::success(redirect, "Your avatar was updated.").into(),
+            Ok(None) => form.context.push_error(Error::validation(NOT_AN_IMAGE).with_name("avatar")),
+            Err(e) => {
+                tracing::error!(error = ?e, "could not save avatar");
+                return Flash::error(redirect, "Could not save your avatar, please try again.").into();
+            }
+        }
+    }
+
+    render_settings_errors(&user, &csrf, config, db, &form.context).await
+}
+
+/// The settings page, showing the errors from one of its forms.
+async fn render_settings_errors(
+    user: &User,
+    csrf: &CsrfToken,
+    config: &AppConfig,
+    db: Connection<AppDb>,
+    form: &Context<'_>,
+) -> RenderOrRedirect {
+    let errors = serde_json::to_value(form)
+        .map(|context| context["errors"].clone())
+        .unwrap_or_default();
+
+    let mut context = settings_context(user, config, db).await;
+    context.insert("flash_messages", &serde_json::json!([]));
+    context.insert("errors", &errors);
+    context.insert("csrf", csrf.value());
+
+    Template::render("accounts/settings", &context.into_json()).into()
+}
+
+const WRONG_PASSWORD_MESSAGE: &str = "is not your current password";
+
+#[derive(Debug, FromForm)]
+pub struct NewPasswordData<'v> {
+    pub current_password: &'v str,
+    /// Checked against `AppConfig::passwords` by the handler.
+    pub password: &'v str,
+    #[field(validate = len(1..))]
+    #[field(validate = eq(self.password))]
+    pub password_confirm: &'v str,
+}
+
+#[derive(Debug, FromForm)]
+pub struct NewPasswordSubmit<'v> {
+    pub account: NewPasswordData<'v>,
+}
+
+impl Describe for NewPasswordSubmit<'_> {
+    fn describe(config: &AppConfig) -> Vec<FieldDescription> {
+        vec![
+            FieldDescription::string("account.current_password", serde_json::json!({})),
+            FieldDescription::string("account.password", password_constraints(config)),
+            FieldDescription::string("account.password_confirm", serde_json::json!({ "equals": "account.password" })),
+        ]
+    }
+}
+
+/// POST-handler for changing the password from the settings page, given
+/// the current one. The account is emailed that its password changed.
+/// Other sessions end on their next request, or once
+/// `accounts.session_cache_ttl` has passed; this one is kept, with the
+/// new fingerprint.
+#[post("/settings/password", data = "<form>")]
+pub async fn change_password<'a>(
+    _limit: AuthFormLimit,
+    _rate: RateLimit,
+    user: User,
+    cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
+    config: &State<AppConfig>,
+    mut db: Connection<AppDb>,
+    form: Form<Contextual<'a, NewPasswordSubmit<'a>>>,
+    queue: PostgresQueue,
+) -> RenderOrRedirect {
+    let mut form = form.into_inner();
+    if let Err(status) = csrf.verify(form.context.field_value(CSRF_FIELD)) {
+        return status.into();
+    }
+
+    let conn: &mut sqlx::PgConnection = db.as_mut();
+    let account = match user.load_account(conn).await {
+        Ok(account) => account,
+        Err(e) => return e.status.into(),
+    };
+
+    let submitted = form.value.as_ref().map(|value| value.account.password);
+    if let Some(password) = submitted {
+        if let Err(errors) = validate_password(config, password, &[account.name.as_str(), account.email.as_str()]).await {
+            form.context.push_errors(errors.with_name("account.password"));
+            form.value = None;
+        }
+    }
+
+    if let Some(value) = &form.value {
+        match Account::change_password(account.id, value.account.current_password, value.account.password, conn).await {
+            Ok(()) => {
+                let _ignore = queue.push(
+                    Message::SendPasswordWasResetEmail(account.email.clone()),
+                    None,
+                    Some(HIGH_PRIORITY),
+                ).await;
+
+                auth::forget_session(account.id);
+                let fingerprint = Account::current_session(account.id, conn)
+                    .await
+                    .ok()
+                    .flatten()
+                    .map(|session| session.fingerprint)
+                    .unwrap_or_default();
+                auth::set_user(cookies, config, User { fingerprint, ..user }, false);
+
+                return Flash::success(Redirect::to(uri!("/accounts/settings")), "Your password was changed.").into();
+            },
+            Err(e) if e.status == Status::Unauthorized => {
+                form.context.push_error(Error::validation(WRONG_PASSWORD_MESSAGE).with_name("account.current_password"));
+            },
+            Err(e) => return e.status.into(),
+        }
+    }
+
+    render_settings_errors(&user, &csrf, config, db, &form.context).await
+}
+
+#[derive(Debug, FromForm)]
+pub struct NewEmailData<'v> {
+    pub current_password: &'v str,
+    /// Checked against `AppConfig::email_domains` by the handler.
+    #[field(validate = contains('@').or_else(msg!("invalid email address")))]
+    pub email: &'v str,
+}
+
+#[derive(Debug, FromForm)]
+pub struct NewEmailSubmit<'v> {
+    pub account: NewEmailData<'v>,
+}
+
+impl Describe for NewEmailSubmit<'_> {
+    fn describe(_config: &AppConfig) -> Vec<FieldDescription> {
+        vec![
+            FieldDescription::string("account.current_password", serde_json::json!({})),
+            FieldDescription::string("account.email", serde_json::json!({ "contains": "@" })),
+        ]
+    }
+}
+
+/// POST-handler for changing the email from the settings page, given the
+/// current password. Nothing changes until the link sent to the new
+/// address is followed; see `confirm_email_change`. Addresses that
+/// already have an account get no link, but the same answer, so the form
+/// can't be used to find out who has one.
+#[post("/settings/email", data = "<form>")]
+pub async fn change_email<'a>(
+    _limit: AuthFormLimit,
+    _rate: RateLimit,
+    user: User,
+    csrf: CsrfToken,
+    config: &State<AppConfig>,
+    mut db: Connection<AppDb>,
+    form: Form<Contextual<'a, NewEmailSubmit<'a>>>,
+    queue: PostgresQueue,
+) -> RenderOrRedirect {
+    let mut form = form.into_inner();
+    if let Err(status) = csrf.verify(form.context.field_value(CSRF_FIELD)) {
+        return status.into();
+    }
+
+    let email = form.value.as_ref().map(|value| value.account.email);
+    if let Some(Err(errors)) = email.map(|email| config.email_domains.validate(email)) {
+        form.context.push_errors(errors.with_name("account.email"));
+        form.value = None;
+    }
+
+    if let Some(value) = &form.value {
+        let conn: &mut sqlx::PgConnection = db.as_mut();
+        let account = match user.load_account(conn).await {
+            Ok(account) => account,
+            Err(e) => return e.status.into(),
+        };
+
+        match Account::check_password(account.id, value.account.current_password, conn).await {
+            Ok(()) => {
+                if Account::id_by_email(value.account.email, config.accounts.email_aliases, conn).await.is_err() {
+                    let _ignore = queue.push(
+                        Message::SendEmailChangeConfirmation {
+                            email: account.email.clone(),
+                            new_email: value.account.email.to_string(),
+                        },
+                        None,
+                        Some(HIGH_PRIORITY),
+                    ).await;
+                }
+
+                let message = format!("We sent a link to {} to confirm the change.", value.account.email);
+                return Flash::success(Redirect::to(uri!("/accounts/settings")), message).into();
+            },
+            Err(e) if e.status == Status::Unauthorized => {
+                form.context.push_error(Error::validation(WRONG_PASSWORD_MESSAGE).with_name("account.current_password"));
+            },
+            Err(e) => return e.status.into(),
+        }
+    }
+
+    render_settings_errors(&user, &csrf, config, db, &form.context).await
+}
+
+/// Changes the account's email and queues the notice to its previous
+/// address, in case the change wasn't the owner's doing.
+async fn apply_email_change(
+    account: &Account,
+    new_email: &str,
+    config: &AppConfig,
+    conn: &mut sqlx::PgConnection,
+    queue: &PostgresQueue,
+) -> crate::error::Result<()> {
+    let old_email = Account::change_email(account.id, new_email, config.accounts.email_aliases, conn).await?;
+
+    queue.push(
+        Message::SendEmailChangeNotice { old_email, new_email: new_email.to_string() },
+        None,
+        Some(HIGH_PRIORITY),
+    ).await
+}
+
+/// Given the link sent to a new address (of form {uidb64}-{ts}-{token},
+/// with the address as `email`), changes the account's email to it. Bad
+/// or used links, and addresses another account took in the meantime,
+/// get the invalid token page, as do too many attempts, per
+/// `TokenRateLimit`.
+#[get("/settings/email/<token>?<email>")]
+pub async fn confirm_email_change(
+    rate: TokenRateLimit,
+    config: &State<AppConfig>,
+    mut db: Connection<AppDb>,
+    token: UserToken,
+    email: &str,
+    queue: PostgresQueue,
+) -> RenderOrRedirect {
+    if rate.exceeded {
+        return Template::render("accounts/invalid_token", &Context::default()).into();
+    }
+
+    let conn: &mut sqlx::PgConnection = db.as_mut();
+    let changed = match Account::validate_email_change_token(&token, email, conn).await {
+        Ok(account) => apply_email_change(&account, email, config, conn, &queue).await,
+        Err(e) if e.status == Status::InternalServerError => {
+            tracing::error!(error = ?e, "could not check email change token");
+            return Status::InternalServerError.into();
+        },
+        Err(e) => Err(e),
+    };
+
+    match changed {
+        Ok(()) => Flash::success(Redirect::to(uri!("/accounts/settings")), "Your email address was changed.").into(),
+        Err(e) => {
+            tracing::info!(error = ?e, "email change not confirmed");
+            Template::render("accounts/invalid_token", &Context::default()).into()
+        }
+    }
+}
+
+/// Downloads everything stored about the current account as a JSON file.
+#[get("/settings/export")]
+pub async fn export_data(
+    user: User,
+    mut db: Connection<AppDb>,
+) -> crate::error::Result<JsonDownload<AccountExport>> {
+    let export = Account::export_data(user.id, db.as_mut()).await?;
+    Ok(JsonDownload {
+        filename: format!("account-{}-{}.json", user.id, export.exported_at.format("%Y-%m-%d")),
+        body: export,
+    })
+}
+
+/// Unlinks an OAuth provider from the current account.
+#[post("/settings/identities/<provider>/unlink", data = "<form>")]
+pub async fn unlink_identity<'a>(
+    user: User,
+    csrf: CsrfToken,
+    mut db: Connection<AppDb>,
+    providers: &State<OAuthProviders>,
+    provider: &str,
+    form: Form<CsrfForm<'a>>,
+) -> Result<Flash<Redirect>, Status> {
+    csrf.verify(form.csrf)?;
+
+    let conn: &mut sqlx::PgConnection = db.as_mut();
+    let redirect = Redirect::to(uri!("/accounts/settings"));
+    Ok(match Identity::unlink(user.id, provider, providers, conn).await {
+        Ok(_) => Flash::success(redirect, format!("Your {} account was unlinked.", provider)),
+        Err(e) if e.status == Status::BadRequest =>
+            Flash::error(redirect, "Set a password before unlinking your only sign-in provider."),
+        Err(e) => {
+            tracing::error!(error = ?e, provider, "could not unlink identity");
+            Flash::error(redirect, format!("Could not unlink your {} account.", provider))
+        },
+    })
+}
+
+/// Deletes the current account, then logs out. Whether the account row is
+/// kept is controlled by the `accounts.soft_delete` setting.
+#[post("/settings/delete", data = "<form>")]
+pub async fn delete_account<'a>(
+    user: User,
+    cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
+    mut db: Connection<AppDb>,
+    config: &State<AppConfig>,
+    providers: &State<OAuthProviders>,
+    form: Form<CsrfForm<'a>>,
+) -> Result<Flash<Redirect>, Status> {
+    csrf.verify(form.csrf)?;
+
+    let conn: &mut sqlx::PgConnection = db.as_mut();
+    Ok(match Account::delete(user.id, config.accounts.soft_delete, providers, conn).await {
+        Ok(_) => {
+            auth::clear_user(cookies, config);
+            Flash::success(Redirect::to(uri!("/")), "Your account was deleted.")
+        },
+        Err(e) => {
+            tracing::error!(account_id = user.id, error = ?e, "could not delete account");
+            Flash::error(Redirect::to(uri!("/accounts/settings")), "Could not delete your account.")
+        }
+    })
+}
+
 #[derive(Clone, Debug, Default, Deserialize, FromForm, Serialize)]
 pub struct ChangePasswordData<'v> {
     pub name: &'v str,
     pub email: &'v str,
-    #[field(validate = len(8..))]
-    #[field(validate = validate_pattern(&REGEX_ANH))]
-    #[field(validate = validate_strength(SafelyUnguessable, vec![self.name, self.email].as_slice()))]
+    /// Checked against `AppConfig::passwords` by the handler.
     pub password: &'v str,
     #[field(validate = len(1..))]
     #[field(validate = eq(self.password))]
@@ -295,23 +1272,42 @@ pub struct ChangePasswordSubmit<'v> {
     pub account: ChangePasswordData<'v>
 }
 
+impl Describe for ChangePasswordSubmit<'_> {
+    fn describe(config: &AppConfig) -> Vec<FieldDescription> {
+        vec![
+            FieldDescription::string("account.name", serde_json::json!({})),
+            FieldDescription::string("account.email", serde_json::json!({})),
+            FieldDescription::string("account.password", password_constraints(config)),
+            FieldDescription::string("account.password_confirm", serde_json::json!({ "equals": "account.password" })),
+        ]
+    }
+}
+
 /// Given a link (of form {uidb64}-{ts}-{token}), verifies the
 /// token and user, and presents them a change password form.
 ///
 /// In general, we do not want to leak information, so any errors here
 /// should simply report as "invalid or expired". It's a bit verbose, but
 /// such is Rust for this type of thing. Write it once and move on. ;P
+/// Too many attempts, per `TokenRateLimit`, look like a bad link too.
 #[get("/reset/<token>")]
 pub async fn reset_password_with_token<'a>(
+    rate: TokenRateLimit,
     // flash: Option<FlashMessage<'_>>,
+    csrf: CsrfToken,
     mut db: Connection<AppDb>,
     token: UserToken,
-) -> Template {
+) -> RenderOrRedirect {
+    if rate.exceeded {
+        return Template::render("accounts/invalid_token", &Context::default()).into();
+    }
+
     let conn: &mut sqlx::PgConnection = db.as_mut();
     match Account::validate_token(&token, conn).await {
         Ok(account) => {
             let context = serde_json::json!({
                 "token": token.to_string(),
+                "csrf": csrf.value(),
                 "values": {
                     "account.name": [account.name],
                     "account.email": [account.email],
@@ -324,26 +1320,60 @@ pub async fn reset_password_with_token<'a>(
             Template::render(
                 "accounts/reset_password/change_password",
                 context
-            )
+            ).into()
+        },
+        Err(e) if e.status == Status::InternalServerError => {
+            tracing::error!(error = ?e, "could not check reset token");
+            Status::InternalServerError.into()
         },
         Err(_) => {
             let context = Context::default();
-            Template::render("accounts/invalid_token", &context)
+            Template::render("accounts/invalid_token", &context).into()
         }
     }
 }
 
+/// Sends the user back to request another reset, with a flash message.
+fn invalid_reset_link() -> RenderOrRedirect {
+    Flash::error(
+        Redirect::to(uri!("/accounts/reset")),
+        "The link you used is invalid. Please request another password reset.",
+    ).into()
+}
+
 /// Verifies the password is fine, and if so, signs the user in and redirects
-/// them to the dashboard with a flash message.
+/// them to the dashboard with a flash message. Too many attempts, per
+/// `TokenRateLimit`, get the invalid token page before anything is checked.
 #[post("/reset/<token>", data = "<form>")]
 pub async fn reset_password<'a>(
+    _limit: AuthFormLimit,
+    rate: TokenRateLimit,
     // flash: Option<FlashMessage<'_>>,
     cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
     mut db: Connection<AppDb>,
     token: UserToken,
-    form: Form<Contextual<'a, ChangePasswordSubmit<'a>>>,
+    config: &State<AppConfig>,
+    mut form: Form<Contextual<'a, ChangePasswordSubmit<'a>>>,
     queue: PostgresQueue,
 ) -> RenderOrRedirect {
+    if let Err(status) = csrf.verify(form.context.field_value(CSRF_FIELD)) {
+        return status.into();
+    }
+
+    if rate.exceeded {
+        return Template::render("accounts/invalid_token", &Context::default()).into();
+    }
+
+    let submitted = form.value.as_ref()
+        .map(|value| (value.account.password, [value.account.name, value.account.email]));
+    if let Some((password, user_inputs)) = submitted {
+        if let Err(errors) = validate_password(config, password, &user_inputs).await {
+            form.context.push_errors(errors.with_name("account.password"));
+            form.value = None;
+        }
+    }
+
     let conn: &mut sqlx::PgConnection = db.as_mut();
     match Account::validate_token(&token, conn).await {
         Ok(account) => {
@@ -358,17 +1388,29 @@ pub async fn reset_password<'a>(
                             account.email.clone(),
                         ),
                         None,
+                        Some(HIGH_PRIORITY),
                     ).await;
 
-                    auth::set_user(cookies, User {
+                    // The new password changes the session fingerprint, which
+                    // logs out any other sessions; this one gets the new value.
+                    auth::forget_session(account.id);
+                    let fingerprint = Account::current_session(account.id, conn)
+                        .await
+                        .ok()
+                        .flatten()
+                        .map(|session| session.fingerprint)
+                        .unwrap_or_default();
+
+                    auth::set_user(cookies, config, User {
                         id: account.id,
+                        fingerprint,
+                        session_version: account.session_version,
                         name: account.name,
                         is_admin: account.is_admin,
                         is_anonymous: false,
-                    });
+                    }, false);
 
-                    // request.flash("Password Reset", "Your password was successfully reset.")?;
-                    Redirect::to(uri!("/dashboard")).into()
+                    Flash::success(Redirect::to(uri!("/dashboard")), "Your password was successfully reset.").into()
                 },
                 None => {
                     Template::render("accounts/reset_password/change_password", &form.context).into()
@@ -376,9 +1418,82 @@ pub async fn reset_password<'a>(
             }
 
         },
-        Err(_) => {
-            // request.flash("Password Reset", "The link you used is invalid. Please request another password reset.")?;
-            Redirect::to(uri!("/")).into()
+        Err(e) if e.status == Status::InternalServerError => {
+            tracing::error!(error = ?e, "could not check reset token");
+            Status::InternalServerError.into()
+        },
+        Err(_) => invalid_reset_link(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::asynchronous::Client;
+    use rocket::routes;
+
+    use super::*;
+    use crate::database::test_connection;
+    use crate::jobs::test_queue;
+
+    #[get("/logged-in?<next>")]
+    fn logged_in(next: Option<&str>) -> Redirect {
+        after_login(next)
+    }
+
+    async fn location_after_login(next: &str) -> Option<String> {
+        let client = Client::tracked(rocket::build().mount("/", routes![logged_in])).await.unwrap();
+        let query = serde_urlencoded::to_string([("next", next)]).unwrap();
+        let response = client.get(format!("/logged-in?{}", query)).dispatch().await;
+        response.headers().get_one("Location").map(String::from)
+    }
+
+    #[rocket::async_test]
+    async fn logging_in_returns_to_a_safe_next_page() {
+        assert_eq!(location_after_login("/accounts/settings?tab=email").await.as_deref(),
+            Some("/accounts/settings?tab=email"));
+    }
+
+    #[rocket::async_test]
+    async fn logging_in_ignores_an_unsafe_next_page() {
+        for next in ["https://evil.example.com/", "//evil.example.com", "/\\evil.example.com"] {
+            assert_eq!(location_after_login(next).await.as_deref(), Some("/dashboard"), "{}", next);
         }
     }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn confirmed_email_change_notifies_the_old_address() {
+        let (mut conn, queue) = (test_connection().await, test_queue().await);
+
+        let suffix = ulid::Ulid::new().to_string().to_lowercase();
+        let old_email = format!("old-{}@example.com", suffix);
+        let new_email = format!("new-{}@example.com", suffix);
+        let config = AppConfig::default();
+        let new_account = NewAccount { name: "Email Change", email: &old_email, password: "a long test password" };
+        Account::register(&new_account, config.accounts.email_aliases, &mut conn).await.unwrap();
+        let account = Account::get_by_email(&old_email, config.accounts.email_aliases, &mut conn).await.unwrap();
+
+        apply_email_change(&account, &new_email, &config, &mut conn, &queue).await.unwrap();
+
+        let notices: Vec<(i64,)> = sqlx::query_as("SELECT count(*) FROM queue
+            WHERE message->'SendEmailChangeNotice'->>'old_email' = $1
+            AND message->'SendEmailChangeNotice'->>'new_email' = $2")
+            .bind(&old_email)
+            .bind(&new_email)
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+        let changed = Account::get(account.id, &mut conn).await.unwrap();
+
+        sqlx::query("DELETE FROM queue WHERE message->'SendEmailChangeNotice'->>'old_email' = $1")
+            .bind(&old_email)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM accounts WHERE id = $1").bind(account.id).execute(&mut conn).await.unwrap();
+
+        assert_eq!(notices, vec![(1,)]);
+        assert_eq!(changed.email, new_email);
+        assert!(changed.has_verified_email);
+    }
 }
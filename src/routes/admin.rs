@@ -0,0 +1,263 @@
+//! Admin routes, mounted at "/admin"
+
+use std::collections::HashMap;
+use std::env;
+
+use anyhow::anyhow;
+use rocket::http::Status;
+use rocket::response::content::RawHtml;
+use rocket::serde::json::Json;
+use rocket::{get, post};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+use serde::Deserialize;
+
+use crate::auth::{self, AdminUser};
+use crate::csrf::{CsrfToken, SameOrigin};
+use crate::database::{AppDb, PoolStats};
+use crate::error;
+use crate::jobs::{Message, PostgresQueue, QueueStats};
+use crate::models::{Account, Invitation};
+
+/// Page size for the account listing, and the most a client may ask for.
+const DEFAULT_PER_PAGE: i64 = 25;
+const MAX_PER_PAGE: i64 = 100;
+
+/// Lists accounts a page at a time, oldest first. Pages start at 1.
+#[get("/accounts?<page>&<per_page>")]
+pub async fn list_accounts(
+    _admin: AdminUser,
+    csrf: CsrfToken,
+    mut db: Connection<AppDb>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+) -> error::Result<Template> {
+    let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+    let page = page.unwrap_or(1).max(1);
+
+    let conn: &mut sqlx::PgConnection = db.as_mut();
+    let total = Account::count(conn).await?;
+    let accounts = Account::list((page - 1) * per_page, per_page, conn).await?;
+
+    Ok(Template::render("admin/accounts", serde_json::json!({
+        "accounts": accounts,
+        "page": page,
+        "per_page": per_page,
+        "total": total,
+        "pages": (total + per_page - 1) / per_page,
+        "csrf": csrf.value(),
+    })))
+}
+
+/// Re-sends the welcome email to a verified account, e.g. when a user
+/// asks support for it again.
+#[post("/accounts/<id>/welcome")]
+pub async fn resend_welcome(
+    _admin: AdminUser,
+    _origin: SameOrigin,
+    mut db: Connection<AppDb>,
+    queue: PostgresQueue,
+    id: i32,
+) -> error::Result<Status> {
+    push_welcome(id, db.as_mut(), &queue).await?;
+    Ok(Status::Accepted)
+}
+
+/// Queues the welcome email for account `id`. Fails with `NotFound` for
+/// no such account, and `Conflict` if it hasn't verified its email.
+async fn push_welcome(id: i32, conn: &mut sqlx::PgConnection, queue: &PostgresQueue) -> error::Result<()> {
+    let account = Account::get(id, conn)
+        .await
+        .map_err(|e| error::Error::with_status(e.error, Status::NotFound))?;
+
+    if !account.has_verified_email {
+        return Err(error::Error::with_status(
+            anyhow!("account {} has not verified its email", id), Status::Conflict));
+    }
+
+    queue.push(Message::SendWelcomeAccountEmail(account.email), None, None).await
+}
+
+/// Deactivates an account: it can no longer log in, and its sessions end
+/// on their next request to this server, and within
+/// `accounts.session_cache_ttl` on any others.
+#[post("/accounts/<id>/deactivate")]
+pub async fn deactivate_account(
+    _admin: AdminUser,
+    _origin: SameOrigin,
+    mut db: Connection<AppDb>,
+    id: i32,
+) -> error::Result<Status> {
+    Account::set_active(id, false, db.as_mut()).await?;
+    auth::forget_session(id);
+    Ok(Status::NoContent)
+}
+
+/// Lets a deactivated account log in again.
+#[post("/accounts/<id>/reactivate")]
+pub async fn reactivate_account(
+    _admin: AdminUser,
+    _origin: SameOrigin,
+    mut db: Connection<AppDb>,
+    id: i32,
+) -> error::Result<Status> {
+    Account::set_active(id, true, db.as_mut()).await?;
+    auth::forget_session(id);
+    Ok(Status::NoContent)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewInvitation {
+    pub email: String,
+}
+
+/// Invites someone to register, for `registration = "invite_only"`, and
+/// emails them the link. Returns the invitation.
+#[post("/invitations", data = "<data>")]
+pub async fn create_invitation(
+    admin: AdminUser,
+    _origin: SameOrigin,
+    mut db: Connection<AppDb>,
+    queue: PostgresQueue,
+    data: Json<NewInvitation>,
+) -> error::Result<(Status, Json<Invitation>)> {
+    let email = data.email.trim();
+    if !email.contains('@') {
+        return Err(error::Error::with_status(anyhow!("invalid email address"), Status::BadRequest));
+    }
+
+    let (invitation, token) = Invitation::create(email, admin.user().id, db.as_mut()).await?;
+    queue.push(Message::SendInvitationEmail { to: email.to_string(), token }, None, None).await?;
+
+    Ok((Status::Created, Json(invitation)))
+}
+
+/// Values every email template preview gets, standing in for what the
+/// jobs put in the context. Query parameters override them.
+fn preview_context(params: &HashMap<&str, &str>) -> tera::Context {
+    let domain = env::var("JELLY_DOMAIN").unwrap_or_default();
+    let mut context = tera::Context::new();
+    context.insert("name", "Sample User");
+    context.insert("email", "sample@example.com");
+    context.insert("new_email", "new-sample@example.com");
+    context.insert("action_url", &format!("{}/accounts/preview-link", domain));
+    context.insert("help_url", &env::var("JELLY_HELP_URL").unwrap_or_default());
+    for (key, value) in params {
+        context.insert(*key, value);
+    }
+    context
+}
+
+/// Renders an email template's HTML with sample values, for working on
+/// the templates, e.g. `/admin/email/preview/welcome?name=Ann`. Both the
+/// `.html` and `.txt` files are rendered, so errors in either show up.
+/// Nothing is sent. A `locale` parameter picks localized templates.
+#[get("/email/preview/<template>?<params..>")]
+pub fn preview_email(
+    _admin: AdminUser,
+    queue: PostgresQueue,
+    template: &str,
+    params: HashMap<&str, &str>,
+) -> error::Result<RawHtml<String>> {
+    let locale = params.get("locale").copied();
+    let email = queue.preview_email(template, preview_context(&params), locale)?;
+    Ok(RawHtml(email.body_html))
+}
+
+/// As `preview_email`, but shows the plaintext body.
+#[get("/email/preview/<template>/text?<params..>")]
+pub fn preview_email_text(
+    _admin: AdminUser,
+    queue: PostgresQueue,
+    template: &str,
+    params: HashMap<&str, &str>,
+) -> error::Result<String> {
+    let locale = params.get("locale").copied();
+    let email = queue.preview_email(template, preview_context(&params), locale)?;
+    Ok(email.body)
+}
+
+/// Connections open and in use in the app's and the job workers' database
+/// pools, for telling whether either is exhausted.
+#[get("/db/stats")]
+pub async fn pool_stats(_admin: AdminUser, db: &AppDb, queue: PostgresQueue) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "app": PoolStats::of(db),
+        "jobs": queue.pool_stats(),
+    }))
+}
+
+/// Job queue depth, lag and throughput, for monitoring.
+#[get("/queue/stats")]
+pub async fn queue_stats(_admin: AdminUser, queue: PostgresQueue) -> error::Result<Json<QueueStats>> {
+    Ok(Json(queue.stats().await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::database::test_connection;
+    use crate::jobs::test_queue;
+    use crate::routes::accounts::NewAccount;
+
+    #[rocket::async_test]
+    async fn previews_render_both_parts_with_the_given_values() {
+        use std::sync::{Arc, RwLock};
+
+        use crate::oauth::client::OAuthProviders;
+
+        env::set_var("EMAIL_DEFAULT_FROM", "app@example.com");
+        let mut tera = tera::Tera::default();
+        tera.add_raw_templates(vec![
+            ("preview-test.html", "<p>Hello {{ name }}</p>"),
+            ("preview-test.txt", "Hello {{ name }} at {{ email }}"),
+        ]).unwrap();
+        // Previews never touch the database, so the pool never connects.
+        let pool = sqlx::PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+        let queue = PostgresQueue::new(
+            pool, Arc::new(RwLock::new(tera)), AppConfig::default(), OAuthProviders::default(), 5, 1);
+
+        let params = HashMap::from([("name", "Ann")]);
+        let email = queue.preview_email("preview-test", preview_context(&params), None).unwrap();
+        let missing = queue.preview_email("no-such-template", preview_context(&params), None);
+
+        assert_eq!(email.body_html, "<p>Hello Ann</p>");
+        assert_eq!(email.body, "Hello Ann at sample@example.com");
+        assert_eq!(missing.map(|_| ()).map_err(|e| e.status), Err(Status::NotFound));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn resending_the_welcome_queues_it_for_verified_accounts_only() {
+        let (mut conn, queue) = (test_connection().await, test_queue().await);
+
+        let email = format!("welcome-{}@example.com", ulid::Ulid::new().to_string().to_lowercase());
+        let aliases = AppConfig::default().accounts.email_aliases;
+        let new_account = NewAccount { name: "Welcome Again", email: &email, password: "a long test password" };
+        Account::register(&new_account, aliases, &mut conn).await.unwrap();
+        let id = Account::id_by_email(&email, aliases, &mut conn).await.unwrap();
+
+        let unverified = push_welcome(id, &mut conn, &queue).await.map_err(|e| e.status);
+        Account::mark_verified(id, &mut conn).await.unwrap();
+        let verified = push_welcome(id, &mut conn, &queue).await.map_err(|e| e.status);
+        let missing = push_welcome(-1, &mut conn, &queue).await.map_err(|e| e.status);
+
+        let (queued,): (i64,) = sqlx::query_as("SELECT count(*) FROM queue WHERE message->>'SendWelcomeAccountEmail' = $1")
+            .bind(&email)
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM queue WHERE message->>'SendWelcomeAccountEmail' = $1")
+            .bind(&email)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM accounts WHERE id = $1").bind(id).execute(&mut conn).await.unwrap();
+
+        assert_eq!(unverified, Err(Status::Conflict));
+        assert_eq!(verified, Ok(()));
+        assert_eq!(missing, Err(Status::NotFound));
+        assert_eq!(queued, 1);
+    }
+}
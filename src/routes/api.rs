@@ -0,0 +1,194 @@
+//! A machine readable description of the account routes, for API
+//! clients, mounted at "/api".
+//!
+//! It isn't OpenAPI, just each route's method, path and form fields, with
+//! the validation each field gets. Form structs list their own fields by
+//! implementing `Describe`, next to their definitions, so the two are
+//! easy to keep in step; password rules come from the running config.
+
+use rocket::serde::json::Json;
+use rocket::{get, State};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::config::AppConfig;
+use crate::csrf::CSRF_FIELD;
+use crate::routes::accounts::{
+    ChangePasswordSubmit, LoginSubmit, NewAccountSubmit, NewEmailSubmit, NewPasswordSubmit, SendLinkSubmit,
+    SettingsSubmit, StrengthCheck,
+};
+
+#[derive(Debug, Serialize)]
+pub struct FieldDescription {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub required: bool,
+    pub constraints: Value,
+}
+
+impl FieldDescription {
+    pub fn string(name: &'static str, constraints: Value) -> Self {
+        FieldDescription { name, kind: "string", required: true, constraints }
+    }
+
+    pub fn boolean(name: &'static str) -> Self {
+        FieldDescription { name, kind: "boolean", required: false, constraints: json!({}) }
+    }
+
+    pub fn optional(mut self) -> Self {
+        self.required = false;
+        self
+    }
+}
+
+/// A form that can list its fields for the API description.
+pub trait Describe {
+    fn describe(config: &AppConfig) -> Vec<FieldDescription>;
+}
+
+#[derive(Debug, Serialize)]
+struct RouteDescription {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    /// Whether the route answers in JSON when asked to with `Accept`.
+    json: bool,
+    /// Whether a login session is needed.
+    authenticated: bool,
+    fields: Vec<FieldDescription>,
+}
+
+impl RouteDescription {
+    fn new(method: &'static str, path: &'static str, summary: &'static str) -> Self {
+        RouteDescription { method, path, summary, json: false, authenticated: false, fields: Vec::new() }
+    }
+
+    fn json(mut self) -> Self {
+        self.json = true;
+        self
+    }
+
+    fn authenticated(mut self) -> Self {
+        self.authenticated = true;
+        self
+    }
+
+    /// The form's fields, plus the CSRF token every POST needs.
+    fn form<F: Describe>(self, config: &AppConfig) -> Self {
+        let mut fields = F::describe(config);
+        fields.push(csrf_field());
+        self.fields(fields)
+    }
+
+    fn fields(mut self, fields: Vec<FieldDescription>) -> Self {
+        self.fields = fields;
+        self
+    }
+}
+
+/// The token from the CSRF cookie, which the form pages (and their JSON
+/// variants) hand out.
+fn csrf_field() -> FieldDescription {
+    FieldDescription::string(CSRF_FIELD, json!({ "equals": "csrf token" }))
+}
+
+fn csrf_only() -> Vec<FieldDescription> {
+    vec![csrf_field()]
+}
+
+/// Lists the account routes. Form bodies are `application/x-www-form-urlencoded`.
+#[get("/")]
+pub fn description(config: &State<AppConfig>) -> Json<Value> {
+    let routes = vec![
+        RouteDescription::new("GET", "/accounts/register", "Registration form; JSON clients get a CSRF token").json(),
+        RouteDescription::new("POST", "/accounts/register", "Create an account and send a verification email")
+            .json()
+            .form::<NewAccountSubmit>(config),
+        RouteDescription::new("POST", "/accounts/password-strength", "Rate a password, with an estimated crack time")
+            .json()
+            .fields(StrengthCheck::describe(config)),
+        RouteDescription::new("GET", "/accounts/login", "Login form; JSON clients get a CSRF token").json(),
+        RouteDescription::new("POST", "/accounts/login", "Log in")
+            .json()
+            .form::<LoginSubmit>(config),
+        RouteDescription::new("POST", "/accounts/logout", "Log out").fields(csrf_only()),
+        RouteDescription::new("POST", "/accounts/logout-all", "Log out of every session")
+            .authenticated()
+            .fields(csrf_only()),
+        RouteDescription::new("GET", "/accounts/verify/<token>", "Verify an email address"),
+        RouteDescription::new("POST", "/accounts/resend", "Resend the verification email")
+            .form::<SendLinkSubmit>(config),
+        RouteDescription::new("POST", "/accounts/reset", "Send a password reset email")
+            .form::<SendLinkSubmit>(config),
+        RouteDescription::new("POST", "/accounts/reset/<token>", "Set a new password")
+            .form::<ChangePasswordSubmit>(config),
+        RouteDescription::new("POST", "/accounts/settings", "Update account settings")
+            .authenticated()
+            .form::<SettingsSubmit>(config),
+        RouteDescription::new("POST", "/accounts/settings/password", "Change the password, given the current one")
+            .authenticated()
+            .form::<NewPasswordSubmit>(config),
+        RouteDescription::new("POST", "/accounts/settings/email", "Send a link confirming a new email address")
+            .authenticated()
+            .form::<NewEmailSubmit>(config),
+        RouteDescription::new("GET", "/accounts/settings/email/<token>", "Confirm a new email address"),
+        RouteDescription::new("POST", "/accounts/settings/identities/<provider>/unlink", "Unlink an OAuth identity")
+            .authenticated()
+            .fields(csrf_only()),
+        RouteDescription::new("POST", "/accounts/settings/delete", "Delete the account")
+            .authenticated()
+            .fields(csrf_only()),
+    ];
+
+    Json(json!({ "routes": routes }))
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::asynchronous::Client;
+    use rocket::routes;
+
+    use super::*;
+    use crate::config::RegistrationMode;
+
+    /// The fields of the registration route, by name, as described.
+    async fn registration_fields(config: AppConfig) -> Value {
+        let client = Client::tracked(rocket::build().manage(config).mount("/api", routes![description]))
+            .await
+            .unwrap();
+        let body: Value = client.get("/api/").dispatch().await.into_json().await.unwrap();
+
+        let route = body["routes"].as_array().unwrap().iter()
+            .find(|route| route["method"] == "POST" && route["path"] == "/accounts/register")
+            .expect("no registration route")
+            .clone();
+        route["fields"].as_array().unwrap().iter()
+            .map(|field| (field["name"].as_str().unwrap().to_string(), field.clone()))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    #[rocket::async_test]
+    async fn registration_fields_are_described_with_their_constraints() {
+        let config = AppConfig::default();
+        let min_length = config.passwords.min_length;
+        let fields = registration_fields(config).await;
+
+        assert_eq!(fields["account.name"]["constraints"]["min_length"], 1);
+        assert_eq!(fields["account.email"]["constraints"]["contains"], "@");
+        assert_eq!(fields["account.password"]["constraints"]["min_length"], min_length);
+        assert_eq!(fields["account.password"]["required"], true);
+        assert!(fields.get(CSRF_FIELD).is_some());
+        assert!(fields.get("invite").is_none());
+    }
+
+    #[rocket::async_test]
+    async fn invite_only_registration_describes_the_invitation() {
+        let mut config = AppConfig::default();
+        config.accounts.registration = RegistrationMode::InviteOnly;
+
+        let fields = registration_fields(config).await;
+        assert_eq!(fields["invite"]["constraints"]["invitation_for_email"], true);
+    }
+}
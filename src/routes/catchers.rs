@@ -0,0 +1,19 @@
+//! Error catchers, registered at "/"
+
+use rocket::catch;
+use rocket::http::Method;
+use rocket::Request;
+
+use crate::response::LoginRedirect;
+
+/// Requests with a corrupt session cookie fail the `User` guard with
+/// `Unauthorized`; send them to the login form instead of an error page,
+/// returning to the page afterwards if it was a `GET`.
+#[catch(401)]
+pub fn unauthorized(req: &Request<'_>) -> LoginRedirect {
+    if req.method() == Method::Get {
+        LoginRedirect::new(Some(&req.uri().to_string()))
+    } else {
+        LoginRedirect::new(None)
+    }
+}
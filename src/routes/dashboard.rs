@@ -0,0 +1,19 @@
+//! Dashboard routes, mounted at "/"
+
+use rocket::get;
+use rocket::request::FlashMessage;
+use rocket_dyn_templates::Template;
+
+use crate::csrf::CsrfToken;
+use crate::models::User;
+use crate::response::flash_context;
+
+/// The landing page for logged in users. Anonymous users forward to
+/// `guests::get`, which sends them to the login page.
+#[get("/dashboard")]
+pub async fn dashboard(user: User, flash: Option<FlashMessage<'_>>, csrf: CsrfToken) -> Template {
+    let mut context = flash_context(flash);
+    context.insert("user", &user);
+    context.insert("csrf", csrf.value());
+    Template::render("dashboard/index", &context.into_json())
+}
@@ -0,0 +1,63 @@
+//! Development-only routes, mounted at "/dev" under the debug profile.
+
+use std::env;
+
+use rocket::get;
+use rocket::http::Status;
+
+use crate::jobs::{Message, PostgresQueue};
+
+/// Runs the named job right away with sample parameters, e.g.
+/// `/dev/jobs/run/SendWelcomeAccountEmail?to=me@example.com`, and reports
+/// how it went. Emails go to `to`, or to `EMAIL_DEFAULT_FROM`; jobs that
+/// look up an account need one with that email. Failures are reported in
+/// full, since this is only for developers. Recurring jobs can't be run
+/// this way; see `Message::sample`.
+#[get("/jobs/run/<name>?<to>")]
+pub async fn run_job(queue: PostgresQueue, name: &str, to: Option<&str>) -> Result<String, (Status, String)> {
+    let default_to = env::var("EMAIL_DEFAULT_FROM").unwrap_or_default();
+    let to = to.unwrap_or(&default_to);
+
+    let message = Message::sample(name, to)
+        .ok_or_else(|| (Status::NotFound, format!("no job named {}", name)))?;
+
+    match queue.run_now(message).await {
+        Ok(()) => Ok(format!("{} ran successfully", name)),
+        Err(e) => Err((Status::InternalServerError, format!("{} failed: {:#}", name, e.error))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::test_queue;
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn runs_a_known_job() {
+        let queue = test_queue().await;
+
+        // Addresses without an account get no login link, so this runs
+        // without sending anything.
+        let result = run_job(queue, "SendMagicLinkEmail", Some("nobody@example.invalid")).await;
+        assert_eq!(result, Ok("SendMagicLinkEmail ran successfully".to_string()));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn unknown_job_is_not_found() {
+        let queue = test_queue().await;
+
+        let result = run_job(queue, "NoSuchJob", None).await;
+        assert_eq!(result.map_err(|(status, _)| status), Err(Status::NotFound));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn recurring_jobs_are_not_found() {
+        let queue = test_queue().await;
+
+        let result = run_job(queue, "PurgeUnverifiedAccounts", None).await;
+        assert_eq!(result.map_err(|(status, _)| status), Err(Status::NotFound));
+    }
+}
@@ -0,0 +1,60 @@
+//! Fallbacks for anonymous requests to pages that need a login, mounted
+//! under each prefix whose routes are guarded by `User` or `AdminUser`.
+//!
+//! Those guards forward anonymous requests, so a path can have both a
+//! user route and a guest route, with the guest one ranked lower. These
+//! catch whatever is left and send guests to the login page. Logged in
+//! users forward on past them, to a 404.
+
+use rocket::http::uri::Origin;
+use rocket::{get, post};
+
+use crate::auth::Guest;
+use crate::response::LoginRedirect;
+
+// Ranked after every other route on the same paths. The page asked for
+// is kept, so the user lands back on it after logging in.
+#[get("/<_..>", rank = 100)]
+pub fn get(_guest: Guest, uri: &Origin<'_>) -> LoginRedirect {
+    LoginRedirect::new(Some(&uri.to_string()))
+}
+
+// There's no returning to a form submission, so posts just log in.
+#[post("/<_..>", rank = 100)]
+pub fn post(_guest: Guest) -> LoginRedirect {
+    LoginRedirect::new(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+    use rocket::routes;
+
+    use super::*;
+
+    async fn client() -> Client {
+        Client::tracked(rocket::build().mount("/dashboard", routes![get, post])).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn guests_are_sent_to_log_in_and_back() {
+        let client = client().await;
+        let response = client.get("/dashboard/reports?year=2022").dispatch().await;
+
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("/accounts/login?next=%2Fdashboard%2Freports%3Fyear%3D2022"),
+        );
+    }
+
+    #[rocket::async_test]
+    async fn guests_posting_are_sent_to_log_in() {
+        let client = client().await;
+        let response = client.post("/dashboard/reports").dispatch().await;
+
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(response.headers().get_one("Location"), Some("/accounts/login"));
+    }
+}
@@ -0,0 +1,57 @@
+//! Health checks for load balancers and uptime monitors, mounted at "/"
+
+use std::time::Duration;
+
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, State};
+
+use crate::database::{self, AppDb};
+use crate::jobs::{PostgresQueue, WorkerHeartbeat};
+
+/// How long the database gets to answer before we report it down.
+const DB_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Seconds without a worker heartbeat before we report it down. The worker
+/// beats between batches of jobs, so this allows for a slow batch as well
+/// as several poll intervals.
+const HEARTBEAT_MAX_AGE: i64 = 30;
+
+/// Reports whether the database is reachable, with the number of queued
+/// jobs. Needs no login.
+#[get("/healthz")]
+pub async fn healthz(db: &AppDb, queue: PostgresQueue) -> (Status, Json<serde_json::Value>) {
+    if let Err(e) = database::ping(db, DB_PING_TIMEOUT).await {
+        tracing::error!(error = %e, "health check failed");
+        return (Status::ServiceUnavailable, Json(serde_json::json!({ "db": "unavailable" })));
+    }
+
+    let queued = queue.count_queued().await.ok();
+    (Status::Ok, Json(serde_json::json!({ "db": "ok", "queued_jobs": queued })))
+}
+
+/// Like `healthz`, but also reports not ready if the job worker has
+/// stopped going round its loop.
+#[get("/readyz")]
+pub async fn readyz(db: &AppDb, heartbeat: &State<WorkerHeartbeat>) -> (Status, Json<serde_json::Value>) {
+    let db_ok = match database::ping(db, DB_PING_TIMEOUT).await {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::error!(error = %e, "readiness check failed");
+            false
+        }
+    };
+
+    let age = heartbeat.age();
+    let worker_ok = matches!(age, Some(age) if age <= HEARTBEAT_MAX_AGE);
+    if !worker_ok {
+        tracing::error!(heartbeat_age_secs = ?age, "readiness check failed: job worker heartbeat is stale");
+    }
+
+    let status = if db_ok && worker_ok { Status::Ok } else { Status::ServiceUnavailable };
+    (status, Json(serde_json::json!({
+        "db": if db_ok { "ok" } else { "unavailable" },
+        "worker": if worker_ok { "ok" } else { "stalled" },
+        "worker_heartbeat_age": age,
+    })))
+}
@@ -1,9 +1,32 @@
-//! Routes for OAuth2
+//! Routes for OAuth2, mounted at "/oauth"
 
-use rocket::form::FromForm;
+use rocket::form::{Contextual, Form, FromForm};
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::response::Redirect;
+use rocket::uri;
+use rocket::{get, post, State};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
 use serde::{Deserialize, Serialize};
 
+use crate::auth;
+use crate::auth::ClientInfo;
+use crate::config::AppConfig;
+use crate::csrf::{CsrfToken, CSRF_FIELD};
+use crate::database::AppDb;
+use crate::jobs::PostgresQueue;
+use crate::models::Account;
 use crate::oauth;
+use crate::oauth::client::OAuthProviders;
+use crate::oauth::{ClientFlow, FlowStorage, OAuthFlow, ProviderTokens, BROWSER_COOKIE, TOKENS_COOKIE};
+use crate::response::RenderOrRedirect;
+
+/// Private cookie holding the `OAuthFlow` between login and callback.
+const FLOW_COOKIE: &str = "oauth_flow";
+
+/// Private cookie holding the verified provider identity between the
+/// callback and the confirmation form.
+const IDENTITY_COOKIE: &str = "oauth_identity";
 
 fn default_provider() -> String {
   oauth::client::DEFAULT_PROVIDER.to_string()
@@ -18,14 +41,14 @@ pub struct OAuthLoginData {
 }
 
 impl OAuthLoginData {
-  pub fn new(provider: &str) -> Self {
-      let provider = if oauth::client::valid_provider(provider) {
+  pub fn new(providers: &OAuthProviders, provider: &str) -> Self {
+      let provider = if providers.is_valid(provider) {
           provider
       } else {
           oauth::client::DEFAULT_PROVIDER
       };
 
-      let hints = oauth::client::provider_hints(provider);
+      let hints = providers.hints(provider);
       OAuthLoginData {
           provider: provider.to_string(),
           email_hint: hints.map_or(false, |hint| hint.uses_email_hint),
@@ -41,3 +64,367 @@ pub struct LinkIdentityData {
     pub name: String,
     pub email: String,
 }
+
+fn render_login(form: &OAuthLoginData, csrf: &CsrfToken, error: Option<&str>) -> Template {
+    let errors = match error {
+        Some(message) => serde_json::json!({ "email": [{ "message": message }] }),
+        None => serde_json::json!({}),
+    };
+
+    Template::render("oauth/login", serde_json::json!({
+        "form": form,
+        "errors": errors,
+        "csrf": csrf.value(),
+    }))
+}
+
+fn render_failed(provider: &str, message: &str) -> Template {
+    Template::render("oauth/failed", serde_json::json!({
+        "provider": provider,
+        "message": message,
+    }))
+}
+
+/// Show the login form for a provider.
+#[get("/login/<provider>")]
+pub async fn login_form(providers: &State<OAuthProviders>, provider: &str, csrf: CsrfToken) -> Template {
+    render_login(&OAuthLoginData::new(providers, provider), &csrf, None)
+}
+
+/// Starts the authorization: stashes the CSRF token and PKCE verifier,
+/// in a private cookie or the database depending on the `FlowStorage`,
+/// and redirects to the provider.
+#[post("/login", data = "<form>")]
+pub async fn login<'a>(
+    cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
+    providers: &State<OAuthProviders>,
+    storage: &State<FlowStorage>,
+    config: &State<AppConfig>,
+    mut db: Connection<AppDb>,
+    form: Form<Contextual<'a, OAuthLoginData>>,
+) -> RenderOrRedirect {
+    if let Err(status) = csrf.verify(form.context.field_value(CSRF_FIELD)) {
+        return status.into();
+    }
+
+    let value = match &form.value {
+        Some(value) => value,
+        None => {
+            let provider = form.context.field_value("provider").unwrap_or_default();
+            return render_login(&OAuthLoginData::new(providers, provider), &csrf, Some("invalid login request")).into();
+        }
+    };
+
+    let client = match providers.client(&value.provider) {
+        Ok(client) => client,
+        Err(_) => return render_login(value, &csrf, Some("unsupported provider")).into(),
+    };
+
+    let login_hint = Some(value.email.as_str()).filter(|email| value.email_hint && !email.is_empty());
+    let (url, csrf_token, pkce_verifier) = client.authorize_url(login_hint);
+
+    let flow = OAuthFlow {
+        provider: value.provider.clone(),
+        email: value.email.clone(),
+        authorization_code: String::new(),
+        csrf_token_secret: csrf_token.secret().clone(),
+        pkce_verifier_secret: pkce_verifier.secret().clone(),
+    };
+    match storage.inner() {
+        FlowStorage::Cookie => cookies.add_private(Cookie::new(FLOW_COOKIE, serde_json::json!(flow).to_string())),
+        FlowStorage::Database => {
+            let browser = oauth::browser_nonce();
+            if let Err(e) = flow.save(&browser, db.as_mut()).await {
+                tracing::error!(provider = %flow.provider, error = %e, "could not store login flow");
+                return render_login(value, &csrf, Some("could not start the login, please try again")).into();
+            }
+            cookies.add_private(config.cookies.scoped(Cookie::new(BROWSER_COOKIE, browser)));
+        },
+    }
+
+    Redirect::to(url).into()
+}
+
+/// The provider redirects here after the user has (or hasn't) authorized
+/// us. If the user declined, or the provider reports any other error,
+/// a friendly page is shown instead of attempting the token exchange.
+/// Otherwise the code is exchanged and the user is asked to confirm
+/// their details.
+#[get("/callback?<code>&<state>&<error>&<error_description>")]
+pub async fn callback<'a>(
+    cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
+    providers: &State<OAuthProviders>,
+    storage: &State<FlowStorage>,
+    config: &State<AppConfig>,
+    mut db: Connection<AppDb>,
+    code: Option<&str>,
+    state: Option<&str>,
+    error: Option<&str>,
+    error_description: Option<&str>,
+) -> Result<Template, Status> {
+    let state = state.unwrap_or_default();
+    let flow = match storage.inner() {
+        FlowStorage::Cookie => {
+            let flow = cookies.get_private(FLOW_COOKIE)
+                .and_then(|cookie| serde_json::from_str::<OAuthFlow>(cookie.value()).ok());
+            cookies.remove_private(Cookie::named(FLOW_COOKIE));
+            flow
+        },
+        // Without the browser's nonce, the callback may have been opened
+        // somewhere other than where the login started, so it's refused.
+        FlowStorage::Database => match cookies.get_private(BROWSER_COOKIE) {
+            Some(browser) => {
+                cookies.remove_private(config.cookies.scoped(Cookie::named(BROWSER_COOKIE)));
+                OAuthFlow::take(state, browser.value(), db.as_mut()).await.unwrap_or_else(|e| {
+                    tracing::error!(error = %e, "could not load login flow");
+                    None
+                })
+            },
+            None => None,
+        },
+    };
+
+    let flow = match flow {
+        Some(flow) => flow,
+        None => return Ok(render_failed("", "Your login session expired. Please try again.")),
+    };
+
+    // The state must be the one sent with this browser's authorization
+    // request, or the callback may be a forged one. The flow has already
+    // been removed, so it can't be replayed either way.
+    if !flow.matches_state(state) {
+        tracing::warn!(provider = %flow.provider, "callback state does not match the login flow");
+        return Err(Status::BadRequest);
+    }
+
+    let code = match (error, code) {
+        (None, Some(code)) => code,
+        (error, _) => {
+            tracing::info!(provider = %flow.provider, error = error.unwrap_or("no code"),
+                description = error_description.unwrap_or_default(), "authorization was not granted");
+
+            return Ok(Template::render("oauth/cancelled", serde_json::json!({
+                "provider": flow.provider,
+                "denied": error == Some("access_denied"),
+            })));
+        }
+    };
+
+    let client = match providers.client(&flow.provider) {
+        Ok(client) => client,
+        Err(_) => return Ok(render_failed(&flow.provider, "That provider is not supported.")),
+    };
+
+    let provider = flow.provider.clone();
+    let client_flow = ClientFlow { client, flow: flow.set_authorization_code(code) };
+    let token_info = match rocket::tokio::task::spawn_blocking(move || oauth::request_token(client_flow)).await {
+        Ok(Ok(token_info)) => token_info,
+        Ok(Err(e)) => {
+            tracing::error!(%provider, error = %e, "token exchange failed");
+            return Ok(render_failed(&provider, "We could not complete the login."));
+        },
+        Err(e) => {
+            tracing::error!(%provider, error = %e, "token exchange task failed");
+            return Ok(render_failed(&provider, "We could not complete the login."));
+        },
+    };
+
+    let (user_info, tokens) = match oauth::fetch_user_info(token_info).await {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            tracing::error!(%provider, error = %e, "profile fetch failed");
+            return Ok(render_failed(&provider, "We could not read your profile."));
+        }
+    };
+
+    // Without them, the identity is still saved; it just can't call the
+    // provider's API until the user logs in with it again.
+    match tokens.stash(db.as_mut()).await {
+        Ok(key) => cookies.add_private(config.cookies.scoped(Cookie::new(TOKENS_COOKIE, key))),
+        Err(e) => tracing::error!(%provider, error = %e, "could not keep tokens"),
+    }
+
+    let identity = LinkIdentityData {
+        provider: provider.clone(),
+        username: user_info.username.unwrap_or_else(|| user_info.id.clone()),
+        name: user_info.name,
+        email: user_info.provider_email.unwrap_or(user_info.login_email),
+    };
+    cookies.add_private(Cookie::new(IDENTITY_COOKIE, serde_json::json!(identity).to_string()));
+
+    Ok(Template::render("oauth/confirm", serde_json::json!({
+        "form": identity,
+        "errors": {},
+        "csrf": csrf.value(),
+    })))
+}
+
+/// Completes the login, registering, merging or linking the provider
+/// identity as needed. The provider and username come from the identity
+/// verified in the callback, never from the submitted form.
+#[post("/confirm", data = "<form>")]
+pub async fn confirm<'a>(
+    cookies: &CookieJar<'a>,
+    csrf: CsrfToken,
+    mut db: Connection<AppDb>,
+    config: &State<AppConfig>,
+    client: ClientInfo,
+    form: Form<Contextual<'a, LinkIdentityData>>,
+    queue: PostgresQueue,
+) -> RenderOrRedirect {
+    if let Err(status) = csrf.verify(form.context.field_value(CSRF_FIELD)) {
+        return status.into();
+    }
+
+    let verified = cookies.get_private(IDENTITY_COOKIE)
+        .and_then(|cookie| serde_json::from_str::<LinkIdentityData>(cookie.value()).ok());
+
+    let verified = match verified {
+        Some(verified) => verified,
+        None => return render_failed("", "Your login session expired. Please try again.").into(),
+    };
+
+    let identity = match &form.value {
+        Some(value) => LinkIdentityData {
+            name: value.name.clone(),
+            email: value.email.clone(),
+            ..verified
+        },
+        None => {
+            return Template::render("oauth/confirm", serde_json::json!({
+                "form": verified,
+                "errors": {},
+                "csrf": csrf.value(),
+            })).into();
+        }
+    };
+
+    let conn: &mut sqlx::PgConnection = db.as_mut();
+    let tokens_key = cookies.get_private(TOKENS_COOKIE).map(|cookie| cookie.value().to_string());
+    let tokens = match &tokens_key {
+        Some(key) => ProviderTokens::load(key, conn).await.unwrap_or_else(|e| {
+            tracing::error!(provider = %identity.provider, error = %e, "could not load tokens");
+            None
+        }),
+        None => None,
+    };
+    let current_account_id = auth::user(cookies)
+        .ok()
+        .filter(|user| !user.is_anonymous)
+        .map(|user| user.id);
+
+    // Only consulted if the identity would register a new account.
+    let allow_registration = config.accounts.registration.is_open()
+        && config.email_domains.validate(&identity.email).is_ok();
+
+    match Account::merge_identity_and_login(
+        identity.clone(),
+        tokens,
+        current_account_id,
+        allow_registration,
+        config.accounts.email_aliases,
+        conn,
+    ).await {
+        Ok(user) => {
+            cookies.remove_private(Cookie::named(IDENTITY_COOKIE));
+            cookies.remove_private(config.cookies.scoped(Cookie::named(TOKENS_COOKIE)));
+            if let Some(key) = &tokens_key {
+                if let Err(e) = ProviderTokens::discard(key, conn).await {
+                    tracing::error!(provider = %identity.provider, error = %e, "could not discard tokens");
+                }
+            }
+            match auth::password_expired(user.id, &queue, conn).await {
+                Ok(false) => {},
+                Ok(true) => return render_failed(&identity.provider, "Your password has expired; we've emailed you a link to choose a new one.").into(),
+                Err(_) => return Status::InternalServerError.into(),
+            }
+            client.record_login(config, user.id, conn).await;
+            auth::set_user(cookies, config, user, false);
+            Redirect::to(uri!("/dashboard")).into()
+        },
+        Err(e) => {
+            tracing::error!(provider = %identity.provider, error = ?e, "could not link identity");
+            Template::render("oauth/confirm", serde_json::json!({
+                "form": identity,
+                "errors": { "email": [{ "message": "could not complete login with these details" }] },
+                "csrf": csrf.value(),
+            })).into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::asynchronous::{Client, LocalResponse};
+    use rocket::routes;
+    use rocket_db_pools::Database;
+
+    use super::*;
+
+    /// A client for the callback, with the flow kept in a cookie. The
+    /// route needs a database connection, so it uses the test database.
+    async fn client() -> Client {
+        let figment = rocket::Config::figment()
+            .merge(("databases.app_db.url", crate::database::test_database_url()));
+        let rocket = rocket::custom(figment)
+            .attach(AppDb::init())
+            .attach(Template::fairing())
+            .manage(AppConfig::default())
+            .manage(OAuthProviders::default())
+            .manage(FlowStorage::Cookie)
+            .mount("/oauth", routes![callback]);
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    async fn callback_with<'c>(client: &'c Client, query: &str) -> LocalResponse<'c> {
+        let flow = OAuthFlow {
+            provider: "github".to_string(),
+            email: String::new(),
+            authorization_code: String::new(),
+            csrf_token_secret: "the-state".to_string(),
+            pkce_verifier_secret: "the-verifier".to_string(),
+        };
+
+        client.get(format!("/oauth/callback?{}", query))
+            .private_cookie(Cookie::new(FLOW_COOKIE, serde_json::json!(flow).to_string()))
+            .dispatch()
+            .await
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn denied_authorization_shows_the_cancelled_page() {
+        let client = client().await;
+
+        let response = callback_with(&client,
+            "state=the-state&error=access_denied&error_description=The+user+denied+access").await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains("Authorization was cancelled"));
+        assert!(body.contains("You chose not to allow access to your Github account"));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn other_provider_errors_show_the_cancelled_page() {
+        let client = client().await;
+
+        for query in ["state=the-state&error=server_error", "state=the-state"] {
+            let response = callback_with(&client, query).await;
+            assert_eq!(response.status(), Status::Ok, "{}", query);
+            let body = response.into_string().await.unwrap();
+            assert!(body.contains("Github did not complete the authorization"), "{}", query);
+        }
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn errors_with_the_wrong_state_are_refused() {
+        let client = client().await;
+
+        let response = callback_with(&client, "state=another-state&error=access_denied").await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+}
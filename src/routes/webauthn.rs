@@ -0,0 +1,270 @@
+//! Routes for passkey registration and login, mounted at "/webauthn"
+//!
+//! The begin and finish endpoints are called by the scripts in the
+//! `passkeys` macro, with JSON bodies. Being JSON, and checked by
+//! `SameOrigin`, they can't be forged by a plain cross-site form.
+
+use anyhow::anyhow;
+use rocket::form::Form;
+use rocket::http::{CookieJar, Status};
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::json::Json;
+use rocket::uri;
+use rocket::{get, post, State};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::Template;
+use serde::Deserialize;
+use webauthn_rs::prelude::{
+    Base64UrlSafeData, CreationChallengeResponse, Passkey, PublicKeyCredential,
+    RegisterPublicKeyCredential, RequestChallengeResponse,
+};
+
+use crate::auth;
+use crate::auth::{ClientInfo, Guest};
+use crate::config::AppConfig;
+use crate::csrf::{CsrfForm, CsrfToken, SameOrigin};
+use crate::database::AppDb;
+use crate::error;
+use crate::jobs::PostgresQueue;
+use crate::models::{Account, User, WebauthnCredential};
+use crate::ratelimit::RateLimit;
+use crate::response::{flash_messages, LoginRedirect};
+use crate::webauthn::{self, Passkeys};
+
+/// The longest name kept for a passkey.
+const MAX_NAME_LENGTH: usize = 64;
+
+fn bad_request(e: anyhow::Error) -> error::Error {
+    error::Error::with_status(e, Status::BadRequest)
+}
+
+/// What guests get from the endpoints for the current account's passkeys:
+/// `Unauthorized`, as JSON to the passkey scripts, and otherwise a
+/// redirect to the login form.
+fn login_required() -> error::Error {
+    error::Error::with_status(anyhow!("log in to manage passkeys"), Status::Unauthorized)
+}
+
+fn passkey_of(credential: &WebauthnCredential) -> error::Result<Passkey> {
+    Ok(serde_json::from_value(credential.passkey.clone())?)
+}
+
+/// The page for logging in with a passkey.
+#[get("/login")]
+pub fn passkey_login_form(flash: Option<FlashMessage<'_>>) -> Template {
+    Template::render("webauthn/login", serde_json::json!({
+        "flash_messages": flash_messages(flash),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginBegin {
+    pub email: String,
+}
+
+/// The challenge for logging in with one of the passkeys of the account
+/// with the given email. Fails with `BadRequest` if it has none.
+#[post("/login/begin", data = "<data>")]
+pub async fn passkey_login_begin(
+    _rate: RateLimit,
+    _origin: SameOrigin,
+    cookies: &CookieJar<'_>,
+    passkeys: &State<Passkeys>,
+    config: &State<AppConfig>,
+    mut db: Connection<AppDb>,
+    data: Json<LoginBegin>,
+) -> error::Result<Json<RequestChallengeResponse>> {
+    let aliases = config.accounts.email_aliases;
+    let credentials = WebauthnCredential::for_email(data.email.trim(), aliases, db.as_mut()).await?;
+    let account_id = credentials.first()
+        .map(|credential| credential.account_id)
+        .ok_or_else(|| bad_request(anyhow!("there are no passkeys for this email")))?;
+
+    let keys = credentials.iter().map(passkey_of).collect::<error::Result<Vec<_>>>()?;
+    let challenge = passkeys.start_authentication(cookies, account_id, &keys).map_err(bad_request)?;
+    Ok(Json(challenge))
+}
+
+/// Logs in with the authenticator's answer to the login challenge. The
+/// session is set up as for a password login, and `{ "redirect": ... }`
+/// says where to go next.
+#[post("/login/finish", data = "<credential>")]
+pub async fn passkey_login_finish(
+    _rate: RateLimit,
+    _origin: SameOrigin,
+    cookies: &CookieJar<'_>,
+    client: ClientInfo,
+    config: &State<AppConfig>,
+    passkeys: &State<Passkeys>,
+    mut db: Connection<AppDb>,
+    credential: Json<PublicKeyCredential>,
+    queue: PostgresQueue,
+) -> error::Result<Json<serde_json::Value>> {
+    let (account_id, result) = passkeys.finish_authentication(cookies, &credential).map_err(bad_request)?;
+
+    let conn: &mut sqlx::PgConnection = db.as_mut();
+    let stored = WebauthnCredential::find(account_id, &webauthn::credential_id(result.cred_id()), conn).await?;
+    let mut passkey = passkey_of(&stored)?;
+    let changed = passkey.update_credential(&result).unwrap_or(false);
+    let passkey = if changed { Some(serde_json::to_value(&passkey)?) } else { None };
+    WebauthnCredential::record_use(stored.id, passkey, conn).await?;
+
+    let account = Account::get(account_id, conn).await?;
+    if !account.is_active {
+        return Err(error::Error::with_status(anyhow!("account is inactive"), Status::Unauthorized));
+    }
+    if auth::password_expired(account.id, &queue, conn).await? {
+        return Err(error::Error::with_status(anyhow!(auth::PASSWORD_EXPIRED_MESSAGE), Status::Forbidden));
+    }
+
+    let _ignore = Account::update_last_login(account.id, conn).await;
+    client.record_login(config, account.id, conn).await;
+
+    auth::set_user(cookies, config, User {
+        id: account.id,
+        fingerprint: account.session_fingerprint(),
+        session_version: account.session_version,
+        name: account.name,
+        is_admin: account.is_admin,
+        is_anonymous: false,
+    }, false);
+
+    Ok(Json(serde_json::json!({ "redirect": "/dashboard" })))
+}
+
+/// The challenge for registering a new passkey to the current account.
+#[post("/register/begin")]
+pub async fn passkey_register_begin(
+    user: User,
+    _origin: SameOrigin,
+    cookies: &CookieJar<'_>,
+    passkeys: &State<Passkeys>,
+    mut db: Connection<AppDb>,
+) -> error::Result<Json<CreationChallengeResponse>> {
+    let conn: &mut sqlx::PgConnection = db.as_mut();
+    let account = user.load_account(conn).await?;
+    let existing = WebauthnCredential::for_account(account.id, conn).await?;
+
+    // Every passkey of an account shares its first one's user handle.
+    let user_handle = existing.first()
+        .map(|credential| credential.user_handle)
+        .unwrap_or_else(sqlx::types::Uuid::new_v4);
+    let exclude = existing.iter()
+        .filter_map(|credential| base64_url::decode(&credential.credential_id).ok())
+        .map(Base64UrlSafeData)
+        .collect();
+
+    let challenge = passkeys.start_registration(
+        cookies,
+        account.id,
+        webauthn::user_handle(&user_handle),
+        &account.email,
+        &account.name,
+        exclude,
+    ).map_err(bad_request)?;
+    Ok(Json(challenge))
+}
+
+#[post("/register/begin", rank = 2)]
+pub fn passkey_register_begin_guest(_guest: Guest) -> error::Error {
+    login_required()
+}
+
+/// Saves the passkey from the authenticator's answer to the registration
+/// challenge, under `name`, and returns `{ "id": ... }`.
+#[post("/register/finish?<name>", data = "<credential>")]
+pub async fn passkey_register_finish(
+    user: User,
+    _origin: SameOrigin,
+    cookies: &CookieJar<'_>,
+    passkeys: &State<Passkeys>,
+    mut db: Connection<AppDb>,
+    name: Option<&str>,
+    credential: Json<RegisterPublicKeyCredential>,
+) -> error::Result<Json<serde_json::Value>> {
+    let (user_handle, passkey) = passkeys.finish_registration(cookies, user.id, &credential).map_err(bad_request)?;
+
+    let name: String = name.map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or("Passkey")
+        .chars()
+        .take(MAX_NAME_LENGTH)
+        .collect();
+
+    let id = WebauthnCredential::create(
+        user.id,
+        webauthn::stored_user_handle(&user_handle),
+        &webauthn::credential_id(passkey.cred_id()),
+        &name,
+        serde_json::to_value(&passkey)?,
+        db.as_mut(),
+    ).await?;
+    Ok(Json(serde_json::json!({ "id": id })))
+}
+
+#[post("/register/finish", rank = 2)]
+pub fn passkey_register_finish_guest(_guest: Guest) -> error::Error {
+    login_required()
+}
+
+/// Removes one of the current account's passkeys.
+#[post("/credentials/<id>/delete", data = "<form>")]
+pub async fn delete_passkey<'a>(
+    user: User,
+    csrf: CsrfToken,
+    mut db: Connection<AppDb>,
+    id: i32,
+    form: Form<CsrfForm<'a>>,
+) -> Result<Flash<Redirect>, Status> {
+    csrf.verify(form.csrf)?;
+
+    let redirect = Redirect::to(uri!("/accounts/settings"));
+    Ok(match WebauthnCredential::delete(user.id, id, db.as_mut()).await {
+        Ok(_) => Flash::success(redirect, "Your passkey was removed."),
+        Err(e) => {
+            tracing::error!(error = ?e, "could not remove passkey");
+            Flash::error(redirect, "Could not remove the passkey.")
+        },
+    })
+}
+
+#[post("/credentials/<_>/delete", rank = 2)]
+pub fn delete_passkey_guest(_guest: Guest) -> LoginRedirect {
+    LoginRedirect::new(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{Accept, Status};
+    use rocket::local::asynchronous::Client;
+    use rocket::routes;
+
+    use super::*;
+
+    async fn client() -> Client {
+        let rocket = rocket::build().mount("/webauthn", routes![
+            passkey_register_begin_guest,
+            passkey_register_finish_guest,
+            delete_passkey_guest,
+        ]);
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn guests_registering_a_passkey_are_unauthorized() {
+        let client = client().await;
+        for uri in ["/webauthn/register/begin", "/webauthn/register/finish?name=Key"] {
+            let response = client.post(uri).header(Accept::JSON).dispatch().await;
+            assert_eq!(response.status(), Status::Unauthorized, "{}", uri);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn guests_deleting_a_passkey_are_sent_to_log_in() {
+        let client = client().await;
+        let response = client.post("/webauthn/credentials/1/delete").dispatch().await;
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(response.headers().get_one("Location"), Some("/accounts/login"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Delivery event webhooks from email providers, mounted at "/webhooks"
+//!
+//! Providers call these, not browsers, so there's no CSRF token or origin
+//! check. Instead each request must carry the shared secret from
+//! `EMAIL_WEBHOOK_SECRET`, read once at startup, in an `X-Webhook-Secret`
+//! header, which Postmark can be set up to send. Without the env var, the
+//! webhooks refuse everything.
+
+use std::env;
+
+use constant_time_eq::constant_time_eq;
+use rocket::http::Status;
+use rocket::{post, State};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+use rocket_db_pools::Connection;
+use serde::Deserialize;
+
+use crate::config::AppConfig;
+use crate::database::AppDb;
+use crate::error;
+use crate::models::{Account, EmailDeliveryStatus};
+
+/// The header carrying the shared secret.
+const SECRET_HEADER: &str = "X-Webhook-Secret";
+
+/// The shared secret webhook calls must carry, managed by Rocket.
+#[derive(Clone, Default)]
+pub struct EmailWebhookSecret(Option<String>);
+
+impl EmailWebhookSecret {
+    pub fn new(secret: Option<String>) -> Self {
+        EmailWebhookSecret(secret.filter(|secret| !secret.is_empty()))
+    }
+
+    pub fn from_env() -> Self {
+        EmailWebhookSecret::new(env::var("EMAIL_WEBHOOK_SECRET").ok())
+    }
+
+    fn matches(&self, given: &str) -> bool {
+        self.0.as_ref().map_or(false, |secret| constant_time_eq(given.as_bytes(), secret.as_bytes()))
+    }
+}
+
+/// Request guard for webhook calls: fails with `Forbidden` unless the
+/// `X-Webhook-Secret` header matches the managed `EmailWebhookSecret`.
+#[derive(Debug)]
+pub struct WebhookSecret;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WebhookSecret {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let secret = req.rocket().state::<EmailWebhookSecret>();
+        match (secret, req.headers().get_one(SECRET_HEADER)) {
+            (Some(secret), Some(given)) if secret.matches(given) =>
+                Outcome::Success(WebhookSecret),
+            _ => {
+                tracing::warn!("email webhook called without a valid secret");
+                Outcome::Failure((Status::Forbidden, ()))
+            }
+        }
+    }
+}
+
+/// The fields we use of a Postmark bounce or spam complaint webhook.
+/// Other record types (deliveries, opens) come through here too if
+/// they're turned on, and are ignored.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PostmarkEvent {
+    pub record_type: String,
+    /// The bounce type, e.g. "HardBounce" or "SoftBounce".
+    #[serde(default, rename = "Type")]
+    pub kind: Option<String>,
+    pub email: String,
+    /// Whether Postmark has stopped sending to the address.
+    #[serde(default)]
+    pub inactive: bool,
+}
+
+impl PostmarkEvent {
+    /// The delivery status the event means for the address, if it means
+    /// we should stop mailing it. Soft bounces and the like don't.
+    pub fn delivery_status(&self) -> Option<EmailDeliveryStatus> {
+        match self.record_type.as_str() {
+            "SpamComplaint" => Some(EmailDeliveryStatus::Complained),
+            "Bounce" if self.inactive || self.kind.as_deref() == Some("HardBounce") =>
+                Some(EmailDeliveryStatus::Bounced),
+            _ => None,
+        }
+    }
+}
+
+/// Receives Postmark's bounce and spam complaint webhooks, and marks the
+/// account with that address as undeliverable. Events for addresses with
+/// no account are accepted and ignored, so Postmark doesn't retry them.
+#[post("/email", format = "json", data = "<event>")]
+pub async fn postmark_event(
+    _secret: WebhookSecret,
+    config: &State<AppConfig>,
+    mut db: Connection<AppDb>,
+    event: Json<PostmarkEvent>,
+) -> error::Result<Status> {
+    if let Some(status) = event.delivery_status() {
+        tracing::info!(record_type = %event.record_type, kind = ?event.kind, "email is undeliverable");
+        Account::set_email_delivery_status(&event.email, status, config.accounts.email_aliases, db.as_mut()).await?;
+    }
+
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use rocket::routes;
+
+    use super::*;
+
+    #[post("/")]
+    fn hook(_secret: WebhookSecret) -> Status {
+        Status::NoContent
+    }
+
+    async fn client(secret: Option<&str>) -> Client {
+        let rocket = rocket::build()
+            .manage(EmailWebhookSecret::new(secret.map(String::from)))
+            .mount("/", routes![hook]);
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    async fn call(client: &Client, given: Option<&str>) -> Status {
+        let mut req = client.post("/");
+        if let Some(given) = given {
+            req = req.header(Header::new(SECRET_HEADER, given.to_string()));
+        }
+        req.dispatch().await.status()
+    }
+
+    #[rocket::async_test]
+    async fn only_the_shared_secret_is_accepted() {
+        let client = client(Some("s3cret")).await;
+        assert_eq!(call(&client, Some("s3cret")).await, Status::NoContent);
+        assert_eq!(call(&client, Some("wrong")).await, Status::Forbidden);
+        assert_eq!(call(&client, None).await, Status::Forbidden);
+    }
+
+    #[rocket::async_test]
+    async fn everything_is_refused_without_a_secret() {
+        for secret in [None, Some("")] {
+            let client = client(secret).await;
+            assert_eq!(call(&client, Some("")).await, Status::Forbidden);
+            assert_eq!(call(&client, None).await, Status::Forbidden);
+        }
+    }
+
+    #[test]
+    fn only_hard_bounces_and_complaints_stop_mail() {
+        let event = |record_type: &str, kind: Option<&str>| PostmarkEvent {
+            record_type: record_type.to_string(),
+            kind: kind.map(String::from),
+            email: "user@example.com".to_string(),
+            inactive: false,
+        };
+
+        assert_eq!(event("Bounce", Some("HardBounce")).delivery_status(), Some(EmailDeliveryStatus::Bounced));
+        assert_eq!(event("SpamComplaint", None).delivery_status(), Some(EmailDeliveryStatus::Complained));
+        assert_eq!(event("Bounce", Some("SoftBounce")).delivery_status(), None);
+        assert_eq!(event("Delivery", None).delivery_status(), None);
+    }
+}
@@ -0,0 +1,306 @@
+//! Storage for files such as avatars, email attachments and exports.
+//!
+//! Files go through the `Storage` trait, managed as `FileStorage`, and
+//! are named by keys, relative paths like "avatars/1-abc.png". The
+//! backend is picked in the `storage` config section:
+//!
+//! ```toml
+//! [default.storage]
+//! backend = "local"
+//! root = "uploads"
+//! url = "/uploads"
+//! ```
+//!
+//! `local`, the default, keeps files in `root` on the app server and
+//! serves them at `url`, so the starter works without cloud credentials.
+//! `s3`, with the `storage-s3` feature, keeps them in an S3 bucket, or
+//! any service with an S3-compatible API; credentials are read from the
+//! usual `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` env vars.
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::anyhow;
+use rocket::fairing::AdHoc;
+use rocket::fs::FileServer;
+use rocket::http::ContentType;
+use rocket::tokio::fs;
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+
+#[rocket::async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores `bytes` under `key`, replacing anything already there.
+    async fn put(&self, key: &str, content_type: &ContentType, bytes: Vec<u8>) -> anyhow::Result<()>;
+
+    /// The contents stored under `key`, or `None` if there are none.
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Removes what is stored under `key`. Missing keys are ignored.
+    async fn delete(&self, key: &str) -> anyhow::Result<()>;
+
+    /// The URL the file under `key` can be fetched from.
+    fn url_for(&self, key: &str) -> String;
+
+    /// The key of a URL given by `url_for`, or `None` for any other URL.
+    fn key_for(&self, url: &str) -> Option<String> {
+        url.strip_prefix(&self.url_for(""))
+            .filter(|key| !key.is_empty())
+            .map(String::from)
+    }
+}
+
+/// The storage in use, as managed state.
+pub type FileStorage = Box<dyn Storage>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    #[default]
+    Local,
+    S3,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// For `local`, the directory files are written to.
+    pub root: String,
+    /// For `local`, the path `root` is served from.
+    pub url: String,
+    pub s3: S3Config,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::Local,
+            root: "uploads".to_string(),
+            url: "/uploads".to_string(),
+            s3: S3Config::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct S3Config {
+    pub bucket: String,
+    /// An AWS region, e.g. "eu-west-1", or any name the service at
+    /// `endpoint` accepts.
+    pub region: String,
+    /// For S3-compatible services, their API URL, e.g.
+    /// "https://nyc3.digitaloceanspaces.com" or "http://localhost:9000".
+    pub endpoint: Option<String>,
+    /// Where files are publicly served from, e.g. a CDN in front of the
+    /// bucket. Defaults to the bucket's own URL.
+    pub public_url: Option<String>,
+}
+
+/// Keeps files in a directory on the app server.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    pub root: PathBuf,
+    /// The path `root` is served from.
+    pub url: String,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str, url: &str) -> Self {
+        LocalStorage {
+            root: PathBuf::from(root),
+            url: format!("/{}", url.trim_matches('/')),
+        }
+    }
+
+    /// The file for `key`, refusing keys that would leave `root`.
+    fn path_for(&self, key: &str) -> anyhow::Result<PathBuf> {
+        let key = Path::new(key);
+        if key.components().all(|c| matches!(c, Component::Normal(_))) {
+            Ok(self.root.join(key))
+        } else {
+            Err(anyhow!("invalid storage key {:?}", key))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, _content_type: &ContentType, bytes: Vec<u8>) -> anyhow::Result<()> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(&path, bytes).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match fs::read(self.path_for(key)?).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        match fs::remove_file(self.path_for(key)?).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn url_for(&self, key: &str) -> String {
+        format!("{}/{}", self.url.trim_end_matches('/'), key)
+    }
+}
+
+#[cfg(feature = "storage-s3")]
+pub mod s3 {
+    use rocket::http::ContentType;
+    use rocket::tokio::io::AsyncReadExt;
+    use rusoto_core::{Region, RusotoError};
+    use rusoto_s3::{
+        DeleteObjectRequest, GetObjectError, GetObjectRequest, PutObjectRequest, S3Client, S3,
+    };
+
+    use super::{S3Config, Storage};
+
+    /// Keeps files in an S3 bucket, or one on an S3-compatible service.
+    pub struct S3Storage {
+        client: S3Client,
+        bucket: String,
+        public_url: String,
+    }
+
+    impl S3Storage {
+        pub fn new(config: &S3Config) -> anyhow::Result<Self> {
+            if config.bucket.is_empty() {
+                anyhow::bail!("storage.s3.bucket is not set");
+            }
+
+            let region = match &config.endpoint {
+                Some(endpoint) => Region::Custom {
+                    name: config.region.clone(),
+                    endpoint: endpoint.trim_end_matches('/').to_string(),
+                },
+                None => config.region.parse()?,
+            };
+
+            let public_url = match (&config.public_url, &config.endpoint) {
+                (Some(url), _) => url.trim_end_matches('/').to_string(),
+                (None, Some(endpoint)) => format!("{}/{}", endpoint.trim_end_matches('/'), config.bucket),
+                (None, None) => format!("https://{}.s3.{}.amazonaws.com", config.bucket, region.name()),
+            };
+
+            Ok(S3Storage {
+                client: S3Client::new(region),
+                bucket: config.bucket.clone(),
+                public_url,
+            })
+        }
+    }
+
+    #[rocket::async_trait]
+    impl Storage for S3Storage {
+        async fn put(&self, key: &str, content_type: &ContentType, bytes: Vec<u8>) -> anyhow::Result<()> {
+            self.client.put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_string(),
+                content_type: Some(content_type.to_string()),
+                body: Some(bytes.into()),
+                ..Default::default()
+            }).await?;
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+            let output = match self.client.get_object(GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_string(),
+                ..Default::default()
+            }).await {
+                Ok(output) => output,
+                Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut bytes = Vec::new();
+            if let Some(body) = output.body {
+                body.into_async_read().read_to_end(&mut bytes).await?;
+            }
+            Ok(Some(bytes))
+        }
+
+        async fn delete(&self, key: &str) -> anyhow::Result<()> {
+            self.client.delete_object(DeleteObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_string(),
+                ..Default::default()
+            }).await?;
+            Ok(())
+        }
+
+        fn url_for(&self, key: &str) -> String {
+            format!("{}/{}", self.public_url, key)
+        }
+    }
+}
+
+/// The type of an image going by its first bytes, and the extension to
+/// store it with. Only PNG, JPEG, GIF and WebP are recognized. The
+/// `Content-Type` a browser sends is whatever the file is named, so this
+/// is what decides whether an upload really is an image.
+pub fn image_type(bytes: &[u8]) -> Option<(ContentType, &'static str)> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some((ContentType::PNG, "png"))
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some((ContentType::JPEG, "jpg"))
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some((ContentType::GIF, "gif"))
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some((ContentType::WEBP, "webp"))
+    } else {
+        None
+    }
+}
+
+/// Manages the configured backend as the `FileStorage`. With `local`,
+/// its directory is served as well. Launch is aborted if the backend
+/// can't be set up.
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("File storage", |rocket| async move {
+        let config = rocket.state::<AppConfig>()
+            .map(|config| config.storage.clone())
+            .unwrap_or_default();
+
+        match config.backend {
+            StorageBackend::Local => {
+                let storage = LocalStorage::new(&config.root, &config.url);
+                if let Err(e) = std::fs::create_dir_all(&storage.root) {
+                    rocket::error!("Could not create the storage directory {}: {}", storage.root.display(), e);
+                    return Err(rocket);
+                }
+
+                let server = FileServer::from(&storage.root);
+                let url = storage.url.clone();
+                Ok(rocket.mount(url, server).manage::<FileStorage>(Box::new(storage)))
+            },
+            #[cfg(feature = "storage-s3")]
+            StorageBackend::S3 => match s3::S3Storage::new(&config.s3) {
+                Ok(storage) => Ok(rocket.manage::<FileStorage>(Box::new(storage))),
+                Err(e) => {
+                    rocket::error!("Could not set up S3 storage: {}", e);
+                    Err(rocket)
+                }
+            },
+            #[cfg(not(feature = "storage-s3"))]
+            StorageBackend::S3 => {
+                rocket::error!("storage.backend is \"s3\", but the storage-s3 feature is not enabled");
+                Err(rocket)
+            },
+        }
+    })
+}
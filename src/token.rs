@@ -107,6 +107,34 @@ fn hash(value: &str, timestamp: u64) -> error::Result<UserToken> {
     Ok(UserToken{ uidb64: None, ts: ts.as_str().to_lowercase(), token: hash })
 }
 
+/// How long tokens are good for, in seconds, from PASSWORD_RESET_TIMEOUT.
+const DEFAULT_PASSWORD_RESET_TIMEOUT: usize = 259200;
+
+// TODO: Use Figment for configuration.
+fn password_reset_timeout() -> usize {
+    env::var("PASSWORD_RESET_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_PASSWORD_RESET_TIMEOUT)
+}
+
+/// Checks `token` against a throwaway value, doing the same work as
+/// `is_token_valid` would for a real account, and always fails. For
+/// when there is no account to check the token against, so that case
+/// takes about as long as a wrong token.
+pub fn dummy_check(token: &str) -> bool {
+    struct Nobody;
+
+    impl OneTimeUseTokenGenerator for Nobody {
+        fn hash_value(&self) -> String {
+            "NoAccountNoPasswordUnverified".to_string()
+        }
+    }
+
+    let _ignore = Nobody.is_token_valid(token);
+    false
+}
+
 /// An entry point for models to implement to enable reset password
 /// and verification logic.
 pub trait OneTimeUseTokenGenerator {
@@ -128,9 +156,17 @@ pub trait OneTimeUseTokenGenerator {
         hash(&value, since as u64).map(|t| t.to_string())
     }
 
-    /// Validates that the token we received is still acceptable;
-    /// internally this does both constant time comparison checks
-    /// as well as timestamp validation.
+    /// Validates that the token we received is still acceptable: that it
+    /// is the token we would make for this value at its timestamp, and
+    /// that the timestamp is no older than PASSWORD_RESET_TIMEOUT.
+    ///
+    /// Tokens arrive in URLs from anyone, so an attacker can submit as
+    /// many as rate limits allow and time the answers. The comparison is
+    /// constant time, so how long it takes says nothing about how much
+    /// of a guess was right, and the expiry is only checked after it, so
+    /// the answer for a stale token looks like any other wrong token.
+    /// Callers without an account to check against should use
+    /// `dummy_check`, so that unknown accounts aren't told apart either.
     fn is_token_valid(&self, token: &str) -> bool {
         // Try to split the token, barf if a bad format is found.
         let split = token.split('-').collect::<Vec<&str>>();
@@ -155,28 +191,64 @@ pub trait OneTimeUseTokenGenerator {
                     return false;
                 }
 
-                // A bit kludgy, but it works fine.
-                let timeout = match env::var("PASSWORD_RESET_TIMEOUT") {
-                    Ok(v) => {
-                        if let Ok(t) = v.parse::<usize>() {
-                            t
-                        } else {
-                            259200
-                        }
-                    }
-
-                    Err(_) => 259200,
-                };
-
+                // Timestamps from the future can't be ours.
                 let since = num_seconds() as usize;
-                if (since - ts) > timeout {
-                    return false;
-                }
-
-                return true;
+                return ts <= since && since - ts <= password_reset_timeout();
             }
         }
 
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Holder;
+
+    impl OneTimeUseTokenGenerator for Holder {
+        fn hash_value(&self) -> String {
+            "1holder@example.com".to_string()
+        }
+    }
+
+    fn token_at(ts: i64) -> String {
+        env::set_var("SECRET_KEY", "token-tests-secret-key");
+        hash(&Holder.hash_value(), ts as u64).unwrap().to_string()
+    }
+
+    #[test]
+    fn fresh_tokens_are_valid() {
+        let token = token_at(num_seconds());
+        assert!(Holder.is_token_valid(&token));
+    }
+
+    #[test]
+    fn tampered_tokens_are_refused() {
+        let token = token_at(num_seconds());
+        let last = token.chars().last().unwrap();
+        let tampered = format!("{}{}", &token[..token.len() - 1], if last == '0' { '1' } else { '0' });
+        assert!(!Holder.is_token_valid(&tampered));
+        assert!(!Holder.is_token_valid("not-a-token-at-all"));
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        let token = token_at(num_seconds() - password_reset_timeout() as i64 - 60);
+        assert!(!Holder.is_token_valid(&token));
+    }
+
+    #[test]
+    fn tokens_from_the_future_are_refused() {
+        let token = token_at(num_seconds() + 3600);
+        assert!(!Holder.is_token_valid(&token));
+    }
+
+    #[test]
+    fn dummy_checks_always_fail() {
+        let token = token_at(num_seconds());
+        assert!(!dummy_check(&token));
+        assert!(!dummy_check("garbage"));
+    }
+}
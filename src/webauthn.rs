@@ -0,0 +1,179 @@
+//! Passkey (WebAuthn) registration and login, with the `webauthn`
+//! feature.
+//!
+//! Each ceremony takes two requests: one for the challenge the browser
+//! passes to the authenticator, and one with the authenticator's answer.
+//! In between, the ceremony's state is kept in a private cookie, as the
+//! OAuth flow is.
+//!
+//! The relying party is the app at `JELLY_DOMAIN`: passkeys are bound to
+//! its host name, so they stop working if it changes.
+
+use std::env;
+
+use anyhow::anyhow;
+use rocket::fairing::AdHoc;
+use rocket::http::{Cookie, CookieJar};
+use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::{
+    AuthenticationResult, CreationChallengeResponse, CredentialID, Passkey, PasskeyAuthentication,
+    PasskeyRegistration, PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse,
+    Url, Uuid, Webauthn, WebauthnBuilder,
+};
+
+use crate::config::AppConfig;
+use crate::cookies::CookieScope;
+
+/// Private cookie holding a `Registration` between begin and finish.
+const REGISTRATION_COOKIE: &str = "webauthn_registration";
+
+/// Private cookie holding an `Authentication` between begin and finish.
+const AUTHENTICATION_COOKIE: &str = "webauthn_authentication";
+
+/// The name shown in authenticators' prompts when the email branding has
+/// no `app_name`.
+const DEFAULT_RP_NAME: &str = "Rocket Starter App";
+
+#[derive(Serialize, Deserialize)]
+struct Registration {
+    account_id: i32,
+    user_handle: Uuid,
+    state: PasskeyRegistration,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Authentication {
+    account_id: i32,
+    state: PasskeyAuthentication,
+}
+
+/// The relying party, as managed state, and the scope of the cookies
+/// holding ceremony state.
+pub struct Passkeys {
+    webauthn: Webauthn,
+    scope: CookieScope,
+}
+
+impl Passkeys {
+    // TODO: Use Figment for configuration.
+    pub fn from_env(name: &str, scope: CookieScope) -> anyhow::Result<Self> {
+        let domain = env::var("JELLY_DOMAIN").map_err(|_| anyhow!("JELLY_DOMAIN is not set"))?;
+        let origin = Url::parse(&domain)?;
+        let id = origin.host_str()
+            .ok_or_else(|| anyhow!("JELLY_DOMAIN {:?} has no host", domain))?
+            .to_string();
+
+        let webauthn = WebauthnBuilder::new(&id, &origin)?
+            .rp_name(name)
+            .build()?;
+        Ok(Passkeys { webauthn, scope })
+    }
+
+    /// The challenge for registering a new passkey to an account. Its
+    /// existing passkeys are excluded, so an authenticator can't register
+    /// twice.
+    pub fn start_registration(
+        &self,
+        cookies: &CookieJar<'_>,
+        account_id: i32,
+        user_handle: Uuid,
+        email: &str,
+        name: &str,
+        existing: Vec<CredentialID>,
+    ) -> anyhow::Result<CreationChallengeResponse> {
+        let exclude = if existing.is_empty() { None } else { Some(existing) };
+        let (challenge, state) = self.webauthn.start_passkey_registration(user_handle, email, name, exclude)?;
+
+        let registration = Registration { account_id, user_handle, state };
+        cookies.add_private(self.scope.scoped(Cookie::new(REGISTRATION_COOKIE, serde_json::to_string(&registration)?)));
+        Ok(challenge)
+    }
+
+    /// Checks the authenticator's answer to the challenge given to the
+    /// same account, and returns the new passkey and the user handle it
+    /// was registered with.
+    pub fn finish_registration(
+        &self,
+        cookies: &CookieJar<'_>,
+        account_id: i32,
+        credential: &RegisterPublicKeyCredential,
+    ) -> anyhow::Result<(Uuid, Passkey)> {
+        let registration: Registration = take_cookie(cookies, &self.scope, REGISTRATION_COOKIE)?;
+        if registration.account_id != account_id {
+            return Err(anyhow!("passkey registration was started by another account"));
+        }
+
+        let passkey = self.webauthn.finish_passkey_registration(credential, &registration.state)?;
+        Ok((registration.user_handle, passkey))
+    }
+
+    /// The challenge for logging in to an account with one of its passkeys.
+    pub fn start_authentication(
+        &self,
+        cookies: &CookieJar<'_>,
+        account_id: i32,
+        passkeys: &[Passkey],
+    ) -> anyhow::Result<RequestChallengeResponse> {
+        let (challenge, state) = self.webauthn.start_passkey_authentication(passkeys)?;
+
+        let authentication = Authentication { account_id, state };
+        cookies.add_private(self.scope.scoped(Cookie::new(AUTHENTICATION_COOKIE, serde_json::to_string(&authentication)?)));
+        Ok(challenge)
+    }
+
+    /// Checks the authenticator's answer to the login challenge, and
+    /// returns the account it logs in to.
+    pub fn finish_authentication(
+        &self,
+        cookies: &CookieJar<'_>,
+        credential: &PublicKeyCredential,
+    ) -> anyhow::Result<(i32, AuthenticationResult)> {
+        let authentication: Authentication = take_cookie(cookies, &self.scope, AUTHENTICATION_COOKIE)?;
+        let result = self.webauthn.finish_passkey_authentication(credential, &authentication.state)?;
+        Ok((authentication.account_id, result))
+    }
+}
+
+/// Reads and removes a ceremony's state, so each challenge is answered
+/// at most once.
+fn take_cookie<T: for<'de> Deserialize<'de>>(
+    cookies: &CookieJar<'_>,
+    scope: &CookieScope,
+    name: &str,
+) -> anyhow::Result<T> {
+    let cookie = cookies.get_private(name).ok_or_else(|| anyhow!("no passkey ceremony in progress"))?;
+    cookies.remove_private(scope.scoped(Cookie::named(name.to_string())));
+    Ok(serde_json::from_str(cookie.value())?)
+}
+
+/// How a credential id is stored, in `webauthn_credentials.credential_id`.
+pub fn credential_id(id: &CredentialID) -> String {
+    base64_url::encode(&id.0)
+}
+
+/// Converts between the `Uuid` stored in the database and webauthn's.
+pub fn user_handle(handle: &sqlx::types::Uuid) -> Uuid {
+    Uuid::from_bytes(*handle.as_bytes())
+}
+
+pub fn stored_user_handle(handle: &Uuid) -> sqlx::types::Uuid {
+    sqlx::types::Uuid::from_bytes(*handle.as_bytes())
+}
+
+/// Manages the `Passkeys`, named after the email branding's `app_name`.
+/// Launch is aborted if `JELLY_DOMAIN` isn't a usable origin.
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Passkeys", |rocket| async move {
+        let config = rocket.state::<AppConfig>().cloned().unwrap_or_default();
+        let name = config.email_branding.app_name.clone()
+            .unwrap_or_else(|| DEFAULT_RP_NAME.to_string());
+
+        match Passkeys::from_env(&name, config.cookies) {
+            Ok(passkeys) => Ok(rocket.manage(passkeys)),
+            Err(e) => {
+                rocket::error!("Could not set up passkeys: {}", e);
+                Err(rocket)
+            }
+        }
+    })
+}